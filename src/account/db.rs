@@ -69,6 +69,34 @@ impl Db {
             .get_string(AccountDbColumn::General.into(), "description".as_bytes())
     }
 
+    pub fn save_first_index(&mut self, first_index: u64) -> Result<(), CloudError> {
+        self.db.save(
+            AccountDbColumn::General.into(),
+            "first_index".as_bytes(),
+            &first_index,
+        )
+    }
+
+    pub fn get_first_index(&self) -> Result<Option<u64>, CloudError> {
+        self.db
+            .get(AccountDbColumn::General.into(), "first_index".as_bytes())
+    }
+
+    pub fn save_skipped_indices(&mut self, indices: Vec<u64>) -> Result<(), CloudError> {
+        self.db.save(
+            AccountDbColumn::General.into(),
+            "skipped_indices".as_bytes(),
+            &indices,
+        )
+    }
+
+    pub fn get_skipped_indices(&self) -> Result<Vec<u64>, CloudError> {
+        Ok(self
+            .db
+            .get(AccountDbColumn::General.into(), "skipped_indices".as_bytes())?
+            .unwrap_or_default())
+    }
+
     pub fn save_memos<'a, I>(&mut self, memos: I) -> Result<(), CloudError> 
     where
         I: Iterator<Item = &'a DecMemo>,
@@ -1,11 +1,13 @@
+use std::{fmt, str::FromStr};
+
 use libzkbob_rs::{libzeropool::{fawkes_crypto::ff_uint::Num, native::account::Account}, address::format_address};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
-use crate::{web3::cached::TxWeb3Info, Fr, helpers::AsU64Amount, PoolParams};
+use crate::{web3::cached::TxWeb3Info, Fr, helpers::AsU64Amount, PoolParams, errors::CloudError};
 
 use super::tx_parser::DecMemo;
 
-#[derive(Serialize, PartialEq, Clone)]
+#[derive(Serialize, Deserialize, PartialEq, Clone)]
 pub enum HistoryTxType {
     Deposit,
     Withdrawal,
@@ -14,6 +16,45 @@ pub enum HistoryTxType {
     ReturnedChange,
     AggregateNotes,
     DirectDeposit,
+    FeeOnly,
+    Pending,
+}
+
+// The names are the same as in serialized records
+impl FromStr for HistoryTxType {
+    type Err = CloudError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "Deposit" => Ok(HistoryTxType::Deposit),
+            "Withdrawal" => Ok(HistoryTxType::Withdrawal),
+            "TransferIn" => Ok(HistoryTxType::TransferIn),
+            "TransferOut" => Ok(HistoryTxType::TransferOut),
+            "ReturnedChange" => Ok(HistoryTxType::ReturnedChange),
+            "AggregateNotes" => Ok(HistoryTxType::AggregateNotes),
+            "DirectDeposit" => Ok(HistoryTxType::DirectDeposit),
+            "FeeOnly" => Ok(HistoryTxType::FeeOnly),
+            "Pending" => Ok(HistoryTxType::Pending),
+            _ => Err(CloudError::BadRequest(format!("unknown tx type: {}", s))),
+        }
+    }
+}
+
+impl fmt::Display for HistoryTxType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            HistoryTxType::Deposit => "Deposit",
+            HistoryTxType::Withdrawal => "Withdrawal",
+            HistoryTxType::TransferIn => "TransferIn",
+            HistoryTxType::TransferOut => "TransferOut",
+            HistoryTxType::ReturnedChange => "ReturnedChange",
+            HistoryTxType::AggregateNotes => "AggregateNotes",
+            HistoryTxType::DirectDeposit => "DirectDeposit",
+            HistoryTxType::FeeOnly => "FeeOnly",
+            HistoryTxType::Pending => "Pending",
+        };
+        write!(f, "{}", name)
+    }
 }
 
 pub struct HistoryTx {
@@ -23,6 +64,87 @@ pub struct HistoryTx {
     pub amount: u64,
     pub fee: u64,
     pub to: Option<String>,
+    pub change: Option<u64>,
+}
+
+#[derive(Default)]
+pub struct HistoryFilter {
+    // inclusive bounds of the record timestamp
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+    // any type matches if it is empty
+    pub tx_types: Vec<HistoryTxType>,
+}
+
+impl HistoryFilter {
+    pub fn is_empty(&self) -> bool {
+        self.from.is_none() && self.to.is_none() && self.tx_types.is_empty()
+    }
+
+    // Pending records have no timestamp yet, so they don't match any range
+    pub fn matches(&self, tx: &HistoryTx) -> bool {
+        self.matches_type_and_time(&tx.tx_type, tx.timestamp)
+    }
+
+    pub fn matches_type_and_time(&self, tx_type: &HistoryTxType, timestamp: u64) -> bool {
+        let in_range = match (self.from, self.to) {
+            (None, None) => true,
+            (from, to) => {
+                *tx_type != HistoryTxType::Pending
+                    && from.map(|from| timestamp >= from).unwrap_or(true)
+                    && to.map(|to| timestamp <= to).unwrap_or(true)
+            }
+        };
+        in_range && (self.tx_types.is_empty() || self.tx_types.contains(tx_type))
+    }
+
+    // all records of one transaction have the same timestamp
+    pub fn is_before_range(&self, records: &[HistoryTx]) -> bool {
+        match (self.from, records.first()) {
+            (Some(from), Some(tx)) => tx.tx_type != HistoryTxType::Pending && tx.timestamp < from,
+            _ => false,
+        }
+    }
+}
+
+// Position of a matching transaction relative to the requested page
+#[derive(Debug, PartialEq)]
+pub enum PageSlot {
+    BeforePage,
+    InPage,
+    AfterPage,
+}
+
+// Counts matching transactions, newest first, to place them relative to the page
+pub struct PageWindow {
+    offset: usize,
+    limit: usize,
+    matched: usize,
+}
+
+impl PageWindow {
+    pub fn new(offset: usize, limit: usize) -> Self {
+        PageWindow { offset, limit, matched: 0 }
+    }
+
+    pub fn next(&mut self) -> PageSlot {
+        self.matched += 1;
+        if self.matched <= self.offset {
+            PageSlot::BeforePage
+        } else if self.matched <= self.offset.saturating_add(self.limit) {
+            PageSlot::InPage
+        } else {
+            PageSlot::AfterPage
+        }
+    }
+
+    pub fn matched(&self) -> usize {
+        self.matched
+    }
+
+    pub fn has_more(&self) -> bool {
+        self.matched > self.offset.saturating_add(self.limit)
+    }
 }
 
 impl HistoryTx {
@@ -38,6 +160,7 @@ impl HistoryTx {
                     amount: token_amount as u64, 
                     fee, 
                     to: None, 
+                    change: None,
                 });
             }
             TxWeb3Info::DepositPermittable(timestamp, fee, token_amount) => {
@@ -48,17 +171,48 @@ impl HistoryTx {
                     amount: token_amount as u64, 
                     fee, 
                     to: None,  
+                    change: None,
                 });
             }
             TxWeb3Info::Transfer(timestamp, fee, _) => {
+                let previous_amount = match last_account {
+                    Some(acc) => *acc.b.as_num(),
+                    None => Num::ZERO,
+                };
+
+                let balance_increase = memo.acc.map(|acc| {
+                    let current_amount = *acc.b.as_num();
+                    if current_amount.to_uint() > previous_amount.to_uint() {
+                        (current_amount - previous_amount).as_u64_amount()
+                    } else {
+                        0
+                    }
+                });
+                // a transaction spends the whole account balance and the input notes, the rest after
+                // the outputs and the fee is the new balance. It is attached once per transaction
+                let mut change = memo.acc.map(|acc| acc.b.as_num().as_u64_amount());
+
+                let moves_value = memo
+                    .out_notes
+                    .iter()
+                    .any(|out_note| out_note.note.b.to_num() != Num::ZERO);
+
+                // the sender paid the fee but neither moved value nor increased its balance
+                if !moves_value && balance_increase == Some(0) {
+                    history.push(HistoryTx { 
+                        tx_type: HistoryTxType::FeeOnly, 
+                        tx_hash, 
+                        timestamp, 
+                        amount: 0, 
+                        fee, 
+                        to: None, 
+                        change: None,
+                    });
+                    return history;
+                }
+
                 if memo.in_notes.is_empty() && memo.out_notes.is_empty() {
-                    let amount = {
-                        let previous_amount = match last_account {
-                            Some(acc) => *acc.b.as_num(),
-                            None => Num::ZERO,
-                        };
-                        memo.acc.unwrap().b.as_num() - previous_amount
-                    };
+                    let amount = memo.acc.unwrap().b.as_num() - previous_amount;
 
                     history.push(HistoryTx { 
                         tx_type: HistoryTxType::AggregateNotes, 
@@ -67,9 +221,36 @@ impl HistoryTx {
                         amount: amount.as_u64_amount(), 
                         fee, 
                         to: None, 
+                        change: None,
                     });
                 }
 
+                // every output goes back to the sender, the whole transaction is a single record
+                let self_transfer = memo.acc.is_some()
+                    && !memo.out_notes.is_empty()
+                    && memo.out_notes.iter().all(|out_note| {
+                        memo.in_notes.iter().any(|in_note| in_note.index == out_note.index)
+                    });
+                if self_transfer {
+                    let amount = memo
+                        .out_notes
+                        .iter()
+                        .map(|out_note| out_note.note.b.to_num().as_u64_amount())
+                        .sum();
+                    let note = &memo.out_notes[0].note;
+
+                    history.push(HistoryTx { 
+                        tx_type: HistoryTxType::ReturnedChange, 
+                        tx_hash, 
+                        timestamp, 
+                        amount, 
+                        fee, 
+                        to: Some(format_address::<PoolParams>(note.d, note.p_d)), 
+                        change,
+                    });
+                    return history;
+                }
+
                 for note in memo.in_notes.iter() {
                     let loopback = memo
                         .out_notes
@@ -91,6 +272,7 @@ impl HistoryTx {
                         amount: note.note.b.to_num().as_u64_amount(), 
                         fee, 
                         to: Some(address), 
+                        change: None,
                     });
                 }
 
@@ -99,6 +281,7 @@ impl HistoryTx {
                         .in_notes
                         .iter().any(|in_note| in_note.index == out_note.index)                        
                 });
+
                 for note in out_notes {
                     let address =
                         format_address::<PoolParams>(note.note.d, note.note.p_d);
@@ -110,6 +293,7 @@ impl HistoryTx {
                         amount: note.note.b.to_num().as_u64_amount(), 
                         fee, 
                         to: Some(address), 
+                        change: change.take(),
                     });
                 }
             }
@@ -121,6 +305,7 @@ impl HistoryTx {
                     amount: (-(fee as i128 + token_amount)) as u64, 
                     fee, 
                     to: None, 
+                    change: None,
                 });
             },
             TxWeb3Info::DirectDeposit(timestamp, fee) => {
@@ -135,10 +320,210 @@ impl HistoryTx {
                         amount: note.note.b.to_num().as_u64_amount(), 
                         fee,
                         to: Some(address), 
+                        change: None,
                     });
                 }
             }
+            TxWeb3Info::Pending => {
+                // timestamp and fee are known only from the transaction itself, they are filled in
+                // once the web3 node catches up
+                let amount = match memo.acc {
+                    Some(_) => memo
+                        .out_notes
+                        .iter()
+                        .filter(|out_note| !memo.in_notes.iter().any(|in_note| in_note.index == out_note.index))
+                        .map(|out_note| out_note.note.b.to_num().as_u64_amount())
+                        .sum(),
+                    None => memo
+                        .in_notes
+                        .iter()
+                        .map(|in_note| in_note.note.b.to_num().as_u64_amount())
+                        .sum(),
+                };
+
+                history.push(HistoryTx { 
+                    tx_type: HistoryTxType::Pending, 
+                    tx_hash, 
+                    timestamp: 0, 
+                    amount, 
+                    fee: 0, 
+                    to: None, 
+                    change: None,
+                });
+            }
         };
         history
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use libzkbob_rs::libzeropool::{
+        fawkes_crypto::ff_uint::NumRepr,
+        native::{boundednum::BoundedNum, note::Note},
+    };
+
+    use super::*;
+    use crate::account::tx_parser::IndexedNote;
+
+    const FEE: u64 = 5;
+
+    fn num(value: u64) -> Num<Fr> {
+        Num::from_uint_reduced(NumRepr::from(value))
+    }
+
+    fn account(balance: u64) -> Account<Fr> {
+        Account {
+            d: BoundedNum::new(num(1)),
+            p_d: num(2),
+            i: BoundedNum::new(num(0)),
+            b: BoundedNum::new(num(balance)),
+            e: BoundedNum::new(num(0)),
+        }
+    }
+
+    fn note(index: u64, amount: u64) -> IndexedNote {
+        IndexedNote {
+            index,
+            note: Note {
+                d: BoundedNum::new(num(3)),
+                p_d: num(4),
+                b: BoundedNum::new(num(amount)),
+                t: BoundedNum::new(num(0)),
+            },
+        }
+    }
+
+    fn transfer(new_balance: u64, out_notes: Vec<IndexedNote>) -> DecMemo {
+        DecMemo {
+            index: 128,
+            acc: Some(account(new_balance)),
+            in_notes: vec![],
+            out_notes,
+            tx_hash: Some("0x01".to_string()),
+        }
+    }
+
+    fn parse(memo: DecMemo, previous_balance: u64) -> Vec<HistoryTx> {
+        HistoryTx::parse(memo, TxWeb3Info::Transfer(1, FEE, 0), Some(account(previous_balance)))
+    }
+
+    // the outputs, the fee and the change add up to the spent balance and notes
+    fn spent(history: &[HistoryTx]) -> u64 {
+        let amount: u64 = history.iter().map(|tx| tx.amount).sum();
+        let change: u64 = history.iter().filter_map(|tx| tx.change).sum();
+        amount + FEE + change
+    }
+
+    #[test]
+    fn change_of_single_recipient_transfer() {
+        // 100 on the balance, 30 sent
+        let history = parse(transfer(65, vec![note(129, 30)]), 100);
+        assert_eq!(history.len(), 1);
+        assert!(history[0].tx_type == HistoryTxType::TransferOut);
+        assert_eq!(history[0].change, Some(65));
+        assert_eq!(spent(&history), 100);
+    }
+
+    #[test]
+    fn change_of_multi_recipient_transfer_is_reported_once() {
+        let history = parse(transfer(45, vec![note(129, 30), note(130, 20)]), 100);
+        assert_eq!(history.len(), 2);
+        assert_eq!(history.iter().filter(|tx| tx.change.is_some()).count(), 1);
+        assert_eq!(spent(&history), 100);
+    }
+
+    #[test]
+    fn change_includes_spent_notes() {
+        // 10 on the balance and 90 in the spent notes
+        let history = parse(transfer(65, vec![note(129, 30)]), 10);
+        assert_eq!(history[0].change, Some(65));
+        assert_eq!(spent(&history), 10 + 90);
+    }
+
+    #[test]
+    fn transfer_of_everything_has_no_change() {
+        let history = parse(transfer(0, vec![note(129, 95)]), 100);
+        assert_eq!(history[0].change, Some(0));
+        assert_eq!(spent(&history), 100);
+    }
+
+    #[test]
+    fn zero_amount_transfer_is_fee_only() {
+        let history = parse(transfer(95, vec![note(129, 0)]), 100);
+        assert_eq!(history.len(), 1);
+        assert!(history[0].tx_type == HistoryTxType::FeeOnly);
+        assert_eq!(history[0].amount, 0);
+        assert_eq!(history[0].fee, FEE);
+        assert!(history[0].to.is_none());
+
+        // without any outputs
+        let history = parse(transfer(95, vec![]), 100);
+        assert_eq!(history.len(), 1);
+        assert!(history[0].tx_type == HistoryTxType::FeeOnly);
+    }
+
+    #[test]
+    fn aggregation_is_not_fee_only() {
+        let history = parse(transfer(125, vec![]), 100);
+        assert_eq!(history.len(), 1);
+        assert!(history[0].tx_type == HistoryTxType::AggregateNotes);
+        assert_eq!(history[0].amount, 25);
+    }
+
+    #[test]
+    fn self_transfer_is_a_single_returned_change_record() {
+        let memo = DecMemo {
+            in_notes: vec![note(129, 30)],
+            ..transfer(65, vec![note(129, 30)])
+        };
+        let history = parse(memo, 100);
+        assert_eq!(history.len(), 1);
+        assert!(history[0].tx_type == HistoryTxType::ReturnedChange);
+        assert_eq!(history[0].amount, 30);
+        assert_eq!(history[0].fee, FEE);
+        assert_eq!(history[0].change, Some(65));
+        assert_eq!(spent(&history), 100);
+    }
+
+    #[test]
+    fn self_transfer_with_several_outputs_sums_them() {
+        let memo = DecMemo {
+            in_notes: vec![note(129, 30), note(130, 20)],
+            ..transfer(45, vec![note(129, 30), note(130, 20)])
+        };
+        let history = parse(memo, 100);
+        assert_eq!(history.len(), 1);
+        assert!(history[0].tx_type == HistoryTxType::ReturnedChange);
+        assert_eq!(history[0].amount, 50);
+        assert_eq!(spent(&history), 100);
+    }
+
+    #[test]
+    fn window_places_matches_around_the_page() {
+        let mut window = PageWindow::new(1, 2);
+        assert_eq!(window.next(), PageSlot::BeforePage);
+        assert_eq!(window.next(), PageSlot::InPage);
+        assert_eq!(window.next(), PageSlot::InPage);
+        assert!(!window.has_more());
+        assert_eq!(window.next(), PageSlot::AfterPage);
+        assert!(window.has_more());
+        assert_eq!(window.matched(), 4);
+    }
+
+    #[test]
+    fn window_without_enough_matches_has_no_more() {
+        let mut window = PageWindow::new(0, 10);
+        for _ in 0..3 {
+            assert_eq!(window.next(), PageSlot::InPage);
+        }
+        assert!(!window.has_more());
+        assert_eq!(window.matched(), 3);
+    }
+
+    #[test]
+    fn window_with_zero_limit() {
+        let mut window = PageWindow::new(0, 0);
+        assert_eq!(window.next(), PageSlot::AfterPage);
+        assert!(window.has_more());
+    }
+}
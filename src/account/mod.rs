@@ -1,32 +1,43 @@
-use std::panic::{self, AssertUnwindSafe};
+use std::{collections::HashSet, panic::{self, AssertUnwindSafe}};
 
 use libzkbob_rs::{
     client::{state::State, UserAccount, TxOutput, TokenAmount, TxType, TransactionData, StateFragment},
     libzeropool::{
         fawkes_crypto::{ff_uint::{Num, NumRepr}, rand::Rng, BorshSerialize},
         POOL_PARAMS, constants,
-        native::account::Account as NativeAccount,
+        native::{account::Account as NativeAccount, boundednum::BoundedNum, key::derive_key_p_d},
     },
+    address::{format_address, parse_address},
     random::CustomRng
 };
-use tokio::sync::RwLock;
+use tokio::{sync::{RwLock, Mutex, MutexGuard}, task};
 use uuid::Uuid;
 
-use crate::{errors::CloudError, Database, Fr, PoolParams, helpers::AsU64Amount, relayer::cached::CachedRelayerClient, web3::cached::CachedWeb3Client};
+use crate::{errors::CloudError, Database, Fr, PoolParams, helpers::{AsU64Amount, key_cipher::KeyCipher}, relayer::cached::{CachedRelayerClient, Transaction}, web3::cached::{CachedWeb3Client, TxWeb3Info}};
 
-use self::{db::Db, types::AccountInfo, tx_parser::ParseResult, history::HistoryTx};
+use self::{db::Db, types::{AccountInfo, ReservedFunds, PlannedTx}, tx_parser::ParseResult, history::{HistoryTx, HistoryFilter, PageWindow, PageSlot}};
 
 pub mod types;
 pub mod history;
 mod tx_parser;
 mod db;
 
+// number of leafs and commitments applied under one state lock
+const UPDATE_BATCH_SIZE: usize = 1000;
+// only the latest indices of skipped transactions are kept
+const MAX_SKIPPED_INDICES: usize = 1000;
+
 pub struct Account {
     pub id: Uuid,
     pub description: String,
+    pub first_index: u64,
+    // apply transactions that fail to parse as commitments only instead of failing the sync
+    pub skip_parse_errors: bool,
 
     db: RwLock<Db>,
     inner: RwLock<UserAccount<Database, PoolParams>>,
+    // state updates are applied in batches, it keeps batches of different updates from interleaving
+    update_lock: Mutex<()>,
 }
 
 impl Account {
@@ -36,6 +47,8 @@ impl Account {
         sk: Option<Vec<u8>>,
         pool_id: Num<Fr>,
         db_path: &str,
+        first_index: Option<u64>,
+        key_cipher: &KeyCipher,
     ) -> Result<Self, CloudError> {
         let mut db = Db::new(db_path)?;
         let state = State::new(db.tree()?, db.txs()?);
@@ -46,34 +59,49 @@ impl Account {
         });
         let inner = UserAccount::from_seed(&sk, pool_id, state, POOL_PARAMS.clone());
 
-        db.save_sk(&sk)?;
+        db.save_sk(&key_cipher.encrypt(&sk)?)?;
         db.save_description(&description)?;
+        if let Some(first_index) = first_index {
+            db.save_first_index(first_index)?;
+        }
 
         Ok(Self {
             id,
             description,
+            first_index: first_index.unwrap_or(0),
+            skip_parse_errors: false,
             db: RwLock::new(db),
             inner: RwLock::new(inner),
+            update_lock: Mutex::new(()),
         })
     }
 
-    pub fn load(id: Uuid, pool_id: Num<Fr>, db_path: &str) -> Result<Self, CloudError> {
-        let db = Db::new(db_path)?;
+    pub fn load(id: Uuid, pool_id: Num<Fr>, db_path: &str, key_cipher: &KeyCipher) -> Result<Self, CloudError> {
+        let mut db = Db::new(db_path)?;
         let state = State::new(db.tree()?, db.txs()?);
 
-        let sk = db
+        let stored_sk = db
             .get_sk()?
             .ok_or(CloudError::InternalError("failed to get sk".to_string()))?;
+        let sk = key_cipher.decrypt(&stored_sk)?;
+        if key_cipher.enabled() && !KeyCipher::is_encrypted(&stored_sk) {
+            db.save_sk(&key_cipher.encrypt(&sk)?)?;
+        }
         let description = db.get_description()?.ok_or(CloudError::InternalError(
             "failed to get description".to_string(),
         ))?;
 
+        let first_index = db.get_first_index()?.unwrap_or(0);
+
         let inner = UserAccount::from_seed(&sk, pool_id, state, POOL_PARAMS.clone());
         Ok(Self {
             id,
             description,
+            first_index,
+            skip_parse_errors: false,
             db: RwLock::new(db),
             inner: RwLock::new(inner),
+            update_lock: Mutex::new(()),
         })
     }
 
@@ -90,7 +118,8 @@ impl Account {
         inner.state.tree.next_index()
     }
 
-    pub async fn info(&self, fee: u64) -> AccountInfo {
+    // The maximum transfer amount excludes funds reserved by in-flight transfers
+    pub async fn info(&self, fee: u64, reserved: &ReservedFunds) -> AccountInfo {
         let balance = {
             self.inner.read().await.state.total_balance().as_u64_amount()
         };
@@ -99,48 +128,145 @@ impl Account {
             id: self.id.to_string(),
             description: self.description.clone(),
             balance,
-            max_transfer_amount: self.max_transfer_amount(fee).await,
-            address: self.generate_address().await,
+            max_transfer_amount: self.max_transfer_amount(fee, reserved).await,
+            address: self.default_address().await,
+            pending_balance: None,
+            spendable_balance: None,
+            degraded: false,
+            last_sync_error: None,
         }
     }
 
+    // Finds an unspent note sent to the address, it is used to match notes with events observed on chain
+    pub async fn find_note(
+        &self,
+        address: &str,
+        amount: u64,
+        from_index: u64,
+        exclude: &HashSet<u64>,
+    ) -> Option<u64> {
+        let inner = self.inner.read().await;
+        inner
+            .state
+            .get_usable_notes()
+            .into_iter()
+            .find(|(index, note)| {
+                *index >= from_index
+                    && !exclude.contains(index)
+                    && note.b.to_num().as_u64_amount() == amount
+                    && format_address::<PoolParams>(note.d, note.p_d) == address
+            })
+            .map(|(index, _)| index)
+    }
+
+    // The address reported by account info, it is indexed when the account is created
+    pub async fn default_address(&self) -> String {
+        self.generate_address_at(0).await
+    }
+
     pub async fn generate_address(&self) -> String {
         let inner = self.inner.read().await;
         inner.generate_address()
     }
 
+    // Deterministic address: the diversifier is derived from the index
+    pub async fn generate_address_at(&self, index: u64) -> String {
+        let inner = self.inner.read().await;
+        let d = BoundedNum::new(Num::from_uint_reduced(NumRepr::from(index)));
+        let p_d = derive_key_p_d(d.to_num(), inner.keys.eta, &inner.params).x;
+        format_address::<PoolParams>(d, p_d)
+    }
+
+    // Total value of the notes that are not spent yet
+    pub async fn notes_value(&self, indices: &[u64]) -> u64 {
+        let notes = self.inner.read().await.state.get_usable_notes();
+        notes
+            .into_iter()
+            .filter(|(index, _)| indices.contains(index))
+            .fold(Num::ZERO, |total, (_, note)| total + note.b.as_num())
+            .as_u64_amount()
+    }
+
+    // Outputs are packed by constants::OUT into dependent payment parts,
+    // all funds are collected before the first of them
     pub async fn get_tx_parts(
         &self,
-        total_amount: u64,
+        outputs: &[(String, u64)],
         fee: u64,
-        to: &str,
-    ) -> Result<Vec<(Option<String>, Num<Fr>)>, CloudError> {
+        reserved: &ReservedFunds,
+    ) -> Result<(Vec<PlannedTx>, ReservedFunds), CloudError> {
+        // amounts are u64 on chain, so the sum must not overflow before it is converted to Num
+        let total_amount = outputs
+            .iter()
+            .try_fold(0u64, |total, (_, amount)| total.checked_add(*amount))
+            .ok_or_else(amount_overflow)?;
+        let payments = outputs
+            .chunks(constants::OUT)
+            .map(|outputs| {
+                let outputs = outputs
+                    .iter()
+                    .map(|(to, amount)| (to.clone(), Num::from_uint_reduced(NumRepr::from(*amount))))
+                    .collect::<Vec<_>>();
+                let amount = outputs.iter().fold(Num::ZERO, |total, (_, amount)| total + *amount);
+                PlannedTx { outputs, amount }
+            })
+            .collect::<Vec<_>>();
+        let payments_fee = fee.checked_mul(payments.len() as u64).ok_or_else(amount_overflow)?;
+        let amount_with_fee = total_amount.checked_add(payments_fee).ok_or_else(amount_overflow)?;
+
         let account = self.inner.read().await;
-        let amount = Num::from_uint_reduced(NumRepr::from(total_amount));
+        let fee_amount = fee;
         let fee = Num::from_uint_reduced(NumRepr::from(fee));
+        // the fees of all payment parts are paid from the collected funds too
+        let required = Num::from_uint_reduced(NumRepr::from(amount_with_fee));
 
+        // funds reserved by in-flight transfers are not available for planning
+        let reserved_balance = Num::from_uint_reduced(NumRepr::from(reserved.balance));
         let mut account_balance = account.state.account_balance();
+        account_balance = if account_balance.to_uint() > reserved_balance.to_uint() {
+            account_balance - reserved_balance
+        } else {
+            Num::ZERO
+        };
         let mut parts = vec![];
+        let mut used = ReservedFunds::default();
 
-        if account_balance.to_uint() >= (amount + fee).to_uint() {
-            parts.push((Some(to.to_string()), amount));
-            return Ok(parts);
+        if account_balance.to_uint() >= required.to_uint() {
+            used.balance = amount_with_fee;
+            return Ok((payments, used));
         }
 
-        let notes = account.state.get_usable_notes();
+        let notes = account
+            .state
+            .get_usable_notes()
+            .into_iter()
+            .filter(|(index, _)| !reserved.notes.contains(index))
+            .collect::<Vec<_>>();
+        let notes = fee_covering_notes(notes, fee_amount, |note| note.b.to_num().as_u64_amount());
         let mut balance_is_sufficient = false;
+        let mut notes_total = Num::ZERO;
         for notes in notes.chunks(3) {
-            let mut note_balance = Num::ZERO;
-            for (_, note) in notes {
-                note_balance += note.b.as_num();
-            }
-
-            if (note_balance + account_balance).to_uint() >= (amount + fee).to_uint() {
-                parts.push((Some(to.to_string()), amount));
+            let note_balance = notes
+                .iter()
+                .fold(Num::ZERO, |total, (_, note)| total + note.b.as_num());
+
+            if (note_balance + account_balance).to_uint() >= required.to_uint() {
+                used.notes.extend(notes.iter().map(|(index, _)| *index));
+                notes_total += note_balance;
+                parts.extend(payments.iter().cloned());
                 balance_is_sufficient = true;
                 break;
             } else {
-                parts.push((None, note_balance - fee));
+                // only the last chunk can be short of the fee, aggregating it would decrease the balance
+                if note_balance.to_uint() < fee.to_uint() {
+                    break;
+                }
+                used.notes.extend(notes.iter().map(|(index, _)| *index));
+                notes_total += note_balance;
+                parts.push(PlannedTx {
+                    outputs: vec![],
+                    amount: note_balance - fee,
+                });
                 account_balance += note_balance - fee;
             }
         }
@@ -149,44 +275,111 @@ impl Account {
             return Err(CloudError::InsufficientBalance);
         }
 
-        Ok(parts)
+        // the part of the transfer that isn't covered by notes is paid from the account balance
+        let total = fee_amount
+            .checked_mul(parts.len() as u64)
+            .and_then(|total_fee| total_fee.checked_add(total_amount))
+            .ok_or_else(amount_overflow)?;
+        let total = Num::from_uint_reduced(NumRepr::from(total));
+        if total.to_uint() > notes_total.to_uint() {
+            used.balance = (total - notes_total).as_u64_amount();
+        }
+
+        Ok((parts, used))
     }
 
-    pub async fn sync(&self, relayer: &CachedRelayerClient, to_index: Option<u64>) -> Result<(), CloudError> {
+    // Returns the indices of transactions that failed to parse and were skipped
+    // `max_txs` limits the number of transactions applied by one call, so a long sync can be done
+    // in batches, each batch is saved before the next one is fetched
+    pub async fn sync(&self, relayer: &CachedRelayerClient, to_index: Option<u64>, max_txs: Option<u64>) -> Result<Vec<u64>, CloudError> {
         let account_index = self.next_index().await;
         let relayer_index = match to_index {
             Some(to_index) => to_index,
             None => relayer.info().await?.delta_index
         };
 
-        let limit = (relayer_index - account_index) / (constants::OUT as u64 + 1);
+        let limit = relayer.tx_count(account_index, relayer_index)?;
+        let limit = max_txs.map(|max_txs| limit.min(max_txs)).unwrap_or(limit);
+        if limit == 0 {
+            return Ok(vec![]);
+        }
         let txs = relayer.transactions(account_index, limit, false).await?;
-        let parse_result = {
-            let inner = self.inner.read().await;
-            tx_parser::parse_txs(txs, &inner.keys.eta, &inner.params)?
-        };
+        let parse_result = self.parse_txs(txs).await?;
+        let skipped_indices = parse_result.skipped_indices.clone();
         self.update_state(parse_result).await?;
-        Ok(())
+        Ok(skipped_indices)
     }
 
-    pub async fn create_transfer(&self, amount: Num<Fr>, to: Option<String>, fee: u64, relayer: &CachedRelayerClient) -> Result<TransactionData<Fr>, CloudError> {
-        let tx_outputs = match to {
-            Some(to) => {
-                vec![TxOutput {
-                    to,
-                    amount: TokenAmount::new(amount),
-                }]
+    // Syncs the account with transactions fetched in advance, e.g. once for many accounts. The transactions
+    // must be consecutive and start at or before the account index. Returns the number of applied transactions
+    pub async fn sync_with_txs(&self, txs: &[Transaction]) -> Result<usize, CloudError> {
+        let account_index = self.next_index().await;
+        let start = match txs.first() {
+            Some(first) if first.index <= account_index => {
+                ((account_index - first.index) / (constants::OUT as u64 + 1)) as usize
             }
-            None => vec![],
+            Some(first) => {
+                return Err(CloudError::InternalError(format!(
+                    "transactions start at index {} after the account index {}",
+                    first.index, account_index
+                )));
+            }
+            None => return Ok(0),
         };
+        if start >= txs.len() {
+            return Ok(0);
+        }
+        if txs[start].index != account_index {
+            return Err(CloudError::InternalError(format!(
+                "expected transaction at index {}, got {}",
+                account_index, txs[start].index
+            )));
+        }
+
+        let parse_result = self.parse_txs(txs[start..].to_vec()).await?;
+        self.update_state(parse_result).await?;
+        Ok(txs.len() - start)
+    }
+
+    // Transfer without outputs aggregates notes into the account balance
+    pub async fn create_transfer(&self, outputs: Vec<(String, Num<Fr>)>, fee: u64, relayer: &CachedRelayerClient) -> Result<TransactionData<Fr>, CloudError> {
+        let tx_outputs = outputs
+            .into_iter()
+            .map(|(to, amount)| TxOutput {
+                to,
+                amount: TokenAmount::new(amount),
+            })
+            .collect::<Vec<_>>();
         let fee = Num::from_uint_reduced(NumRepr::from(fee));
         let transfer = TxType::Transfer(TokenAmount::new(fee), vec![], tx_outputs);
-        
-        let extra_state = self.get_optimistic_state(relayer).await?;
+        self.create_tx(transfer, relayer).await
+    }
+
+    // `to` is a 0x prefixed L1 address, `native_amount` of the withdrawn amount is swapped to the native token
+    pub async fn create_withdrawal(&self, amount: Num<Fr>, native_amount: u64, to: &str, fee: u64, relayer: &CachedRelayerClient) -> Result<TransactionData<Fr>, CloudError> {
+        let to = parse_l1_address(to)?;
+        let fee = Num::from_uint_reduced(NumRepr::from(fee));
+        let native_amount = Num::from_uint_reduced(NumRepr::from(native_amount));
+        let withdrawal = TxType::Withdraw(
+            TokenAmount::new(fee),
+            vec![],
+            TokenAmount::new(amount),
+            to,
+            TokenAmount::new(native_amount),
+            TokenAmount::new(Num::ZERO),
+        );
+        self.create_tx(withdrawal, relayer).await
+    }
+
+    // A sync can't apply its batches meanwhile: the optimistic state is fetched from the synced
+    // index, so the tx must be built on the state that doesn't move after it is read
+    async fn create_tx(&self, tx_type: TxType<Fr>, relayer: &CachedRelayerClient) -> Result<TransactionData<Fr>, CloudError> {
+        let update = self.update_lock.lock().await;
+        let extra_state = self.get_optimistic_state(relayer, &update).await?;
         let account = self.inner.read().await;
         let tx = panic::catch_unwind(AssertUnwindSafe(|| {
             account
-                .create_tx(transfer, None, Some(extra_state))
+                .create_tx(tx_type, None, Some(extra_state))
                 .map_err(|e| CloudError::BadRequest(e.to_string()))
         }))
         .map_err(|_| {
@@ -196,38 +389,133 @@ impl Account {
         Ok(tx)
     }
 
-    pub async fn history(&self, web3: &CachedWeb3Client) -> Result<Vec<HistoryTx>, CloudError> {
+    // Hashes of all transactions of the account that are synced
+    pub async fn tx_hashes(&self) -> Result<HashSet<String>, CloudError> {
+        let memos = self.db.read().await.get_memos()?;
+        Ok(memos.into_iter().filter_map(|memo| memo.tx_hash).collect())
+    }
+
+    // Records of the account transactions that the relayer accepted but that are not mined yet,
+    // newest first. The account state is not changed
+    pub async fn optimistic_history(&self, relayer: &CachedRelayerClient) -> Result<Vec<HistoryTx>, CloudError> {
+        let account_index = self.next_index().await;
+        let relayer_index = relayer.get_info(true).await?.optimistic_delta_index;
+
+        let limit = relayer.tx_count(account_index, relayer_index)?;
+        if limit == 0 {
+            return Ok(vec![]);
+        }
+        let txs = relayer
+            .transactions(account_index, limit, true)
+            .await?
+            .into_iter()
+            .filter(|tx| tx.optimistic)
+            .collect();
+
+        let parse_result = self.parse_txs(txs).await?;
+        Ok(parse_result
+            .decrypted_memos
+            .into_iter()
+            .rev()
+            .flat_map(|memo| HistoryTx::parse(memo, TxWeb3Info::Pending, None))
+            .collect())
+    }
+
+    // With allow_pending, transactions that the web3 node doesn't see yet are returned as Pending records.
+    // Offset and limit count matching transactions newest first, one transaction can produce several records.
+    // Returns the records of the window, newest transaction first, the number of matching transactions
+    // and whether there are more of them after the window. With a filter the transactions are resolved
+    // only until the window is full, so the number counts the matching transactions seen up to then
+    pub async fn history(
+        &self,
+        web3: &CachedWeb3Client,
+        allow_pending: bool,
+        filter: &HistoryFilter,
+        offset: usize,
+        limit: usize,
+    ) -> Result<(Vec<HistoryTx>, usize, bool), CloudError> {
         let memos = {
             self.db.read().await.get_memos()?
         };
 
+        // the previous account is needed to parse a transaction
+        let mut last_accounts = Vec::with_capacity(memos.len());
         let mut last_account: Option<NativeAccount<Fr>> = None;
+        for memo in memos.iter() {
+            last_accounts.push(last_account);
+            if let Some(acc) = memo.acc {
+                last_account = Some(acc);
+            }
+        }
+
+        let mut window = PageWindow::new(offset, limit);
         let mut history = vec![];
-        for memo in memos {
+        for (memo, last_account) in memos.into_iter().zip(last_accounts).rev() {
+            // without a filter every transaction matches, so the ones outside of the window are not resolved
+            if filter.is_empty() && window.next() != PageSlot::InPage {
+                continue;
+            }
+
             let tx_hash = memo.tx_hash.as_ref().unwrap();
             let info = web3.get_web3_info(tx_hash).await?;
-            
-            let account = memo.acc;
-            history.append(&mut HistoryTx::parse(memo, info, last_account));
-
-            if let Some(acc) = account {
-                last_account = Some(acc);
+            if let TxWeb3Info::Pending = info {
+                if !allow_pending {
+                    return Err(CloudError::InternalError("transaction not found".to_string()));
+                }
             }
+            let records = HistoryTx::parse(memo, info, last_account);
+
+            let records = match filter.is_empty() {
+                true => records,
+                false => {
+                    // memos are ordered by time, so the rest of them are before the range too
+                    if filter.is_before_range(&records) {
+                        break;
+                    }
+                    let records = records
+                        .into_iter()
+                        .filter(|record| filter.matches(record))
+                        .collect::<Vec<_>>();
+                    if records.is_empty() {
+                        continue;
+                    }
+                    match window.next() {
+                        PageSlot::BeforePage => continue,
+                        PageSlot::InPage => records,
+                        // one match after the window is enough to know there are more
+                        PageSlot::AfterPage => break,
+                    }
+                }
+            };
+            history.push(records);
         }
-        Ok(history)
+        Ok((history.into_iter().flatten().collect(), window.matched(), window.has_more()))
     }
 
+    // Funds reserved by in-flight transfers are excluded the same way as in get_tx_parts
     pub async fn max_transfer_amount(
         &self,
         fee: u64,
+        reserved: &ReservedFunds,
     ) -> u64 {
         let fee = Num::from_uint_reduced(NumRepr::from(fee));
 
-        let (mut account_balance, notes) = {
+        let (account_balance, notes) = {
             let account = self.inner.read().await;
             (account.state.account_balance(), account.state.get_usable_notes())
         };
-        
+        let notes = notes
+            .into_iter()
+            .filter(|(index, _)| !reserved.notes.contains(index))
+            .collect::<Vec<_>>();
+        let notes = fee_covering_notes(notes, fee.as_u64_amount(), |note| note.b.to_num().as_u64_amount());
+        let reserved_balance = Num::from_uint_reduced(NumRepr::from(reserved.balance));
+        let mut account_balance = if account_balance.to_uint() > reserved_balance.to_uint() {
+            account_balance - reserved_balance
+        } else {
+            Num::ZERO
+        };
+
         let mut max_amount = if account_balance.to_uint() > fee.to_uint() {
             account_balance - fee
         } else {
@@ -253,26 +541,23 @@ impl Account {
         max_amount.as_u64_amount()
     }
 
-    async fn get_optimistic_state(&self, relayer: &CachedRelayerClient) -> Result<StateFragment<Fr>, CloudError> {
+    async fn get_optimistic_state(&self, relayer: &CachedRelayerClient, update: &MutexGuard<'_, ()>) -> Result<StateFragment<Fr>, CloudError> {
         let account_index = self.next_index().await;
-        let relayer_index = relayer.info().await?.optimistic_delta_index;
+        let relayer_index = relayer.get_info(true).await?.optimistic_delta_index;
 
-        let limit = (relayer_index - account_index) / (constants::OUT as u64 + 1);
-        let txs = relayer.transactions(account_index, limit, true).await?;
+        let limit = relayer.tx_count(account_index, relayer_index)?;
+        let txs = match limit {
+            0 => vec![],
+            limit => relayer.transactions(account_index, limit, true).await?,
+        };
         
         let (mined, pending): (Vec<_>, Vec<_>) = txs.into_iter().partition(|tx| !tx.optimistic);
         
         // update state with mined txs
-        let mined_parse_result = {
-            let inner = self.inner.read().await;
-            tx_parser::parse_txs(mined, &inner.keys.eta, &inner.params)?
-        };
-        self.update_state(mined_parse_result).await?;     
+        let mined_parse_result = self.parse_txs(mined).await?;
+        self.apply_update(update, mined_parse_result).await?;
 
-        let parse_result = {
-            let inner = self.inner.read().await;
-            tx_parser::parse_txs(pending, &inner.keys.eta, &inner.params)?
-        };
+        let parse_result = self.parse_txs(pending).await?;
 
         Ok(StateFragment { 
             new_leafs: parse_result.state_update.new_leafs, 
@@ -282,29 +567,347 @@ impl Account {
         })
     }
 
+    async fn parse_txs(&self, txs: Vec<Transaction>) -> Result<ParseResult, CloudError> {
+        let (skipped, txs): (Vec<_>, Vec<_>) = txs
+            .into_iter()
+            .partition(|tx| tx.index < self.first_index);
+
+        let mut parse_result = {
+            let inner = self.inner.read().await;
+            tx_parser::parse_txs(txs, &inner.keys.eta, &inner.params, self.skip_parse_errors)?
+        };
+        if !parse_result.skipped_indices.is_empty() {
+            tracing::warn!("account {} skipped transactions that failed to parse: {:?}", self.id, parse_result.skipped_indices);
+        }
+
+        if !skipped.is_empty() {
+            let mut new_commitments = tx_parser::commitments_only(skipped).state_update.new_commitments;
+            new_commitments.append(&mut parse_result.state_update.new_commitments);
+            parse_result.state_update.new_commitments = new_commitments;
+        }
+        Ok(parse_result)
+    }
+
     async fn update_state(&self, parse_result: ParseResult) -> Result<(), CloudError> {
-        let state_update = parse_result.state_update;
-        let mut inner = self.inner.write().await;
-        if !state_update.new_leafs.is_empty() || !state_update.new_commitments.is_empty() {
-            inner
-                .state
-                .tree
-                .add_leafs_and_commitments(state_update.new_leafs, state_update.new_commitments);
+        let update = self.update_lock.lock().await;
+        self.apply_update(&update, parse_result).await
+    }
+
+    // The update is applied in batches of transactions in index order, the write lock is released
+    // between batches, so readers aren't blocked for the whole initial sync. Readers see the state
+    // synced up to the last applied batch, accounts and notes are applied with their batch. It is
+    // a valid state of an earlier index, but a reader that needs the state to stay at the index
+    // it has read (e.g. to build a tx on top of it) must hold the update lock
+    async fn apply_update(&self, _update: &MutexGuard<'_, ()>, parse_result: ParseResult) -> Result<(), CloudError> {
+        let from_index = self.next_index().await;
+        let mut state_update = parse_result.state_update;
+        state_update.new_leafs.sort_by_key(|(index, _)| *index);
+        state_update.new_commitments.sort_by_key(|(index, _)| *index);
+        state_update.new_accounts.sort_by_key(|(index, _)| *index);
+        let mut new_notes = state_update.new_notes.into_iter().flatten().collect::<Vec<_>>();
+        new_notes.sort_by_key(|(index, _)| *index);
+
+        let mut leafs = state_update.new_leafs.into_iter().peekable();
+        let mut commitments = state_update.new_commitments.into_iter().peekable();
+        let mut accounts = state_update.new_accounts.into_iter().peekable();
+        let mut notes = new_notes.into_iter().peekable();
+        loop {
+            let mut batch_leafs = Vec::new();
+            let mut batch_commitments = Vec::new();
+            while batch_leafs.len() + batch_commitments.len() < UPDATE_BATCH_SIZE {
+                let take_leaf = match (leafs.peek(), commitments.peek()) {
+                    (Some((leaf_index, _)), Some((commitment_index, _))) => leaf_index < commitment_index,
+                    (Some(_), None) => true,
+                    (None, Some(_)) => false,
+                    (None, None) => break,
+                };
+                match take_leaf {
+                    true => batch_leafs.extend(leafs.next()),
+                    false => batch_commitments.extend(commitments.next()),
+                }
+            }
+
+            // accounts and notes belong to the transactions before the next batch
+            let next_index = match (leafs.peek(), commitments.peek()) {
+                (Some((leaf_index, _)), Some((commitment_index, _))) => *leaf_index.min(commitment_index),
+                (Some((index, _)), None) | (None, Some((index, _))) => *index,
+                (None, None) => u64::MAX,
+            };
+
+            {
+                let mut inner = self.inner.write().await;
+                if !batch_leafs.is_empty() || !batch_commitments.is_empty() {
+                    inner.state.tree.add_leafs_and_commitments(batch_leafs, batch_commitments);
+                }
+                while let Some((at_index, account)) = accounts.next_if(|(index, _)| *index < next_index) {
+                    inner.state.add_account(at_index, account);
+                }
+                while let Some((at_index, note)) = notes.next_if(|(index, _)| *index < next_index) {
+                    inner.state.add_note(at_index, note);
+                }
+            }
+
+            if next_index == u64::MAX {
+                break;
+            }
+            task::yield_now().await;
         }
 
-        state_update
-            .new_accounts
-            .into_iter()
-            .for_each(|(at_index, account)| {
-                inner.state.add_account(at_index, account);
-            });
-
-        state_update.new_notes.into_iter().for_each(|notes| {
-            notes.into_iter().for_each(|(at_index, note)| {
-                inner.state.add_note(at_index, note);
-            });
+        // skipped transactions are recorded on every path that applies transactions to the state
+        let mut db = self.db.write().await;
+        if !parse_result.skipped_indices.is_empty() {
+            let stored = db.get_skipped_indices()?;
+            let indices = merge_skipped_indices(stored, from_index, &parse_result.skipped_indices, MAX_SKIPPED_INDICES);
+            db.save_skipped_indices(indices)?;
+        }
+        db.save_memos(parse_result.decrypted_memos.iter())
+    }
+
+    // Indices of transactions that failed to parse and were skipped, the ones after the synced state
+    // are left from the state that was removed by a resync
+    pub async fn skipped_indices(&self) -> Result<Vec<u64>, CloudError> {
+        let next_index = self.next_index().await;
+        let indices = self.db.read().await.get_skipped_indices()?;
+        Ok(indices.into_iter().filter(|index| *index < next_index).collect())
+    }
+}
+
+// Stored indices from `from_index` on belong to a removed state and are replaced by the new ones
+fn merge_skipped_indices(stored: Vec<u64>, from_index: u64, skipped: &[u64], max: usize) -> Vec<u64> {
+    let mut indices = stored
+        .into_iter()
+        .filter(|index| *index < from_index)
+        .chain(skipped.iter().copied())
+        .collect::<Vec<_>>();
+    indices.sort_unstable();
+    indices.dedup();
+    let excess = indices.len().saturating_sub(max);
+    indices.drain(..excess);
+    indices
+}
+
+// Notes are aggregated by 3 and every aggregation pays the fee, so a note that doesn't cover
+// its share of the fee would decrease the balance, such dust notes are left unspent
+fn fee_covering_notes<T, F: Fn(&T) -> u64>(notes: Vec<(u64, T)>, fee: u64, value: F) -> Vec<(u64, T)> {
+    let fee_share = fee / 3 + u64::from(fee % 3 != 0);
+    notes
+        .into_iter()
+        .filter(|(_, note)| value(note) >= fee_share)
+        .collect()
+}
+
+fn amount_overflow() -> CloudError {
+    CloudError::BadRequest("amount with fee overflows u64".to_string())
+}
+
+pub fn validate_shielded_address(address: &str) -> Result<(), CloudError> {
+    parse_address::<PoolParams>(address)
+        .map(|_| ())
+        .map_err(|_| CloudError::BadRequest(format!("invalid shielded address: {}", address)))
+}
+
+pub fn parse_l1_address(address: &str) -> Result<Vec<u8>, CloudError> {
+    let bytes = address
+        .strip_prefix("0x")
+        .and_then(|address| hex::decode(address).ok())
+        .filter(|bytes| bytes.len() == 20);
+    bytes.ok_or_else(|| CloudError::BadRequest("invalid L1 address".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, sync::{atomic::{AtomicBool, Ordering}, Arc}, time::{Duration, Instant}};
+
+    use libzkbob_rs::libzeropool::fawkes_crypto::ff_uint::{Num, NumRepr};
+    use uuid::Uuid;
+
+    use crate::{cloud::account_cache::tests::new_account, errors::CloudError, helpers::tests::TestDir, relayer::cached::tests::test_relayer};
+
+    use super::{fee_covering_notes, merge_skipped_indices, parse_l1_address, validate_shielded_address, tx_parser::{ParseResult, StateUpdate}, UPDATE_BATCH_SIZE};
+
+    fn commitments(count: u64) -> ParseResult {
+        ParseResult {
+            state_update: StateUpdate {
+                new_commitments: (0..count)
+                    .map(|i| (i * 128, Num::from_uint_reduced(NumRepr::from(i + 1))))
+                    .collect(),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    #[actix_web::test]
+    async fn default_address_does_not_change() {
+        let dir = TestDir::new();
+        let account = new_account(&dir, Uuid::new_v4());
+        let address = account.default_address().await;
+        assert_eq!(account.default_address().await, address);
+        assert_eq!(account.generate_address_at(0).await, address);
+        assert_ne!(account.generate_address().await, address);
+    }
+
+    #[actix_web::test]
+    async fn sync_is_noop_at_relayer_index() {
+        let dir = TestDir::new();
+        let account = new_account(&dir, Uuid::new_v4());
+        account.update_state(commitments(2)).await.unwrap();
+
+        let relayer = test_relayer(&dir, 1024, 256).await;
+        assert_eq!(account.sync(&relayer, None, None).await.unwrap(), Vec::<u64>::new());
+        assert_eq!(account.sync(&relayer, Some(256), Some(10)).await.unwrap(), Vec::<u64>::new());
+        assert_eq!(account.next_index().await, 256);
+    }
+
+    #[actix_web::test]
+    async fn sync_is_noop_when_account_is_ahead_of_relayer() {
+        let dir = TestDir::new();
+        let account = new_account(&dir, Uuid::new_v4());
+        account.update_state(commitments(4)).await.unwrap();
+        assert_eq!(account.next_index().await, 512);
+
+        // transactions can't be fetched, the sync must not request them
+        let relayer = test_relayer(&dir, 1024, 256).await;
+        assert_eq!(account.sync(&relayer, None, None).await.unwrap(), Vec::<u64>::new());
+        assert_eq!(account.sync(&relayer, Some(0), None).await.unwrap(), Vec::<u64>::new());
+        assert_eq!(account.next_index().await, 512);
+
+        let relayer = test_relayer(&dir, 128, 256).await;
+        assert!(matches!(
+            account.sync(&relayer, None, None).await,
+            Err(CloudError::RelayerBehindLocalState(256, 512))
+        ));
+    }
+
+    #[test]
+    fn readers_wait_for_one_batch_at_most() {
+        const COMMITMENTS: u64 = 10_000;
+        let dir = TestDir::new();
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let account = Arc::new(new_account(&dir, Uuid::new_v4()));
+            let done = Arc::new(AtomicBool::new(false));
+            let updater = {
+                let (account, done) = (account.clone(), done.clone());
+                tokio::spawn(async move {
+                    let started_at = Instant::now();
+                    account.update_state(commitments(COMMITMENTS)).await.unwrap();
+                    done.store(true, Ordering::SeqCst);
+                    started_at.elapsed()
+                })
+            };
+
+            let mut max_wait = Duration::ZERO;
+            let mut seen = HashSet::new();
+            while !done.load(Ordering::SeqCst) {
+                let started_at = Instant::now();
+                let index = account.next_index().await;
+                max_wait = max_wait.max(started_at.elapsed());
+                seen.insert(index);
+                tokio::task::yield_now().await;
+            }
+            let total = updater.await.unwrap();
+
+            assert_eq!(account.next_index().await, COMMITMENTS * 128);
+            // the update is applied in several batches, a reader waits for one of them
+            let batches = (COMMITMENTS as usize + UPDATE_BATCH_SIZE - 1) / UPDATE_BATCH_SIZE;
+            assert!(batches > 2);
+            assert!(max_wait < total / 2, "read waited {:?} of {:?}", max_wait, total);
+            // readers see the states in between, each one is a prefix synced to a batch boundary
+            assert!(seen.iter().all(|index| index % (UPDATE_BATCH_SIZE as u64 * 128) == 0 || *index == COMMITMENTS * 128));
         });
+    }
+
+    #[actix_web::test]
+    async fn state_does_not_move_while_update_lock_is_held() {
+        let dir = TestDir::new();
+        let account = Arc::new(new_account(&dir, Uuid::new_v4()));
+        let update = account.update_lock.lock().await;
+        let updater = {
+            let account = account.clone();
+            tokio::spawn(async move { account.update_state(commitments(10)).await.unwrap() })
+        };
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(account.next_index().await, 0);
+
+        drop(update);
+        updater.await.unwrap();
+        assert_eq!(account.next_index().await, 10 * 128);
+    }
+
+    fn indices(notes: Vec<(u64, u64)>) -> Vec<u64> {
+        notes.into_iter().map(|(index, _)| index).collect()
+    }
+
+    #[test]
+    fn dust_notes_are_skipped() {
+        let notes = vec![(0, 100), (128, 33), (256, 34), (384, 0)];
+        // the fee share of 100 is 34
+        assert_eq!(indices(fee_covering_notes(notes, 100, |value| *value)), vec![0, 256]);
+    }
+
+    #[test]
+    fn exact_fee_share_is_kept() {
+        let notes = vec![(0, 30), (128, 29)];
+        assert_eq!(indices(fee_covering_notes(notes, 90, |value| *value)), vec![0]);
+    }
+
+    #[test]
+    fn full_chunk_of_kept_notes_covers_the_fee() {
+        let notes = vec![(0, 34), (128, 34), (256, 34), (384, 1)];
+        let notes = fee_covering_notes(notes, 100, |value| *value);
+        assert!(notes.chunks(3).next().unwrap().iter().map(|(_, value)| value).sum::<u64>() >= 100);
+        assert_eq!(notes.len(), 3);
+    }
+
+    #[test]
+    fn skipped_indices_are_merged_sorted_and_deduplicated() {
+        let indices = merge_skipped_indices(vec![128, 512], 1024, &[1024, 512], 10);
+        assert_eq!(indices, vec![128, 512, 1024]);
+    }
+
+    #[test]
+    fn skipped_indices_of_removed_state_are_dropped() {
+        let indices = merge_skipped_indices(vec![128, 512, 1024], 256, &[384], 10);
+        assert_eq!(indices, vec![128, 384]);
+    }
+
+    #[test]
+    fn only_latest_skipped_indices_are_kept() {
+        let indices = merge_skipped_indices(vec![0, 128, 256], 384, &[384, 512], 3);
+        assert_eq!(indices, vec![256, 384, 512]);
+    }
+
+    #[test]
+    fn zero_fee_keeps_every_note() {
+        let notes = vec![(0, 0), (128, 1)];
+        assert_eq!(indices(fee_covering_notes(notes, 0, |value| *value)), vec![0, 128]);
+    }
+
+    #[test]
+    fn shielded_address_is_validated() {
+        assert!(validate_shielded_address("NtYD4uisxHGXWXowLsXjBWMbLf9BFWtu4QwRZTGURFAGp8QhHc6E7jMp4V7UUc8").is_ok());
+        for address in [
+            "",
+            "dummy",
+            // the checksum doesn't match
+            "NtYD4uisxHGXWXowLsXjBWMbLf9BFWtu4QwRZTGURFAGp8QhHc6E7jMp4V7UUc9",
+            "NtYD4uisxHGXWXowLsXjBWMbLf9BFWtu4QwRZTGURFAGp8QhHc6E7jMp4V7UU",
+            // the pool prefix isn't supported
+            "zkbob_polygon:NtYD4uisxHGXWXowLsXjBWMbLf9BFWtu4QwRZTGURFAGp8QhHc6E7jMp4V7UUc8",
+            "0x1234567890123456789012345678901234567890",
+        ] {
+            assert!(validate_shielded_address(address).is_err(), "{}", address);
+        }
+    }
 
-        self.db.write().await.save_memos(parse_result.decrypted_memos.iter())
+    #[test]
+    fn l1_address_is_validated() {
+        assert_eq!(parse_l1_address("0x1234567890123456789012345678901234567890").unwrap().len(), 20);
+        assert!(parse_l1_address("1234567890123456789012345678901234567890").is_err());
+        assert!(parse_l1_address("0x12345678901234567890123456789012345678").is_err());
+        assert!(parse_l1_address("0x123456789012345678901234567890123456789z").is_err());
     }
 }
@@ -5,6 +5,8 @@ use rayon::prelude::{IntoParallelIterator, ParallelIterator};
 use serde::{Serialize, Deserialize};
 use thiserror::Error;
 
+use zkbob_utils_rs::tracing;
+
 use crate::{relayer::cached::Transaction, Fr, PoolParams, errors::CloudError};
 
 #[derive(Debug, Error)]
@@ -15,14 +17,14 @@ pub enum ParseError {
     IncorrectPrefix(u64, u32, u32),
 }
 
-// impl ParseError {
-//     pub fn index(&self) -> u64 {
-//         match *self {
-//             ParseError::NoPrefix(idx)  => idx,
-//             ParseError::IncorrectPrefix(idx,  _, _)  => idx,
-//         }
-//     }
-// }
+impl ParseError {
+    pub fn index(&self) -> u64 {
+        match *self {
+            ParseError::NoPrefix(idx)  => idx,
+            ParseError::IncorrectPrefix(idx,  _, _)  => idx,
+        }
+    }
+}
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct IndexedNote {
@@ -50,42 +52,80 @@ pub struct DecMemo {
 #[derive(Default, Debug)]
 pub struct ParseResult {
     pub decrypted_memos: Vec<DecMemo>,
-    pub state_update: StateUpdate
+    pub state_update: StateUpdate,
+    // indices of the transactions that failed to parse and were skipped
+    pub skipped_indices: Vec<u64>,
 }
 
-pub fn parse_txs(txs: Vec<Transaction>, eta: &Num<Fr>, params: &PoolParams) -> Result<ParseResult, CloudError> {
-    let (parse_results, parse_errors): (Vec<_>, Vec<_>) = txs.into_par_iter()
-        .map(|tx| -> Result<ParseResult, ParseError> {
-            parse_tx(tx, eta, params)
+// With skip_errors, transactions that fail to parse are applied as commitments only,
+// so the tree stays consistent with the pool, and their indices are returned in the result
+pub fn parse_txs(txs: Vec<Transaction>, eta: &Num<Fr>, params: &PoolParams, skip_errors: bool) -> Result<ParseResult, CloudError> {
+    let results: Vec<_> = txs.into_par_iter()
+        .map(|tx| -> Result<ParseResult, (ParseError, Num<Fr>)> {
+            let commitment = tx.commitment;
+            parse_tx(tx, eta, params).map_err(|err| (err, commitment))
         })
-        .partition(Result::is_ok);
+        .collect();
 
-    if parse_errors.is_empty() {
-        let parse_result = parse_results
-            .into_iter()
-            .map(Result::unwrap)
-            .fold(Default::default(), |acc: ParseResult, parse_result| {
-                ParseResult {
-                    decrypted_memos: vec![acc.decrypted_memos, parse_result.decrypted_memos].concat(),
-                    state_update: StateUpdate {
-                        new_leafs: vec![acc.state_update.new_leafs, parse_result.state_update.new_leafs].concat(),
-                        new_commitments: vec![acc.state_update.new_commitments, parse_result.state_update.new_commitments].concat(),
-                        new_accounts: vec![acc.state_update.new_accounts, parse_result.state_update.new_accounts].concat(),
-                        new_notes: vec![acc.state_update.new_notes, parse_result.state_update.new_notes].concat()
-                    }
+    let mut parse_results = Vec::with_capacity(results.len());
+    let mut all_errs = Vec::new();
+    for result in results {
+        match result {
+            Ok(parse_result) => parse_results.push(parse_result),
+            Err((err, commitment)) => {
+                tracing::error!("failed to parse transaction: {}", err);
+                all_errs.push(err.index());
+                if skip_errors {
+                    parse_results.push(commitments_only_at(err.index(), commitment));
                 }
-        });
-        Ok(parse_result)
-    } else {
-        // let errors: Vec<_> = parse_errors
-        //     .into_iter()
-        //     .map(|err| -> ParseError {
-        //         let err = err.unwrap_err();
-        //         err
-        //     })
-        //     .collect();
-        //let all_errs: Vec<u64> = errors.into_iter().map(|err| err.index()).collect();
-        Err(CloudError::StateSyncError)
+            }
+        }
+    }
+
+    if !all_errs.is_empty() && !skip_errors {
+        all_errs.sort_unstable();
+        return Err(CloudError::StateSyncError(all_errs));
+    }
+
+    let parse_result = parse_results
+        .into_iter()
+        .fold(Default::default(), |acc: ParseResult, parse_result| {
+            ParseResult {
+                decrypted_memos: vec![acc.decrypted_memos, parse_result.decrypted_memos].concat(),
+                state_update: StateUpdate {
+                    new_leafs: vec![acc.state_update.new_leafs, parse_result.state_update.new_leafs].concat(),
+                    new_commitments: vec![acc.state_update.new_commitments, parse_result.state_update.new_commitments].concat(),
+                    new_accounts: vec![acc.state_update.new_accounts, parse_result.state_update.new_accounts].concat(),
+                    new_notes: vec![acc.state_update.new_notes, parse_result.state_update.new_notes].concat()
+                },
+                skipped_indices: vec![],
+            }
+    });
+    Ok(ParseResult {
+        skipped_indices: all_errs,
+        ..parse_result
+    })
+}
+
+fn commitments_only_at(index: u64, commitment: Num<Fr>) -> ParseResult {
+    ParseResult {
+        state_update: StateUpdate {
+            new_commitments: vec![(index, commitment)],
+            ..Default::default()
+        },
+        ..Default::default()
+    }
+}
+
+// Only commitments are needed to keep the tree root consistent with the pool,
+// memos below the sync hint index are not decrypted
+pub fn commitments_only(txs: Vec<Transaction>) -> ParseResult {
+    ParseResult {
+        state_update: StateUpdate {
+            new_commitments: txs.into_iter().map(|tx| (tx.index, tx.commitment)).collect(),
+            ..Default::default()
+        },
+        ..Default::default()
     }
 }
 
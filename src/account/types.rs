@@ -1,6 +1,11 @@
+use std::collections::HashSet;
+
+use libzkbob_rs::libzeropool::fawkes_crypto::ff_uint::Num;
 use serde::{Serialize, Deserialize};
 
-#[derive(Serialize, Deserialize)]
+use crate::Fr;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct AccountInfo {
     pub id: String,
@@ -8,4 +13,26 @@ pub struct AccountInfo {
     pub balance: u64,
     pub max_transfer_amount: u64,
     pub address: String,
-}
\ No newline at end of file
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pending_balance: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub spendable_balance: Option<u64>,
+    #[serde(default)]
+    pub degraded: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_sync_error: Option<String>,
+}
+
+#[derive(Default, Clone, Debug)]
+pub struct ReservedFunds {
+    pub notes: HashSet<u64>,
+    pub balance: u64,
+}
+
+// A planned transaction, note aggregation has no outputs
+#[derive(Clone, Debug)]
+pub struct PlannedTx {
+    pub outputs: Vec<(String, Num<Fr>)>,
+    // total amount of the outputs or the aggregated amount
+    pub amount: Num<Fr>,
+}
@@ -0,0 +1,227 @@
+use reqwest::{Client, RequestBuilder, StatusCode};
+use serde::{de::DeserializeOwned, Deserialize};
+
+use crate::{
+    account::types::AccountInfo,
+    errors::CloudError,
+    routes::SUPPORT_ID_HEADER,
+    types::{
+        AccountInfoRequest, CalculateFeeRequest, CalculateFeeResponse, ExportKeyResponse,
+        GenerateAddressResponse, HistoryRequest, HistoryResponse, SignupRequest, SignupResponse,
+        TransactionStatusRequest, TransactionStatusResponse, TransferRequest, TransferResponse,
+    },
+};
+
+// Typed client for other services, it uses the same request and response types as the routes
+pub struct CloudClient {
+    client: Client,
+    url: String,
+    token: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ErrorResponse {
+    error: String,
+    // older services don't return it
+    #[serde(default)]
+    code: Option<String>,
+}
+
+impl CloudClient {
+    pub fn new(url: &str, token: Option<String>) -> Self {
+        CloudClient {
+            client: Client::new(),
+            url: url.trim_end_matches('/').to_string(),
+            token,
+        }
+    }
+
+    pub async fn signup(&self, request: &SignupRequest) -> Result<SignupResponse, CloudError> {
+        self.send(self.client.post(self.endpoint("/signup")).json(request)).await
+    }
+
+    pub async fn account_info(&self, id: &str) -> Result<AccountInfo, CloudError> {
+        let query = AccountInfoRequest { id: id.to_string() };
+        self.send(self.client.get(self.endpoint("/account")).query(&query)).await
+    }
+
+    pub async fn generate_address(&self, id: &str) -> Result<GenerateAddressResponse, CloudError> {
+        let query = AccountInfoRequest { id: id.to_string() };
+        self.send(self.client.get(self.endpoint("/generateAddress")).query(&query)).await
+    }
+
+    pub async fn history(&self, request: &HistoryRequest) -> Result<HistoryResponse, CloudError> {
+        self.send(self.client.get(self.endpoint("/history")).query(request)).await
+    }
+
+    pub async fn calculate_fee(&self, request: &CalculateFeeRequest) -> Result<CalculateFeeResponse, CloudError> {
+        self.send(self.client.get(self.endpoint("/calculateFee")).query(request)).await
+    }
+
+    pub async fn transfer(
+        &self,
+        request: &TransferRequest,
+        support_id: Option<&str>,
+    ) -> Result<TransferResponse, CloudError> {
+        let mut builder = self.client.post(self.endpoint("/transfer")).json(request);
+        if let Some(support_id) = support_id {
+            builder = builder.header(SUPPORT_ID_HEADER, support_id);
+        }
+        self.send(builder).await
+    }
+
+    pub async fn transaction_status(&self, transaction_id: &str) -> Result<TransactionStatusResponse, CloudError> {
+        let query = TransactionStatusRequest {
+            transaction_id: transaction_id.to_string(),
+        };
+        self.send(self.client.get(self.endpoint("/transactionStatus")).query(&query)).await
+    }
+
+    pub async fn export_key(&self, id: &str) -> Result<ExportKeyResponse, CloudError> {
+        let query = AccountInfoRequest { id: id.to_string() };
+        self.send(self.client.get(self.endpoint("/export")).query(&query)).await
+    }
+
+    fn endpoint(&self, path: &str) -> String {
+        format!("{}{}", self.url, path)
+    }
+
+    async fn send<R: DeserializeOwned>(&self, builder: RequestBuilder) -> Result<R, CloudError> {
+        let builder = match &self.token {
+            Some(token) => builder.bearer_auth(token),
+            None => builder,
+        };
+
+        let response = builder
+            .send()
+            .await
+            .map_err(|err| CloudError::InternalError(format!("request failed: {}", err)))?;
+
+        let status = response.status();
+        if status.is_success() {
+            return response
+                .json()
+                .await
+                .map_err(|err| CloudError::InternalError(format!("failed to parse response: {}", err)));
+        }
+
+        // the service returns errors as {"error": message, "code": code}, unit variants are restored
+        // from the code, the other ones keep the code and the message
+        let (message, code) = response
+            .json::<ErrorResponse>()
+            .await
+            .map(|response| (response.error, response.code))
+            .unwrap_or_else(|_| (status.to_string(), None));
+        if let Some(code) = code {
+            return Err(CloudError::restore(code, message));
+        }
+        Err(match status {
+            StatusCode::UNAUTHORIZED => CloudError::AccessDenied,
+            StatusCode::BAD_REQUEST => CloudError::BadRequest(message),
+            _ => CloudError::InternalError(message),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{web::{get, post, Json, Query}, App, HttpResponse, HttpServer};
+    use actix_web_httpauth::extractors::bearer::BearerAuth;
+
+    use crate::{
+        errors::CloudError,
+        types::{CalculateFeeRequest, SignupRequest, SignupResponse, TransactionStatusRequest, TransactionStatusResponse},
+    };
+
+    use super::CloudClient;
+
+    const TOKEN: &str = "token";
+
+    // The handlers return the same types and error envelope as the routes
+    async fn signup(request: Json<SignupRequest>, bearer: BearerAuth) -> Result<HttpResponse, CloudError> {
+        if bearer.token() != TOKEN {
+            return Err(CloudError::AccessDenied);
+        }
+        Ok(HttpResponse::Ok().json(SignupResponse {
+            account_id: request.0.id.unwrap_or_default(),
+            warning: request.0.first_index.map(|index| format!("first index {}", index)),
+        }))
+    }
+
+    async fn transaction_status(request: Query<TransactionStatusRequest>) -> Result<HttpResponse, CloudError> {
+        if request.transaction_id != "known" {
+            return Err(CloudError::TransactionNotFound);
+        }
+        Ok(HttpResponse::Ok().json(TransactionStatusResponse {
+            status: "Done".to_string(),
+            timestamp: 1,
+            tx_hash: Some("0x1".to_string()),
+            linked_tx_hashes: None,
+            failure_reason: None,
+            pool_index: Some(128),
+        }))
+    }
+
+    async fn calculate_fee(_request: Query<CalculateFeeRequest>) -> Result<HttpResponse, CloudError> {
+        Err(CloudError::FieldTooLong("to".to_string(), 10))
+    }
+
+    async fn start() -> String {
+        let server = HttpServer::new(|| {
+            App::new()
+                .route("/signup", post().to(signup))
+                .route("/transactionStatus", get().to(transaction_status))
+                .route("/calculateFee", get().to(calculate_fee))
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let addr = server.addrs()[0];
+        actix_web::rt::spawn(server.run());
+        format!("http://{}/", addr)
+    }
+
+    fn signup_request() -> SignupRequest {
+        SignupRequest {
+            id: Some("e7da526d-3f46-4f10-adf9-0f4fa9bb15ab".to_string()),
+            description: "Bob".to_string(),
+            sk: None,
+            first_index: Some(256),
+            attributes: Default::default(),
+        }
+    }
+
+    #[actix_web::test]
+    async fn typed_responses_are_returned() {
+        let client = CloudClient::new(&start().await, Some(TOKEN.to_string()));
+
+        let response = client.signup(&signup_request()).await.unwrap();
+        assert_eq!(response.account_id, "e7da526d-3f46-4f10-adf9-0f4fa9bb15ab");
+        assert_eq!(response.warning.as_deref(), Some("first index 256"));
+
+        let status = client.transaction_status("known").await.unwrap();
+        assert_eq!(status.status, "Done");
+        assert_eq!(status.pool_index, Some(128));
+    }
+
+    #[actix_web::test]
+    async fn error_envelope_is_mapped_to_cloud_error() {
+        let url = start().await;
+
+        let client = CloudClient::new(&url, Some("wrong".to_string()));
+        assert!(matches!(client.signup(&signup_request()).await, Err(CloudError::AccessDenied)));
+
+        let client = CloudClient::new(&url, Some(TOKEN.to_string()));
+        assert!(matches!(client.transaction_status("unknown").await, Err(CloudError::TransactionNotFound)));
+
+        // errors with a payload keep the code and the message
+        let request = CalculateFeeRequest { account_id: "1".to_string(), amount: 1, to: None };
+        match client.calculate_fee(&request).await {
+            Err(CloudError::StoredError(code, message)) => {
+                assert_eq!(code, "FieldTooLong");
+                assert!(message.contains("to"));
+            }
+            result => panic!("unexpected result {:?}", result.map(|_| ())),
+        }
+    }
+}
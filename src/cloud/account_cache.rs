@@ -0,0 +1,434 @@
+use std::{collections::{HashMap, HashSet}, future::Future, sync::{atomic::{AtomicUsize, Ordering}, Arc, Mutex as StdMutex}};
+
+use tokio::sync::{Mutex, OwnedMutexGuard, RwLock};
+use uuid::Uuid;
+use zkbob_utils_rs::tracing;
+
+use crate::{account::Account, errors::CloudError};
+
+use super::cleanup::AccountCleanup;
+
+struct CachedAccount {
+    account: Arc<Account>,
+    last_used: u64,
+    // handles given out by get, each one is released by its AccountCleanup
+    in_use: Arc<AtomicUsize>,
+}
+
+impl CachedAccount {
+    fn is_busy(&self) -> bool {
+        self.in_use.load(Ordering::SeqCst) > 0
+    }
+}
+
+// Loaded accounts, idle ones are kept up to the capacity and evicted in least recently used order.
+// An account is busy while requests hold its handle, busy accounts are never evicted:
+// a reload would create a second instance next to the one in use. The cache is trimmed when
+// an account is inserted, so it can exceed the capacity until the next load if all accounts were busy
+pub(crate) struct AccountCache {
+    capacity: usize,
+    accounts: HashMap<Uuid, CachedAccount>,
+    // incremented on each access, orders the accounts by their last use
+    clock: u64,
+    // accounts that are rebuilt and can't be used meanwhile
+    blocked: HashSet<Uuid>,
+}
+
+impl AccountCache {
+    pub fn new(capacity: usize) -> Self {
+        AccountCache {
+            capacity,
+            accounts: HashMap::new(),
+            clock: 0,
+            blocked: HashSet::new(),
+        }
+    }
+
+    // The account is in use until the returned cleanup is dropped
+    pub fn get(&mut self, id: Uuid) -> Option<(Arc<Account>, AccountCleanup)> {
+        self.clock += 1;
+        let clock = self.clock;
+        self.accounts.get_mut(&id).map(|cached| {
+            cached.last_used = clock;
+            (cached.account.clone(), AccountCleanup::new(cached.in_use.clone()))
+        })
+    }
+
+    // The inserted account is in use until the returned cleanup is dropped, so the trim keeps it
+    pub fn insert(&mut self, id: Uuid, account: Arc<Account>) -> AccountCleanup {
+        self.clock += 1;
+        let in_use = Arc::new(AtomicUsize::new(0));
+        let cleanup = AccountCleanup::new(in_use.clone());
+        self.accounts.insert(id, CachedAccount { account, last_used: self.clock, in_use });
+        self.trim();
+        cleanup
+    }
+
+    pub fn is_busy(&self, id: Uuid) -> bool {
+        self.accounts.get(&id).map(CachedAccount::is_busy).unwrap_or(false)
+    }
+
+    // Removes an idle account, so it is reloaded on next access
+    pub fn evict(&mut self, id: Uuid) -> Result<(), CloudError> {
+        if self.is_busy(id) {
+            return Err(CloudError::AccountIsBusy);
+        }
+        self.accounts.remove(&id);
+        Ok(())
+    }
+
+    // Evicts the least recently used idle accounts until the cache fits the capacity
+    fn trim(&mut self) {
+        while self.accounts.len() > self.capacity {
+            let lru = self
+                .accounts
+                .iter()
+                .filter(|(_, cached)| !cached.is_busy())
+                .min_by_key(|(_, cached)| cached.last_used)
+                .map(|(id, _)| *id);
+            match lru {
+                Some(id) => {
+                    self.accounts.remove(&id);
+                    tracing::debug!("account {} is evicted from cache", id);
+                }
+                // the busy ones are evicted by the following inserts once they are released
+                None => break,
+            }
+        }
+    }
+
+    pub fn block(&mut self, id: Uuid) {
+        self.blocked.insert(id);
+    }
+
+    pub fn unblock(&mut self, id: Uuid) {
+        self.blocked.remove(&id);
+    }
+
+    pub fn is_blocked(&self, id: Uuid) -> bool {
+        self.blocked.contains(&id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.accounts.len()
+    }
+
+    pub fn busy(&self) -> usize {
+        self.accounts.values().filter(|cached| cached.is_busy()).count()
+    }
+}
+
+// Returns the loaded account or loads it. A cold account is looked up again and inserted under
+// its load lock, so concurrent callers load it once. Requests to an account that is being
+// resynced fail instead of waiting for the resync
+pub(crate) async fn get_or_load<F, Fut>(
+    accounts: &RwLock<AccountCache>,
+    locks: &AccountLocks,
+    id: Uuid,
+    load: F,
+) -> Result<(Arc<Account>, AccountCleanup), CloudError>
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = Result<Account, CloudError>>,
+{
+    if let Some(account) = cached(accounts, id).await? {
+        return Ok(account);
+    }
+
+    let _lock = locks.lock(id).await;
+    if let Some(account) = cached(accounts, id).await? {
+        return Ok(account);
+    }
+    let account = Arc::new(load().await?);
+    let cleanup = accounts.write().await.insert(id, account.clone());
+    Ok((account, cleanup))
+}
+
+async fn cached(accounts: &RwLock<AccountCache>, id: Uuid) -> Result<Option<(Arc<Account>, AccountCleanup)>, CloudError> {
+    let mut accounts = accounts.write().await;
+    if accounts.is_blocked(id) {
+        return Err(CloudError::AccountIsBusy);
+    }
+    Ok(accounts.get(id))
+}
+
+// Per account locks that serialize loading, so an account db is never opened twice
+// while the cache itself is locked only to look up and insert accounts
+pub(crate) struct AccountLocks {
+    locks: Arc<StdMutex<HashMap<Uuid, Arc<Mutex<()>>>>>,
+}
+
+impl AccountLocks {
+    pub fn new() -> Self {
+        AccountLocks {
+            locks: Arc::new(StdMutex::new(HashMap::new())),
+        }
+    }
+
+    pub async fn lock(&self, id: Uuid) -> AccountLockGuard {
+        let lock = {
+            let mut locks = self.locks.lock().expect("account locks are poisoned");
+            locks.entry(id).or_default().clone()
+        };
+        AccountLockGuard {
+            id,
+            locks: self.locks.clone(),
+            guard: Some(lock.lock_owned().await),
+        }
+    }
+}
+
+pub(crate) struct AccountLockGuard {
+    id: Uuid,
+    locks: Arc<StdMutex<HashMap<Uuid, Arc<Mutex<()>>>>>,
+    guard: Option<OwnedMutexGuard<()>>,
+}
+
+impl Drop for AccountLockGuard {
+    fn drop(&mut self) {
+        self.guard.take();
+        // the lock is kept while other callers wait for it
+        if let Ok(mut locks) = self.locks.lock() {
+            if locks.get(&self.id).map(|lock| Arc::strong_count(lock) == 1).unwrap_or(false) {
+                locks.remove(&self.id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{sync::{atomic::{AtomicUsize, Ordering}, Arc}, time::Duration};
+
+    use libzkbob_rs::libzeropool::fawkes_crypto::ff_uint::Num;
+    use tokio::{sync::RwLock, time};
+    use uuid::Uuid;
+
+    use crate::{account::Account, config::KeyEncryptionConfig, errors::CloudError, helpers::{key_cipher::KeyCipher, tests::TestDir}};
+
+    use super::{get_or_load, AccountCache, AccountLocks};
+
+    pub(crate) fn new_account(dir: &TestDir, id: Uuid) -> Account {
+        let cipher = KeyCipher::new(&KeyEncryptionConfig { enabled: false, master_key: None, backup_key: None }).unwrap();
+        Account::new(id, "test".to_string(), None, Num::ZERO, &dir.db_path(), None, &cipher).unwrap()
+    }
+
+    pub(crate) fn test_account(dir: &TestDir) -> (Uuid, Arc<Account>) {
+        let id = Uuid::new_v4();
+        (id, Arc::new(new_account(dir, id)))
+    }
+
+    fn held(cache: &AccountCache) -> Vec<bool> {
+        let mut ids = cache.accounts.iter().map(|(id, cached)| (*id, cached.is_busy())).collect::<Vec<_>>();
+        ids.sort();
+        ids.into_iter().map(|(_, busy)| busy).collect()
+    }
+
+    #[test]
+    fn test_idle_account_is_evicted() {
+        let dir = TestDir::new();
+        let mut cache = AccountCache::new(10);
+        let (id, account) = test_account(&dir);
+        cache.insert(id, account);
+        assert!(cache.evict(id).is_ok());
+        assert_eq!(cache.len(), 0);
+        assert!(cache.get(id).is_none());
+    }
+
+    #[test]
+    fn test_busy_account_is_not_evicted() {
+        let dir = TestDir::new();
+        let mut cache = AccountCache::new(10);
+        let (id, account) = test_account(&dir);
+        cache.insert(id, account);
+        let (_, first) = cache.get(id).unwrap();
+        let (_, second) = cache.get(id).unwrap();
+        assert!(matches!(cache.evict(id), Err(CloudError::AccountIsBusy)));
+        assert_eq!(cache.busy(), 1);
+
+        drop(first);
+        assert!(matches!(cache.evict(id), Err(CloudError::AccountIsBusy)));
+        drop(second);
+        assert_eq!(cache.busy(), 0);
+        assert!(cache.evict(id).is_ok());
+        assert_eq!(cache.len(), 0);
+    }
+
+    #[test]
+    fn test_released_account_is_evicted_by_next_insert() {
+        let dir = TestDir::new();
+        let mut cache = AccountCache::new(0);
+        let (a, account) = test_account(&dir);
+        drop(cache.insert(a, account));
+        // releasing the account doesn't trim the cache
+        assert_eq!(cache.len(), 1);
+
+        let (b, account) = test_account(&dir);
+        let _b = cache.insert(b, account);
+        assert!(cache.get(a).is_none());
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn test_insert_evicts_least_recently_used_idle_accounts() {
+        let dir = TestDir::new();
+        let mut cache = AccountCache::new(2);
+        let (a, account) = test_account(&dir);
+        cache.insert(a, account);
+        let (b, account) = test_account(&dir);
+        cache.insert(b, account);
+        let (_, in_use) = cache.get(a).unwrap();
+        cache.get(b);
+
+        // a is used before b but it is busy, so b is evicted
+        let (c, account) = test_account(&dir);
+        cache.insert(c, account);
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get(b).is_none());
+
+        // all accounts are busy, the cache exceeds the capacity until they are released
+        let (_, c_in_use) = cache.get(c).unwrap();
+        let (d, account) = test_account(&dir);
+        let d_in_use = cache.insert(d, account);
+        assert_eq!(cache.len(), 3);
+        assert_eq!(held(&cache), vec![true; 3]);
+
+        // lookups and releases never trim, the next insert does
+        drop((in_use, c_in_use, d_in_use));
+        assert!(cache.get(a).is_some());
+        assert_eq!(cache.len(), 3);
+        let (e, account) = test_account(&dir);
+        let _e_in_use = cache.insert(e, account);
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get(a).is_some());
+        assert!(cache.get(e).is_some());
+    }
+
+    #[actix_web::test]
+    async fn test_concurrent_callers_load_account_once() {
+        let dir = Arc::new(TestDir::new());
+        let accounts = Arc::new(RwLock::new(AccountCache::new(10)));
+        let locks = Arc::new(AccountLocks::new());
+        let loads = Arc::new(AtomicUsize::new(0));
+        let id = Uuid::new_v4();
+
+        let handles = (0..20)
+            .map(|_| {
+                let (accounts, locks, loads, dir) = (accounts.clone(), locks.clone(), loads.clone(), dir.clone());
+                tokio::spawn(async move {
+                    get_or_load(&accounts, &locks, id, || async {
+                        loads.fetch_add(1, Ordering::SeqCst);
+                        // the slow load lets the other callers reach the lock meanwhile
+                        time::sleep(Duration::from_millis(20)).await;
+                        Ok(new_account(&dir, id))
+                    })
+                    .await
+                    .unwrap()
+                })
+            })
+            .collect::<Vec<_>>();
+        let mut loaded = Vec::new();
+        for handle in handles {
+            loaded.push(handle.await.unwrap());
+        }
+
+        assert_eq!(loads.load(Ordering::SeqCst), 1);
+        assert!(loaded.iter().all(|account| Arc::ptr_eq(account, &loaded[0])));
+        assert_eq!(accounts.read().await.len(), 1);
+    }
+
+    #[actix_web::test]
+    async fn test_failed_load_is_not_cached() {
+        let dir = TestDir::new();
+        let accounts = RwLock::new(AccountCache::new(10));
+        let locks = AccountLocks::new();
+        let id = Uuid::new_v4();
+
+        let result = get_or_load(&accounts, &locks, id, || async { Err(CloudError::AccountNotFound) }).await;
+        assert!(matches!(result, Err(CloudError::AccountNotFound)));
+        assert_eq!(accounts.read().await.len(), 0);
+
+        accounts.write().await.block(id);
+        let result = get_or_load(&accounts, &locks, id, || async { Ok(new_account(&dir, id)) }).await;
+        assert!(matches!(result, Err(CloudError::AccountIsBusy)));
+    }
+
+    #[actix_web::test]
+    async fn test_accounts_in_use_are_never_evicted() {
+        let dir = Arc::new(TestDir::new());
+        let accounts = Arc::new(RwLock::new(AccountCache::new(2)));
+        let locks = Arc::new(AccountLocks::new());
+        let ids = (0..6).map(|_| Uuid::new_v4()).collect::<Vec<_>>();
+        let loads = Arc::new(AtomicUsize::new(0));
+
+        let handles = (0..24)
+            .map(|task| {
+                let (accounts, locks, loads, ids, dir) = (accounts.clone(), locks.clone(), loads.clone(), ids.clone(), dir.clone());
+                tokio::spawn(async move {
+                    for round in 0..10 {
+                        let id = ids[(task + round) % ids.len()];
+                        let (account, cleanup) = get_or_load(&accounts, &locks, id, || async {
+                            loads.fetch_add(1, Ordering::SeqCst);
+                            Ok(new_account(&dir, id))
+                        })
+                        .await
+                        .unwrap();
+                        time::sleep(Duration::from_millis(1)).await;
+                        // the handle keeps the same instance in the cache while it is held
+                        let cached = accounts.write().await.get(id).map(|(cached, _)| cached);
+                        assert!(cached.map(|cached| Arc::ptr_eq(&cached, &account)).unwrap_or(false));
+                        drop(cleanup);
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for handle in handles {
+            handle.await.unwrap();
+        }
+
+        let mut cache = accounts.write().await;
+        assert_eq!(cache.busy(), 0);
+        assert!(loads.load(Ordering::SeqCst) >= ids.len());
+        let (id, account) = test_account(&dir);
+        cache.insert(id, account);
+        assert!(cache.len() <= 2);
+    }
+
+    #[actix_web::test]
+    async fn test_account_locks_under_stress() {
+        let locks = Arc::new(AccountLocks::new());
+        let ids = (0..4).map(|_| Uuid::new_v4()).collect::<Vec<_>>();
+        // holders per account, a second holder means the lock is broken
+        let holders = Arc::new(ids.iter().map(|_| AtomicUsize::new(0)).collect::<Vec<_>>());
+        let max_parallel = Arc::new(AtomicUsize::new(0));
+        let running = Arc::new(AtomicUsize::new(0));
+
+        let handles = (0..64)
+            .map(|task| {
+                let (locks, ids, holders) = (locks.clone(), ids.clone(), holders.clone());
+                let (max_parallel, running) = (max_parallel.clone(), running.clone());
+                tokio::spawn(async move {
+                    for round in 0..20 {
+                        let index = (task * 7 + round) % ids.len();
+                        let _guard = locks.lock(ids[index]).await;
+                        assert_eq!(holders[index].fetch_add(1, Ordering::SeqCst), 0);
+                        let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                        max_parallel.fetch_max(now, Ordering::SeqCst);
+                        tokio::task::yield_now().await;
+                        running.fetch_sub(1, Ordering::SeqCst);
+                        holders[index].fetch_sub(1, Ordering::SeqCst);
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for handle in handles {
+            handle.await.unwrap();
+        }
+
+        // different accounts are locked independently
+        assert!(max_parallel.load(Ordering::SeqCst) > 1);
+        assert!(max_parallel.load(Ordering::SeqCst) <= ids.len());
+        // released locks are dropped, so the map doesn't grow with the number of accounts
+        assert!(locks.locks.lock().unwrap().is_empty());
+    }
+}
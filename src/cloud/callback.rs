@@ -0,0 +1,203 @@
+use std::{net::{IpAddr, SocketAddr, ToSocketAddrs}, time::Duration};
+
+use once_cell::sync::Lazy;
+use reqwest::{redirect, Client, Url};
+use tokio::time;
+use zkbob_utils_rs::tracing;
+
+use crate::{errors::CloudError, types::{TransactionStatusResponse, TransferCallback}};
+
+use super::{ZkBobCloud, types::TransferPart, fee_payer};
+
+static CLIENT: Lazy<Client> = Lazy::new(Client::new);
+
+// callbackUrl of a request must be an http(s) url of one of the allowed hosts
+pub(crate) fn validate_callback_url(url: &str, allowed_hosts: &[String]) -> Result<(), CloudError> {
+    let url = match Url::parse(url) {
+        Ok(url) if url.scheme() == "http" || url.scheme() == "https" => url,
+        _ => return Err(CloudError::BadRequest("callbackUrl should be a valid http(s) url".to_string())),
+    };
+    let host = url.host_str().unwrap_or_default();
+    if !allowed_hosts.iter().any(|allowed| allowed.eq_ignore_ascii_case(host)) {
+        return Err(CloudError::BadRequest(format!("callbackUrl host '{}' is not allowed", host)));
+    }
+    Ok(())
+}
+
+// Addresses that a callback of a request must not reach: the service network and the host itself
+fn is_internal_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                // shared address space 100.64.0.0/10
+                || (ip.octets()[0] == 100 && ip.octets()[1] & 0xc0 == 64)
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_internal_ip(IpAddr::V4(ip)),
+            None => {
+                ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    // unique local fc00::/7 and link-local fe80::/10
+                    || ip.segments()[0] & 0xfe00 == 0xfc00
+                    || ip.segments()[0] & 0xffc0 == 0xfe80
+            }
+        },
+    }
+}
+
+// The client for callbackUrl of a request. The host is resolved once and pinned, so it can't be
+// rebound to an internal address after the check, and redirects are not followed
+async fn request_client(url: &str) -> Result<Client, String> {
+    let url = Url::parse(url).map_err(|err| err.to_string())?;
+    let host = url.host_str().ok_or("url has no host")?.to_string();
+    let port = url.port_or_known_default().ok_or("url has no port")?;
+
+    let lookup = host.clone();
+    let addrs: Vec<SocketAddr> = tokio::task::spawn_blocking(move || (lookup.as_str(), port).to_socket_addrs())
+        .await
+        .map_err(|err| err.to_string())?
+        .map_err(|err| err.to_string())?
+        .collect();
+    if addrs.is_empty() {
+        return Err(format!("{} is not resolved", host));
+    }
+    if let Some(addr) = addrs.iter().find(|addr| is_internal_ip(addr.ip())) {
+        return Err(format!("{} resolves to the internal address {}", host, addr.ip()));
+    }
+
+    Client::builder()
+        .resolve(&host, addrs[0])
+        .redirect(redirect::Policy::none())
+        .build()
+        .map_err(|err| err.to_string())
+}
+
+// A transfer is terminal when its last part is final: failures of earlier parts are propagated to it.
+// The callback is delivered in the background, so callback endpoints cannot block or fail the workers.
+// The fees of a terminal transfer are scheduled for reimbursement here as well
+pub(crate) async fn notify_if_final(cloud: &ZkBobCloud, part: &TransferPart) {
+    if !part.status.is_final() {
+        return;
+    }
+
+    let (task, parts) = {
+        let db = cloud.db.read().await;
+        let task = match db.get_task(&part.transaction_id) {
+            Ok(task) => task,
+            Err(err) => {
+                tracing::warn!("[callback] failed to get transfer {}: {}", &part.transaction_id, err);
+                return;
+            }
+        };
+        if task.parts.last() != Some(&part.id) {
+            return;
+        }
+
+        let mut parts = Vec::new();
+        for id in task.parts.iter() {
+            match db.get_part(id) {
+                Ok(part) => parts.push(part),
+                Err(err) => {
+                    tracing::warn!("[callback] failed to get part {}: {}", id, err);
+                    return;
+                }
+            }
+        }
+        (task, parts)
+    };
+
+    fee_payer::schedule_top_up(cloud, &task, &parts).await;
+
+    // the configured url is trusted, it may point to the internal network
+    let (url, from_request) = match (task.callback_url.clone(), cloud.config.callback.url.clone()) {
+        (Some(url), _) => (url, true),
+        (None, Some(url)) => (url, false),
+        (None, None) => return,
+    };
+
+    let callback = TransferCallback {
+        transaction_id: task.transaction_id,
+        support_id: task.support_id,
+        status: TransactionStatusResponse::from(parts),
+    };
+    let max_attempts = cloud.config.callback.max_attempts.max(1);
+    let retry_delay = Duration::from_secs(cloud.config.callback.retry_delay_sec);
+    let timeout = Duration::from_secs(cloud.config.callback.timeout_sec);
+    tokio::spawn(async move {
+        let client = match from_request {
+            true => match request_client(&url).await {
+                Ok(client) => client,
+                Err(err) => {
+                    tracing::error!("[callback] refusing to report transfer {}: {}", &callback.transaction_id, err);
+                    return;
+                }
+            },
+            false => CLIENT.clone(),
+        };
+        for attempt in 1..=max_attempts {
+            let result = client
+                .post(&url)
+                .timeout(timeout)
+                .json(&callback)
+                .send()
+                .await
+                .and_then(|response| response.error_for_status());
+
+            match result {
+                Ok(_) => {
+                    tracing::info!("[callback] transfer {} is reported to the callback url", &callback.transaction_id);
+                    return;
+                }
+                Err(err) => {
+                    tracing::warn!("[callback] failed to report transfer {}, attempt: {}: {}", &callback.transaction_id, attempt, err);
+                }
+            }
+            if attempt < max_attempts {
+                time::sleep(retry_delay).await;
+            }
+        }
+        tracing::error!("[callback] giving up reporting transfer {}", &callback.transaction_id);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn callback_url_host_must_be_allowed() {
+        let allowed = vec!["hooks.example.com".to_string()];
+        assert!(validate_callback_url("https://hooks.example.com/zkbob", &allowed).is_ok());
+        assert!(validate_callback_url("https://HOOKS.example.com/zkbob", &allowed).is_ok());
+        assert!(validate_callback_url("https://evil.example.com/zkbob", &allowed).is_err());
+        assert!(validate_callback_url("ftp://hooks.example.com/zkbob", &allowed).is_err());
+        assert!(validate_callback_url("not a url", &allowed).is_err());
+        // overrides are disabled without an allowlist
+        assert!(validate_callback_url("https://hooks.example.com/zkbob", &[]).is_err());
+    }
+
+    #[test]
+    fn internal_addresses_are_rejected() {
+        for ip in [
+            "127.0.0.1", "10.1.2.3", "172.16.0.1", "192.168.1.1", "169.254.169.254", "0.0.0.0", "100.64.0.1",
+            "::1", "::", "fd00::1", "fe80::1", "::ffff:127.0.0.1", "::ffff:169.254.169.254",
+        ] {
+            assert!(is_internal_ip(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in ["8.8.8.8", "100.128.0.1", "2001:4860:4860::8888", "::ffff:8.8.8.8"] {
+            assert!(!is_internal_ip(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[actix_web::test]
+    async fn loopback_callback_is_refused() {
+        assert!(request_client("http://127.0.0.1:8080/hook").await.is_err());
+        assert!(request_client("http://localhost:8080/hook").await.is_err());
+    }
+}
@@ -1,39 +1,90 @@
-use std::{sync::Arc, collections::HashMap, thread, process};
+use std::{any::Any, future::Future, panic::{self, AssertUnwindSafe}, sync::{atomic::{AtomicUsize, Ordering}, Arc}, thread, time::Duration};
 
-use tokio::sync::RwLock;
-use uuid::Uuid;
+use actix_web::web::Data;
 use zkbob_utils_rs::tracing;
 
-use crate::account::Account;
+use super::ZkBobCloud;
 
+// pause before a panicked worker is started again, so a persistent failure doesn't spin
+const WORKER_RESTART_DELAY: Duration = Duration::from_secs(5);
+
+// A handle of a loaded account, the cache doesn't evict the account until all its handles are dropped
 pub struct AccountCleanup {
-    pub(crate) id: Uuid,
-    pub(crate) accounts: Arc<RwLock<HashMap<Uuid, Arc<Account>>>>
+    in_use: Arc<AtomicUsize>,
 }
 
 impl AccountCleanup {
-    pub fn new(id: Uuid, accounts: Arc<RwLock<HashMap<Uuid, Arc<Account>>>>) -> AccountCleanup {
-        AccountCleanup { id, accounts }
+    // Created by the cache under its lock, so an account is never evicted while a handle is given out
+    pub(crate) fn new(in_use: Arc<AtomicUsize>) -> AccountCleanup {
+        in_use.fetch_add(1, Ordering::SeqCst);
+        AccountCleanup { in_use }
     }
 }
 
 impl Drop for AccountCleanup {
     fn drop(&mut self) {
-        let id = self.id;
-        let accounts = self.accounts.clone();
-        tokio::spawn(async move {
-            accounts.write().await.remove(&id);
-        });
+        self.in_use.fetch_sub(1, Ordering::SeqCst);
     }
 }
 
-pub struct WorkerCleanup;
-
-impl Drop for WorkerCleanup {
-    fn drop(&mut self) {
-        if thread::panicking() {
-            tracing::error!("panic in worker, stopping application");
-            process::exit(1);
+// Runs the worker in its own thread with a tokio runtime. A worker that panics is started again
+// with a new runtime, tasks spawned by the panicked one are dropped with its runtime.
+pub(crate) fn spawn_worker<F, Fut>(name: &'static str, cloud: Data<ZkBobCloud>, worker: F)
+where
+    F: Fn(Data<ZkBobCloud>) -> Fut + Send + 'static,
+    Fut: Future<Output = ()>,
+{
+    thread::spawn(move || loop {
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            let rt = tokio::runtime::Runtime::new().expect("failed to init tokio runtime");
+            rt.block_on(worker(cloud.clone()));
+        }));
+        match result {
+            Ok(()) => return,
+            Err(err) => {
+                tracing::error!("panic in {} worker: {}, restarting it", name, panic_message(err.as_ref()));
+                cloud.metrics.worker_restarts.with_label_values(&[name]).inc();
+                thread::sleep(WORKER_RESTART_DELAY);
+            }
         }
+    });
+}
+
+// Runs a task of a worker and returns the panic message if it panicked. A panicked task
+// leaves its queue message behind, so the worker must account for the panic itself
+pub(crate) async fn run_catching_panic<Fut>(task: Fut) -> Option<String>
+where
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    match tokio::spawn(task).await {
+        Err(err) if err.is_panic() => Some(panic_message(err.into_panic().as_ref())),
+        _ => None,
     }
-}
\ No newline at end of file
+}
+
+fn panic_message(err: &(dyn Any + Send)) -> String {
+    err.downcast_ref::<&str>()
+        .map(|message| message.to_string())
+        .or_else(|| err.downcast_ref::<String>().cloned())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::run_catching_panic;
+
+    #[actix_web::test]
+    async fn panic_message_is_returned() {
+        let message = run_catching_panic(async { panic!("broken part") }).await;
+        assert_eq!(message.as_deref(), Some("broken part"));
+
+        let id = 7;
+        let message = run_catching_panic(async move { panic!("broken part {}", id) }).await;
+        assert_eq!(message.as_deref(), Some("broken part 7"));
+    }
+
+    #[actix_web::test]
+    async fn completed_task_has_no_panic() {
+        assert!(run_catching_panic(async {}).await.is_none());
+    }
+}
@@ -1,9 +1,9 @@
 use uuid::Uuid;
 use zkbob_utils_rs::tracing;
 
-use crate::{errors::CloudError, helpers::db::KeyValueDb};
+use crate::{errors::CloudError, helpers::{db::KeyValueDb, fault::FaultInjector}};
 
-use super::types::{TransferPart, TransferTask, ReportTask, AccountData};
+use super::types::{TransferPart, TransferTask, ReportTask, AccountData, SubmissionReceipt, NoteReservation, PendingDirectDeposit, FeeTopUp};
 
 pub(crate) struct Db {
     db_path: String,
@@ -12,10 +12,39 @@ pub(crate) struct Db {
 
 impl Db {
     pub fn new(db_path: &str) -> Result<Self, CloudError> {
-        Ok(Db {
+        let mut db = Db {
             db_path: db_path.to_string(),
             db: KeyValueDb::new(&format!("{}/cloud", db_path), CloudDbColumn::count())?,
-        })
+        };
+        db.index_account_parts()?;
+        Ok(db)
+    }
+
+    pub fn set_faults(&mut self, faults: FaultInjector) {
+        self.db.set_faults(faults);
+    }
+
+    // Parts saved before the index existed are indexed once
+    fn index_account_parts(&mut self) -> Result<(), CloudError> {
+        if self.db.exists(CloudDbColumn::AccountParts.into(), PARTS_INDEXED_KEY)? {
+            return Ok(());
+        }
+        let parts = self.get_parts()?;
+        tracing::info!("indexing {} transfer parts by account", parts.len());
+        let mut values = Vec::with_capacity(parts.len() + 1);
+        for part in parts.iter() {
+            values.push(self.part_index_entry(part)?);
+        }
+        values.push((CloudDbColumn::AccountParts.into(), PARTS_INDEXED_KEY.to_vec(), self.db.serialize(&true)?));
+        self.db.save_batch(values.into_iter())
+    }
+
+    fn part_index_entry(&self, part: &TransferPart) -> Result<(u32, Vec<u8>, Vec<u8>), CloudError> {
+        Ok((
+            CloudDbColumn::AccountParts.into(),
+            account_part_key(&part.account_id, &part.id),
+            self.db.serialize(&part.id)?,
+        ))
     }
 
     pub fn account_db_path(&self, id: Uuid) -> String {
@@ -31,6 +60,17 @@ impl Db {
         self.db.get(CloudDbColumn::Accounts.into(), id.as_bytes())
     }
 
+    // The data is saved only if `update` returns it
+    pub fn update_account<F>(&mut self, id: Uuid, update: F) -> Result<(), CloudError>
+    where
+        F: FnOnce(AccountData) -> Option<AccountData>,
+    {
+        match self.get_account(id)?.and_then(update) {
+            Some(data) => self.save_account(id, &data),
+            None => Ok(()),
+        }
+    }
+
     pub fn account_exists(&self, id: Uuid) -> Result<bool, CloudError> {
         self.db.exists(CloudDbColumn::Accounts.into(), id.as_bytes())
     }
@@ -52,20 +92,47 @@ impl Db {
         Ok(accounts)
     }
 
+    // Returns the accounts inside the window and the total number of accounts
+    pub fn get_accounts_page(&self, offset: usize, limit: usize) -> Result<(Vec<(Uuid, AccountData)>, usize), CloudError> {
+        let (kv, total) = self.db.get_page_with_keys(CloudDbColumn::Accounts.into(), offset, limit)?;
+        let mut accounts = Vec::new();
+        for (id, data) in kv {
+            let id = Uuid::from_slice(&id).map_err(|err| {
+                tracing::error!("failed to parse account id: {:?}: {:?}", id, err);
+                CloudError::DataBaseReadError("failed to parse account id".to_string())
+            })?;
+            accounts.push((id, data));
+        }
+        Ok((accounts, total))
+    }
+
+    // The task, its parts and the reservation of its notes are saved atomically
     pub fn save_task<'a, I>(
         &mut self,
         task: &TransferTask,
         parts: I,
+        reservation: Option<&NoteReservation>,
     ) -> Result<(), CloudError> 
     where
         I: Iterator<Item = &'a TransferPart>,
     {
-        self.db.save(
+        let mut values = vec![(
             CloudDbColumn::Tasks.into(),
-            task.transaction_id.as_bytes(),
-            task,
-        )?;
-        self.db.save_all(CloudDbColumn::Tasks.into(), parts, |part| part.id.as_bytes().to_vec())
+            task.transaction_id.as_bytes().to_vec(),
+            self.db.serialize(task)?,
+        )];
+        for part in parts {
+            values.push((CloudDbColumn::Tasks.into(), part.id.as_bytes().to_vec(), self.db.serialize(part)?));
+            values.push(self.part_index_entry(part)?);
+        }
+        if let Some(reservation) = reservation {
+            values.push((
+                CloudDbColumn::Reservations.into(),
+                reservation.transaction_id.as_bytes().to_vec(),
+                self.db.serialize(reservation)?,
+            ));
+        }
+        self.db.save_batch(values.into_iter())
     }
 
     pub fn get_task(&self, id: &str) -> Result<TransferTask, CloudError> {
@@ -89,6 +156,45 @@ impl Db {
             .ok_or(CloudError::InternalError("task part not found in db".to_string()))
     }
 
+    pub fn get_tasks(&self) -> Result<Vec<TransferTask>, CloudError> {
+        self.db
+            .get_all_filtered(CloudDbColumn::Tasks.into(), |key| !key.contains(&b'.'))
+    }
+
+    pub fn delete_task(&mut self, task: &TransferTask, parts: &[TransferPart]) -> Result<(), CloudError> {
+        let tx_hashes = parts.iter().filter_map(|part| part.tx_hash.as_ref());
+        self.db.delete_keys(CloudDbColumn::TransactionId.into(), tx_hashes)?;
+
+        let index_keys = parts.iter().map(|part| account_part_key(&part.account_id, &part.id));
+        self.db.delete_keys(CloudDbColumn::AccountParts.into(), index_keys)?;
+
+        let keys = task
+            .parts
+            .iter()
+            .map(|id| id.as_bytes())
+            .chain([task.transaction_id.as_bytes()]);
+        self.db.delete_keys(CloudDbColumn::Tasks.into(), keys)
+    }
+
+    pub fn get_parts(&self) -> Result<Vec<TransferPart>, CloudError> {
+        // tasks and parts share the column, only part ids contain '.'
+        self.db
+            .get_all_filtered(CloudDbColumn::Tasks.into(), |key| key.contains(&b'.'))
+    }
+
+    // Parts are looked up by the account index, so only the parts of the account are read
+    pub fn get_account_parts(&self, account_id: Uuid) -> Result<Vec<TransferPart>, CloudError> {
+        let prefix = account_part_key(&account_id.as_hyphenated().to_string(), "");
+        let part_ids: Vec<String> = self.db.get_by_prefix(CloudDbColumn::AccountParts.into(), &prefix)?;
+        let mut parts = Vec::with_capacity(part_ids.len());
+        for id in part_ids {
+            if let Some(part) = self.db.get(CloudDbColumn::Tasks.into(), id.as_bytes())? {
+                parts.push(part);
+            }
+        }
+        Ok(parts)
+    }
+
     pub fn save_transaction_id(&mut self , tx_hash: &str, transaction_id: &str) -> Result<(), CloudError> {
         self.db.save_string(CloudDbColumn::TransactionId.into(), tx_hash.as_bytes(), transaction_id)
     }
@@ -97,6 +203,149 @@ impl Db {
         self.db.get_string(CloudDbColumn::TransactionId.into(), tx_hash.as_bytes())
     }
 
+    pub fn save_addresses<'a, I>(&mut self, account_id: Uuid, addresses: I) -> Result<(), CloudError>
+    where
+        I: Iterator<Item = &'a String>,
+    {
+        let account_id = account_id.as_hyphenated().to_string();
+        self.db.save_all_raw(
+            CloudDbColumn::Addresses.into(),
+            addresses.map(|address| (address.as_bytes(), account_id.as_bytes())),
+        )
+    }
+
+    pub fn get_address_owner(&self, address: &str) -> Result<Option<String>, CloudError> {
+        self.db
+            .get_string(CloudDbColumn::Addresses.into(), address.as_bytes())
+    }
+
+    // Only the latest transfers are kept per support id, so a noisy client can't grow the index unbounded
+    pub fn index_support_id(
+        &mut self,
+        support_id: &str,
+        transaction_id: &str,
+        max_transfers: usize,
+    ) -> Result<(), CloudError> {
+        let mut transaction_ids: Vec<String> = self
+            .db
+            .get(CloudDbColumn::SupportIds.into(), support_id.as_bytes())?
+            .unwrap_or_default();
+        transaction_ids.push(transaction_id.to_string());
+        if transaction_ids.len() > max_transfers {
+            transaction_ids.drain(..transaction_ids.len() - max_transfers);
+        }
+        self.db.save(
+            CloudDbColumn::SupportIds.into(),
+            support_id.as_bytes(),
+            &transaction_ids,
+        )
+    }
+
+    pub fn get_support_id_transfers(&self, support_id: &str) -> Result<Vec<String>, CloudError> {
+        Ok(self
+            .db
+            .get(CloudDbColumn::SupportIds.into(), support_id.as_bytes())?
+            .unwrap_or_default())
+    }
+
+    // A part marked as sent always has the receipt of its submission
+    pub fn save_submitted_part(&mut self, part: &TransferPart, receipt: &SubmissionReceipt) -> Result<(), CloudError> {
+        let values = vec![
+            (CloudDbColumn::Tasks.into(), part.id.as_bytes().to_vec(), self.db.serialize(part)?),
+            (CloudDbColumn::Receipts.into(), receipt.part_id.as_bytes().to_vec(), self.db.serialize(receipt)?),
+        ];
+        self.db.save_batch(values.into_iter())
+    }
+
+    pub fn get_receipt(&self, part_id: &str) -> Result<Option<SubmissionReceipt>, CloudError> {
+        self.db.get(CloudDbColumn::Receipts.into(), part_id.as_bytes())
+    }
+
+    pub fn save_fee_top_up(&mut self, top_up: &FeeTopUp) -> Result<(), CloudError> {
+        self.db.save(
+            CloudDbColumn::FeeTopUps.into(),
+            top_up.transaction_id.as_bytes(),
+            top_up,
+        )
+    }
+
+    pub fn get_fee_top_ups(&self) -> Result<Vec<FeeTopUp>, CloudError> {
+        self.db.get_all(CloudDbColumn::FeeTopUps.into())
+    }
+
+    pub fn delete_fee_top_up(&mut self, transaction_id: &str) -> Result<(), CloudError> {
+        self.db
+            .delete(CloudDbColumn::FeeTopUps.into(), transaction_id.as_bytes())
+    }
+
+    // Only the latest `max` dead letters are kept
+    pub fn save_dead_letter(&mut self, part: &TransferPart, max: usize) -> Result<(), CloudError> {
+        self.db.save(CloudDbColumn::DeadLetters.into(), part.id.as_bytes(), part)?;
+        let outdated = outdated_dead_letters(self.get_dead_letters(), max);
+        if outdated.is_empty() {
+            return Ok(());
+        }
+        self.delete_dead_letters(&outdated)
+    }
+
+    // Dead letters in the order they failed
+    pub fn get_dead_letters(&self) -> Vec<TransferPart> {
+        let mut parts: Vec<TransferPart> = self.db.get_all_readable(CloudDbColumn::DeadLetters.into());
+        parts.sort_by_key(|part| part.timestamp);
+        parts
+    }
+
+    pub fn delete_dead_letters(&mut self, part_ids: &[String]) -> Result<(), CloudError> {
+        self.db
+            .delete_keys(CloudDbColumn::DeadLetters.into(), part_ids.iter())
+    }
+
+    pub fn get_reservations(&self) -> Result<Vec<NoteReservation>, CloudError> {
+        self.db.get_all(CloudDbColumn::Reservations.into())
+    }
+
+    pub fn delete_reservation(&mut self, transaction_id: &str) -> Result<(), CloudError> {
+        self.db
+            .delete(CloudDbColumn::Reservations.into(), transaction_id.as_bytes())
+    }
+
+    pub fn save_pending_direct_deposit(&mut self, deposit: &PendingDirectDeposit) -> Result<(), CloudError> {
+        self.db.save(
+            CloudDbColumn::PendingDirectDeposits.into(),
+            deposit.nonce.as_bytes(),
+            deposit,
+        )
+    }
+
+    pub fn get_pending_direct_deposits(&self) -> Result<Vec<PendingDirectDeposit>, CloudError> {
+        self.db.get_all(CloudDbColumn::PendingDirectDeposits.into())
+    }
+
+    pub fn delete_pending_direct_deposit(&mut self, nonce: &str) -> Result<(), CloudError> {
+        self.db
+            .delete(CloudDbColumn::PendingDirectDeposits.into(), nonce.as_bytes())
+    }
+
+    pub fn save_watched_block(&mut self, block: u64) -> Result<(), CloudError> {
+        self.db
+            .save(CloudDbColumn::WatcherState.into(), "dd_block".as_bytes(), &block)
+    }
+
+    pub fn get_watched_block(&self) -> Result<Option<u64>, CloudError> {
+        self.db
+            .get(CloudDbColumn::WatcherState.into(), "dd_block".as_bytes())
+    }
+
+    pub fn save_default_addresses_indexed(&mut self) -> Result<(), CloudError> {
+        self.db
+            .save(CloudDbColumn::WatcherState.into(), "default_addresses".as_bytes(), &true)
+    }
+
+    pub fn default_addresses_indexed(&self) -> Result<bool, CloudError> {
+        self.db
+            .exists(CloudDbColumn::WatcherState.into(), "default_addresses".as_bytes())
+    }
+
     pub fn save_report_task(&mut self, id: Uuid, task: &ReportTask) -> Result<(), CloudError> {
         self.db.save(CloudDbColumn::Reports.into(), id.as_bytes(), task)
     }
@@ -105,21 +354,61 @@ impl Db {
         self.db.get(CloudDbColumn::Reports.into(), id.as_bytes())
     }
 
+    pub fn get_report_tasks(&self) -> Result<Vec<(Uuid, ReportTask)>, CloudError> {
+        let kv = self.db.get_all_with_keys(CloudDbColumn::Reports.into())?;
+        let mut tasks = Vec::new();
+        for (id, task) in kv {
+            let id = Uuid::from_slice(&id).map_err(|err| {
+                tracing::error!("failed to parse report id: {:?}: {:?}", id, err);
+                CloudError::DataBaseReadError("failed to parse report id".to_string())
+            })?;
+            tasks.push((id, task));
+        }
+        Ok(tasks)
+    }
+
+    pub fn delete_report_task(&mut self, id: Uuid) -> Result<(), CloudError> {
+        self.db.delete(CloudDbColumn::Reports.into(), id.as_bytes())
+    }
+
     pub fn clean_reports(&mut self) -> Result<(), CloudError> {
         self.db.delete_all(CloudDbColumn::Reports.into())
     }
 }
 
+const PARTS_INDEXED_KEY: &[u8] = b"indexed";
+
+// Account ids are hyphenated uuids, so the prefix of one account doesn't match another one
+fn account_part_key(account_id: &str, part_id: &str) -> Vec<u8> {
+    format!("{}/{}", account_id, part_id).into_bytes()
+}
+
+// Ids of the oldest parts beyond `max`, the parts are sorted by the failure time
+fn outdated_dead_letters(parts: Vec<TransferPart>, max: usize) -> Vec<String> {
+    let excess = parts.len().saturating_sub(max);
+    parts.into_iter().take(excess).map(|part| part.id).collect()
+}
+
 pub enum CloudDbColumn {
     Accounts,
     Tasks,
     TransactionId,
     Reports,
+    Receipts,
+    Reservations,
+    Addresses,
+    SupportIds,
+    PendingDirectDeposits,
+    WatcherState,
+    FeeTopUps,
+    DeadLetters,
+    // keys are "{account_id}/{part_id}"
+    AccountParts,
 }
 
 impl CloudDbColumn {
     pub fn count() -> u32 {
-        4
+        13
     }
 }
 
@@ -128,3 +417,31 @@ impl From<CloudDbColumn> for u32 {
         val as u32
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{account_part_key, outdated_dead_letters};
+    use crate::cloud::{tests::part, types::TransferStatus};
+
+    #[test]
+    fn oldest_dead_letters_are_outdated() {
+        let parts = (0..5).map(|index| part(index, TransferStatus::New, 1, None)).collect();
+        assert_eq!(outdated_dead_letters(parts, 3), vec!["transfer.0", "transfer.1"]);
+    }
+
+    #[test]
+    fn dead_letters_within_limit_are_kept() {
+        let parts = (0..3).map(|index| part(index, TransferStatus::New, 1, None)).collect();
+        assert!(outdated_dead_letters(parts, 3).is_empty());
+        assert!(outdated_dead_letters(vec![], 0).is_empty());
+    }
+
+    #[test]
+    fn account_part_keys_share_account_prefix() {
+        let account_id = "3fa85f64-5717-4562-b3fc-2c963f66afa6";
+        let prefix = account_part_key(account_id, "");
+        assert_eq!(account_part_key(account_id, "transfer.0"), b"3fa85f64-5717-4562-b3fc-2c963f66afa6/transfer.0".to_vec());
+        assert!(account_part_key(account_id, "transfer.0").starts_with(&prefix));
+        assert!(!account_part_key("3fa85f64-5717-4562-b3fc-2c963f66afa7", "transfer.0").starts_with(&prefix));
+    }
+}
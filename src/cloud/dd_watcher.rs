@@ -0,0 +1,330 @@
+use std::{collections::HashSet, str::FromStr, sync::Arc, time::Duration};
+
+use actix_web::web::Data;
+use libzkbob_rs::{
+    address::format_address,
+    libzeropool::{
+        fawkes_crypto::ff_uint::{Num, NumRepr, Uint},
+        native::boundednum::BoundedNum,
+    },
+};
+use tokio::time;
+use web3::{
+    signing::keccak256,
+    transports::Http,
+    types::{Address, BlockNumber, FilterBuilder, Log, H256, U256},
+    Web3,
+};
+use zkbob_utils_rs::tracing;
+
+use crate::{errors::CloudError, helpers::timestamp, Fr, PoolParams};
+
+use super::{cleanup::spawn_worker, types::PendingDirectDeposit, ZkBobCloud};
+
+const SUBMIT_DIRECT_DEPOSIT_EVENT: &str =
+    "SubmitDirectDeposit(address,uint256,address,(bytes10,bytes32),uint64)";
+// emitted when the relayer includes the deposits with the given nonces into the pool
+const COMPLETE_DIRECT_DEPOSIT_BATCH_EVENT: &str = "CompleteDirectDepositBatch(uint256[])";
+
+struct Watcher {
+    web3: Web3<Http>,
+    contract: Address,
+    submit_topic: H256,
+    complete_topic: H256,
+}
+
+pub(crate) fn run_dd_watcher(cloud: Data<ZkBobCloud>) -> Result<(), CloudError> {
+    let config = &cloud.config.direct_deposit_watcher;
+    if !config.enabled {
+        return Ok(());
+    }
+
+    let transport = Http::new(&cloud.config.web3.provider_endpoint).map_err(|err| {
+        CloudError::ConfigError(format!("failed to init web3 transport: {}", err))
+    })?;
+    let contract = Address::from_str(&config.contract_address).map_err(|err| {
+        CloudError::ConfigError(format!("invalid direct deposit contract address: {}", err))
+    })?;
+    let watcher = Watcher {
+        web3: Web3::new(transport),
+        contract,
+        submit_topic: H256::from(keccak256(SUBMIT_DIRECT_DEPOSIT_EVENT.as_bytes())),
+        complete_topic: H256::from(keccak256(COMPLETE_DIRECT_DEPOSIT_BATCH_EVENT.as_bytes())),
+    };
+
+    let watcher = Arc::new(watcher);
+    spawn_worker("dd watcher", cloud, move |cloud| {
+        let watcher = watcher.clone();
+        async move {
+            let interval = Duration::from_secs(cloud.config.direct_deposit_watcher.poll_interval_sec);
+            if let Err(err) = index_default_addresses(&cloud).await {
+                tracing::warn!("[dd watcher] failed to index default addresses, retrying on the next start: {}", err);
+            }
+            loop {
+                match ingest(&cloud, &watcher).await {
+                    Ok(0) => {}
+                    Ok(count) => tracing::info!("[dd watcher] found {} pending direct deposits", count),
+                    Err(err) => tracing::warn!("[dd watcher] failed to fetch direct deposit events: {}", err),
+                }
+                if let Err(err) = reconcile(&cloud).await {
+                    tracing::warn!("[dd watcher] failed to reconcile pending direct deposits: {}", err);
+                }
+                time::sleep(interval).await;
+            }
+        }
+    });
+    Ok(())
+}
+
+async fn ingest(cloud: &ZkBobCloud, watcher: &Watcher) -> Result<usize, CloudError> {
+    let last_block = watcher
+        .web3
+        .eth()
+        .block_number()
+        .await
+        .map_err(|err| {
+            tracing::warn!("[dd watcher] failed to fetch block number: {}", err);
+            CloudError::Web3Error
+        })?
+        .as_u64();
+
+    // the watcher doesn't backfill deposits submitted before it was enabled
+    let from_block = match cloud.db.read().await.get_watched_block()? {
+        Some(block) => block + 1,
+        None => last_block,
+    };
+    if from_block > last_block {
+        return Ok(0);
+    }
+    let to_block = last_block.min(from_block + cloud.config.direct_deposit_watcher.max_blocks_per_poll.max(1) - 1);
+
+    let filter = FilterBuilder::default()
+        .address(vec![watcher.contract])
+        .topics(Some(vec![watcher.submit_topic, watcher.complete_topic]), None, None, None)
+        .from_block(BlockNumber::Number(from_block.into()))
+        .to_block(BlockNumber::Number(to_block.into()))
+        .build();
+    let logs = watcher.web3.eth().logs(filter).await.map_err(|err| {
+        tracing::warn!("[dd watcher] failed to fetch logs: {}", err);
+        CloudError::Web3Error
+    })?;
+
+    let from_index = cloud.relayer.info().await?.optimistic_delta_index;
+    let mut count = 0;
+    for log in logs {
+        if log.topics.first() == Some(&watcher.complete_topic) {
+            complete_deposits(cloud, &log).await?;
+            continue;
+        }
+        let (nonce, address, amount) = match parse_event(&log) {
+            Some(event) => event,
+            None => {
+                tracing::warn!("[dd watcher] failed to parse direct deposit event: {:?}", log.transaction_hash);
+                continue;
+            }
+        };
+
+        // only addresses generated by the service can be matched with accounts
+        let account_id = match cloud.db.read().await.get_address_owner(&address)? {
+            Some(account_id) => account_id,
+            None => continue,
+        };
+
+        cloud.db.write().await.save_pending_direct_deposit(&PendingDirectDeposit {
+            nonce,
+            account_id,
+            address,
+            amount,
+            from_index,
+            tx_hash: log.transaction_hash.map(|tx_hash| format!("{:#x}", tx_hash)),
+            timestamp: timestamp(),
+            completed: false,
+        })?;
+        count += 1;
+    }
+
+    cloud.db.write().await.save_watched_block(to_block)?;
+    Ok(count)
+}
+
+fn parse_event(log: &Log) -> Option<(String, String, u64)> {
+    let nonce = U256::from_big_endian(log.topics.get(2)?.as_bytes());
+    let (address, amount) = parse_deposit(&log.data.0)?;
+    Some((nonce.to_string(), address, amount))
+}
+
+// Event data: fallbackUser, zkAddress.diversifier (bytes10), zkAddress.pk, deposit.
+// The contract keeps the bytes of the decoded address, where the diversifier and pk are little-endian
+fn parse_deposit(data: &[u8]) -> Option<(String, u64)> {
+    if data.len() < 128 {
+        return None;
+    }
+
+    let mut diversifier = [0u8; 32];
+    diversifier[..10].copy_from_slice(&data[32..42]);
+    let d = BoundedNum::new(Num::<Fr>::from_uint_reduced(NumRepr(Uint::from_little_endian(&diversifier))));
+    let p_d = Num::<Fr>::from_uint_reduced(NumRepr(Uint::from_little_endian(&data[64..96])));
+    let amount = U256::from_big_endian(&data[96..128]).low_u64();
+
+    Some((format_address::<PoolParams>(d, p_d), amount))
+}
+
+// Event data is the abi encoded uint256[]: the offset of the array, its length and the nonces
+fn parse_completed_nonces(data: &[u8]) -> Option<Vec<String>> {
+    let word = |offset: usize| data.get(offset..offset.checked_add(32)?).map(U256::from_big_endian);
+    let small = |value: U256| (value <= U256::from(u32::MAX)).then(|| value.as_usize());
+    let offset = small(word(0)?)?;
+    let len = small(word(offset)?)?;
+    (0..len)
+        .map(|i| word(offset + 32 * (i + 1)).map(|nonce| nonce.to_string()))
+        .collect()
+}
+
+async fn complete_deposits(cloud: &ZkBobCloud, log: &Log) -> Result<(), CloudError> {
+    let nonces = match parse_completed_nonces(&log.data.0) {
+        Some(nonces) => nonces.into_iter().collect::<HashSet<_>>(),
+        None => {
+            tracing::warn!("[dd watcher] failed to parse completed direct deposits: {:?}", log.transaction_hash);
+            return Ok(());
+        }
+    };
+    let deposits = cloud.db.read().await.get_pending_direct_deposits()?;
+    for mut deposit in deposits.into_iter().filter(|deposit| nonces.contains(&deposit.nonce)) {
+        deposit.completed = true;
+        cloud.db.write().await.save_pending_direct_deposit(&deposit)?;
+    }
+    Ok(())
+}
+
+// Default addresses are indexed when an account is created or loaded, accounts created by older versions
+// that were not loaded since are loaded once, so deposits to their default address are matched too
+async fn index_default_addresses(cloud: &ZkBobCloud) -> Result<(), CloudError> {
+    if cloud.db.read().await.default_addresses_indexed()? {
+        return Ok(());
+    }
+    let ids = cloud
+        .db
+        .read()
+        .await
+        .get_accounts()?
+        .into_iter()
+        .map(|(id, _)| id)
+        .collect::<Vec<_>>();
+    for id in ids {
+        cloud.get_account(id).await?;
+    }
+    cloud.db.write().await.save_default_addresses_indexed()
+}
+
+async fn reconcile(cloud: &ZkBobCloud) -> Result<(), CloudError> {
+    let deposits = cloud.db.read().await.get_pending_direct_deposits()?;
+    let expiration_threshold =
+        timestamp().saturating_sub(cloud.config.direct_deposit_watcher.expiration_sec);
+
+    // accounts are synced only when their deposits are included into the pool
+    let mut account_ids = HashSet::new();
+    for deposit in deposits {
+        if deposit.timestamp < expiration_threshold {
+            tracing::warn!(
+                "[dd watcher] direct deposit {} to account {} didn't appear in time, dropping it",
+                deposit.nonce,
+                deposit.account_id
+            );
+            cloud.db.write().await.delete_pending_direct_deposit(&deposit.nonce)?;
+            continue;
+        }
+        if deposit.completed {
+            account_ids.insert(deposit.account_id);
+        }
+    }
+
+    for account_id in account_ids {
+        let id = match uuid::Uuid::from_str(&account_id) {
+            Ok(id) => id,
+            Err(_) => continue,
+        };
+        let result = async {
+            let (account, _cleanup) = cloud.get_account(id).await?;
+            cloud.sync_to_latest(id, &account).await?;
+            cloud.reconcile_direct_deposits(id, &account).await
+        }
+        .await;
+        if let Err(err) = result {
+            tracing::warn!("[dd watcher] failed to reconcile deposits of account {}: {}", account_id, err);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use libzkbob_rs::{
+        address::{format_address, parse_address},
+        libzeropool::{
+            fawkes_crypto::ff_uint::{Num, NumRepr},
+            native::boundednum::BoundedNum,
+        },
+    };
+    use web3::types::U256;
+
+    use crate::PoolParams;
+
+    use super::{parse_completed_nonces, parse_deposit};
+
+    fn word(value: u64) -> [u8; 32] {
+        let mut word = [0u8; 32];
+        U256::from(value).to_big_endian(&mut word);
+        word
+    }
+
+    // fallbackUser, zkAddress as the address bytes (10 bytes of diversifier padded to a word, 32 bytes of pk), deposit
+    fn deposit_data(diversifier: [u8; 10], pk: [u8; 32], amount: u64) -> Vec<u8> {
+        let mut data = vec![0u8; 32];
+        let mut padded = [0u8; 32];
+        padded[..10].copy_from_slice(&diversifier);
+        data.extend_from_slice(&padded);
+        data.extend_from_slice(&pk);
+        data.extend_from_slice(&word(amount));
+        data
+    }
+
+    #[test]
+    fn deposit_address_is_little_endian() {
+        let mut diversifier = [0u8; 10];
+        diversifier[0] = 1;
+        let mut pk = [0u8; 32];
+        pk[0] = 2;
+        let (address, amount) = parse_deposit(&deposit_data(diversifier, pk, 1_000_000)).unwrap();
+
+        let d = BoundedNum::new(Num::from_uint_reduced(NumRepr::from(1u64)));
+        let p_d = Num::from_uint_reduced(NumRepr::from(2u64));
+        assert_eq!(address, format_address::<PoolParams>(d, p_d));
+        assert_eq!(amount, 1_000_000);
+
+        let (parsed_d, parsed_p_d) = parse_address::<PoolParams>(&address).unwrap();
+        assert_eq!(parsed_d.to_num(), d.to_num());
+        assert_eq!(parsed_p_d, p_d);
+    }
+
+    #[test]
+    fn short_deposit_data_is_rejected() {
+        assert!(parse_deposit(&[0u8; 127]).is_none());
+    }
+
+    #[test]
+    fn completed_nonces_are_parsed() {
+        let mut data = word(32).to_vec();
+        data.extend_from_slice(&word(3));
+        for nonce in [5, 6, 1 << 40] {
+            data.extend_from_slice(&word(nonce));
+        }
+        assert_eq!(
+            parse_completed_nonces(&data),
+            Some(vec!["5".to_string(), "6".to_string(), (1u64 << 40).to_string()])
+        );
+        assert_eq!(parse_completed_nonces(&[word(32), word(0)].concat()), Some(vec![]));
+        // the length exceeds the data
+        assert_eq!(parse_completed_nonces(&[word(32), word(2), word(5)].concat()), None);
+        assert_eq!(parse_completed_nonces(&[]), None);
+    }
+}
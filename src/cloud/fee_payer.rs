@@ -0,0 +1,129 @@
+use std::time::Duration;
+
+use actix_web::web::Data;
+use tokio::time;
+use uuid::Uuid;
+use zkbob_utils_rs::tracing;
+
+use crate::{errors::CloudError, helpers::timestamp};
+
+use super::{cleanup::spawn_worker, types::{FeeTopUp, Transfer, TransferPart, TransferStatus, TransferTask}, ZkBobCloud};
+
+// Ids of top-up transfers, requests with such ids are rejected so users can't take them
+pub(crate) const FEE_TOP_UP_PREFIX: &str = "fee-top-up:";
+
+pub(crate) fn top_up_id(transaction_id: &str) -> String {
+    format!("{}{}", FEE_TOP_UP_PREFIX, transaction_id)
+}
+
+// Fees are reimbursed once the transfer is terminal, only for the parts that were mined.
+// The top-up is persisted first and sent by the worker, so a failed top-up is retried
+pub(crate) async fn schedule_top_up(cloud: &ZkBobCloud, task: &TransferTask, parts: &[TransferPart]) {
+    let account_id = match parts.first() {
+        Some(part) => part.account_id.clone(),
+        None => return,
+    };
+    let fee_payer = match cloud.fee_payer {
+        Some(fee_payer) if fee_payer.to_string() != account_id => fee_payer,
+        _ => return,
+    };
+    if task.transaction_id.starts_with(FEE_TOP_UP_PREFIX) {
+        return;
+    }
+
+    // the fee of a part can be raised when it is sent, the stored one is the paid fee
+    let fee = parts
+        .iter()
+        .filter(|part| part.status == TransferStatus::Done)
+        .fold(0u64, |total, part| total.saturating_add(part.fee));
+    if fee == 0 {
+        return;
+    }
+
+    let top_up = FeeTopUp {
+        transaction_id: task.transaction_id.clone(),
+        account_id,
+        fee,
+        support_id: task.support_id.clone(),
+        attempt: 0,
+        timestamp: timestamp(),
+    };
+    match cloud.db.write().await.save_fee_top_up(&top_up) {
+        Ok(()) => tracing::info!("[fee payer] fee {} of transfer {} is scheduled for top-up by {}", fee, &task.transaction_id, fee_payer),
+        Err(err) => tracing::error!("ALERT: failed to schedule fee top-up of transfer {}: {}", &task.transaction_id, err),
+    }
+}
+
+pub(crate) fn run_fee_top_up_worker(cloud: Data<ZkBobCloud>) {
+    if cloud.fee_payer.is_none() {
+        return;
+    }
+
+    spawn_worker("fee top-up", cloud, move |cloud| async move {
+        let interval = Duration::from_secs(cloud.config.fee_top_up_interval_sec);
+        loop {
+            match cloud.db.read().await.get_fee_top_ups() {
+                Ok(top_ups) => {
+                    for top_up in top_ups {
+                        process(&cloud, top_up).await;
+                    }
+                }
+                Err(err) => tracing::warn!("[fee payer] failed to get pending top-ups: {}", err),
+            }
+            time::sleep(interval).await;
+        }
+    });
+}
+
+async fn process(cloud: &ZkBobCloud, mut top_up: FeeTopUp) {
+    let fee_payer = match cloud.fee_payer {
+        Some(fee_payer) => fee_payer,
+        None => return,
+    };
+
+    let id = top_up_id(&top_up.transaction_id);
+    match submit(cloud, &top_up, fee_payer, &id).await {
+        // the top-up was submitted before its record was removed
+        Ok(()) | Err(CloudError::DuplicateTransactionId) => {
+            tracing::info!("[fee payer] fee {} of transfer {} is topped up by {}", top_up.fee, &top_up.transaction_id, &id);
+            if let Err(err) = cloud.db.write().await.delete_fee_top_up(&top_up.transaction_id) {
+                tracing::warn!("[fee payer] failed to remove top-up of transfer {}: {}", &top_up.transaction_id, err);
+            }
+        }
+        Err(err) => {
+            top_up.attempt += 1;
+            tracing::error!("ALERT: failed to top up fee of transfer {} from fee payer {}, attempt: {}: {}", &top_up.transaction_id, fee_payer, top_up.attempt, err);
+            if let Err(err) = cloud.db.write().await.save_fee_top_up(&top_up) {
+                tracing::warn!("[fee payer] failed to save top-up of transfer {}: {}", &top_up.transaction_id, err);
+            }
+        }
+    }
+}
+
+async fn submit(cloud: &ZkBobCloud, top_up: &FeeTopUp, fee_payer: Uuid, id: &str) -> Result<(), CloudError> {
+    let account_id = top_up
+        .account_id
+        .parse()
+        .map_err(|_| CloudError::InternalError(format!("bad account id {}", &top_up.account_id)))?;
+    // the default address is indexed already, so retries don't generate new addresses
+    // and top-ups don't count against the address limit of the user
+    let to = {
+        let (account, _cleanup) = cloud.get_account(account_id).await?;
+        account.default_address().await
+    };
+    cloud
+        .submit_transfer(Transfer {
+            id: id.to_string(),
+            account_id: fee_payer,
+            outputs: vec![(to, top_up.fee)],
+            support_id: top_up.support_id.clone(),
+            admin: true,
+            withdrawal: false,
+            native_amount: 0,
+            callback_url: None,
+            sweep: false,
+            internal: true,
+        })
+        .await?;
+    Ok(())
+}
@@ -0,0 +1,88 @@
+use prometheus::{Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder};
+use zkbob_utils_rs::tracing;
+
+use crate::errors::CloudError;
+
+// Counters are incremented by the workers, gauges are sampled when the metrics are rendered
+pub(crate) struct Metrics {
+    registry: Registry,
+    pub transfers_created: IntCounter,
+    pub parts_proved: IntCounter,
+    pub proving_duration: Histogram,
+    pub relayer_send_failures: IntCounter,
+    pub status_polls: IntCounterVec,
+    pub reports: IntCounterVec,
+    pub worker_restarts: IntCounterVec,
+    pub loaded_accounts: IntGauge,
+    pub busy_accounts: IntGauge,
+    pub queue_depth: IntGaugeVec,
+}
+
+impl Metrics {
+    pub fn new() -> Result<Self, CloudError> {
+        let metrics = Metrics {
+            registry: Registry::new_custom(Some("zkbob_cloud".to_string()), None).map_err(metrics_error)?,
+            transfers_created: IntCounter::new("transfers_created_total", "Number of accepted transfers")
+                .map_err(metrics_error)?,
+            parts_proved: IntCounter::new("parts_proved_total", "Number of proved transfer parts")
+                .map_err(metrics_error)?,
+            proving_duration: Histogram::with_opts(
+                HistogramOpts::new("proving_duration_seconds", "Duration of transfer part proving")
+                    .buckets(vec![1.0, 2.5, 5.0, 10.0, 20.0, 30.0, 60.0, 120.0]),
+            )
+            .map_err(metrics_error)?,
+            relayer_send_failures: IntCounter::new("relayer_send_failures_total", "Number of failed requests to send a transaction to the relayer")
+                .map_err(metrics_error)?,
+            status_polls: IntCounterVec::new(
+                Opts::new("status_polls_total", "Number of relayer job status polls by job status"),
+                &["status"],
+            )
+            .map_err(metrics_error)?,
+            reports: IntCounterVec::new(
+                Opts::new("reports_total", "Number of processed reports by outcome"),
+                &["outcome"],
+            )
+            .map_err(metrics_error)?,
+            worker_restarts: IntCounterVec::new(
+                Opts::new("worker_restarts_total", "Number of worker restarts after a panic"),
+                &["worker"],
+            )
+            .map_err(metrics_error)?,
+            loaded_accounts: IntGauge::new("loaded_accounts", "Number of accounts loaded in memory")
+                .map_err(metrics_error)?,
+            busy_accounts: IntGauge::new("busy_accounts", "Number of loaded accounts in use by requests or workers")
+                .map_err(metrics_error)?,
+            queue_depth: IntGaugeVec::new(
+                Opts::new("queue_depth", "Number of messages in the queue including the ones being processed"),
+                &["queue"],
+            )
+            .map_err(metrics_error)?,
+        };
+
+        metrics.registry.register(Box::new(metrics.transfers_created.clone())).map_err(metrics_error)?;
+        metrics.registry.register(Box::new(metrics.parts_proved.clone())).map_err(metrics_error)?;
+        metrics.registry.register(Box::new(metrics.proving_duration.clone())).map_err(metrics_error)?;
+        metrics.registry.register(Box::new(metrics.relayer_send_failures.clone())).map_err(metrics_error)?;
+        metrics.registry.register(Box::new(metrics.status_polls.clone())).map_err(metrics_error)?;
+        metrics.registry.register(Box::new(metrics.reports.clone())).map_err(metrics_error)?;
+        metrics.registry.register(Box::new(metrics.worker_restarts.clone())).map_err(metrics_error)?;
+        metrics.registry.register(Box::new(metrics.loaded_accounts.clone())).map_err(metrics_error)?;
+        metrics.registry.register(Box::new(metrics.busy_accounts.clone())).map_err(metrics_error)?;
+        metrics.registry.register(Box::new(metrics.queue_depth.clone())).map_err(metrics_error)?;
+        Ok(metrics)
+    }
+
+    // Renders the metrics in the text exposition format
+    pub fn render(&self) -> Result<String, CloudError> {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .map_err(metrics_error)?;
+        String::from_utf8(buffer).map_err(metrics_error)
+    }
+}
+
+fn metrics_error<E: std::fmt::Display>(err: E) -> CloudError {
+    tracing::error!("metrics error: {}", err);
+    CloudError::InternalError("metrics error".to_string())
+}
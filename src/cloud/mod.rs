@@ -4,43 +4,78 @@ mod send_worker;
 mod status_worker;
 mod report_worker;
 mod cleanup;
+mod retention_worker;
+mod dd_watcher;
+mod sync_coalescer;
+mod retry_attempts;
+mod sync_worker;
+mod callback;
+mod metrics;
+mod account_cache;
+mod fee_payer;
+mod rate_limiter;
+mod resync;
+mod reconcile;
 
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::{HashMap, HashSet}, sync::Arc, path::Path, str::FromStr, time::{Duration, Instant}};
 
 use actix_web::web::Data;
-use libzkbob_rs::libzeropool::fawkes_crypto::{backend::bellman_groth16::Parameters, ff_uint::Num};
-use tokio::{sync::RwLock, fs};
+use libzkbob_rs::libzeropool::{constants, fawkes_crypto::{backend::bellman_groth16::{Parameters, verifier::VK}, ff_uint::Num}};
+use sha2::{Digest, Sha256};
+use tokio::{sync::{RwLock, Semaphore}, fs};
 use uuid::Uuid;
 use zkbob_utils_rs::{contracts::pool::Pool, tracing};
 
 use crate::{
-    account::{types::AccountInfo, Account},
-    cloud::types::{TransferPart, TransferStatus, TransferTask, AccountData},
-    config::Config,
+    account::{types::{AccountInfo, ReservedFunds}, history::{HistoryTx, HistoryTxType, HistoryFilter}, Account, parse_l1_address, validate_shielded_address},
+    cloud::types::{TransferPart, TransferStatus, TransferTask, AccountData, NoteReservation, TransferOutput},
+    config::{BackpressureConfig, Config, HealthDependency, LimitsConfig, QueueBackend},
     errors::CloudError,
-    helpers::{timestamp, queue::Queue},
-    relayer::cached::CachedRelayerClient,
+    helpers::{AsU64Amount, timestamp, check_length, invert, truncate, queue::Queue, semaphore::TaskSemaphore, fault::{FaultInjector, FaultRule}, key_cipher::{self, KeyCipher}},
+    relayer::cached::{CachedRelayerClient, Transaction},
     web3::cached::CachedWeb3Client,
     Engine, Fr,
 };
 
-use self::{db::Db, send_worker::run_send_worker, status_worker::run_status_worker, types::{AccountShortInfo, Transfer, ReportTask, ReportStatus, AccountImportData, CloudHistoryTx}, cleanup::AccountCleanup, report_worker::run_report_worker};
+use self::{db::Db, send_worker::run_send_worker, status_worker::run_status_worker, types::{AccountAttributes, AccountShortInfo, Transfer, ReportTask, ReportStatus, AccountImportData, CloudHistoryTx, CircuitInfo, TransferPartTrace, SupportBundle, SupportQueues, IndexedAddress, RepairAction, SyncResult, ResyncStatus, SubmissionReceipt, RetryBudget, SaturationStatus, ReconcileStatus, SyncStatus, HealthStatus, DependencyHealth}, cleanup::AccountCleanup, report_worker::run_report_worker, retention_worker::run_retention_worker, dd_watcher::run_dd_watcher, sync_coalescer::SyncCoalescer, retry_attempts::RetryAttempts, metrics::Metrics, sync_worker::{run_sync_worker, run_background_sync_worker, sync_progress, SyncTimes, BackgroundSyncs}, account_cache::{get_or_load, AccountCache, AccountLocks}, fee_payer::{run_fee_top_up_worker, FEE_TOP_UP_PREFIX}, rate_limiter::RateLimiter, resync::{run_resync_worker, Resyncs}, reconcile::{run_reconcile_worker, Reconciliations}};
 
 pub struct ZkBobCloud {
     pub(crate) config: Data<Config>,
     pub(crate) db: RwLock<Db>,
     pub(crate) pool_id: Num<Fr>,
     pub(crate) params: Arc<Parameters<Engine>>,
+    pub(crate) params_hash: String,
+    pub(crate) vk: Arc<VK<Engine>>,
 
-    pub(crate) relayer_fee: u64,
+    // last known relayer fee and the time it was fetched
+    relayer_fee: RwLock<(u64, Instant)>,
     pub(crate) relayer: CachedRelayerClient,
     pub(crate) web3: CachedWeb3Client,
 
     pub(crate) send_queue: Arc<RwLock<Queue>>,
     pub(crate) status_queue: Arc<RwLock<Queue>>,
     pub(crate) report_queue: Arc<RwLock<Queue>>,
+    // send tasks in progress, one per part, bounded by send_worker.max_parallel
+    pub(crate) prover: Arc<TaskSemaphore>,
+    // bounds the number of proofs computed at once by the send tasks holding `prover` permits
+    pub(crate) proving: Semaphore,
 
-    pub(crate) accounts: Arc<RwLock<HashMap<Uuid, Arc<Account>>>>,
+    pub(crate) accounts: Arc<RwLock<AccountCache>>,
+    account_locks: AccountLocks,
+    pub(crate) status_attempts: RetryAttempts,
+    pub(crate) metrics: Metrics,
+    faults: FaultInjector,
+
+    fee_payer: Option<Uuid>,
+    key_cipher: KeyCipher,
+    backup_cipher: Option<KeyCipher>,
+    syncs: SyncCoalescer<(Uuid, Option<u64>)>,
+    sync_times: SyncTimes,
+    background_syncs: BackgroundSyncs,
+    resyncs: Resyncs,
+    reconciliations: Reconciliations,
+    reservation_locks: AccountLocks,
+    address_limiter: RateLimiter<Uuid>,
 }
 
 impl ZkBobCloud {
@@ -49,48 +84,137 @@ impl ZkBobCloud {
         pool: Pool,
         pool_id: Num<Fr>,
         params: Parameters<Engine>,
+        params_hash: String,
     ) -> Result<Data<Self>, CloudError> {
-        let db = Db::new(&config.db_path)?;
-        let relayer = CachedRelayerClient::new(&config.relayer_url, &config.db_path)?;
+        let faults = FaultInjector::new(&config.fault_injection)?;
+        let key_cipher = KeyCipher::new(&config.key_encryption)?;
+        let fee_payer = invert(config.fee_payer_account_id.as_deref().map(|id| {
+            Uuid::from_str(id).map_err(|_| CloudError::ConfigError(format!("invalid fee payer account id: {}", id)))
+        }))?;
+        let backup_cipher = invert(config.key_encryption.backup_key.as_deref().map(KeyCipher::from_hex_key))?;
+
+        let mut db = Db::new(&config.db_path)?;
+        db.set_faults(faults.clone());
+        encrypt_stored_keys(&mut db, &key_cipher)?;
+        let relayer = CachedRelayerClient::new(
+            &config.relayer_url.urls(),
+            &config.db_path,
+            config.max_relayer_index_lag,
+            Duration::from_millis(config.relayer_info_ttl_ms),
+            faults.clone(),
+        )?;
         let relayer_fee = relayer.fee().await?;
+        if !relayer_fee_is_plausible(&config, relayer_fee) {
+            tracing::error!(
+                "relayer fee {} is out of bounds [{}, {}]",
+                relayer_fee,
+                config.relayer_fee.min,
+                config.relayer_fee.max
+            );
+        }
 
-        let web3 = CachedWeb3Client::new(pool, &config.db_path).await?;
+        if let Some(max_concurrent) = config.send_worker.max_concurrent {
+            if max_concurrent > config.send_worker.max_parallel {
+                tracing::warn!(
+                    "send_worker.max_concurrent {} exceeds send_worker.max_parallel {}, at most {} proofs are computed at once",
+                    max_concurrent,
+                    config.send_worker.max_parallel,
+                    config.send_worker.max_parallel
+                );
+            }
+        }
+
+        let web3 = CachedWeb3Client::new(pool, &config.web3.provider_endpoint, &config.db_path, faults.clone()).await?;
 
         let send_queue = Queue::new(
+            &config,
             "send",
-            &config.redis_url,
             config.send_worker.queue_delay_sec,
             config.send_worker.queue_hidden_sec,
+            faults.clone(),
         )
         .await?;
 
         let status_queue = Queue::new(
+            &config,
             "status",
-            &config.redis_url,
             config.status_worker.queue_delay_sec,
             config.status_worker.queue_hidden_sec,
+            faults.clone(),
         )
         .await?;
             
-        let report_queue = Queue::new("report", &config.redis_url, 0, 180).await?;
+        let report_queue = Queue::new(&config, "report", 0, 180, faults.clone()).await?;
+
+        check_stored_limits(&db, &config);
+        if let Some(fee_payer) = fee_payer {
+            if !db.account_exists(fee_payer)? {
+                tracing::error!("fee payer account {} doesn't exist, fees will not be topped up", fee_payer);
+            }
+        }
+
+        if let Some(expected_params_hash) = &config.expected_params_hash {
+            if !expected_params_hash.eq_ignore_ascii_case(&params_hash) {
+                tracing::error!(
+                    "loaded transfer params hash {} doesn't match expected {}, transfers will fail",
+                    params_hash,
+                    expected_params_hash
+                );
+            }
+        }
+
+        let vk = params.get_vk();
 
         let cloud = Data::new(Self {
             config: config.clone(),
             db: RwLock::new(db),
             pool_id,
             params: Arc::new(params),
-            relayer_fee,
+            params_hash,
+            vk: Arc::new(vk),
+            relayer_fee: RwLock::new((relayer_fee, Instant::now())),
             relayer,
             web3,
             send_queue: Arc::new(RwLock::new(send_queue)),
             status_queue: Arc::new(RwLock::new(status_queue)),
             report_queue: Arc::new(RwLock::new(report_queue)),
-            accounts: Arc::new(RwLock::new(HashMap::new())),
+            prover: Arc::new(TaskSemaphore::new(config.send_worker.max_parallel)),
+            proving: Semaphore::new(config.send_worker.max_concurrent_proofs()),
+            accounts: Arc::new(RwLock::new(AccountCache::new(config.max_loaded_accounts))),
+            account_locks: AccountLocks::new(),
+            status_attempts: RetryAttempts::new(),
+            metrics: Metrics::new()?,
+            faults,
+            fee_payer,
+            key_cipher,
+            backup_cipher,
+            syncs: SyncCoalescer::new(),
+            sync_times: SyncTimes::new(),
+            background_syncs: BackgroundSyncs::new(),
+            resyncs: Resyncs::new(),
+            reconciliations: Reconciliations::new(),
+            reservation_locks: AccountLocks::new(),
+            address_limiter: RateLimiter::new(
+                config.limits.max_generated_addresses_per_minute,
+                Duration::from_secs(60),
+            ),
         });
 
+        // the in-memory queues start empty, unfinished parts are queued again as rsmq would redeliver them
+        if let QueueBackend::Memory = cloud.config.queue_backend {
+            cloud.requeue_unfinished_parts().await?;
+        }
+
         run_send_worker(cloud.clone());
         run_status_worker(cloud.clone());
         run_report_worker(cloud.clone(), 5);
+        run_retention_worker(cloud.clone());
+        run_dd_watcher(cloud.clone())?;
+        run_sync_worker(cloud.clone());
+        run_background_sync_worker(cloud.clone());
+        run_fee_top_up_worker(cloud.clone());
+        run_resync_worker(cloud.clone());
+        run_reconcile_worker(cloud.clone());
         
         Ok(cloud)
     }
@@ -98,32 +222,115 @@ impl ZkBobCloud {
     pub async fn new_account(
         &self,
         description: String,
+        attributes: AccountAttributes,
         id: Option<Uuid>,
         sk: Option<Vec<u8>>,
+        first_index: Option<u64>,
     ) -> Result<Uuid, CloudError> {
+        check_length("description", &description, self.config.limits.max_description_length)?;
+        check_attributes(&attributes, &self.config.limits)?;
+
         let id = id.unwrap_or(uuid::Uuid::new_v4());
         if self.db.read().await.account_exists(id)? {
             return Err(CloudError::DuplicateAccountId);
         }
 
+        if let Some(first_index) = first_index {
+            self.validate_first_index(first_index).await?;
+        }
+
         let db_path = self.db.read().await.account_db_path(id);
-        let account = Account::new(id, description.clone(), sk, self.pool_id, &db_path)?;
+        let mut account = Account::new(id, description.clone(), sk, self.pool_id, &db_path, first_index, &self.key_cipher)?;
+        account.skip_parse_errors = self.config.skip_parse_errors;
         let id = account.id;
-        self.db.write().await.save_account(
+        let sk = self.encode_sk(&account.export_key().await?)?;
+        let default_address = account.default_address().await;
+        let mut db = self.db.write().await;
+        db.save_account(
             id,
             &AccountData {
                 db_path,
                 description,
-                sk: account.export_key().await?,
+                sk,
+                first_index,
+                sync_failures: 0,
+                last_sync_error: None,
+                skipped_indices: vec![],
+                attributes,
             },
         )?;
+        db.save_addresses(id, [&default_address].into_iter())?;
         tracing::info!("created a new account: {}", id);
         Ok(id)
     }
 
-    pub async fn import_accounts(&self, accounts: Vec<AccountImportData>) -> Result<(), CloudError> {
+    // A failed account doesn't abort the import, existing accounts fail with DuplicateAccountId
+    pub async fn import_accounts(&self, accounts: Vec<AccountImportData>) -> Vec<(Uuid, Result<(), CloudError>)> {
+        let mut results = Vec::new();
         for account in accounts {
-            self.new_account(account.description, Some(account.id), Some(account.sk)).await?;
+            let result = self
+                .new_account(account.description, account.attributes, Some(account.id), Some(account.sk), account.first_index)
+                .await
+                .map(|_| ());
+            if let Err(err) = &result {
+                tracing::warn!("failed to import account {}: {}", account.id, err);
+            }
+            results.push((account.id, result));
+        }
+        results
+    }
+
+    // Reads stored data only, accounts are not loaded or synced
+    pub async fn export_accounts(&self) -> Result<Vec<AccountImportData>, CloudError> {
+        self.db
+            .read()
+            .await
+            .get_accounts()?
+            .into_iter()
+            .map(|(id, data)| {
+                Ok::<_, CloudError>(AccountImportData {
+                    id,
+                    description: data.description,
+                    attributes: data.attributes,
+                    sk: self.decode_sk(&data.sk)?,
+                    first_index: data.first_index,
+                })
+            })
+            .collect()
+    }
+
+    pub fn encrypt_bundle(&self, data: &[u8]) -> Result<String, CloudError> {
+        let cipher = self.backup_cipher.as_ref().ok_or(CloudError::BadRequest(
+            "backup key is not configured".to_string(),
+        ))?;
+        Ok(hex::encode(cipher.encrypt(data)?))
+    }
+
+    pub fn decrypt_bundle(&self, bundle: &str) -> Result<Vec<u8>, CloudError> {
+        let cipher = self.backup_cipher.as_ref().ok_or(CloudError::BadRequest(
+            "backup key is not configured".to_string(),
+        ))?;
+        let bundle = hex::decode(bundle)?;
+        if !KeyCipher::is_encrypted(&bundle) {
+            return Err(CloudError::BadRequest("bundle is not encrypted".to_string()));
+        }
+        cipher.decrypt(&bundle)
+    }
+
+    async fn validate_first_index(&self, first_index: u64) -> Result<(), CloudError> {
+        if first_index % (constants::OUT as u64 + 1) != 0 {
+            return Err(CloudError::BadRequest(format!(
+                "firstIndex must be a multiple of {}",
+                constants::OUT + 1
+            )));
+        }
+
+        let relayer_index = self.relayer.info().await?.delta_index;
+        if first_index > relayer_index {
+            return Err(CloudError::BadRequest(format!(
+                "firstIndex cannot exceed the current pool index {}",
+                relayer_index
+            )));
         }
         Ok(())
     }
@@ -133,10 +340,9 @@ impl ZkBobCloud {
             .get_account(id)?
             .ok_or(CloudError::AccountNotFound)?;
 
-        let accounts = self.accounts.write().await;
-        if accounts.get(&id).is_some() {
-            return Err(CloudError::AccountIsBusy);
-        }
+        // the account db is closed before its files are removed and can't be loaded meanwhile
+        let _lock = self.account_locks.lock(id).await;
+        self.accounts.write().await.evict(id)?;
 
         fs::remove_dir_all(&data.db_path).await.map_err(|err| {
             tracing::warn!("failed to delete account data: {}", err);
@@ -146,54 +352,513 @@ impl ZkBobCloud {
         self.db.write().await.delete_account(id)
     }
 
-    pub async fn list_accounts(&self) -> Result<Vec<AccountShortInfo>, CloudError> {
-        Ok(self
+    // An account in use can't be evicted: a reload would create a second instance next to the one in use
+    pub async fn evict_account(&self, id: Uuid) -> Result<(), CloudError> {
+        if !self.db.read().await.account_exists(id)? {
+            return Err(CloudError::AccountNotFound);
+        }
+
+        // a concurrent load is finished first, so the account it inserts is evicted too
+        let _lock = self.account_locks.lock(id).await;
+        self.accounts.write().await.evict(id)?;
+        tracing::info!("account {} is evicted, it will be reloaded on next access", id);
+        Ok(())
+    }
+
+    // The limit is capped by max_accounts_page_size
+    pub async fn list_accounts(&self, offset: u64, limit: u64) -> Result<(Vec<AccountShortInfo>, u64), CloudError> {
+        let limit = limit.min(self.config.max_accounts_page_size);
+        let (accounts, total) = self
             .db
             .read()
             .await
-            .get_accounts()?
+            .get_accounts_page(offset as usize, limit as usize)?;
+        let accounts = accounts
             .into_iter()
-            .map(|(id, data)| AccountShortInfo {
-                id: id.as_hyphenated().to_string(),
-                description: data.description,
-                sk: data.sk,
+            .map(|(id, data)| {
+                let degraded = self.is_degraded(&data);
+                Ok::<_, CloudError>(AccountShortInfo {
+                    id: id.as_hyphenated().to_string(),
+                    description: data.description,
+                    sk: hex::encode(self.decode_sk(&data.sk)?),
+                    degraded,
+                    last_sync_error: data.last_sync_error.filter(|_| degraded),
+                })
             })
-            .collect())
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok((accounts, total as u64))
+    }
+
+    // Private keys in the cloud db are stored as hex, encrypted when key encryption is enabled
+    fn encode_sk(&self, sk: &str) -> Result<String, CloudError> {
+        Ok(hex::encode(self.key_cipher.encrypt(&hex::decode(sk)?)?))
+    }
+
+    fn decode_sk(&self, sk: &str) -> Result<Vec<u8>, CloudError> {
+        self.key_cipher.decrypt(&hex::decode(sk)?)
     }
 
-    pub async fn account_info(&self, id: Uuid) -> Result<AccountInfo, CloudError> {
+    // A degraded account is reported with its last synced state instead of failing the request
+    // With the sync worker enabled, an account synced within max_staleness_sec is not synced again
+    pub async fn account_info(&self, id: Uuid, max_staleness_sec: Option<u64>) -> Result<AccountInfo, CloudError> {
         let (account, _cleanup) = self.get_account(id).await?;
-        account.sync(&self.relayer, None).await?;
-        let info = account.info(self.relayer_fee).await;
+        let max_staleness_sec = max_staleness_sec.or(
+            self.config.sync_worker.enabled.then_some(self.config.sync_worker.max_staleness_sec)
+        );
+        let synced = match max_staleness_sec {
+            Some(max_staleness_sec) if self.sync_times.is_fresh(id, max_staleness_sec) => Ok(()),
+            _ => self.sync_for_request(id, &account).await,
+        };
+        let (degraded, last_sync_error) = self.sync_health(id).await?;
+        if let Err(err) = &synced {
+            if !degraded {
+                return Err(err.clone());
+            }
+            tracing::warn!("account {} is degraded, returning stale info", id);
+        }
+
+        let reserved = self.reserved_funds(id, &account).await?;
+        let mut info = account.info(self.current_fee().await, &reserved).await;
+        info.degraded = degraded;
+        info.last_sync_error = last_sync_error;
+        if self.config.report_spendable_balance {
+            info.spendable_balance = Some(info.balance.saturating_sub(self.pending_outgoing(id, &account).await?));
+        }
+        if self.config.direct_deposit_watcher.enabled && synced.is_ok() {
+            info.pending_balance = Some(self.reconcile_direct_deposits(id, &account).await?);
+        }
         Ok(info)
     }
 
+    // Amounts and fees of unfinished parts that are not reflected in the synced balance yet,
+    // a mined part waiting for confirmations is already in the synced state
+    pub(crate) async fn pending_outgoing(&self, id: Uuid, account: &Account) -> Result<u64, CloudError> {
+        let parts = self.db.read().await.get_account_parts(id)?;
+        let synced_hashes = if parts.iter().any(|part| !part.status.is_final() && part.tx_hash.is_some()) {
+            account.tx_hashes().await?
+        } else {
+            HashSet::new()
+        };
+        Ok(pending_outgoing_amount(&parts, |part| {
+            part.tx_hash.as_ref().map(|tx_hash| synced_hashes.contains(tx_hash)).unwrap_or(false)
+        }))
+    }
+
+    // Starts the reconciliation in the background, see reconcile::run_reconcile_worker
+    pub fn start_reconcile(&self) -> ReconcileStatus {
+        self.reconciliations.start()
+    }
+
+    pub fn reconcile_status(&self) -> Result<ReconcileStatus, CloudError> {
+        self.reconciliations.status().ok_or(CloudError::ReconcileNotFound)
+    }
+
+    pub(crate) async fn sync_to_latest(&self, id: Uuid, account: &Account) -> Result<(), CloudError> {
+        self.sync_batch(id, account, None).await
+    }
+
+    // Requests that need the latest state don't wait for a long sync, see check_sync_gap
+    async fn sync_for_request(&self, id: Uuid, account: &Account) -> Result<(), CloudError> {
+        self.check_sync_gap(id, account).await?;
+        self.sync_to_latest(id, account).await
+    }
+
+    // Syncs at most max_txs transactions towards the latest relayer index
+    pub(crate) async fn sync_batch(&self, id: Uuid, account: &Account, max_txs: Option<u64>) -> Result<(), CloudError> {
+        let sync = self.tracked_sync_batch(id, account, None, max_txs);
+        match self.config.coalesce_syncs {
+            true => self.syncs.sync((id, max_txs), sync).await,
+            false => sync.await,
+        }
+    }
+
+    // With incremental sync enabled, an account that is too far behind the relayer is synced
+    // in the background and the request fails until it catches up, instead of waiting for the sync
+    async fn check_sync_gap(&self, id: Uuid, account: &Account) -> Result<(), CloudError> {
+        if !self.config.incremental_sync.enabled {
+            return Ok(());
+        }
+        if self.background_syncs.is_syncing(id) {
+            return Err(CloudError::AccountIsNotSynced);
+        }
+
+        let info = self.relayer.info().await?;
+        let remaining = self.relayer.tx_count(account.next_index().await, info.delta_index)?;
+        if remaining > self.config.incremental_sync.max_blocking_gap {
+            tracing::info!("account {} is {} transactions behind the relayer, syncing it in the background", id, remaining);
+            self.background_syncs.schedule(id);
+            return Err(CloudError::AccountIsNotSynced);
+        }
+        Ok(())
+    }
+
+    async fn tracked_sync(&self, id: Uuid, account: &Account, to_index: Option<u64>) -> Result<(), CloudError> {
+        self.tracked_sync_batch(id, account, to_index, None).await
+    }
+
+    // A limited sync doesn't mark the account as fresh, it may still be behind
+    async fn tracked_sync_batch(&self, id: Uuid, account: &Account, to_index: Option<u64>, max_txs: Option<u64>) -> Result<(), CloudError> {
+        let result = account.sync(&self.relayer, to_index, max_txs).await;
+        if result.is_ok() && max_txs.is_none() {
+            self.sync_times.record(id);
+        }
+        if let Err(err) = self.track_sync_result(id, &result).await {
+            tracing::warn!("failed to save sync state of account {}: {}", id, err);
+        }
+        result.map(|_| ())
+    }
+
+    // Syncs with transactions fetched by the caller, the failures are tracked as for other syncs
+    pub(crate) async fn tracked_sync_with_txs(&self, id: Uuid, account: &Account, txs: &[Transaction]) -> Result<usize, CloudError> {
+        let result = account.sync_with_txs(txs).await;
+        if result.is_ok() {
+            self.sync_times.record(id);
+        }
+        let tracked = result.as_ref().map(|_| vec![]).map_err(Clone::clone);
+        if let Err(err) = self.track_sync_result(id, &tracked).await {
+            tracing::warn!("failed to save sync state of account {}: {}", id, err);
+        }
+        result
+    }
+
+    // Consecutive sync failures are stored with the account, the account is degraded once
+    // they reach the configured threshold and recovers on the next successful sync.
+    // Indices of skipped transactions are recorded by the account state itself
+    async fn track_sync_result(&self, id: Uuid, result: &Result<Vec<u64>, CloudError>) -> Result<(), CloudError> {
+        // it is updated under the write lock, so concurrent syncs don't overwrite each other's counts
+        self.db.write().await.update_account(id, |data| {
+            let was_degraded = self.is_degraded(&data);
+            let failures = data.sync_failures;
+            let data = tracked_sync_data(data, result)?;
+            match result {
+                Ok(_) if was_degraded => tracing::info!("account {} recovered after {} failed syncs", id, failures),
+                Err(err) if data.sync_failures == self.config.degraded_sync_failures.max(1) => {
+                    tracing::warn!("account {} is degraded after {} failed syncs, last error: {:?}", id, data.sync_failures, err);
+                }
+                _ => {}
+            }
+            Some(data)
+        })
+    }
+
+    fn is_degraded(&self, data: &AccountData) -> bool {
+        data.sync_failures >= self.config.degraded_sync_failures.max(1)
+    }
+
+    async fn sync_health(&self, id: Uuid) -> Result<(bool, Option<String>), CloudError> {
+        Ok(match self.db.read().await.get_account(id)? {
+            Some(data) if self.is_degraded(&data) => (true, data.last_sync_error),
+            _ => (false, None),
+        })
+    }
+
+    // Pending direct deposits are completed once the synced account has the deposited note,
+    // returns the total amount of the ones that are still pending
+    pub(crate) async fn reconcile_direct_deposits(&self, id: Uuid, account: &Account) -> Result<u64, CloudError> {
+        let account_id = id.to_string();
+        let deposits = self.db.read().await.get_pending_direct_deposits()?;
+
+        let mut matched = HashSet::new();
+        let mut pending_balance = 0;
+        for deposit in deposits.into_iter().filter(|deposit| deposit.account_id == account_id) {
+            match account.find_note(&deposit.address, deposit.amount, deposit.from_index, &matched).await {
+                Some(index) => {
+                    matched.insert(index);
+                    tracing::info!("direct deposit {} to account {} is completed at index {}", deposit.nonce, account_id, index);
+                    self.db.write().await.delete_pending_direct_deposit(&deposit.nonce)?;
+                }
+                None => pending_balance += deposit.amount,
+            }
+        }
+        Ok(pending_balance)
+    }
+
+    pub async fn sync_account(&self, id: Uuid) -> Result<SyncResult, CloudError> {
+        let (account, _cleanup) = self.get_account(id).await?;
+        let relayer_index = self.relayer.info().await?.delta_index;
+        let (degraded, _) = self.sync_health(id).await?;
+        self.tracked_sync(id, &account, Some(relayer_index)).await?;
+        let next_index = account.next_index().await;
+        // indices recorded with the account data by older versions are reported too
+        let mut skipped_indices = self
+            .db
+            .read()
+            .await
+            .get_account(id)?
+            .map(|data| data.skipped_indices)
+            .unwrap_or_default();
+        skipped_indices.extend(account.skipped_indices().await?);
+        skipped_indices.sort_unstable();
+        skipped_indices.dedup();
+        Ok(SyncResult {
+            next_index,
+            relayer_index,
+            synced: next_index >= relayer_index,
+            recovered: degraded,
+            skipped_indices,
+        })
+    }
+
+    // Reports sync progress of the account without syncing it
+    pub async fn sync_status(&self, id: Uuid) -> Result<SyncStatus, CloudError> {
+        let (account, _cleanup) = self.get_account(id).await?;
+        let info = self.relayer.info().await?;
+        let next_index = account.next_index().await;
+        Ok(SyncStatus {
+            next_index,
+            delta_index: info.delta_index,
+            optimistic_delta_index: info.optimistic_delta_index,
+            remaining_transactions: info.delta_index.saturating_sub(next_index) / (constants::OUT as u64 + 1),
+            progress_percent: sync_progress(account.first_index, next_index, info.delta_index),
+            syncing: self.background_syncs.is_syncing(id),
+        })
+    }
+
+    // Every generated address is indexed, so the calls are rate limited per account
     pub async fn generate_address(&self, id: Uuid) -> Result<String, CloudError> {
+        if !self.address_limiter.check(&id) {
+            return Err(CloudError::TooManyRequests);
+        }
         let (account, _cleanup) = self.get_account(id).await?;
         let address = account.generate_address().await;
+        self.db.write().await.save_addresses(id, [&address].into_iter())?;
         Ok(address)
     }
 
-    pub async fn history(&self, id: Uuid) -> Result<Vec<CloudHistoryTx>, CloudError> {
+    pub async fn generate_addresses(&self, id: Uuid, start: u64, count: u64) -> Result<Vec<IndexedAddress>, CloudError> {
+        if count > self.config.max_generated_addresses {
+            return Err(CloudError::BadRequest(format!(
+                "count cannot exceed {}",
+                self.config.max_generated_addresses
+            )));
+        }
+        let end = start
+            .checked_add(count)
+            .ok_or(CloudError::BadRequest("index range overflow".to_string()))?;
+        if !self.address_limiter.check(&id) {
+            return Err(CloudError::TooManyRequests);
+        }
+
+        let (account, _cleanup) = self.get_account(id).await?;
+        let mut addresses = Vec::new();
+        for index in start..end {
+            addresses.push(IndexedAddress {
+                index,
+                address: account.generate_address_at(index).await,
+            });
+        }
+        self.db
+            .write()
+            .await
+            .save_addresses(id, addresses.iter().map(|address| &address.address))?;
+        Ok(addresses)
+    }
+
+    // Returns records of the window newest first, the number of matching transactions and whether
+    // there are more of them after the window, the limit is capped by max_history_page_size
+    pub async fn history(
+        &self,
+        id: Uuid,
+        filter: &HistoryFilter,
+        offset: u64,
+        limit: u64,
+        optimistic: bool,
+    ) -> Result<(Vec<CloudHistoryTx>, u64, bool), CloudError> {
+        if let (Some(from), Some(to)) = (filter.from, filter.to) {
+            if from > to {
+                return Err(CloudError::BadRequest("from should not be greater than to".to_string()));
+            }
+        }
+
         let (account, _cleanup) = self.get_account(id).await?;
-        account.sync(&self.relayer, None).await?;
-        // TODO: optimistic history?
-        let history = account.history(&self.web3).await?;
+        self.sync_for_request(id, &account).await?;
+
+        // transactions that are not mined yet are added to the first page, they are not counted in total
         let mut result = vec![];
+        if offset == 0 {
+            let mut known = account.tx_hashes().await?;
+            let mut optimistic_records = vec![];
+            if optimistic {
+                for record in account.optimistic_history(&self.relayer).await? {
+                    known.insert(record.tx_hash.clone());
+                    if !filter.matches(&record) {
+                        continue;
+                    }
+                    let transaction_id = self.db.read().await.get_transaction_id(&record.tx_hash)?;
+                    let mut record = CloudHistoryTx::new(record, transaction_id);
+                    record.pending = true;
+                    optimistic_records.push(record);
+                }
+            }
+
+            result = self.pending_part_records(id, &known, filter).await?;
+            result.append(&mut optimistic_records);
+        }
+
+        let limit = limit.min(self.config.max_history_page_size);
+        let (history, total, has_more) = account
+            .history(&self.web3, self.config.partial_history, filter, offset as usize, limit as usize)
+            .await?;
         for record in history {
             let transaction_id = self.db.read().await.get_transaction_id(&record.tx_hash)?;
-            result.push(CloudHistoryTx::new(record, transaction_id));
+            let counterparty_account_id = self.counterparty_account_id(id, &record, transaction_id.as_deref()).await?;
+            let mut record = CloudHistoryTx::new(record, transaction_id);
+            record.counterparty_account_id = counterparty_account_id;
+            result.push(record);
+        }
+        let mut linked = self.linked_aggregations(id, &result).await?;
+        result.append(&mut linked);
+        Ok((result, total as u64, has_more))
+    }
+
+    // Transfer parts of the account that are not synced yet, newest first. A part is skipped
+    // once its tx hash is among the known ones, see pending_parts
+    async fn pending_part_records(
+        &self,
+        id: Uuid,
+        known: &HashSet<String>,
+        filter: &HistoryFilter,
+    ) -> Result<Vec<CloudHistoryTx>, CloudError> {
+        let parts = self.db.read().await.get_account_parts(id)?;
+
+        let mut records = vec![];
+        for part in pending_parts(parts, known) {
+            let tx_type = match (part.withdrawal, part.is_aggregation()) {
+                (true, _) => HistoryTxType::Withdrawal,
+                (false, true) => HistoryTxType::AggregateNotes,
+                (false, false) => HistoryTxType::TransferOut,
+            };
+            if !filter.matches_type_and_time(&tx_type, part.timestamp) {
+                continue;
+            }
+
+            let outputs = match part.is_aggregation() {
+                true => vec![(None, part.amount)],
+                false => part
+                    .tx_outputs()
+                    .into_iter()
+                    .map(|(to, amount)| (Some(to), amount))
+                    .collect(),
+            };
+            for (to, amount) in outputs {
+                records.push(CloudHistoryTx {
+                    tx_type: tx_type.clone(),
+                    tx_hash: part.tx_hash.clone(),
+                    timestamp: part.timestamp,
+                    amount: amount.as_u64_amount(),
+                    fee: part.fee,
+                    to,
+                    transaction_id: Some(part.transaction_id.clone()),
+                    change: None,
+                    counterparty_account_id: None,
+                    pending: true,
+                    status: Some(part.status.status()),
+                });
+            }
+        }
+        Ok(records)
+    }
+
+    // Aggregations of a transfer are mined before it, so they can be outside of the history window.
+    // They are restored from the transfer parts to keep linked tx hashes and fees of the page complete
+    async fn linked_aggregations(&self, id: Uuid, records: &[CloudHistoryTx]) -> Result<Vec<CloudHistoryTx>, CloudError> {
+        let id = id.as_hyphenated().to_string();
+        let known = records
+            .iter()
+            .filter_map(|record| record.tx_hash.as_deref())
+            .collect::<HashSet<_>>();
+        let transaction_ids = records
+            .iter()
+            .filter(|record| record.tx_type != HistoryTxType::AggregateNotes)
+            .filter_map(|record| record.transaction_id.as_deref())
+            .collect::<HashSet<_>>();
+
+        let db = self.db.read().await;
+        let mut linked = vec![];
+        for transaction_id in transaction_ids {
+            let task = match db.get_task(transaction_id) {
+                Ok(task) => task,
+                Err(_) => continue,
+            };
+            for part_id in task.parts {
+                let part = db.get_part(&part_id)?;
+                if part.account_id != id || !part.is_aggregation() || part.status != TransferStatus::Done {
+                    continue;
+                }
+                let tx_hash = match part.tx_hash {
+                    Some(tx_hash) if !known.contains(tx_hash.as_str()) => tx_hash,
+                    _ => continue,
+                };
+                linked.push(CloudHistoryTx {
+                    tx_type: HistoryTxType::AggregateNotes,
+                    tx_hash: Some(tx_hash),
+                    timestamp: part.timestamp,
+                    amount: part.amount.as_u64_amount(),
+                    fee: part.fee,
+                    to: None,
+                    transaction_id: Some(transaction_id.to_string()),
+                    change: None,
+                    counterparty_account_id: None,
+                    pending: false,
+                    status: None,
+                });
+            }
         }
-        Ok(result)
+        Ok(linked)
+    }
+
+    // Resolves the other side of a transfer if it is an account managed by this service:
+    // the receiver is found by the address generated by the service,
+    // the sender is found by the transfer task that produced the transaction
+    async fn counterparty_account_id(
+        &self,
+        id: Uuid,
+        record: &HistoryTx,
+        transaction_id: Option<&str>,
+    ) -> Result<Option<String>, CloudError> {
+        let db = self.db.read().await;
+        let counterparty = match record.tx_type {
+            HistoryTxType::TransferOut => match &record.to {
+                Some(to) => db.get_address_owner(to)?,
+                None => None,
+            },
+            HistoryTxType::TransferIn => match transaction_id {
+                Some(transaction_id) => db
+                    .get_task(transaction_id)
+                    .ok()
+                    .and_then(|task| task.parts.first().cloned())
+                    .and_then(|part_id| db.get_part(&part_id).ok())
+                    .map(|part| part.account_id),
+                None => None,
+            },
+            _ => None,
+        };
+        let id = id.as_hyphenated().to_string();
+        Ok(counterparty.filter(|counterparty| *counterparty != id))
     }
 
-    pub async fn calculate_fee(&self, id: Uuid, amount: u64) -> Result<(u64, u64), CloudError> {
+    // The placeholder receiver is used when the destination is not known yet
+    pub async fn calculate_fee(&self, id: Uuid, amount: u64, to: Option<String>) -> Result<(u64, u64), CloudError> {
+        if let Some(to) = &to {
+            validate_shielded_address(to)?;
+        }
+        let fee = self.current_fee().await;
+        self.check_relayer_fee(fee)?;
+        self.check_amount(amount, fee)?;
         let (account, _cleanup) = self.get_account(id).await?;
-        account.sync(&self.relayer, None).await?;
-        let parts = account
-            .get_tx_parts(amount, self.relayer_fee, "dummy")
-            .await?;
-        Ok((parts.len() as u64, parts.len() as u64 * self.relayer_fee))
+        self.sync_for_request(id, &account).await?;
+        let reserved = self.reserved_funds(id, &account).await?;
+        // the number of parts doesn't depend on the receiver, so without one the transfer
+        // is planned to the account's own address
+        let to = match to {
+            Some(to) => to,
+            None => account.default_address().await,
+        };
+        let (parts, _) = account.get_tx_parts(&[(to, amount)], fee, &reserved).await?;
+        let total_fee = (parts.len() as u64)
+            .checked_mul(fee)
+            .ok_or(CloudError::BadRequest("total fee overflows u64".to_string()))?;
+        Ok((parts.len() as u64, total_fee))
     }
 
     pub async fn export_key(&self, id: Uuid) -> Result<String, CloudError> {
@@ -201,54 +866,305 @@ impl ZkBobCloud {
         account.export_key().await
     }
 
-    pub async fn transfer(&self, request: Transfer) -> Result<String, CloudError> {
+    // Returns the transaction id and the transferred amount
+    pub async fn transfer(&self, request: Transfer) -> Result<(String, u64), CloudError> {
+        if request.id.starts_with(FEE_TOP_UP_PREFIX) {
+            return Err(CloudError::BadRequest(format!("transaction id cannot start with '{}'", FEE_TOP_UP_PREFIX)));
+        }
+        // the fees are reimbursed by a transfer with the prefixed id, it must fit the limit too
+        if self.fee_payer.filter(|fee_payer| *fee_payer != request.account_id).is_some() {
+            let max_length = self.config.limits.max_transaction_id_length.saturating_sub(FEE_TOP_UP_PREFIX.len());
+            check_length("transactionId", &request.id, max_length)?;
+        }
+
+        let id = request.id.clone();
+        let (_, amount) = self.submit_transfer(request).await?;
+        self.metrics.transfers_created.inc();
+        Ok((id, amount))
+    }
+
+    pub async fn withdraw(&self, request: Transfer) -> Result<(String, u64), CloudError> {
+        match request.outputs.as_slice() {
+            [(to, amount)] => {
+                parse_l1_address(to)?;
+                if request.native_amount > *amount {
+                    return Err(CloudError::BadRequest("native amount exceeds withdrawal amount".to_string()));
+                }
+            }
+            _ => return Err(CloudError::BadRequest("withdrawal must have a single receiver".to_string())),
+        };
+        self.transfer(request).await
+    }
+
+    // Returns the total fee paid by the sender and the transferred amount
+    async fn submit_transfer(&self, request: Transfer) -> Result<(u64, u64), CloudError> {
         if request.id.contains('.') {
             return Err(CloudError::InvalidTransactionId);
         }
+        check_length("transactionId", &request.id, self.config.limits.max_transaction_id_length)?;
+        if request.outputs.is_empty() || request.outputs.len() > self.config.limits.max_transfer_outputs {
+            return Err(CloudError::BadRequest(format!(
+                "transfer must have from 1 to {} outputs",
+                self.config.limits.max_transfer_outputs
+            )));
+        }
+        if request.sweep && (request.withdrawal || request.outputs.len() != 1) {
+            return Err(CloudError::BadRequest("sweep must be a transfer to a single receiver".to_string()));
+        }
+        // withdrawal receivers are L1 addresses, they are checked by withdraw
+        if !request.withdrawal {
+            for (to, _) in request.outputs.iter() {
+                validate_shielded_address(to)?;
+            }
+        }
+        if let Some(callback_url) = &request.callback_url {
+            callback::validate_callback_url(callback_url, &self.config.callback.allowed_hosts)?;
+        }
 
         if self.db.read().await.task_exists(&request.id)? {
             return Err(CloudError::DuplicateTransactionId);
         }
 
+        if applies_backpressure(&self.config.backpressure, request.admin) {
+            let saturation = self.saturation().await?;
+            if saturation.saturated {
+                tracing::warn!(
+                    "rejecting transfer {}: service is saturated, send queue depth: {}, prover in flight: {}",
+                    &request.id,
+                    saturation.send_queue_depth,
+                    saturation.prover_in_flight
+                );
+                return Err(CloudError::ServiceIsBusy);
+            }
+        }
+
+        // withdrawals are charged the current relayer fee, including the aggregation parts
+        let fee = match request.withdrawal {
+            true => self.relayer.withdrawal_fee().await?,
+            false => self.current_fee().await,
+        };
+        self.check_relayer_fee(fee)?;
+        if !request.sweep {
+            // fee top-ups carry the exact relayer fee, it isn't a multiple of the denomination
+            let denomination = match request.internal {
+                true => None,
+                false => self.config.amount_denomination,
+            };
+            for (_, amount) in request.outputs.iter() {
+                check_amount(*amount, fee, denomination)?;
+            }
+        }
+
         let (account, _cleanup) = self.get_account(request.account_id).await?;
-        account.sync(&self.relayer, None).await?;
+        self.sync_for_request(request.account_id, &account).await?;
 
-        let tx_parts = account
-            .get_tx_parts(request.amount, self.relayer_fee, &request.to)
+        // planning and reservation must be atomic, otherwise concurrent transfers can over-spend
+        let _reservation_guard = self.reservation_locks.lock(request.account_id).await;
+        let reserved = self.reserved_funds(request.account_id, &account).await?;
+        // the maximum is resolved under the reservation lock, so it accounts for concurrent transfers
+        let outputs = match request.sweep {
+            true => {
+                // the rest that is not a multiple of the denomination stays on the account
+                let amount = round_to_denomination(
+                    account.max_transfer_amount(fee, &reserved).await,
+                    self.config.amount_denomination,
+                );
+                if amount == 0 {
+                    return Err(CloudError::InsufficientBalance);
+                }
+                tracing::info!("transfer {} sweeps {} from account {}", &request.id, amount, request.account_id);
+                request.outputs.iter().map(|(to, _)| (to.clone(), amount)).collect()
+            }
+            false => request.outputs.clone(),
+        };
+        let amount = outputs.iter().fold(0u64, |total, (_, amount)| total.saturating_add(*amount));
+        let (tx_parts, used) = account
+            .get_tx_parts(&outputs, fee, &reserved)
             .await?;
 
+        let support_id = request.support_id.filter(|support_id| {
+            let indexable = !support_id.is_empty()
+                && support_id.len() <= self.config.limits.max_transaction_id_length;
+            if !indexable {
+                tracing::warn!("transfer {} has support id that can't be indexed", &request.id);
+            }
+            indexable
+        });
         let mut task = TransferTask {
             transaction_id: request.id.clone(),
             parts: Vec::new(),
+            support_id: support_id.clone(),
+            callback_url: request.callback_url,
         };
         let mut parts = Vec::new();
         for (i, tx_part) in tx_parts.into_iter().enumerate() {
+            // a single output is stored as `to`, so such parts look the same as before multi-output transfers
+            let (to, outputs) = match tx_part.outputs.as_slice() {
+                [(to, _)] => (Some(to.clone()), vec![]),
+                outputs => (
+                    None,
+                    outputs
+                        .iter()
+                        .map(|(to, amount)| TransferOutput { to: to.clone(), amount: *amount })
+                        .collect(),
+                ),
+            };
             let part = TransferPart {
                 id: format!("{}.{}", &request.id, i),
                 transaction_id: request.id.clone(),
                 account_id: request.account_id.to_string(),
-                amount: tx_part.1,
-                fee: self.relayer_fee,
-                to: tx_part.0,
+                amount: tx_part.amount,
+                fee,
+                withdrawal: request.withdrawal && to.is_some(),
+                native_amount: match request.withdrawal && to.is_some() {
+                    true => request.native_amount,
+                    false => 0,
+                },
+                to,
+                outputs,
                 status: TransferStatus::New,
                 job_id: None,
                 tx_hash: None,
                 depends_on: (i > 0).then_some(format!("{}.{}", &request.id, i - 1)),
                 attempt: 0,
                 timestamp: timestamp(),
+                pool_index: None,
+                params_hash: None,
+                relayer_url: None,
+                nullifier: None,
             };
             parts.push(part);
             task.parts.push(format!("{}.{}", &request.id, i));
         }
 
-        self.db.write().await.save_task(&task, parts.iter())?;
+        let reservation = self.config.reserve_notes.then(|| NoteReservation {
+            transaction_id: request.id.clone(),
+            account_id: request.account_id.to_string(),
+            notes: used.notes.into_iter().collect(),
+        });
+        {
+            let mut db = self.db.write().await;
+            db.save_task(&task, parts.iter(), reservation.as_ref())?;
+            if let Some(support_id) = &support_id {
+                db.index_support_id(
+                    support_id,
+                    &request.id,
+                    self.config.limits.max_transfers_per_support_id,
+                )?;
+            }
+        }
 
+        let total_fee = fee
+            .checked_mul(parts.len() as u64)
+            .ok_or(CloudError::InternalError("fee overflow".to_string()))?;
         let mut send_queue = self.send_queue.write().await;
         for part in parts {
             send_queue.send(part.id).await?;
         }
 
-        Ok(request.id)
+        Ok((total_fee, amount))
+    }
+
+    pub async fn saturation(&self) -> Result<SaturationStatus, CloudError> {
+        let send_queue_depth = self.send_queue.write().await.len().await?;
+        Ok(saturation_status(&self.config.backpressure, send_queue_depth, &self.prover))
+    }
+
+    // Dependencies are checked one by one, so the latency of each check is not affected by the others
+    pub async fn health(&self) -> HealthStatus {
+        let timeout = Duration::from_millis(self.config.health.timeout_ms);
+        let mut dependencies = Vec::new();
+        for name in [HealthDependency::Redis, HealthDependency::Relayer, HealthDependency::Rpc] {
+            let started = Instant::now();
+            let check = async {
+                match name {
+                    HealthDependency::Redis => self.send_queue.write().await.ping().await,
+                    HealthDependency::Relayer => self.relayer.get_info(true).await.map(|_| ()),
+                    HealthDependency::Rpc => self.web3.block_number().await.map(|_| ()),
+                }
+            };
+            let result = tokio::time::timeout(timeout, check)
+                .await
+                .unwrap_or_else(|_| Err(CloudError::InternalError(format!("timed out after {} ms", timeout.as_millis()))));
+            dependencies.push(DependencyHealth {
+                critical: self.config.health.critical.contains(&name),
+                name,
+                healthy: result.is_ok(),
+                latency_ms: started.elapsed().as_millis() as u64,
+                error: result.err().map(|err| format!("{:?}", err)),
+            });
+        }
+
+        HealthStatus {
+            healthy: dependencies.iter().all(|dependency| dependency.healthy || !dependency.critical),
+            degraded: dependencies.iter().any(|dependency| !dependency.healthy && !dependency.critical),
+            dependencies,
+        }
+    }
+
+    // Gauges are sampled on each scrape, queue depth includes messages being processed
+    pub async fn metrics(&self) -> Result<String, CloudError> {
+        let (loaded_accounts, busy_accounts) = {
+            let accounts = self.accounts.read().await;
+            (accounts.len(), accounts.busy())
+        };
+        self.metrics.loaded_accounts.set(loaded_accounts as i64);
+        self.metrics.busy_accounts.set(busy_accounts as i64);
+
+        let queues = [("send", &self.send_queue), ("status", &self.status_queue), ("report", &self.report_queue)];
+        for (name, queue) in queues {
+            match queue.write().await.len().await {
+                Ok(depth) => self.metrics.queue_depth.with_label_values(&[name]).set(depth as i64),
+                Err(err) => tracing::warn!("failed to get {} queue depth: {}", name, err),
+            }
+        }
+        self.metrics.render()
+    }
+
+    // A transfer reserves its notes and the balance its parts will take until the account state reflects them,
+    // so the funds are not counted twice between mining and sync. Reservations are released lazily
+    // once every part of the transfer is final and synced
+    async fn reserved_funds(&self, account_id: Uuid, account: &Account) -> Result<ReservedFunds, CloudError> {
+        let mut reserved = ReservedFunds::default();
+        if !self.config.reserve_notes {
+            return Ok(reserved);
+        }
+
+        let account_id = account_id.to_string();
+        let reservations = self.db.read().await.get_reservations()?;
+        let mut synced_hashes = None;
+        for reservation in reservations {
+            if reservation.account_id != account_id {
+                continue;
+            }
+
+            let parts = match self.transfer_status(&reservation.transaction_id).await {
+                Ok(parts) => parts,
+                Err(_) if !self.db.read().await.task_exists(&reservation.transaction_id)? => vec![],
+                Err(err) => return Err(err),
+            };
+            if parts.iter().any(|part| part.tx_hash.is_some()) && synced_hashes.is_none() {
+                synced_hashes = Some(account.tx_hashes().await?);
+            }
+            let synced_hashes = synced_hashes.as_ref();
+            let is_synced = |part: &TransferPart| {
+                match (&part.tx_hash, synced_hashes) {
+                    (Some(tx_hash), Some(synced_hashes)) => synced_hashes.contains(tx_hash),
+                    _ => false,
+                }
+            };
+            if is_released(&parts, is_synced) {
+                self.db.write().await.delete_reservation(&reservation.transaction_id)?;
+                continue;
+            }
+
+            let notes_value = account.notes_value(&reservation.notes).await;
+            reserved.balance = reserved
+                .balance
+                .saturating_add(reserved_balance(&parts, is_synced, notes_value));
+            reserved.notes.extend(reservation.notes);
+        }
+        Ok(reserved)
     }
 
     pub async fn transfer_status(&self, id: &str) -> Result<Vec<TransferPart>, CloudError> {
@@ -262,56 +1178,1296 @@ impl ZkBobCloud {
         Ok(parts)
     }
 
-    pub async fn generate_report(&self) -> Result<Uuid, CloudError> {
-        let id = Uuid::new_v4();
-        let task = ReportTask {
-            status: ReportStatus::New,
-            attempt: 0,
-            report: None,
-        };
-        self.db.write().await.save_report_task(id, &task)?;
-        self.report_queue.write().await.send(id.as_hyphenated().to_string()).await?;
-        Ok(id)
-    }
+    // Parts that are not picked up by the send worker yet are marked as cancelled and removed from the send queue.
+    // Returns whether each part was cancelled
+    pub async fn cancel_transfer(&self, transaction_id: &str) -> Result<Vec<(TransferPart, bool)>, CloudError> {
+        let mut parts = Vec::new();
+        {
+            // parts are read and saved under the same lock so that worker updates are not reverted
+            let mut db = self.db.write().await;
+            let task = db.get_task(transaction_id)?;
+            for id in task.parts {
+                let part = db.get_part(&id)?;
+                if part.status != TransferStatus::New {
+                    parts.push((part, false));
+                    continue;
+                }
 
-    pub async fn get_report(&self, id: Uuid) -> Result<Option<ReportTask>, CloudError> {
-        self.db.read().await.get_report_task(id)
-    }
+                let part = TransferPart {
+                    status: TransferStatus::Cancelled,
+                    timestamp: timestamp(),
+                    ..part
+                };
+                db.save_part(&part)?;
+                tracing::info!("[cancel transfer] part {} is cancelled", &part.id);
+                parts.push((part, true));
+            }
+        }
+        parts.sort_by_key(|(part, _)| part.index().unwrap_or(u64::MAX));
 
-    pub async fn clean_reports(&self) -> Result<(), CloudError> {
-        self.db.write().await.clean_reports()
-    }
+        // it is not critical, the send worker drops cancelled parts anyway
+        for (part, _) in parts.iter().filter(|(_, cancelled)| *cancelled) {
+            if let Err(err) = self.send_queue.write().await.remove(&part.id).await {
+                tracing::warn!("[cancel transfer] failed to remove part {} from send queue: {}", &part.id, err);
+            }
+        }
 
-    pub fn validate_token(&self, bearer_token: &str) -> Result<(), CloudError> {
-        if self.config.admin_token != bearer_token {
-            return Err(CloudError::AccessDenied);
+        if let Some((part, true)) = parts.last() {
+            callback::notify_if_final(self, part).await;
         }
-        Ok(())
+        Ok(parts)
     }
 
-    pub(crate) async fn get_account(
-        &self,
-        id: Uuid,
-    ) -> Result<(Arc<Account>, AccountCleanup), CloudError> {
-        let data = self
-            .db
-            .read()
-            .await
-            .get_account(id)?
-            .ok_or(CloudError::AccountNotFound)?;
+    // Updates attempt counters of unfinished parts and resumes parts that failed because retries
+    // were exhausted, parts rejected by the relayer stay failed. Returns ids of resumed parts.
+    pub async fn update_retry_budget(&self, transaction_id: &str, budget: RetryBudget) -> Result<Vec<String>, CloudError> {
+        let mut send = Vec::new();
+        let mut check_status = Vec::new();
+        {
+            // parts are read and saved under the same lock so that worker updates are not reverted
+            let mut db = self.db.write().await;
+            let task = db.get_task(transaction_id)?;
+            let mut parts = Vec::new();
+            for id in task.parts {
+                parts.push(db.get_part(&id)?);
+            }
+            parts.sort_by_key(|part| part.index().unwrap_or(u64::MAX));
 
-        let mut accounts = self.accounts.write().await;
-        match accounts.get(&id) {
-            Some(account) => Ok((account.clone(), AccountCleanup::new(id, self.accounts.clone()))),
-            None => {
-                let account = Account::load(id, self.pool_id, &data.db_path).or_else(|_| {
-                    let sk = hex::decode(data.sk)?;
-                    Account::new(id, data.description, Some(sk), self.pool_id, &data.db_path)
-                })?;
-                let account = Arc::new(account);
-                accounts.insert(id, account.clone());
-                Ok((account, AccountCleanup::new(id, self.accounts.clone())))
+            let mut resumed = HashSet::new();
+            for part in parts {
+                let max_attempts = match part.job_id {
+                    Some(_) => self.config.status_worker.max_attempts,
+                    None => self.config.send_worker.max_attempts,
+                };
+                let attempt = match budget {
+                    RetryBudget::Reset => 0,
+                    RetryBudget::Attempts(attempts) => max_attempts.saturating_sub(attempts),
+                };
+
+                let resume = match resumes_with_budget(&part, attempt, max_attempts, &resumed) {
+                    Some(resume) => resume,
+                    None => continue,
+                };
+
+                let part = match resume {
+                    true => TransferPart {
+                        status: match part.job_id {
+                            Some(_) => TransferStatus::Relaying,
+                            None => TransferStatus::New,
+                        },
+                        attempt,
+                        timestamp: timestamp(),
+                        ..part
+                    },
+                    false => TransferPart { attempt, ..part },
+                };
+                db.save_part(&part)?;
+                self.status_attempts.forget(&part.id);
+
+                if resume {
+                    tracing::info!("[retry budget] resuming part {} with status {:?}", &part.id, part.status);
+                    resumed.insert(part.id.clone());
+                    match part.status {
+                        TransferStatus::Relaying => check_status.push(part.id),
+                        _ => send.push(part.id),
+                    }
+                }
             }
         }
+
+        let mut resumed = Vec::new();
+        for id in send {
+            self.send_queue.write().await.send(id.clone()).await?;
+            resumed.push(id);
+        }
+        for id in check_status {
+            self.status_queue.write().await.send(id.clone()).await?;
+            resumed.push(id);
+        }
+        Ok(resumed)
+    }
+
+    // Resets failed parts to New with a full retry budget and sends them again, parts that are
+    // not failed are left as they are. With reserve_notes the funds of the requeued parts are reserved
+    // again, as the reservation is released once the parts fail. Returns ids of requeued parts in the sending order.
+    pub async fn requeue_transfer(&self, transaction_id: &str) -> Result<Vec<String>, CloudError> {
+        let task = self.db.read().await.get_task(transaction_id)?;
+        let failed = requeueable_parts(self.transfer_status(transaction_id).await?)?;
+        let account_id = Uuid::from_str(&failed[0].account_id)
+            .map_err(|_| CloudError::InternalError(format!("part {} has a bad account id", &failed[0].id)))?;
+
+        let _reservation_guard = self.reservation_locks.lock(account_id).await;
+        let reservation = match self.config.reserve_notes {
+            true => {
+                let (account, _cleanup) = self.get_account(account_id).await?;
+                self.sync_for_request(account_id, &account).await?;
+                Some(self.reserve_requeued(transaction_id, account_id, &account, &failed).await?)
+            }
+            false => None,
+        };
+
+        let failed_ids = failed.iter().map(|part| part.id.clone()).collect::<HashSet<_>>();
+        let mut requeued = Vec::new();
+        {
+            // parts are read and saved under the same lock so that worker updates are not reverted
+            let mut db = self.db.write().await;
+            for id in task.parts.iter() {
+                let part = db.get_part(id)?;
+                if !failed_ids.contains(&part.id) || !matches!(part.status, TransferStatus::Failed(_)) {
+                    continue;
+                }
+                requeued.push((
+                    TransferPart {
+                        status: TransferStatus::New,
+                        attempt: 0,
+                        job_id: None,
+                        tx_hash: None,
+                        pool_index: None,
+                        relayer_url: None,
+                        nullifier: None,
+                        timestamp: timestamp(),
+                        ..part.clone()
+                    },
+                    part,
+                ));
+            }
+            if requeued.is_empty() {
+                return Err(CloudError::BadRequest("transfer has no failed parts".to_string()));
+            }
+            // a part waits for the one it depends on, so they are sent in the order of the chain
+            requeued.sort_by_key(|(part, _)| part.index().unwrap_or(u64::MAX));
+            db.save_task(&task, requeued.iter().map(|(part, _)| part), reservation.as_ref())?;
+        }
+
+        for (sent, (part, _)) in requeued.iter().enumerate() {
+            self.status_attempts.forget(&part.id);
+            if let Err(err) = self.send_queue.write().await.send(part.id.clone()).await {
+                tracing::error!("[requeue transfer] failed to send part {} to the send queue: {}", &part.id, err);
+                self.revert_requeued(requeued[sent..].iter().map(|(_, original)| original)).await;
+                return Err(err);
+            }
+            tracing::info!("[requeue transfer] part {} is requeued", &part.id);
+        }
+
+        let requeued = requeued.into_iter().map(|(part, _)| part.id).collect::<Vec<_>>();
+        // it is not critical, dead letters are only for inspection
+        if let Err(err) = self.db.write().await.delete_dead_letters(&requeued) {
+            tracing::warn!("[requeue transfer] failed to remove dead letters of {}: {}", transaction_id, err);
+        }
+        Ok(requeued)
+    }
+
+    async fn requeue_unfinished_parts(&self) -> Result<(), CloudError> {
+        let parts = self.db.read().await.get_parts()?;
+        let (send, check_status) = unfinished_parts(parts);
+        tracing::info!("requeueing {} unsent and {} sent unfinished parts", send.len(), check_status.len());
+        let mut send_queue = self.send_queue.write().await;
+        for id in send {
+            send_queue.send(id).await?;
+        }
+        let mut status_queue = self.status_queue.write().await;
+        for id in check_status {
+            status_queue.send(id).await?;
+        }
+        Ok(())
+    }
+
+    // The notes are planned again for the outputs of the requeued parts,
+    // the transfer is refused if the account can't cover them anymore
+    async fn reserve_requeued(
+        &self,
+        transaction_id: &str,
+        account_id: Uuid,
+        account: &Account,
+        parts: &[TransferPart],
+    ) -> Result<NoteReservation, CloudError> {
+        let reserved = self.reserved_funds(account_id, account).await?;
+        let outputs = parts
+            .iter()
+            .flat_map(|part| part.tx_outputs())
+            .map(|(to, amount)| (to, amount.as_u64_amount()))
+            .collect::<Vec<_>>();
+        let fee = parts.iter().map(|part| part.fee).max().unwrap_or(0);
+        let (_, used) = account.get_tx_parts(&outputs, fee, &reserved).await?;
+        Ok(NoteReservation {
+            transaction_id: transaction_id.to_string(),
+            account_id: account_id.to_string(),
+            notes: used.notes.into_iter().collect(),
+        })
+    }
+
+    // Parts that were not sent to the queue are restored as failed, unless a worker changed them meanwhile
+    async fn revert_requeued<'a, I: Iterator<Item = &'a TransferPart>>(&self, parts: I) {
+        let mut db = self.db.write().await;
+        for original in parts {
+            match db.get_part(&original.id) {
+                Ok(part) if part.status == TransferStatus::New => {
+                    if let Err(err) = db.save_part(original) {
+                        tracing::error!("[requeue transfer] failed to restore part {}: {}", &original.id, err);
+                    }
+                }
+                Ok(_) => {}
+                Err(err) => tracing::error!("[requeue transfer] failed to read part {}: {}", &original.id, err),
+            }
+        }
+    }
+
+    // Transfers removed by retention are skipped
+    pub async fn transfers_by_support_id(
+        &self,
+        support_id: &str,
+    ) -> Result<Vec<(String, Vec<TransferPart>)>, CloudError> {
+        let transaction_ids = self.db.read().await.get_support_id_transfers(support_id)?;
+        let mut transfers = Vec::new();
+        for transaction_id in transaction_ids {
+            if !self.db.read().await.task_exists(&transaction_id)? {
+                continue;
+            }
+            let parts = self.transfer_status(&transaction_id).await?;
+            transfers.push((transaction_id, parts));
+        }
+        Ok(transfers)
+    }
+
+    pub async fn failed_transfers(&self) -> Vec<TransferPart> {
+        self.db.read().await.get_dead_letters()
+    }
+
+    pub async fn transfer_trace(
+        &self,
+        id: &str,
+        statuses: Option<Vec<String>>,
+        offset: usize,
+        limit: Option<usize>,
+    ) -> Result<Vec<TransferPartTrace>, CloudError> {
+        let mut parts = self.transfer_status(id).await?;
+        parts.sort_by_key(|part| part.index().unwrap_or(u64::MAX));
+
+        let part_statuses = parts
+            .iter()
+            .map(|part| (part.id.clone(), part.status.clone()))
+            .collect::<HashMap<_, _>>();
+
+        let limit = limit.unwrap_or(parts.len());
+        let parts = parts
+            .into_iter()
+            .filter(|part| match &statuses {
+                Some(statuses) => statuses.contains(&part.status.status()),
+                None => true,
+            })
+            .skip(offset)
+            .take(limit);
+
+        let db = self.db.read().await;
+        let mut trace = Vec::new();
+        for part in parts {
+            trace.push(TransferPartTrace {
+                part_index: part.index(),
+                previous_part_status: part
+                    .depends_on
+                    .as_ref()
+                    .and_then(|depends_on| part_statuses.get(depends_on).cloned()),
+                receipt: db.get_receipt(&part.id)?,
+                part,
+            });
+        }
+        Ok(trace)
+    }
+
+    pub async fn receipt(&self, part_id: &str) -> Result<SubmissionReceipt, CloudError> {
+        self.db
+            .read()
+            .await
+            .get_receipt(part_id)?
+            .ok_or(CloudError::TransactionNotFound)
+    }
+
+    pub async fn support_bundle(&self, id: Uuid, history_size: usize) -> Result<SupportBundle, CloudError> {
+        let (account, _cleanup) = self.get_account(id).await?;
+        self.sync_to_latest(id, &account).await?;
+        let info = account.info(self.current_fee().await, &ReservedFunds::default()).await;
+        let account_index = account.next_index().await;
+        let relayer_index = self.relayer.info().await?.delta_index;
+
+        let (mut history, _, _) = self.history(id, &HistoryFilter::default(), 0, history_size as u64, false).await?;
+        history.reverse();
+
+        let parts = self.db.read().await.get_account_parts(id)?;
+        let (pending_parts, failed_parts) = support_parts(parts, history_size);
+        let queues = self.support_queues(&pending_parts).await?;
+
+        let bundle = SupportBundle {
+            timestamp: timestamp(),
+            account: info,
+            account_index,
+            relayer_index,
+            history,
+            pending_parts,
+            failed_parts,
+            queues,
+        };
+        Ok(bundle.redacted())
+    }
+
+    // Queue messages are listed without receiving them, so the workers are not affected
+    async fn support_queues(&self, pending_parts: &[TransferPart]) -> Result<SupportQueues, CloudError> {
+        let send = {
+            let mut send_queue = self.send_queue.write().await;
+            (send_queue.len().await?, send_queue.list::<String>().await?)
+        };
+        let status = {
+            let mut status_queue = self.status_queue.write().await;
+            (status_queue.len().await?, status_queue.list::<String>().await?)
+        };
+        Ok(SupportQueues {
+            send_depth: send.0,
+            status_depth: status.0,
+            send: queued_parts(pending_parts, &send.1),
+            status: queued_parts(pending_parts, &status.1),
+        })
+    }
+
+    pub async fn generate_report(&self, include_keys: bool, public_key: Option<String>) -> Result<Uuid, CloudError> {
+        if let Some(public_key) = &public_key {
+            if !include_keys {
+                return Err(CloudError::BadRequest("publicKey requires includeKeys".to_string()));
+            }
+            key_cipher::parse_public_key(public_key)?;
+        }
+
+        let id = Uuid::new_v4();
+        let task = ReportTask {
+            status: ReportStatus::New,
+            attempt: 0,
+            report: None,
+            created_at: timestamp(),
+            include_keys,
+            public_key,
+            progress: None,
+        };
+        self.db.write().await.save_report_task(id, &task)?;
+        self.report_queue.write().await.send(id.as_hyphenated().to_string()).await?;
+        Ok(id)
+    }
+
+    pub async fn get_report(&self, id: Uuid) -> Result<Option<ReportTask>, CloudError> {
+        self.db.read().await.get_report_task(id)
+    }
+
+    // The newest reports go first
+    pub async fn list_reports(&self) -> Result<Vec<(Uuid, ReportTask)>, CloudError> {
+        let mut tasks = self.db.read().await.get_report_tasks()?;
+        tasks.sort_by(|(_, a), (_, b)| b.created_at.cmp(&a.created_at));
+        Ok(tasks)
+    }
+
+    // Without older_than_sec all reports are removed, returns the number of removed reports
+    pub async fn clean_reports(&self, older_than_sec: Option<u64>) -> Result<usize, CloudError> {
+        match older_than_sec {
+            Some(older_than_sec) => self.remove_expired_reports(older_than_sec).await,
+            None => {
+                let count = self.db.read().await.get_report_tasks()?.len();
+                self.db.write().await.clean_reports()?;
+                Ok(count)
+            }
+        }
+    }
+
+    // New reports are never removed because their queue message is still pending
+    pub(crate) async fn remove_expired_reports(&self, older_than_sec: u64) -> Result<usize, CloudError> {
+        let threshold = timestamp().saturating_sub(older_than_sec);
+        let tasks = self.db.read().await.get_report_tasks()?;
+
+        let mut count = 0;
+        for (id, task) in tasks {
+            if matches!(task.status, ReportStatus::New) {
+                continue;
+            }
+            // older tasks don't have created_at, the report timestamp is used for them
+            let created_at = match task.created_at {
+                0 => task.report.as_ref().map(|report| report.timestamp).unwrap_or(0),
+                created_at => created_at,
+            };
+            if created_at >= threshold {
+                continue;
+            }
+            self.db.write().await.delete_report_task(id)?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    pub fn circuit_info(&self) -> Result<CircuitInfo, CloudError> {
+        let vk = serde_json::to_value(self.vk.as_ref()).map_err(|err| {
+            tracing::error!("failed to serialize verifying key: {}", err);
+            CloudError::InternalError("failed to serialize verifying key".to_string())
+        })?;
+        let vk_hash = hex::encode(Sha256::digest(vk.to_string().as_bytes()));
+        Ok(CircuitInfo {
+            params_hash: self.params_hash.clone(),
+            vk_hash,
+            vk,
+        })
+    }
+
+    pub fn fault_rules(&self) -> Result<HashMap<String, FaultRule>, CloudError> {
+        self.faults.rules()
+    }
+
+    pub fn set_fault_rule(&self, site: &str, rule: Option<FaultRule>) -> Result<(), CloudError> {
+        self.faults.set_rule(site, rule)
+    }
+
+    pub fn validate_token(&self, bearer_token: &str) -> Result<(), CloudError> {
+        if self.config.admin_token != bearer_token {
+            return Err(CloudError::AccessDenied);
+        }
+        Ok(())
+    }
+
+    // The cached fee is refreshed once it is older than fee_ttl_sec. If the relayer is unavailable,
+    // the last known fee is used and the refresh is retried after another ttl
+    pub(crate) async fn current_fee(&self) -> u64 {
+        let ttl = Duration::from_secs(self.config.relayer_fee.fee_ttl_sec);
+        {
+            let (fee, fetched_at) = *self.relayer_fee.read().await;
+            if fetched_at.elapsed() < ttl {
+                return fee;
+            }
+        }
+
+        // concurrent requests wait for the refresh instead of fetching the fee too
+        let mut cached = self.relayer_fee.write().await;
+        if cached.1.elapsed() < ttl {
+            return cached.0;
+        }
+        match self.relayer.fee().await {
+            Ok(fee) => {
+                if fee != cached.0 {
+                    tracing::info!("relayer fee changed from {} to {}", cached.0, fee);
+                }
+                *cached = (fee, Instant::now());
+            }
+            Err(err) => {
+                tracing::warn!("failed to refresh relayer fee, using the last known fee {}: {}", cached.0, err);
+                cached.1 = Instant::now();
+            }
+        }
+        cached.0
+    }
+
+    pub(crate) fn check_amount(&self, amount: u64, fee: u64) -> Result<(), CloudError> {
+        check_amount(amount, fee, self.config.amount_denomination)
+    }
+
+    pub(crate) fn check_relayer_fee(&self, fee: u64) -> Result<(), CloudError> {
+        if relayer_fee_is_plausible(&self.config, fee) {
+            return Ok(());
+        }
+
+        tracing::error!(
+            "relayer fee {} is out of bounds [{}, {}]",
+            fee,
+            self.config.relayer_fee.min,
+            self.config.relayer_fee.max
+        );
+        if self.config.relayer_fee.block_transfers {
+            return Err(CloudError::InvalidRelayerFee);
+        }
+        Ok(())
+    }
+
+    // The cloud db and the account db can diverge (e.g. after a failed delete),
+    // recreating the account silently could resurrect a deleted account or hide corruption
+    fn load_account(&self, id: Uuid, data: AccountData) -> Result<Account, CloudError> {
+        let mut account = self.load_or_recreate_account(id, data)?;
+        account.skip_parse_errors = self.config.skip_parse_errors;
+        Ok(account)
+    }
+
+    fn load_or_recreate_account(&self, id: Uuid, data: AccountData) -> Result<Account, CloudError> {
+        let load_result = match Path::new(&data.db_path).exists() {
+            true => Account::load(id, self.pool_id, &data.db_path, &self.key_cipher),
+            false => Err(CloudError::InternalError("account data directory is missing".to_string())),
+        };
+
+        match load_result {
+            Ok(account) => Ok(account),
+            Err(err) if self.config.recreate_diverged_accounts => {
+                tracing::warn!("failed to load account {}: {}, recreating it from sk", id, err);
+                let sk = self.decode_sk(&data.sk)?;
+                Account::new(id, data.description, Some(sk), self.pool_id, &data.db_path, data.first_index, &self.key_cipher)
+            }
+            Err(err) => {
+                tracing::error!("account {} is registered in the cloud db but failed to load: {}, repair is required", id, err);
+                Err(CloudError::AccountStateDiverged)
+            }
+        }
+    }
+
+    pub async fn repair_account(&self, id: Uuid, action: RepairAction) -> Result<(), CloudError> {
+        let data = self
+            .db
+            .read()
+            .await
+            .get_account(id)?
+            .ok_or(CloudError::AccountNotFound)?;
+
+        // the account db is closed before it is recreated or removed and can't be loaded meanwhile
+        let _lock = self.account_locks.lock(id).await;
+        self.accounts.write().await.evict(id)?;
+
+        match action {
+            RepairAction::Recreate => {
+                tracing::info!("recreating account {} from sk", id);
+                let sk = self.decode_sk(&data.sk)?;
+                Account::new(id, data.description, Some(sk), self.pool_id, &data.db_path, data.first_index, &self.key_cipher)?;
+                Ok(())
+            }
+            RepairAction::Delete => {
+                tracing::info!("deleting diverged account {}", id);
+                if Path::new(&data.db_path).exists() {
+                    fs::remove_dir_all(&data.db_path).await.map_err(|err| {
+                        tracing::warn!("failed to delete account data: {}", err);
+                        CloudError::InternalError("failed to delete account data".to_string())
+                    })?;
+                }
+                self.db.write().await.delete_account(id)
+            }
+        }
+    }
+
+    // Schedules a rebuild of the state of an account that diverged from the relayer, e.g. after
+    // a relayer rollback, see resync::run_resync_worker
+    pub async fn resync_account(&self, id: Uuid) -> Result<ResyncStatus, CloudError> {
+        self.db
+            .read()
+            .await
+            .get_account(id)?
+            .ok_or(CloudError::AccountNotFound)?;
+        self.resyncs.schedule(id).ok_or(CloudError::AccountIsBusy)
+    }
+
+    pub fn resync_status(&self, id: Uuid) -> Result<ResyncStatus, CloudError> {
+        self.resyncs.status(id).ok_or(CloudError::ResyncNotFound)
+    }
+
+    pub(crate) async fn get_account(
+        &self,
+        id: Uuid,
+    ) -> Result<(Arc<Account>, AccountCleanup), CloudError> {
+        get_or_load(&self.accounts, &self.account_locks, id, || async {
+            // it is read under the lock, so an account deleted meanwhile is not loaded
+            let data = self
+                .db
+                .read()
+                .await
+                .get_account(id)?
+                .ok_or(CloudError::AccountNotFound)?;
+            let account = self.load_account(id, data)?;
+            // accounts created before the default address was indexed get it on the first load
+            let default_address = account.default_address().await;
+            self.db.write().await.save_addresses(id, [&default_address].into_iter())?;
+            Ok(account)
+        })
+        .await
+    }
+}
+
+// Parts that are not final and whose tx hash is not known yet, newest first. A part sent again
+// keeps its tx hash or nullifier, so only the newest part with the same one is kept
+fn pending_parts(mut parts: Vec<TransferPart>, known: &HashSet<String>) -> Vec<TransferPart> {
+    parts.sort_by(|a, b| b.timestamp.cmp(&a.timestamp));
+    let mut seen = HashSet::new();
+    parts
+        .into_iter()
+        .filter(|part| !part.status.is_final())
+        .filter(|part| !part.tx_hash.as_ref().map(|tx_hash| known.contains(tx_hash)).unwrap_or(false))
+        .filter(|part| {
+            let tx_hash = part.tx_hash.as_ref().map(|tx_hash| seen.insert(format!("tx:{}", tx_hash))).unwrap_or(true);
+            let nullifier = part.nullifier.as_ref().map(|nullifier| seen.insert(format!("nullifier:{}", nullifier))).unwrap_or(true);
+            tx_hash && nullifier
+        })
+        .collect()
+}
+
+// a parse error lists every failed index, the stored error is capped
+const MAX_SYNC_ERROR_LENGTH: usize = 1024;
+
+// Returns None when the sync result doesn't change the stored data
+fn tracked_sync_data(data: AccountData, result: &Result<Vec<u64>, CloudError>) -> Option<AccountData> {
+    match result {
+        Ok(_) if data.sync_failures == 0 => None,
+        Ok(_) => Some(AccountData {
+            sync_failures: 0,
+            last_sync_error: None,
+            ..data
+        }),
+        Err(err) => Some(AccountData {
+            sync_failures: data.sync_failures.saturating_add(1),
+            last_sync_error: Some(truncate(format!("{:?}", err), MAX_SYNC_ERROR_LENGTH)),
+            ..data
+        }),
+    }
+}
+
+// Aggregation parts move funds to the account itself, so only their fees are outgoing
+fn pending_outgoing_amount(parts: &[TransferPart], is_synced: impl Fn(&TransferPart) -> bool) -> u64 {
+    parts
+        .iter()
+        .filter(|part| !part.status.is_final() && !is_synced(part))
+        .map(|part| part.outflow())
+        .fold(0u64, |total, amount| total.saturating_add(amount))
+}
+
+// Non-final parts and the latest failed parts, newest first
+fn support_parts(parts: Vec<TransferPart>, failed_limit: usize) -> (Vec<TransferPart>, Vec<TransferPart>) {
+    let (pending, mut failed): (Vec<_>, Vec<_>) = parts
+        .into_iter()
+        .filter(|part| !part.status.is_final() || matches!(part.status, TransferStatus::Failed(_)))
+        .partition(|part| !part.status.is_final());
+    failed.sort_by(|a, b| b.timestamp.cmp(&a.timestamp));
+    failed.truncate(failed_limit);
+    (pending, failed)
+}
+
+// Ids of the parts that have a message in the queue
+fn queued_parts(parts: &[TransferPart], messages: &[(String, String)]) -> Vec<String> {
+    let queued = messages.iter().map(|(_, id)| id.as_str()).collect::<HashSet<_>>();
+    parts
+        .iter()
+        .filter(|part| queued.contains(part.id.as_str()))
+        .map(|part| part.id.clone())
+        .collect()
+}
+
+// Ids of the parts to send and of the sent parts to check, the parts of a transfer are queued in chain order
+fn unfinished_parts(mut parts: Vec<TransferPart>) -> (Vec<String>, Vec<String>) {
+    parts.sort_by(|a, b| (&a.transaction_id, a.index()).cmp(&(&b.transaction_id, b.index())));
+    let mut send = Vec::new();
+    let mut check_status = Vec::new();
+    for part in parts {
+        match part.status {
+            TransferStatus::New => send.push(part.id),
+            TransferStatus::Relaying | TransferStatus::Mining => check_status.push(part.id),
+            _ => {}
+        }
+    }
+    (send, check_status)
+}
+
+// Failed parts of a transfer in the order of the chain. A part whose transaction may be on chain
+// is never requeued, errors are matched by code as they can be restored from an old part
+fn requeueable_parts(parts: Vec<TransferPart>) -> Result<Vec<TransferPart>, CloudError> {
+    let mut failed = parts
+        .into_iter()
+        .filter(|part| matches!(part.status, TransferStatus::Failed(_)))
+        .collect::<Vec<_>>();
+    if let Some(part) = failed.iter().find(|part| match &part.status {
+        TransferStatus::Failed(err) => matches!(err.code().as_str(), "TransactionReverted" | "TransactionStatusUnknown"),
+        _ => false,
+    }) {
+        return Err(CloudError::BadRequest(format!(
+            "part {} may have been mined, check its tx hash instead of requeuing it",
+            part.id
+        )));
+    }
+    if failed.is_empty() {
+        return Err(CloudError::BadRequest("transfer has no failed parts".to_string()));
+    }
+    failed.sort_by_key(|part| part.index().unwrap_or(u64::MAX));
+    Ok(failed)
+}
+
+// Whether a part is resumed with the new attempt counter, None leaves the part as it is.
+// Errors are matched by code: an error restored from an old part may keep only its code
+fn resumes_with_budget(part: &TransferPart, attempt: u32, max_attempts: u32, resumed: &HashSet<String>) -> Option<bool> {
+    let resume = match &part.status {
+        TransferStatus::Failed(err) => match err.code().as_str() {
+            "TaskRejectedByRelayer" => false,
+            "PreviousTxFailed" => part
+                .depends_on
+                .as_ref()
+                .map(|depends_on| resumed.contains(depends_on))
+                .unwrap_or(false),
+            _ => part.attempt >= max_attempts && attempt < max_attempts,
+        },
+        _ => false,
+    };
+    match part.status.is_final() && !resume {
+        true => None,
+        false => Some(resume),
+    }
+}
+
+// A reservation is kept until every part is final and the mined ones are in the synced state
+fn is_released<F: Fn(&TransferPart) -> bool>(parts: &[TransferPart], is_synced: F) -> bool {
+    parts.iter().all(|part| match &part.status {
+        TransferStatus::Done => is_synced(part),
+        status => status.is_final(),
+    })
+}
+
+// The balance that the parts not reflected in the synced state will take from the account.
+// Failed and cancelled parts take nothing, the unspent reserved notes are spent first
+fn reserved_balance<F: Fn(&TransferPart) -> bool>(parts: &[TransferPart], is_synced: F, notes_value: u64) -> u64 {
+    parts
+        .iter()
+        .filter(|part| !matches!(part.status, TransferStatus::Failed(_) | TransferStatus::Cancelled))
+        .filter(|part| !is_synced(part))
+        .fold(0u64, |total, part| total.saturating_add(part.outflow()))
+        .saturating_sub(notes_value)
+}
+
+// Admin requests bypass the backpressure if configured
+fn applies_backpressure(config: &BackpressureConfig, admin: bool) -> bool {
+    config.enabled && !(admin && config.exempt_admin)
+}
+
+fn saturation_status(config: &BackpressureConfig, send_queue_depth: u64, prover: &TaskSemaphore) -> SaturationStatus {
+    let prover_saturated_sec = prover.saturated_for().map(|duration| duration.as_secs());
+    let saturated = send_queue_depth > config.max_queue_depth
+        || prover_saturated_sec
+            .map(|sec| sec >= config.max_prover_saturation_sec)
+            .unwrap_or(false);
+    SaturationStatus {
+        send_queue_depth,
+        prover_in_flight: prover.in_flight(),
+        prover_saturated_sec,
+        saturated,
+    }
+}
+
+fn check_attributes(attributes: &AccountAttributes, limits: &LimitsConfig) -> Result<(), CloudError> {
+    if attributes.tags.len() > limits.max_account_tags {
+        return Err(CloudError::BadRequest(format!(
+            "too many tags: {}, the limit is {}",
+            attributes.tags.len(),
+            limits.max_account_tags
+        )));
+    }
+    for tag in &attributes.tags {
+        check_length("tags", tag, limits.max_tag_length)?;
+    }
+    if let Some(metadata) = &attributes.metadata {
+        check_length("metadata", &metadata.to_string(), limits.max_metadata_length)?;
+    }
+    Ok(())
+}
+
+// Records created before the limits were introduced are only reported, never truncated
+fn check_stored_limits(db: &Db, config: &Config) {
+    match db.get_accounts() {
+        Ok(accounts) => {
+            for (id, data) in accounts {
+                if data.description.len() > config.limits.max_description_length {
+                    tracing::warn!(
+                        "account {} has description of {} bytes that exceeds the limit of {}",
+                        id,
+                        data.description.len(),
+                        config.limits.max_description_length
+                    );
+                }
+            }
+        }
+        Err(err) => tracing::warn!("failed to check stored accounts against limits: {}", err),
+    }
+}
+
+// Keys stored before encryption was enabled are encrypted on startup
+fn encrypt_stored_keys(db: &mut Db, key_cipher: &KeyCipher) -> Result<(), CloudError> {
+    if !key_cipher.enabled() {
+        return Ok(());
+    }
+
+    for (id, mut data) in db.get_accounts()? {
+        let sk = hex::decode(&data.sk)?;
+        if KeyCipher::is_encrypted(&sk) {
+            continue;
+        }
+        data.sk = hex::encode(key_cipher.encrypt(&sk)?);
+        db.save_account(id, &data)?;
+        tracing::info!("encrypted stored key of account {}", id);
+    }
+    Ok(())
+}
+
+// The sum of all outputs with fees is checked for overflow when the transfer is planned
+fn check_amount(amount: u64, fee: u64, denomination: Option<u64>) -> Result<(), CloudError> {
+    if amount == 0 {
+        return Err(CloudError::BadRequest("amount must be greater than zero".to_string()));
+    }
+    if amount.checked_add(fee).is_none() {
+        return Err(CloudError::BadRequest(format!("amount {} with relayer fee {} overflows u64", amount, fee)));
+    }
+    if let Some(denomination) = denomination.filter(|denomination| *denomination > 1) {
+        if amount % denomination != 0 {
+            return Err(CloudError::BadRequest(format!("amount must be a multiple of {}", denomination)));
+        }
+    }
+    Ok(())
+}
+
+fn round_to_denomination(amount: u64, denomination: Option<u64>) -> u64 {
+    match denomination.filter(|denomination| *denomination > 1) {
+        Some(denomination) => amount - amount % denomination,
+        None => amount,
+    }
+}
+
+fn relayer_fee_is_plausible(config: &Config, fee: u64) -> bool {
+    fee > 0 && fee >= config.relayer_fee.min && fee <= config.relayer_fee.max
+}
+
+#[cfg(test)]
+mod tests {
+    use libzkbob_rs::libzeropool::fawkes_crypto::ff_uint::NumRepr;
+
+    use crate::helpers::{queue::{MemoryQueue, TaskQueue}, tests::TestDir};
+
+    use super::*;
+
+    pub(crate) fn part(index: u64, status: TransferStatus, amount: u64, to: Option<&str>) -> TransferPart {
+        TransferPart {
+            id: format!("transfer.{}", index),
+            transaction_id: "transfer".to_string(),
+            account_id: Uuid::nil().to_string(),
+            amount: Num::from_uint_reduced(NumRepr::from(amount)),
+            fee: 10,
+            to: to.map(|to| to.to_string()),
+            status,
+            job_id: None,
+            tx_hash: Some(format!("0x{}", index)),
+            depends_on: None,
+            attempt: 0,
+            timestamp: 0,
+            pool_index: None,
+            params_hash: None,
+            relayer_url: None,
+            withdrawal: false,
+            native_amount: 0,
+            outputs: vec![],
+            nullifier: None,
+        }
+    }
+
+    fn synced(hashes: &[&str]) -> impl Fn(&TransferPart) -> bool + '_ {
+        move |part| part.tx_hash.as_deref().map(|hash| hashes.contains(&hash)).unwrap_or(false)
+    }
+
+    #[test]
+    fn rejected_parts_are_not_resumed() {
+        let resumed = HashSet::new();
+        let rejected = part(0, TransferStatus::Failed(CloudError::TaskRejectedByRelayer("x".to_string())), 1, None);
+        assert_eq!(resumes_with_budget(&rejected, 0, 5, &resumed), None);
+
+        // an error restored from an old part keeps only its code
+        let stored = CloudError::StoredError("TaskRejectedByRelayer".to_string(), "relayer returned error: 'x'".to_string());
+        let rejected = TransferPart { attempt: 5, ..part(0, TransferStatus::Failed(stored), 1, None) };
+        assert_eq!(resumes_with_budget(&rejected, 0, 5, &resumed), None);
+    }
+
+    #[test]
+    fn exhausted_parts_are_resumed() {
+        let resumed = HashSet::new();
+        let exhausted = TransferPart { attempt: 5, ..part(0, TransferStatus::Failed(CloudError::RetriesExhausted), 1, None) };
+        assert_eq!(resumes_with_budget(&exhausted, 2, 5, &resumed), Some(true));
+        // the new budget is exhausted too
+        assert_eq!(resumes_with_budget(&exhausted, 5, 5, &resumed), None);
+        // in-flight parts only get the new attempt counter
+        assert_eq!(resumes_with_budget(&part(0, TransferStatus::Relaying, 1, None), 2, 5, &resumed), Some(false));
+        assert_eq!(resumes_with_budget(&part(0, TransferStatus::Done, 1, None), 2, 5, &resumed), None);
+        assert_eq!(resumes_with_budget(&part(0, TransferStatus::Cancelled, 1, None), 2, 5, &resumed), None);
+    }
+
+    #[test]
+    fn dependent_parts_follow_resumed_parts() {
+        let dependent = TransferPart {
+            depends_on: Some("transfer.0".to_string()),
+            ..part(1, TransferStatus::Failed(CloudError::PreviousTxFailed), 1, None)
+        };
+        assert_eq!(resumes_with_budget(&dependent, 0, 5, &HashSet::new()), None);
+        let resumed = HashSet::from(["transfer.0".to_string()]);
+        assert_eq!(resumes_with_budget(&dependent, 0, 5, &resumed), Some(true));
+
+        let stored = TransferPart {
+            status: TransferStatus::Failed(CloudError::StoredError("PreviousTxFailed".to_string(), "previous tx failed".to_string())),
+            ..dependent
+        };
+        assert_eq!(resumes_with_budget(&stored, 0, 5, &resumed), Some(true));
+    }
+
+    #[test]
+    fn unsynced_parts_are_reserved() {
+        let parts = vec![
+            part(0, TransferStatus::Done, 90, None),
+            part(1, TransferStatus::Mining, 100, Some("receiver")),
+        ];
+        assert_eq!(reserved_balance(&parts, synced(&[]), 0), 10 + 110);
+        // the aggregation is in the synced state, its fee is not reserved twice
+        assert_eq!(reserved_balance(&parts, synced(&["0x0"]), 0), 110);
+        // the payment is mined and synced too, the balance already reflects it
+        assert_eq!(reserved_balance(&parts, synced(&["0x0", "0x1"]), 0), 0);
+    }
+
+    #[test]
+    fn synced_mining_parts_are_not_pending() {
+        let parts = vec![
+            part(0, TransferStatus::Done, 90, None),
+            part(1, TransferStatus::Mining, 100, Some("receiver")),
+            part(2, TransferStatus::New, 50, Some("receiver")),
+        ];
+        assert_eq!(pending_outgoing_amount(&parts, synced(&[])), 110 + 60);
+        // mined and synced but still waiting for confirmations, the balance already reflects it
+        assert_eq!(pending_outgoing_amount(&parts, synced(&["0x1"])), 60);
+        assert_eq!(pending_outgoing_amount(&parts, synced(&["0x0", "0x1"])), 60);
+    }
+
+    #[test]
+    fn unspent_reserved_notes_cover_the_outflow_first() {
+        let parts = vec![part(0, TransferStatus::New, 100, Some("receiver"))];
+        assert_eq!(reserved_balance(&parts, synced(&[]), 40), 70);
+        assert_eq!(reserved_balance(&parts, synced(&[]), 200), 0);
+    }
+
+    #[test]
+    fn failed_parts_take_nothing() {
+        let parts = vec![
+            part(0, TransferStatus::Failed(CloudError::TransactionExpired), 90, None),
+            part(1, TransferStatus::Failed(CloudError::PreviousTxFailed), 100, Some("receiver")),
+        ];
+        assert_eq!(reserved_balance(&parts, synced(&[]), 0), 0);
+        assert!(is_released(&parts, synced(&[])));
+    }
+
+    #[test]
+    fn reservation_is_released_once_final_and_synced() {
+        let parts = vec![
+            part(0, TransferStatus::Done, 90, None),
+            part(1, TransferStatus::Mining, 100, Some("receiver")),
+        ];
+        assert!(!is_released(&parts, synced(&["0x0", "0x1"])));
+
+        let parts = vec![
+            part(0, TransferStatus::Done, 90, None),
+            part(1, TransferStatus::Done, 100, Some("receiver")),
+        ];
+        // mined but not synced yet
+        assert!(!is_released(&parts, synced(&["0x0"])));
+        assert!(is_released(&parts, synced(&["0x0", "0x1"])));
+        // a transfer without parts was removed
+        assert!(is_released(&[], synced(&[])));
+    }
+
+    #[test]
+    fn support_bundle_lists_pending_and_latest_failed_parts() {
+        let failed = |index, timestamp| TransferPart {
+            timestamp,
+            ..part(index, TransferStatus::Failed(CloudError::RelayerSendError), 1, None)
+        };
+        let parts = vec![
+            part(0, TransferStatus::Done, 1, None),
+            failed(1, 100),
+            failed(2, 300),
+            failed(3, 200),
+            part(4, TransferStatus::Mining, 1, None),
+            part(5, TransferStatus::New, 1, None),
+            part(6, TransferStatus::Cancelled, 1, None),
+        ];
+        let (pending, failed) = support_parts(parts, 2);
+        let ids = |parts: &[TransferPart]| parts.iter().map(|part| part.id.clone()).collect::<Vec<_>>();
+        assert_eq!(ids(&pending), vec!["transfer.4", "transfer.5"]);
+        assert_eq!(ids(&failed), vec!["transfer.2", "transfer.3"]);
+    }
+
+    #[test]
+    fn support_bundle_shows_queued_parts() {
+        let parts = vec![
+            part(0, TransferStatus::New, 1, None),
+            part(1, TransferStatus::New, 1, None),
+        ];
+        let messages = vec![
+            ("a".to_string(), "transfer.1".to_string()),
+            ("b".to_string(), "another.0".to_string()),
+        ];
+        assert_eq!(queued_parts(&parts, &messages), vec!["transfer.1"]);
+        assert!(queued_parts(&parts, &[]).is_empty());
+    }
+
+    #[test]
+    fn unfinished_parts_are_requeued_after_restart() {
+        let mut other = part(0, TransferStatus::New, 1, None);
+        other.id = "another.0".to_string();
+        other.transaction_id = "another".to_string();
+        let parts = vec![
+            part(3, TransferStatus::New, 1, None),
+            part(10, TransferStatus::New, 1, None),
+            part(2, TransferStatus::Mining, 1, None),
+            part(1, TransferStatus::Relaying, 1, None),
+            part(0, TransferStatus::Done, 1, None),
+            part(4, TransferStatus::Failed(CloudError::RelayerSendError), 1, None),
+            other,
+        ];
+        let (send, check_status) = unfinished_parts(parts);
+        assert_eq!(send, vec!["another.0", "transfer.3", "transfer.10"]);
+        assert_eq!(check_status, vec!["transfer.1", "transfer.2"]);
+    }
+
+    #[test]
+    fn failed_parts_are_requeued_in_chain_order() {
+        let parts = vec![
+            part(2, TransferStatus::Failed(CloudError::PreviousTxFailed), 1, None),
+            part(0, TransferStatus::Done, 1, None),
+            part(1, TransferStatus::Failed(CloudError::RelayerSendError), 1, None),
+        ];
+        let ids = requeueable_parts(parts).unwrap().into_iter().map(|part| part.id).collect::<Vec<_>>();
+        assert_eq!(ids, vec!["transfer.1", "transfer.2"]);
+    }
+
+    #[test]
+    fn transfer_without_failed_parts_is_not_requeued() {
+        let parts = vec![part(0, TransferStatus::Done, 1, None), part(1, TransferStatus::Mining, 1, None)];
+        assert!(matches!(requeueable_parts(parts), Err(CloudError::BadRequest(_))));
+    }
+
+    #[test]
+    fn possibly_mined_parts_are_not_requeued() {
+        let errors = [
+            CloudError::TransactionReverted("0x1".to_string()),
+            CloudError::TransactionStatusUnknown,
+            CloudError::StoredError("TransactionStatusUnknown".to_string(), "transaction status is unknown".to_string()),
+        ];
+        for err in errors {
+            let parts = vec![
+                part(0, TransferStatus::Failed(err), 1, None),
+                part(1, TransferStatus::Failed(CloudError::PreviousTxFailed), 1, None),
+            ];
+            assert!(matches!(requeueable_parts(parts), Err(CloudError::BadRequest(_))));
+        }
+    }
+
+    #[test]
+    fn pending_parts_skip_final_and_known() {
+        let parts = vec![
+            TransferPart { timestamp: 1, ..part(0, TransferStatus::Done, 1, None) },
+            TransferPart { timestamp: 2, ..part(1, TransferStatus::Mining, 1, None) },
+            TransferPart { timestamp: 3, ..part(2, TransferStatus::Relaying, 1, None) },
+            TransferPart { timestamp: 4, tx_hash: None, ..part(3, TransferStatus::New, 1, None) },
+        ];
+        let known = HashSet::from(["0x1".to_string()]);
+        let ids = pending_parts(parts, &known).into_iter().map(|part| part.id).collect::<Vec<_>>();
+        assert_eq!(ids, vec!["transfer.3", "transfer.2"]);
+    }
+
+    #[test]
+    fn pending_parts_are_deduplicated() {
+        let parts = vec![
+            TransferPart { timestamp: 1, ..part(0, TransferStatus::Relaying, 1, None) },
+            TransferPart { timestamp: 2, tx_hash: Some("0x0".to_string()), ..part(1, TransferStatus::Mining, 1, None) },
+            TransferPart { timestamp: 3, tx_hash: None, nullifier: Some("42".to_string()), ..part(2, TransferStatus::Relaying, 1, None) },
+            TransferPart { timestamp: 4, tx_hash: None, nullifier: Some("42".to_string()), ..part(3, TransferStatus::Relaying, 1, None) },
+            TransferPart { timestamp: 5, tx_hash: None, ..part(4, TransferStatus::New, 1, None) },
+            TransferPart { timestamp: 6, tx_hash: None, ..part(5, TransferStatus::New, 1, None) },
+        ];
+        let ids = pending_parts(parts, &HashSet::new()).into_iter().map(|part| part.id).collect::<Vec<_>>();
+        // parts that are not sent yet have neither, so they are all kept
+        assert_eq!(ids, vec!["transfer.5", "transfer.4", "transfer.3", "transfer.1"]);
+    }
+
+    #[test]
+    fn amount_boundaries() {
+        let fee = 100;
+        assert!(check_amount(0, fee, None).is_err());
+        assert!(check_amount(u64::MAX, fee, None).is_err());
+        assert!(check_amount(u64::MAX - fee + 1, fee, None).is_err());
+        assert!(check_amount(u64::MAX - fee, fee, None).is_ok());
+        assert!(check_amount(u64::MAX, 0, None).is_ok());
+        assert!(check_amount(1, fee, None).is_ok());
+    }
+
+    #[test]
+    fn amount_must_be_multiple_of_denomination() {
+        assert!(check_amount(1_500, 10, Some(1_000)).is_err());
+        assert!(check_amount(999, 10, Some(1_000)).is_err());
+        assert!(check_amount(2_000, 10, Some(1_000)).is_ok());
+        // zero and one disable the check
+        assert!(check_amount(1_500, 10, Some(0)).is_ok());
+        assert!(check_amount(1_500, 10, Some(1)).is_ok());
+        // zero is rejected before the denomination check
+        assert_eq!(
+            check_amount(0, 10, Some(1_000)),
+            Err(CloudError::BadRequest("amount must be greater than zero".to_string()))
+        );
+    }
+
+    #[test]
+    fn sweep_amount_is_rounded_down_to_denomination() {
+        assert_eq!(round_to_denomination(2_999, Some(1_000)), 2_000);
+        assert_eq!(round_to_denomination(3_000, Some(1_000)), 3_000);
+        assert_eq!(round_to_denomination(999, Some(1_000)), 0);
+        assert_eq!(round_to_denomination(u64::MAX, Some(1_000)), u64::MAX - u64::MAX % 1_000);
+        assert_eq!(round_to_denomination(2_999, Some(1)), 2_999);
+        assert_eq!(round_to_denomination(2_999, Some(0)), 2_999);
+        assert_eq!(round_to_denomination(2_999, None), 2_999);
+        // the rounded amount passes the denomination check
+        assert!(check_amount(round_to_denomination(2_999, Some(1_000)), 10, Some(1_000)).is_ok());
+    }
+
+    fn account_data(sync_failures: u32) -> AccountData {
+        AccountData {
+            description: String::new(),
+            db_path: String::new(),
+            sk: String::new(),
+            first_index: None,
+            sync_failures,
+            last_sync_error: (sync_failures > 0).then(|| "Web3Error".to_string()),
+            skipped_indices: vec![],
+            attributes: Default::default(),
+        }
+    }
+
+    fn backpressure(max_queue_depth: u64, max_prover_saturation_sec: u64) -> BackpressureConfig {
+        BackpressureConfig {
+            enabled: true,
+            max_queue_depth,
+            max_prover_saturation_sec,
+            retry_after_sec: 10,
+            exempt_admin: true,
+        }
+    }
+
+    #[actix_web::test]
+    async fn full_send_queue_saturates_the_service() {
+        let config = backpressure(3, 60);
+        let prover = TaskSemaphore::new(2);
+        let mut queue = MemoryQueue::new(0, 0);
+        for i in 0..3 {
+            queue.send(format!("part.{}", i), None).await.unwrap();
+        }
+        assert!(!saturation_status(&config, queue.len().await.unwrap(), &prover).saturated);
+
+        queue.send("part.3".to_string(), None).await.unwrap();
+        let status = saturation_status(&config, queue.len().await.unwrap(), &prover);
+        assert!(status.saturated);
+        assert_eq!(status.send_queue_depth, 4);
+        assert_eq!(status.prover_in_flight, 0);
+    }
+
+    #[actix_web::test]
+    async fn busy_prover_saturates_the_service() {
+        let config = backpressure(100, 0);
+        let prover = TaskSemaphore::new(2);
+        let first = prover.try_acquire("part.1").await.unwrap();
+        assert!(!saturation_status(&config, 0, &prover).saturated);
+
+        let second = prover.try_acquire("part.2").await.unwrap();
+        let status = saturation_status(&config, 0, &prover);
+        assert!(status.saturated);
+        assert_eq!(status.prover_in_flight, 2);
+        assert_eq!(status.prover_saturated_sec, Some(0));
+
+        // a released slot ends the saturation
+        drop(second);
+        assert!(!saturation_status(&config, 0, &prover).saturated);
+        drop(first);
+
+        let status = saturation_status(&backpressure(100, 60), 0, &prover);
+        assert!(!status.saturated && status.prover_saturated_sec.is_none());
+    }
+
+    #[test]
+    fn admin_requests_can_bypass_backpressure() {
+        let mut config = backpressure(0, 0);
+        assert!(applies_backpressure(&config, false));
+        assert!(!applies_backpressure(&config, true));
+        config.exempt_admin = false;
+        assert!(applies_backpressure(&config, true));
+        config.enabled = false;
+        assert!(!applies_backpressure(&config, false));
+    }
+
+    #[test]
+    fn account_attributes_are_limited() {
+        let limits = LimitsConfig {
+            max_description_length: 1024,
+            max_transaction_id_length: 128,
+            max_transfers_per_support_id: 100,
+            max_transfer_outputs: 1000,
+            max_generated_addresses_per_minute: 60,
+            max_account_tags: 2,
+            max_tag_length: 8,
+            max_metadata_length: 16,
+        };
+        let attributes = |tags: &[&str], metadata: Option<serde_json::Value>| AccountAttributes {
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+            metadata,
+        };
+
+        assert!(check_attributes(&attributes(&[], None), &limits).is_ok());
+        assert!(check_attributes(&attributes(&["a", "b"], Some(serde_json::json!({"a": 1}))), &limits).is_ok());
+        assert!(matches!(
+            check_attributes(&attributes(&["a", "b", "c"], None), &limits),
+            Err(CloudError::BadRequest(_))
+        ));
+        assert!(matches!(
+            check_attributes(&attributes(&["too long tag"], None), &limits),
+            Err(CloudError::FieldTooLong(field, 8)) if field == "tags"
+        ));
+        assert!(matches!(
+            check_attributes(&attributes(&[], Some(serde_json::json!({"customer": "too long"}))), &limits),
+            Err(CloudError::FieldTooLong(field, 16)) if field == "metadata"
+        ));
+    }
+
+    #[test]
+    fn failed_syncs_are_counted() {
+        let data = tracked_sync_data(account_data(0), &Err(CloudError::Web3Error)).unwrap();
+        assert_eq!(data.sync_failures, 1);
+        let data = tracked_sync_data(data, &Err(CloudError::RelayerSendError)).unwrap();
+        assert_eq!(data.sync_failures, 2);
+        assert_eq!(data.last_sync_error.as_deref(), Some("RelayerSendError"));
+        let data = tracked_sync_data(account_data(u32::MAX), &Err(CloudError::Web3Error)).unwrap();
+        assert_eq!(data.sync_failures, u32::MAX);
+    }
+
+    #[test]
+    fn successful_sync_resets_failures() {
+        assert!(tracked_sync_data(account_data(0), &Ok(vec![])).is_none());
+        let data = tracked_sync_data(account_data(3), &Ok(vec![])).unwrap();
+        assert_eq!(data.sync_failures, 0);
+        assert!(data.last_sync_error.is_none());
+    }
+
+    #[test]
+    fn stored_sync_error_is_truncated() {
+        let err = CloudError::StateSyncError((0..10_000).map(|i| i * 128).collect());
+        let data = tracked_sync_data(account_data(0), &Err(err)).unwrap();
+        let stored = data.last_sync_error.unwrap();
+        assert_eq!(stored.chars().count(), MAX_SYNC_ERROR_LENGTH);
+        assert!(stored.starts_with("StateSyncError([0, 128"));
+        assert!(stored.ends_with("..."));
+    }
+
+    #[actix_web::test]
+    async fn concurrent_failures_are_not_lost() {
+        let dir = TestDir::new();
+        let db = Arc::new(tokio::sync::RwLock::new(db::Db::new(&dir.db_path()).unwrap()));
+        let id = Uuid::new_v4();
+        db.write().await.save_account(id, &account_data(0)).unwrap();
+
+        let tasks = (0..20).map(|_| {
+            let db = db.clone();
+            tokio::spawn(async move {
+                tokio::task::yield_now().await;
+                db.write()
+                    .await
+                    .update_account(id, |data| tracked_sync_data(data, &Err(CloudError::Web3Error)))
+                    .unwrap();
+            })
+        });
+        for task in tasks.collect::<Vec<_>>() {
+            task.await.unwrap();
+        }
+        assert_eq!(db.read().await.get_account(id).unwrap().unwrap().sync_failures, 20);
     }
 }
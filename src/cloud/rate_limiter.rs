@@ -0,0 +1,94 @@
+use std::{collections::HashMap, hash::Hash, sync::Mutex, time::{Duration, Instant}};
+
+// Fixed window limit of calls per key. Windows that are over are dropped on every call,
+// so only keys active within the last window are kept in memory.
+pub(crate) struct RateLimiter<K> {
+    limit: u32,
+    window: Duration,
+    windows: Mutex<HashMap<K, (Instant, u32)>>,
+}
+
+impl<K: Eq + Hash + Clone> RateLimiter<K> {
+    pub fn new(limit: u32, window: Duration) -> Self {
+        RateLimiter {
+            limit,
+            window,
+            windows: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Counts the call and returns false when the key is over the limit, 0 disables the limit
+    pub fn check(&self, key: &K) -> bool {
+        self.check_at(key, Instant::now())
+    }
+
+    fn check_at(&self, key: &K, now: Instant) -> bool {
+        if self.limit == 0 {
+            return true;
+        }
+        let mut windows = match self.windows.lock() {
+            Ok(windows) => windows,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        windows.retain(|_, (started, _)| now.saturating_duration_since(*started) < self.window);
+        let (_, count) = windows.entry(key.clone()).or_insert((now, 0));
+        if *count >= self.limit {
+            return false;
+        }
+        *count += 1;
+        true
+    }
+
+    #[cfg(test)]
+    fn tracked(&self) -> usize {
+        self.windows.lock().unwrap().len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::RateLimiter;
+
+    #[test]
+    fn test_limit_is_per_key() {
+        let limiter = RateLimiter::new(2, Duration::from_secs(60));
+        let now = Instant::now();
+        assert!(limiter.check_at(&1, now));
+        assert!(limiter.check_at(&1, now));
+        assert!(!limiter.check_at(&1, now));
+        assert!(limiter.check_at(&2, now));
+    }
+
+    #[test]
+    fn test_window_resets() {
+        let limiter = RateLimiter::new(1, Duration::from_secs(60));
+        let now = Instant::now();
+        assert!(limiter.check_at(&1, now));
+        assert!(!limiter.check_at(&1, now + Duration::from_secs(59)));
+        assert!(limiter.check_at(&1, now + Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn test_expired_windows_are_dropped() {
+        let limiter = RateLimiter::new(1, Duration::from_secs(60));
+        let now = Instant::now();
+        for key in 0..10 {
+            assert!(limiter.check_at(&key, now));
+        }
+        assert_eq!(limiter.tracked(), 10);
+        assert!(limiter.check_at(&100, now + Duration::from_secs(60)));
+        assert_eq!(limiter.tracked(), 1);
+    }
+
+    #[test]
+    fn test_zero_disables_limit() {
+        let limiter = RateLimiter::new(0, Duration::from_secs(60));
+        let now = Instant::now();
+        for _ in 0..100 {
+            assert!(limiter.check_at(&1, now));
+        }
+        assert_eq!(limiter.tracked(), 0);
+    }
+}
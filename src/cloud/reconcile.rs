@@ -0,0 +1,271 @@
+use std::{collections::HashSet, sync::Mutex};
+
+use actix_web::web::Data;
+use tokio::sync::Notify;
+use uuid::Uuid;
+use zkbob_utils_rs::tracing;
+
+use crate::{account::types::ReservedFunds, errors::CloudError, helpers::timestamp};
+
+use super::{
+    cleanup::{run_catching_panic, spawn_worker},
+    pending_outgoing_amount,
+    types::{JobState, ReconcileAccountError, ReconcileReport, ReconcileStatus},
+    ZkBobCloud,
+};
+
+// Status of the last reconciliation, it is kept in memory only
+pub(crate) struct Reconciliations {
+    status: Mutex<Option<ReconcileStatus>>,
+    pending: Mutex<bool>,
+    notify: Notify,
+}
+
+impl Reconciliations {
+    pub fn new() -> Self {
+        Reconciliations {
+            status: Mutex::new(None),
+            pending: Mutex::new(false),
+            notify: Notify::new(),
+        }
+    }
+
+    // A reconciliation that is already running is returned instead of starting another one
+    pub fn start(&self) -> ReconcileStatus {
+        let mut status = self.status.lock().expect("reconciliations are poisoned");
+        if let Some(running @ ReconcileStatus { status: JobState::Running, .. }) = status.as_ref() {
+            return running.clone();
+        }
+        let started = ReconcileStatus {
+            status: JobState::Running,
+            started_at: timestamp(),
+            report: None,
+            error: None,
+        };
+        *status = Some(started.clone());
+        *self.pending.lock().expect("reconciliations are poisoned") = true;
+        self.notify.notify_one();
+        started
+    }
+
+    pub fn status(&self) -> Option<ReconcileStatus> {
+        self.status.lock().ok().and_then(|status| status.clone())
+    }
+
+    fn take_pending(&self) -> bool {
+        std::mem::take(&mut *self.pending.lock().expect("reconciliations are poisoned"))
+    }
+
+    fn finish(&self, result: Result<ReconcileReport, CloudError>) {
+        let mut status = match self.status.lock() {
+            Ok(status) => status,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        if let Some(status) = status.as_mut() {
+            match result {
+                Ok(report) => {
+                    status.status = JobState::Completed;
+                    status.report = Some(report);
+                }
+                Err(err) => {
+                    status.status = JobState::Failed;
+                    status.error = Some(err.to_string());
+                }
+            }
+        }
+    }
+
+    // A reconciliation interrupted by a worker restart is started again
+    fn resume(&self) {
+        let running = matches!(
+            self.status.lock().expect("reconciliations are poisoned").as_ref(),
+            Some(ReconcileStatus { status: JobState::Running, .. })
+        );
+        *self.pending.lock().expect("reconciliations are poisoned") = running;
+    }
+}
+
+pub(crate) fn run_reconcile_worker(cloud: Data<ZkBobCloud>) {
+    spawn_worker("reconcile", cloud, move |cloud| async move {
+        cloud.reconciliations.resume();
+        loop {
+            if !cloud.reconciliations.take_pending() {
+                cloud.reconciliations.notify.notified().await;
+                continue;
+            }
+
+            let task = {
+                let cloud = cloud.clone();
+                async move {
+                    let result = reconcile(&cloud).await;
+                    match &result {
+                        Ok(report) => tracing::info!(
+                            "[reconcile worker] reconciled {} accounts at index {}, {} failed",
+                            report.accounts,
+                            report.pool_index,
+                            report.errors.len()
+                        ),
+                        Err(err) => tracing::warn!("[reconcile worker] failed to reconcile: {}", err),
+                    }
+                    cloud.reconciliations.finish(result);
+                }
+            };
+            if let Some(message) = run_catching_panic(task).await {
+                tracing::error!("[reconcile worker] panic while reconciling: {}", message);
+                cloud.reconciliations.finish(Err(CloudError::InternalError(format!("panic while reconciling: {}", message))));
+            }
+        }
+    });
+}
+
+// Accounts are synced to the relayer index, the ones that fail to sync are counted with their last synced balance.
+// The index is fetched bypassing the cache, so the accounts are not compared against an outdated one
+async fn reconcile(cloud: &ZkBobCloud) -> Result<ReconcileReport, CloudError> {
+    let info = cloud.relayer.get_info(true).await?;
+    let ids = cloud
+        .db
+        .read()
+        .await
+        .get_accounts()?
+        .into_iter()
+        .map(|(id, _)| id)
+        .collect::<Vec<_>>();
+    let fee = cloud.current_fee().await;
+
+    let mut totals = Totals::new(info.delta_index);
+    for id in ids {
+        match reconcile_account(cloud, id, info.delta_index, fee).await {
+            Ok((balance, next_index)) => totals.add(balance, next_index),
+            Err(err) => {
+                tracing::warn!("[reconcile worker] failed to reconcile account {}: {}", id, err);
+                totals.fail(id, err);
+            }
+        }
+    }
+
+    let parts = cloud.db.read().await.get_parts()?;
+    let in_flight_transfers = parts
+        .iter()
+        .filter(|part| !part.status.is_final())
+        .map(|part| part.transaction_id.as_str())
+        .collect::<HashSet<_>>()
+        .len();
+
+    Ok(ReconcileReport {
+        timestamp: timestamp(),
+        pool_index: info.delta_index,
+        optimistic_pool_index: info.optimistic_delta_index,
+        accounts: totals.accounts,
+        unsynced_accounts: totals.unsynced_accounts,
+        total_balance: totals.total_balance,
+        in_flight_transfers,
+        // totals are per synced index, so every unfinished part is counted as in flight
+        pending_outgoing: pending_outgoing_amount(&parts, |_| false),
+        errors: totals.errors,
+    })
+}
+
+// Returns the balance and the index the account is synced to, a failed sync keeps the last synced state
+async fn reconcile_account(cloud: &ZkBobCloud, id: Uuid, pool_index: u64, fee: u64) -> Result<(u64, u64), CloudError> {
+    let (account, _cleanup) = cloud.get_account(id).await?;
+    if let Err(err) = cloud.tracked_sync(id, &account, Some(pool_index)).await {
+        tracing::warn!("[reconcile worker] failed to sync account {}: {}", id, err);
+    }
+    let balance = account.info(fee, &ReservedFunds::default()).await.balance;
+    Ok((balance, account.next_index().await))
+}
+
+struct Totals {
+    pool_index: u64,
+    accounts: usize,
+    unsynced_accounts: usize,
+    total_balance: u64,
+    errors: Vec<ReconcileAccountError>,
+}
+
+impl Totals {
+    fn new(pool_index: u64) -> Self {
+        Totals {
+            pool_index,
+            accounts: 0,
+            unsynced_accounts: 0,
+            total_balance: 0,
+            errors: Vec::new(),
+        }
+    }
+
+    // An account synced past the index by a concurrent request is not behind it
+    fn add(&mut self, balance: u64, next_index: u64) {
+        self.accounts += 1;
+        if next_index < self.pool_index {
+            self.unsynced_accounts += 1;
+        }
+        self.total_balance = self.total_balance.saturating_add(balance);
+    }
+
+    // Accounts that can't be loaded have no balance to count
+    fn fail(&mut self, id: Uuid, err: CloudError) {
+        self.accounts += 1;
+        self.unsynced_accounts += 1;
+        self.errors.push(ReconcileAccountError {
+            account_id: id.to_string(),
+            error: err.to_string(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use crate::{cloud::types::JobState, errors::CloudError};
+
+    use super::{Reconciliations, Totals};
+
+    #[test]
+    fn failed_accounts_are_collected() {
+        let mut totals = Totals::new(1280);
+        totals.add(100, 1280);
+        totals.fail(Uuid::nil(), CloudError::AccountIsBusy);
+        totals.add(50, 1408);
+        totals.add(10, 1152);
+        assert_eq!(totals.accounts, 4);
+        assert_eq!(totals.unsynced_accounts, 2);
+        assert_eq!(totals.total_balance, 160);
+        assert_eq!(totals.errors.len(), 1);
+        assert_eq!(totals.errors[0].account_id, Uuid::nil().to_string());
+        assert_eq!(totals.errors[0].error, CloudError::AccountIsBusy.to_string());
+    }
+
+    #[test]
+    fn running_reconciliation_is_not_restarted() {
+        let reconciliations = Reconciliations::new();
+        assert!(reconciliations.status().is_none());
+        let started = reconciliations.start();
+        assert_eq!(reconciliations.start().started_at, started.started_at);
+        assert!(reconciliations.take_pending());
+        assert!(!reconciliations.take_pending());
+
+        reconciliations.finish(Err(CloudError::Web3Error));
+        let status = reconciliations.status().unwrap();
+        assert_eq!(status.status, JobState::Failed);
+        assert!(status.error.is_some());
+
+        reconciliations.start();
+        assert_eq!(reconciliations.status().unwrap().status, JobState::Running);
+        assert!(reconciliations.take_pending());
+    }
+
+    #[test]
+    fn interrupted_reconciliation_is_resumed() {
+        let reconciliations = Reconciliations::new();
+        reconciliations.start();
+        assert!(reconciliations.take_pending());
+        reconciliations.resume();
+        assert!(reconciliations.take_pending());
+
+        reconciliations.finish(Err(CloudError::Web3Error));
+        reconciliations.resume();
+        assert!(!reconciliations.take_pending());
+    }
+}
@@ -1,39 +1,53 @@
-use std::{thread, str::FromStr};
+use std::{future::Future, str::FromStr, sync::Arc, time::{Duration, Instant}};
 
 use actix_web::web::Data;
+use futures::{stream, Stream, StreamExt};
+use tokio::sync::Mutex;
 use uuid::Uuid;
 use zkbob_utils_rs::tracing;
 
-use crate::{cloud::types::AccountReport, helpers::{timestamp, queue::receive_blocking}};
+use crate::{account::types::ReservedFunds, cloud::types::AccountReport, errors::CloudError, helpers::{timestamp, queue::{receive_blocking, DEFAULT_POLL_INTERVAL_MS, DEFAULT_RECONNECT_BACKOFF_MS}, key_cipher}, relayer::cached::Transaction};
 
-use super::{cleanup::WorkerCleanup, ZkBobCloud, types::{ReportTask, ReportStatus, Report}};
+use super::{cleanup::spawn_worker, ZkBobCloud, types::{ReportTask, ReportStatus, Report, ReportProgress}};
 
 
 pub(crate) fn run_report_worker(cloud: Data<ZkBobCloud>, max_attempts: u32) {
-    thread::spawn( move || {
-        let _cleanup = WorkerCleanup;
-        let rt = tokio::runtime::Runtime::new().expect("failed to init tokio runtime");
-        rt.block_on(async move {
-            loop {
-                let (redis_id, id) = receive_blocking::<String>(cloud.report_queue.clone()).await;
-
-                let process_result = process(&cloud, &id, max_attempts).await;
-                if let Some(update) = process_result.update {
-                    if let Err(err) = cloud.db.write().await.save_report_task(Uuid::from_str(&id).unwrap(), &update) {
-                        tracing::error!("[report task: {}] failed to save processed task in db: {}", &id, err);
-                        continue;
-                    }
+    spawn_worker("report", cloud, move |cloud| async move {
+        loop {
+            let (redis_id, id) = receive_blocking::<String>(
+                cloud.report_queue.clone(),
+                Duration::from_millis(DEFAULT_POLL_INTERVAL_MS),
+                Duration::from_millis(DEFAULT_RECONNECT_BACKOFF_MS),
+            ).await;
+
+            // a task that panics fails its report instead of restarting the worker
+            let process_result = {
+                let cloud = cloud.clone();
+                let id = id.clone();
+                tokio::spawn(async move { process(&cloud, &id, max_attempts).await }).await
+            };
+            let process_result = match process_result {
+                Ok(process_result) => process_result,
+                Err(err) => {
+                    tracing::error!("[report task: {}] panicked: {}, marking report as failed", &id, err);
+                    ProcessResult::panicked(&cloud, &id).await
+                }
+            };
+            if let Some(update) = process_result.update {
+                if let Err(err) = cloud.db.write().await.save_report_task(Uuid::from_str(&id).unwrap(), &update) {
+                    tracing::error!("[report task: {}] failed to save processed task in db: {}", &id, err);
+                    continue;
+                }
 
-                    if process_result.delete {
-                        let mut report_queue = cloud.report_queue.write().await;
-                        if let Err(err) = report_queue.delete(&redis_id).await {
-                            tracing::error!("[report task: {}] failed to delete task from queue: {}", &id, err);
-                            continue;
-                        }
+                if process_result.delete {
+                    let mut report_queue = cloud.report_queue.write().await;
+                    if let Err(err) = report_queue.delete(&redis_id).await {
+                        tracing::error!("[report task: {}] failed to delete task from queue: {}", &id, err);
+                        continue;
                     }
                 }
             }
-        });
+        }
     });
 }
 
@@ -46,7 +60,7 @@ async fn process(cloud: &ZkBobCloud, id: &str, max_attempts: u32) -> ProcessResu
         }
     };
     
-    let task = match cloud.db.read().await.get_report_task(id) {
+    let mut task = match cloud.db.read().await.get_report_task(id) {
         Ok(Some(task)) => task,
         _ => {
             tracing::error!("[report task: {}] failed to get from db", id);
@@ -56,69 +70,221 @@ async fn process(cloud: &ZkBobCloud, id: &str, max_attempts: u32) -> ProcessResu
 
     tracing::info!("[report task: {}] processing...", id);
 
+    let keys = match (task.include_keys, task.public_key.as_deref()) {
+        (false, _) => Ok(ReportKeys::Excluded),
+        (true, None) => Ok(ReportKeys::Plain),
+        (true, Some(public_key)) => key_cipher::parse_public_key(public_key).map(ReportKeys::Encrypted),
+    };
+    let keys = match keys {
+        Ok(keys) => keys,
+        Err(err) => {
+            tracing::warn!("[report task: {}] invalid public key: {}", id, err);
+            cloud.metrics.reports.with_label_values(&["failed"]).inc();
+            return ProcessResult::error_without_retry(task);
+        }
+    };
+
     let accounts = match cloud.db.read().await.get_accounts() {
         Ok(accounts) => accounts,
         Err(err) => {
             tracing::warn!("[report task: {}] failed to get accounts from db, attempt: {}. Error: {}", id, task.attempt, err);
-            return ProcessResult::error_with_retry_attempts(task, max_attempts);
+            return ProcessResult::error_with_retry_attempts(cloud, task, max_attempts);
         }
     };
 
-    let to_index = match cloud.relayer.info().await {
+    // reports are generated at the latest index, a cached one can be a few seconds old
+    let to_index = match cloud.relayer.get_info(true).await {
         Ok(info) => info.delta_index,
         Err(err) => {
             tracing::warn!("[report task: {}] failed to fetch info from relayer, attempt: {}. Error: {}", id, task.attempt, err);
-            return ProcessResult::error_with_retry_attempts(task, max_attempts);
+            return ProcessResult::error_with_retry_attempts(cloud, task, max_attempts);
         }
     };
 
-    let mut reports = vec![];
-    let count = accounts.len();
-    for (i, (account_id, _)) in accounts.into_iter().enumerate() {
-        let (account, _cleanup) = match cloud.get_account(account_id).await {
-            Ok((account, cleanup)) => (account, cleanup),
-            Err(err) => {
-                tracing::warn!("[report task: {}] failed to get account {}, attempt: {}. Error: {}", id, account_id, task.attempt, err);
-                return ProcessResult::error_with_retry_attempts(task, max_attempts);
-            }
-        };
+    let started_at = Instant::now();
 
-        if let Err(err) = account.sync(&cloud.relayer, Some(to_index)).await {
-            tracing::warn!("[report task: {}] failed to sync account {}, attempt: {}. Error: {}", id, account_id, task.attempt, err);
-            return ProcessResult::error_with_retry_attempts(task, max_attempts);
-        }
+    // the transactions are fetched once and shared by the accounts, so that each account
+    // only decrypts its part of them instead of downloading it again
+    let txs = SharedTxs::new(to_index);
+    let count = accounts.len();
+    save_progress(cloud, id, &mut task, 0, count).await;
+    let txs = &txs;
+    let keys = &keys;
+    let accounts = accounts.into_iter().map(|(account_id, data)| (account_id, data.description)).collect();
+    let mut results = account_reports(accounts, cloud.config.report_worker.concurrency, |account_id| {
+        account_report(cloud, account_id, txs, keys)
+    });
 
-        let info = account.info(cloud.relayer_fee).await;
-        let sk = match account.export_key().await {
-            Ok(sk) => sk,
+    let mut reports = Vec::with_capacity(count);
+    // number of transactions that the accounts would have fetched with separate syncs
+    let mut synced_txs = 0;
+    let mut failed = 0;
+    while let Some((account_id, description, result)) = results.next().await {
+        match result {
+            Ok((report, applied)) => {
+                synced_txs += applied;
+                reports.push(report);
+            }
             Err(err) => {
-                tracing::warn!("[report task: {}] failed to export key from account {}, attempt: {}. Error: {}", id, account_id, task.attempt, err);
-                return ProcessResult::error_with_retry_attempts(task, max_attempts);
+                tracing::warn!("[report task: {}] failed to process account {}: {}", id, account_id, err);
+                failed += 1;
+                reports.push(AccountReport::failed(account_id, description, err));
             }
-        };
-
-        reports.push( AccountReport {
-            id: info.id,
-            description: info.description,
-            balance: info.balance,
-            max_transfer_amount: info.max_transfer_amount,
-            address: info.address,
-            sk,
-        });
+        }
 
-        if i % 10 == 0 {
-            tracing::info!("[report task: {}] {} % processed", id, (i * 100) / count)
+        if let Some(percent) = progress_percent(reports.len(), count) {
+            tracing::info!("[report task: {}] {} % processed", id, percent);
+            save_progress(cloud, id, &mut task, reports.len(), count).await;
         }
     }
+    task.progress = Some(ReportProgress { processed: count, total: count });
+    // accounts complete out of order
+    reports.sort_by(|a, b| a.id.cmp(&b.id));
 
+    let (fetched, fetch_duration) = txs.fetched().await;
     let report = Report {
         timestamp: timestamp(),
         pool_index: to_index,
         accounts: reports,
     };
 
-    tracing::info!("[report task: {}] processed successfully", id);
-    ProcessResult::success(task, report)
+    tracing::info!(
+        "[report task: {}] processed in {:?} with {} failed accounts, fetched {} transactions in {:?} instead of {} with separate syncs ({:.1}x less)",
+        id,
+        started_at.elapsed(),
+        failed,
+        fetched,
+        fetch_duration,
+        synced_txs,
+        synced_txs as f64 / fetched.max(1) as f64,
+    );
+    match failed {
+        0 => {
+            cloud.metrics.reports.with_label_values(&["completed"]).inc();
+            ProcessResult::success(task, report, ReportStatus::Completed)
+        }
+        _ => {
+            cloud.metrics.reports.with_label_values(&["completed_with_errors"]).inc();
+            ProcessResult::success(task, report, ReportStatus::CompletedWithErrors)
+        }
+    }
+}
+
+// Reports of at most `concurrency` accounts are built at a time, they complete out of order
+fn account_reports<'a, F, Fut>(
+    accounts: Vec<(Uuid, String)>,
+    concurrency: usize,
+    report: F,
+) -> impl Stream<Item = (Uuid, String, Fut::Output)> + 'a
+where
+    F: Fn(Uuid) -> Fut + 'a,
+    Fut: Future + 'a,
+{
+    stream::iter(accounts)
+        .map(move |(account_id, description)| {
+            let report = report(account_id);
+            async move { (account_id, description, report.await) }
+        })
+        .buffer_unordered(concurrency.max(1))
+}
+
+// Progress is reported every 10 accounts and once all of them are processed
+fn progress_percent(processed: usize, total: usize) -> Option<usize> {
+    (processed % 10 == 0 || processed == total).then(|| processed * 100 / total.max(1))
+}
+
+// Progress is saved with the task, so a report that fails or crashes keeps its last known progress
+async fn save_progress(cloud: &ZkBobCloud, id: Uuid, task: &mut ReportTask, processed: usize, total: usize) {
+    task.progress = Some(ReportProgress { processed, total });
+    if let Err(err) = cloud.db.write().await.save_report_task(id, task) {
+        tracing::warn!("[report task: {}] failed to save progress: {}", id, err);
+    }
+}
+
+// Syncs the account with the shared transactions, returns its report and the number of applied transactions
+async fn account_report(cloud: &ZkBobCloud, account_id: Uuid, txs: &SharedTxs, keys: &ReportKeys) -> Result<(AccountReport, usize), CloudError> {
+    let (account, _cleanup) = cloud.get_account(account_id).await?;
+    let txs = txs
+        .from_index(account.next_index().await, |from_index, to_index| fetch_txs(cloud, from_index, to_index))
+        .await?;
+    let applied = cloud.tracked_sync_with_txs(account_id, &account, &txs).await?;
+    let info = account.info(cloud.current_fee().await, &ReservedFunds::default()).await;
+    let (sk, encrypted_sk) = match keys {
+        ReportKeys::Excluded => (None, None),
+        ReportKeys::Plain => (Some(account.export_key().await?), None),
+        ReportKeys::Encrypted(public_key) => {
+            let sk = hex::decode(account.export_key().await?)?;
+            (None, Some(hex::encode(key_cipher::seal(public_key, &sk)?)))
+        }
+    };
+
+    let report = AccountReport {
+        id: info.id,
+        description: info.description,
+        balance: info.balance,
+        max_transfer_amount: info.max_transfer_amount,
+        address: info.address,
+        sk,
+        encrypted_sk,
+        error: None,
+    };
+    Ok((report, applied))
+}
+
+enum ReportKeys {
+    Excluded,
+    Plain,
+    Encrypted([u8; 32]),
+}
+
+// Transactions up to the report index, the range is extended back when an account
+// is behind all the accounts processed before it, so each transaction is fetched once
+struct SharedTxs {
+    fetched: Mutex<FetchedTxs>,
+}
+
+struct FetchedTxs {
+    from_index: u64,
+    txs: Arc<Vec<Transaction>>,
+    duration: Duration,
+}
+
+impl SharedTxs {
+    fn new(to_index: u64) -> Self {
+        SharedTxs {
+            fetched: Mutex::new(FetchedTxs { from_index: to_index, txs: Arc::new(vec![]), duration: Duration::ZERO }),
+        }
+    }
+
+    async fn from_index<F, Fut>(&self, from_index: u64, fetch: F) -> Result<Arc<Vec<Transaction>>, CloudError>
+    where
+        F: FnOnce(u64, u64) -> Fut,
+        Fut: Future<Output = Result<Vec<Transaction>, CloudError>>,
+    {
+        let mut fetched = self.fetched.lock().await;
+        if from_index < fetched.from_index {
+            let started_at = Instant::now();
+            let mut txs = fetch(from_index, fetched.from_index).await?;
+            txs.extend(fetched.txs.iter().cloned());
+            fetched.txs = Arc::new(txs);
+            fetched.from_index = from_index;
+            fetched.duration += started_at.elapsed();
+        }
+        Ok(fetched.txs.clone())
+    }
+
+    async fn fetched(&self) -> (usize, Duration) {
+        let fetched = self.fetched.lock().await;
+        (fetched.txs.len(), fetched.duration)
+    }
+}
+
+async fn fetch_txs(cloud: &ZkBobCloud, from_index: u64, to_index: u64) -> Result<Vec<Transaction>, CloudError> {
+    let limit = cloud.relayer.tx_count(from_index, to_index)?;
+    if limit == 0 {
+        return Ok(vec![]);
+    }
+    cloud.relayer.transactions(from_index, limit, false).await
 }
 
 struct ProcessResult {
@@ -127,9 +293,9 @@ struct ProcessResult {
 }
 
 impl ProcessResult {
-    fn success(task: ReportTask, report: Report) -> ProcessResult {
+    fn success(task: ReportTask, report: Report, status: ReportStatus) -> ProcessResult {
         let task = ReportTask {
-            status: ReportStatus::Completed,
+            status,
             report: Some(report),
             ..task
         };
@@ -146,10 +312,12 @@ impl ProcessResult {
         }
     }
 
-    fn error_with_retry_attempts(task: ReportTask, max_attempts: u32) -> ProcessResult {
+    fn error_with_retry_attempts(cloud: &ZkBobCloud, task: ReportTask, max_attempts: u32) -> ProcessResult {
         if task.attempt >= max_attempts {
+            cloud.metrics.reports.with_label_values(&["failed"]).inc();
             return ProcessResult::error_without_retry(task);
         }
+        cloud.metrics.reports.with_label_values(&["retried"]).inc();
 
         let task = ReportTask {
             attempt: task.attempt + 1,
@@ -161,6 +329,18 @@ impl ProcessResult {
         }
     }
 
+    async fn panicked(cloud: &ZkBobCloud, id: &str) -> ProcessResult {
+        cloud.metrics.reports.with_label_values(&["failed"]).inc();
+        let task = match Uuid::from_str(id) {
+            Ok(id) => cloud.db.read().await.get_report_task(id).ok().flatten(),
+            Err(_) => None,
+        };
+        match task {
+            Some(task) => ProcessResult::error_without_retry(task),
+            None => ProcessResult::delete_from_queue(),
+        }
+    }
+
     fn error_without_retry(task: ReportTask) -> ProcessResult {
         let task = ReportTask {
             status: ReportStatus::Failed,
@@ -171,4 +351,112 @@ impl ProcessResult {
             update: Some(task),
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use std::{sync::{atomic::{AtomicUsize, Ordering}, Mutex}, time::Duration};
+
+    use futures::StreamExt;
+    use libzkbob_rs::libzeropool::fawkes_crypto::ff_uint::Num;
+    use uuid::Uuid;
+
+    use crate::{errors::CloudError, relayer::cached::Transaction};
+
+    use super::{account_reports, progress_percent, SharedTxs};
+
+    fn txs(from_index: u64, to_index: u64) -> Vec<Transaction> {
+        (from_index..to_index)
+            .step_by(128)
+            .map(|index| Transaction {
+                index,
+                memo: vec![],
+                commitment: Num::ZERO,
+                tx_hash: format!("0x{:x}", index),
+                optimistic: false,
+            })
+            .collect()
+    }
+
+    async fn get(shared: &SharedTxs, from_index: u64, fetches: &Mutex<Vec<(u64, u64)>>) -> Vec<u64> {
+        let txs = shared
+            .from_index(from_index, |from, to| async move {
+                fetches.lock().unwrap().push((from, to));
+                Ok::<_, CloudError>(txs(from, to))
+            })
+            .await
+            .unwrap();
+        txs.iter().map(|tx| tx.index).collect()
+    }
+
+    #[actix_web::test]
+    async fn test_each_range_is_fetched_once() {
+        let shared = SharedTxs::new(1024);
+        let fetches = Mutex::new(vec![]);
+
+        assert_eq!(get(&shared, 768, &fetches).await, vec![768, 896]);
+        // an account that is ahead reuses the fetched transactions
+        assert_eq!(get(&shared, 896, &fetches).await, vec![768, 896]);
+        assert_eq!(get(&shared, 512, &fetches).await, vec![512, 640, 768, 896]);
+        assert_eq!(get(&shared, 1024, &fetches).await.len(), 4);
+
+        assert_eq!(*fetches.lock().unwrap(), vec![(768, 1024), (512, 768)]);
+        assert_eq!(shared.fetched().await.0, 4);
+    }
+
+    #[actix_web::test]
+    async fn test_failed_fetch_keeps_fetched_txs() {
+        let shared = SharedTxs::new(512);
+        let fetches = Mutex::new(vec![]);
+        get(&shared, 256, &fetches).await;
+
+        let result = shared
+            .from_index(0, |_, _| async { Err::<Vec<Transaction>, _>(CloudError::RelayerSendError) })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(get(&shared, 0, &fetches).await, vec![0, 128, 256, 384]);
+        assert_eq!(*fetches.lock().unwrap(), vec![(256, 512), (0, 256)]);
+    }
+
+    // Returns the max number of reports built at the same time and the completed accounts
+    async fn run_reports(accounts: usize, concurrency: usize) -> (usize, Vec<Uuid>) {
+        let in_flight = AtomicUsize::new(0);
+        let max_in_flight = AtomicUsize::new(0);
+        let accounts = (0..accounts).map(|_| (Uuid::new_v4(), "test".to_string())).collect();
+        let completed = account_reports(accounts, concurrency, |account_id| {
+            let (in_flight, max_in_flight) = (&in_flight, &max_in_flight);
+            async move {
+                let current = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                max_in_flight.fetch_max(current, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(5)).await;
+                in_flight.fetch_sub(1, Ordering::SeqCst);
+                account_id
+            }
+        })
+        .map(|(account_id, _, result)| {
+            assert_eq!(account_id, result);
+            account_id
+        })
+        .collect::<Vec<_>>()
+        .await;
+        (max_in_flight.load(Ordering::SeqCst), completed)
+    }
+
+    #[actix_web::test]
+    async fn test_reports_are_built_concurrently() {
+        let (max_in_flight, completed) = run_reports(25, 4).await;
+        assert_eq!(max_in_flight, 4);
+        assert_eq!(completed.len(), 25);
+
+        // a zero concurrency processes accounts one by one
+        let (max_in_flight, completed) = run_reports(5, 0).await;
+        assert_eq!(max_in_flight, 1);
+        assert_eq!(completed.len(), 5);
+    }
+
+    #[test]
+    fn test_progress_is_reported_every_10_accounts() {
+        let reported = (1..=25).filter_map(|processed| progress_percent(processed, 25)).collect::<Vec<_>>();
+        assert_eq!(reported, vec![40, 80, 100]);
+        assert_eq!(progress_percent(0, 0), Some(0));
+    }
+}
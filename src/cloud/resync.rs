@@ -0,0 +1,392 @@
+use std::{collections::{HashMap, VecDeque}, path::Path, sync::{Arc, Mutex}, time::Duration};
+
+use actix_web::web::Data;
+use tokio::{fs, sync::Notify, time};
+use uuid::Uuid;
+use zkbob_utils_rs::tracing;
+
+use crate::{account::Account, errors::CloudError, helpers::timestamp};
+
+use super::{
+    callback,
+    cleanup::{run_catching_panic, spawn_worker},
+    types::{AccountData, ResyncResult, JobState, ResyncStatus, TransferPart, TransferStatus},
+    ZkBobCloud,
+};
+
+// how long the switch to the new state waits for the account to become idle
+const SWITCH_ATTEMPTS: u32 = 60;
+const SWITCH_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+// Statuses of account resyncs, they are kept in memory only. A resync that is interrupted
+// by a restart leaves the old state of the account in place, so it can be requested again
+pub(crate) struct Resyncs {
+    statuses: Mutex<HashMap<Uuid, ResyncStatus>>,
+    pending: Mutex<VecDeque<Uuid>>,
+    notify: Notify,
+}
+
+impl Resyncs {
+    pub fn new() -> Self {
+        Resyncs {
+            statuses: Mutex::new(HashMap::new()),
+            pending: Mutex::new(VecDeque::new()),
+            notify: Notify::new(),
+        }
+    }
+
+    // Returns None if the account is already being resynced
+    pub fn schedule(&self, id: Uuid) -> Option<ResyncStatus> {
+        let mut statuses = self.statuses.lock().expect("resyncs are poisoned");
+        if let Some(ResyncStatus { status: JobState::Running, .. }) = statuses.get(&id) {
+            return None;
+        }
+        let status = ResyncStatus {
+            status: JobState::Running,
+            started_at: timestamp(),
+            result: None,
+            error: None,
+        };
+        statuses.insert(id, status.clone());
+        self.pending.lock().expect("resyncs are poisoned").push_back(id);
+        self.notify.notify_one();
+        Some(status)
+    }
+
+    pub fn status(&self, id: Uuid) -> Option<ResyncStatus> {
+        self.statuses
+            .lock()
+            .ok()
+            .and_then(|statuses| statuses.get(&id).cloned())
+    }
+
+    fn next(&self) -> Option<Uuid> {
+        self.pending.lock().expect("resyncs are poisoned").pop_front()
+    }
+
+    fn finish(&self, id: Uuid, result: Result<ResyncResult, CloudError>) {
+        let mut statuses = match self.statuses.lock() {
+            Ok(statuses) => statuses,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        if let Some(status) = statuses.get_mut(&id) {
+            match result {
+                Ok(result) => {
+                    status.status = JobState::Completed;
+                    status.result = Some(result);
+                }
+                Err(err) => {
+                    status.status = JobState::Failed;
+                    status.error = Some(err.to_string());
+                }
+            }
+        }
+    }
+
+    // Resyncs interrupted by a worker restart are scheduled again
+    fn resume(&self) {
+        let statuses = self.statuses.lock().expect("resyncs are poisoned");
+        let mut pending = self.pending.lock().expect("resyncs are poisoned");
+        pending.clear();
+        pending.extend(
+            statuses
+                .iter()
+                .filter(|(_, status)| status.status == JobState::Running)
+                .map(|(id, _)| *id),
+        );
+    }
+}
+
+// Resyncs run one at a time, each of them syncs the account from scratch
+pub(crate) fn run_resync_worker(cloud: Data<ZkBobCloud>) {
+    spawn_worker("resync", cloud, move |cloud| async move {
+        cloud.resyncs.resume();
+        loop {
+            let id = match cloud.resyncs.next() {
+                Some(id) => id,
+                None => {
+                    cloud.resyncs.notify.notified().await;
+                    continue;
+                }
+            };
+
+            let task = {
+                let cloud = cloud.clone();
+                async move {
+                    let result = resync(&cloud, id).await;
+                    match &result {
+                        Ok(result) => tracing::info!("[resync worker] account {} is resynced to index {}, failed {} unsent parts", id, result.next_index, result.failed_parts.len()),
+                        Err(err) => tracing::warn!("[resync worker] failed to resync account {}: {}", id, err),
+                    }
+                    cloud.resyncs.finish(id, result);
+                }
+            };
+            if let Some(message) = run_catching_panic(task).await {
+                tracing::error!("[resync worker] panic while resyncing account {}: {}", id, message);
+                cloud.accounts.write().await.unblock(id);
+                cloud.resyncs.finish(id, Err(CloudError::InternalError(format!("panic while resyncing: {}", message))));
+            }
+        }
+    });
+}
+
+// The new state is synced next to the old one, which keeps serving the account until the sync
+// completes. Then the account is switched to the new state and the old one is removed
+async fn resync(cloud: &ZkBobCloud, id: Uuid) -> Result<ResyncResult, CloudError> {
+    let (data, base_path) = {
+        let db = cloud.db.read().await;
+        let data = db.get_account(id)?.ok_or(CloudError::AccountNotFound)?;
+        (data, db.account_db_path(id))
+    };
+    remove_stale_states(&base_path, &data.db_path).await;
+
+    let db_path = format!("{}.{}", base_path, Uuid::new_v4().as_simple());
+    tracing::info!("[resync worker] syncing new state of account {} at {}", id, db_path);
+    let account = match sync_new_state(cloud, id, &data, &db_path).await {
+        Ok(account) => account,
+        Err(err) => {
+            remove_state_dir(&db_path).await;
+            return Err(err);
+        }
+    };
+
+    match switch_state(cloud, id, account.clone(), &db_path).await {
+        Ok((failed_parts, old_path)) => {
+            remove_state_dir(&old_path).await;
+            cloud.sync_times.record(id);
+            Ok(ResyncResult {
+                next_index: account.next_index().await,
+                failed_parts,
+            })
+        }
+        Err(err) => {
+            drop(account);
+            remove_state_dir(&db_path).await;
+            Err(err)
+        }
+    }
+}
+
+async fn sync_new_state(cloud: &ZkBobCloud, id: Uuid, data: &AccountData, db_path: &str) -> Result<Arc<Account>, CloudError> {
+    let sk = cloud.decode_sk(&data.sk)?;
+    let mut account = Account::new(id, data.description.clone(), Some(sk), cloud.pool_id, db_path, data.first_index, &cloud.key_cipher)?;
+    account.skip_parse_errors = cloud.config.skip_parse_errors;
+    account.sync(&cloud.relayer, None, None).await?;
+    Ok(Arc::new(account))
+}
+
+// Requests to the account fail with AccountIsBusy while it is switched, on failure it keeps the old state.
+// Returns the failed parts and the path of the old state
+async fn switch_state(cloud: &ZkBobCloud, id: Uuid, account: Arc<Account>, db_path: &str) -> Result<(Vec<String>, String), CloudError> {
+    let _lock = cloud.account_locks.lock(id).await;
+    cloud.accounts.write().await.block(id);
+
+    let result = async {
+        let failed_parts = fail_unsent_parts_when_idle(cloud, id).await?;
+        // the account data is read again, it could change while the new state was synced
+        let mut db = cloud.db.write().await;
+        let data = db.get_account(id)?.ok_or(CloudError::AccountNotFound)?;
+        let old_path = data.db_path.clone();
+        // skipped indices are collected again by the new state
+        let data = AccountData {
+            db_path: db_path.to_string(),
+            sync_failures: 0,
+            last_sync_error: None,
+            skipped_indices: vec![],
+            ..data
+        };
+        db.save_account(id, &data)?;
+        Ok((failed_parts, old_path))
+    }
+    .await;
+
+    let mut accounts = cloud.accounts.write().await;
+    if result.is_ok() {
+        accounts.insert(id, account);
+    }
+    accounts.unblock(id);
+    result
+}
+
+// Waits until the old state is released and no part of the account is being proved or sent
+async fn fail_unsent_parts_when_idle(cloud: &ZkBobCloud, id: Uuid) -> Result<Vec<String>, CloudError> {
+    for _ in 0..SWITCH_ATTEMPTS {
+        let evicted = cloud.accounts.write().await.evict(id).is_ok();
+        if evicted {
+            if let Some(failed_parts) = fail_unsent_parts(cloud, id).await? {
+                return Ok(failed_parts);
+            }
+        }
+        time::sleep(SWITCH_RETRY_INTERVAL).await;
+    }
+    Err(CloudError::AccountIsBusy)
+}
+
+// Parts that are not sent to the relayer yet were planned with the old state. Returns None while
+// the send worker processes any of them, it would send the part after it is failed
+async fn fail_unsent_parts(cloud: &ZkBobCloud, id: Uuid) -> Result<Option<Vec<String>>, CloudError> {
+    let mut failed = Vec::new();
+    {
+        // the send worker takes a part before it reads it, so a part that isn't taken now
+        // is read by the worker only after it is failed
+        let mut db = cloud.db.write().await;
+        let parts = unsent_parts(db.get_account_parts(id)?);
+        for part in parts.iter() {
+            if cloud.prover.is_in_progress(&part.id).await {
+                tracing::debug!("[resync worker] part {} is being sent, waiting for it", &part.id);
+                return Ok(None);
+            }
+        }
+        for part in parts {
+            let part = TransferPart {
+                status: TransferStatus::Failed(CloudError::AccountResynced),
+                timestamp: timestamp(),
+                ..part
+            };
+            db.save_part(&part)?;
+            tracing::info!("[resync worker] part {} is failed", &part.id);
+            failed.push(part);
+        }
+    }
+
+    for part in failed.iter() {
+        if let Err(err) = cloud.send_queue.write().await.remove(&part.id).await {
+            tracing::warn!("[resync worker] failed to remove part {} from send queue: {}", &part.id, err);
+        }
+        callback::notify_if_final(cloud, part).await;
+    }
+    Ok(Some(failed.into_iter().map(|part| part.id).collect()))
+}
+
+fn unsent_parts(parts: Vec<TransferPart>) -> Vec<TransferPart> {
+    parts
+        .into_iter()
+        .filter(|part| matches!(part.status, TransferStatus::New | TransferStatus::Proving))
+        .collect()
+}
+
+// States left by interrupted resyncs or failed removals, they are named after the account
+async fn remove_stale_states(base_path: &str, current_path: &str) {
+    let base = Path::new(base_path);
+    let dir = match base.parent() {
+        Some(dir) => dir,
+        None => return,
+    };
+    let mut entries = match fs::read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let path = entry.path();
+        if is_stale_state(&path, base, Path::new(current_path)) {
+            tracing::info!("[resync worker] removing stale state {}", path.display());
+            remove_state_dir(&path.to_string_lossy()).await;
+        }
+    }
+}
+
+fn is_stale_state(path: &Path, base: &Path, current: &Path) -> bool {
+    if path == current {
+        return false;
+    }
+    let prefix = format!("{}.", base.display());
+    path == base || path.to_string_lossy().starts_with(&prefix)
+}
+
+async fn remove_state_dir(path: &str) {
+    if !Path::new(path).exists() {
+        return;
+    }
+    if let Err(err) = fs::remove_dir_all(path).await {
+        tracing::warn!("[resync worker] failed to remove account state {}: {}", path, err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use uuid::Uuid;
+
+    use crate::{cloud::{tests::part, types::{ResyncResult, JobState, TransferStatus}}, errors::CloudError};
+
+    use super::{is_stale_state, unsent_parts, Resyncs};
+
+    #[test]
+    fn account_is_resynced_once_at_a_time() {
+        let resyncs = Resyncs::new();
+        let id = Uuid::new_v4();
+
+        assert_eq!(resyncs.schedule(id).map(|status| status.status), Some(JobState::Running));
+        assert!(resyncs.schedule(id).is_none());
+        assert_eq!(resyncs.next(), Some(id));
+        assert_eq!(resyncs.next(), None);
+
+        let result = ResyncResult { next_index: 256, failed_parts: vec!["transfer.0".to_string()] };
+        resyncs.finish(id, Ok(result.clone()));
+        let status = resyncs.status(id).unwrap();
+        assert_eq!(status.status, JobState::Completed);
+        assert_eq!(status.result, Some(result));
+
+        // a finished resync can be requested again
+        assert!(resyncs.schedule(id).is_some());
+        assert_eq!(resyncs.next(), Some(id));
+        assert!(resyncs.status(id).unwrap().result.is_none());
+    }
+
+    #[test]
+    fn failed_resync_keeps_error() {
+        let resyncs = Resyncs::new();
+        let id = Uuid::new_v4();
+        resyncs.schedule(id);
+        resyncs.finish(id, Err(CloudError::AccountIsBusy));
+
+        let status = resyncs.status(id).unwrap();
+        assert_eq!(status.status, JobState::Failed);
+        assert_eq!(status.error.as_deref(), Some("account is busy"));
+        assert!(resyncs.status(Uuid::new_v4()).is_none());
+    }
+
+    #[test]
+    fn interrupted_resyncs_are_resumed() {
+        let resyncs = Resyncs::new();
+        let (running, finished) = (Uuid::new_v4(), Uuid::new_v4());
+        resyncs.schedule(running);
+        resyncs.schedule(finished);
+        assert_eq!(resyncs.next(), Some(running));
+        assert_eq!(resyncs.next(), Some(finished));
+        resyncs.finish(finished, Err(CloudError::AccountIsBusy));
+
+        resyncs.resume();
+        assert_eq!(resyncs.next(), Some(running));
+        assert_eq!(resyncs.next(), None);
+    }
+
+    #[test]
+    fn parts_being_proved_are_unsent() {
+        let parts = vec![
+            part(0, TransferStatus::Done, 1, None),
+            part(1, TransferStatus::Mining, 1, None),
+            part(2, TransferStatus::Relaying, 1, None),
+            part(3, TransferStatus::Proving, 1, None),
+            part(4, TransferStatus::New, 1, None),
+            part(5, TransferStatus::Failed(CloudError::InsufficientBalance), 1, None),
+            part(6, TransferStatus::Cancelled, 1, None),
+        ];
+        let unsent: Vec<_> = unsent_parts(parts).into_iter().map(|part| part.id).collect();
+        assert_eq!(unsent, vec!["transfer.3", "transfer.4"]);
+    }
+
+    #[test]
+    fn stale_states_are_named_after_account() {
+        let base = Path::new("db/accounts_data/3fa85f64-5717-4562-b3fc-2c963f66afa6");
+        let current = Path::new("db/accounts_data/3fa85f64-5717-4562-b3fc-2c963f66afa6.b2");
+
+        assert!(is_stale_state(base, base, current));
+        assert!(is_stale_state(Path::new("db/accounts_data/3fa85f64-5717-4562-b3fc-2c963f66afa6.a1"), base, current));
+        assert!(!is_stale_state(current, base, current));
+        assert!(!is_stale_state(base, base, base));
+        assert!(!is_stale_state(Path::new("db/accounts_data/3fa85f64-5717-4562-b3fc-2c963f66afa7"), base, current));
+        assert!(!is_stale_state(Path::new("db/accounts_data/3fa85f64-5717-4562-b3fc-2c963f66afa6a"), base, current));
+    }
+}
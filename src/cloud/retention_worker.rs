@@ -0,0 +1,65 @@
+use std::time::Duration;
+
+use actix_web::web::Data;
+use tokio::time;
+use zkbob_utils_rs::tracing;
+
+use crate::{errors::CloudError, helpers::timestamp};
+
+use super::{cleanup::spawn_worker, ZkBobCloud};
+
+pub(crate) fn run_retention_worker(cloud: Data<ZkBobCloud>) {
+    let retention_sec = cloud.config.transfer_retention.retention_days * 24 * 60 * 60;
+    let report_retention_sec = cloud.config.report_retention_sec;
+    if retention_sec == 0 && report_retention_sec == 0 {
+        return;
+    }
+
+    spawn_worker("retention", cloud, move |cloud| async move {
+        let interval = Duration::from_secs(cloud.config.transfer_retention.sweep_interval_sec);
+        loop {
+            if retention_sec > 0 {
+                match sweep(&cloud, retention_sec).await {
+                    Ok(count) => tracing::info!("[retention] removed {} expired transfers", count),
+                    Err(err) => tracing::warn!("[retention] failed to remove expired transfers: {}", err),
+                }
+            }
+            if report_retention_sec > 0 {
+                match cloud.remove_expired_reports(report_retention_sec).await {
+                    Ok(count) => tracing::info!("[retention] removed {} expired reports", count),
+                    Err(err) => tracing::warn!("[retention] failed to remove expired reports: {}", err),
+                }
+            }
+            time::sleep(interval).await;
+        }
+    });
+}
+
+async fn sweep(cloud: &ZkBobCloud, retention_sec: u64) -> Result<usize, CloudError> {
+    let tasks = cloud.db.read().await.get_tasks()?;
+    let threshold = timestamp().saturating_sub(retention_sec);
+
+    let mut count = 0;
+    for task in tasks {
+        let parts = {
+            let db = cloud.db.read().await;
+            task.parts
+                .iter()
+                .map(|id| db.get_part(id))
+                .collect::<Result<Vec<_>, _>>()?
+        };
+
+        // only transfers whose every part is final and older than the retention period
+        let expired = parts
+            .iter()
+            .all(|part| part.status.is_final() && part.timestamp < threshold);
+        if !expired {
+            continue;
+        }
+
+        cloud.db.write().await.delete_task(&task, &parts)?;
+        tracing::debug!("[retention] removed transfer {}", &task.transaction_id);
+        count += 1;
+    }
+    Ok(count)
+}
@@ -0,0 +1,42 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use super::types::TransferPart;
+
+// Retry attempts that are not persisted yet. Retries change only the attempt counter,
+// so it is kept in memory and saved to the db once in a while to bound db writes.
+pub(crate) struct RetryAttempts {
+    attempts: Mutex<HashMap<String, u32>>,
+}
+
+impl RetryAttempts {
+    pub fn new() -> Self {
+        RetryAttempts {
+            attempts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn restore(&self, part: TransferPart) -> TransferPart {
+        let attempt = self
+            .attempts
+            .lock()
+            .ok()
+            .and_then(|attempts| attempts.get(&part.id).copied())
+            .unwrap_or(0);
+        TransferPart {
+            attempt: attempt.max(part.attempt),
+            ..part
+        }
+    }
+
+    pub fn record(&self, part_id: &str, attempt: u32) {
+        if let Ok(mut attempts) = self.attempts.lock() {
+            attempts.insert(part_id.to_string(), attempt);
+        }
+    }
+
+    pub fn forget(&self, part_id: &str) {
+        if let Ok(mut attempts) = self.attempts.lock() {
+            attempts.remove(part_id);
+        }
+    }
+}
@@ -1,62 +1,119 @@
-use std::{thread, str::FromStr, sync::Arc};
+use std::{str::FromStr, time::{Duration, Instant}};
 
 use actix_web::web::Data;
-use libzkbob_rs::proof::prove_tx;
+use libzkbob_rs::{client::TransactionData, proof::prove_tx, libzeropool::fawkes_crypto::backend::bellman_groth16::verifier::verify};
 use memo_parser::calldata::transact::memo::TxType;
-use tokio::task;
+use tokio::{task, time};
 use uuid::Uuid;
 use zkbob_utils_rs::{tracing, relayer::types::{Proof, TransactionRequest}};
 
-use crate::{errors::CloudError, helpers::{timestamp, queue::receive_blocking, semaphore::TaskSemaphore}};
+use sha2::{Digest, Sha256};
 
-use super::{ZkBobCloud, types::{TransferPart, TransferStatus}, cleanup::WorkerCleanup};
+use crate::{account::Account, errors::CloudError, helpers::{AsU64Amount, timestamp, queue::{receive_blocking, requeue_with_delay}}, Fr};
+
+use super::{ZkBobCloud, types::{TransferPart, TransferStatus, SubmissionReceipt}, cleanup::{spawn_worker, run_catching_panic}, callback};
+
+// a part accepted by the relayer can't be sent again, so saving it is retried
+const SUBMITTED_SAVE_ATTEMPTS: u32 = 3;
+const SUBMITTED_SAVE_INTERVAL: Duration = Duration::from_millis(200);
 
 pub(crate) fn run_send_worker(cloud: Data<ZkBobCloud>) {
-    thread::spawn( move || {
-        let _cleanup = WorkerCleanup;
-        let rt = tokio::runtime::Runtime::new().expect("failed to init tokio runtime");
-        rt.block_on(async move {
-            let max_attempts = cloud.config.send_worker.max_attempts;
-            let max_parallel = cloud.config.send_worker.max_parallel;
-            let semaphore = Arc::new(TaskSemaphore::new(max_parallel));
-            loop {
-                let (redis_id, id) = receive_blocking::<String>(cloud.send_queue.clone()).await;
-
-                let cloud = cloud.clone();
-                let semaphore = semaphore.clone();
-                tokio::spawn(async move {
-                    let _permit = match semaphore.try_acquire(&redis_id).await {
-                        Ok(permit) => permit,
-                        Err(_) => return
-                    };
-                    
-                    let process_result = process(&cloud, &id, max_attempts).await;
-                    if let Some(update) = process_result.update {
-                        if let Err(err) = cloud.db.write().await.save_part(&update) {
-                            tracing::error!("[send task: {}] failed to save processed task in db: {}", &id, err);
-                            return;
-                        }
-                    }
+    spawn_worker("send", cloud, move |cloud| async move {
+        let max_attempts = cloud.config.send_worker.max_attempts;
+        let semaphore = cloud.prover.clone();
+        let poll_interval = cloud.config.send_worker.poll_interval();
+        let reconnect_backoff = cloud.config.send_worker.reconnect_backoff();
+        loop {
+            let (redis_id, id) = receive_blocking::<String>(cloud.send_queue.clone(), poll_interval, reconnect_backoff).await;
 
-                    if process_result.check_status {
-                        if let Err(err) = cloud.status_queue.write().await.send(id.clone()).await {
-                            tracing::error!("[send task: {}] failed to send task to check status queue: {}", &id, err);
-                            return;
-                        }
-                    }
-                    
-                    if process_result.delete {
-                        let mut send_queue = cloud.send_queue.write().await;
-                        if let Err(err) = send_queue.delete(&redis_id).await {
-                            tracing::error!("[send task: {}] failed to delete task from queue: {}", &id, err);
-                        }
+            let cloud = cloud.clone();
+            let semaphore = semaphore.clone();
+            tokio::spawn(async move {
+                let task = {
+                    let (cloud, redis_id, id) = (cloud.clone(), redis_id.clone(), id.clone());
+                    async move {
+                        // keyed by the part id, so a task that is queued twice is not processed concurrently
+                        let _permit = match semaphore.try_acquire(&id).await {
+                            Ok(permit) => permit,
+                            Err(_) => return
+                        };
+
+                        let process_result = process(&cloud, &id, max_attempts).await;
+                        complete(&cloud, &redis_id, &id, process_result).await;
                     }
-                });
-            }
-        })        
+                };
+                if let Some(message) = run_catching_panic(task).await {
+                    let process_result = process_panic(&cloud, &id, max_attempts, &message).await;
+                    complete(&cloud, &redis_id, &id, process_result).await;
+                }
+            });
+        }
     });
 }
 
+async fn complete(cloud: &ZkBobCloud, redis_id: &str, id: &str, process_result: ProcessResult) {
+    if let Some(update) = process_result.update {
+        let saved = match &process_result.receipt {
+            Some(receipt) => {
+                retry_save(SUBMITTED_SAVE_ATTEMPTS, SUBMITTED_SAVE_INTERVAL, || async {
+                    cloud.db.write().await.save_submitted_part(&update, receipt)
+                })
+                .await
+            }
+            None => cloud.db.write().await.save_part(&update),
+        };
+        if let Err(err) = saved {
+            tracing::error!("[send task: {}] failed to save processed task in db: {}", id, err);
+            return;
+        }
+        callback::notify_if_final(cloud, &update).await;
+    }
+
+    if process_result.check_status {
+        if let Err(err) = cloud.status_queue.write().await.send(id.to_string()).await {
+            tracing::error!("[send task: {}] failed to send task to check status queue: {}", id, err);
+            return;
+        }
+    }
+
+    if let Some(part) = process_result.dead_letter {
+        if let Err(err) = cloud.db.write().await.save_dead_letter(&part, cloud.config.max_dead_letters) {
+            tracing::error!("[send task: {}] failed to save dead letter: {}", id, err);
+        }
+    }
+
+    if let Some(delay) = process_result.retry_attempt.and_then(|attempt| cloud.config.send_worker.retry_delay(attempt)) {
+        tracing::info!("[send task: {}] retrying in {} sec", id, delay);
+        if let Err(err) = requeue_with_delay(&cloud.send_queue, redis_id, id.to_string(), delay).await {
+            tracing::error!("[send task: {}] failed to requeue task with delay: {}", id, err);
+        }
+    }
+
+    if process_result.delete {
+        let mut send_queue = cloud.send_queue.write().await;
+        if let Err(err) = send_queue.delete(redis_id).await {
+            tracing::error!("[send task: {}] failed to delete task from queue: {}", id, err);
+        }
+    }
+}
+
+// A panic counts as a failed attempt, so a part that panics every time fails once its attempts are exhausted
+// instead of being received again forever
+async fn process_panic(cloud: &ZkBobCloud, id: &str, max_attempts: u32, message: &str) -> ProcessResult {
+    tracing::error!("[send task: {}] panic while processing task: {}", id, message);
+    match get_part(cloud, id).await {
+        Ok(part) if part.status == TransferStatus::New => {
+            let err = CloudError::InternalError(format!("panic while sending: {}", message));
+            ProcessResult::error_with_retry_attempts(part, err, max_attempts)
+        }
+        Ok(_) => ProcessResult::retry_later(),
+        Err(err) => {
+            tracing::error!("[send task: {}] cannot get task from db: {}, deleting task", id, err);
+            ProcessResult::delete_from_queue()
+        }
+    }
+}
+
 async fn process(cloud: &ZkBobCloud, id: &str, max_attempts: u32) -> ProcessResult {
     let part = match get_part(cloud, id).await {
         Ok(part) => part,
@@ -66,6 +123,10 @@ async fn process(cloud: &ZkBobCloud, id: &str, max_attempts: u32) -> ProcessResu
         }
     };
 
+    if cloud.config.send_worker.log_queue_latency {
+        tracing::info!("[send task: {}] time in queue: {} sec, attempt: {}", id, timestamp().saturating_sub(part.timestamp), part.attempt);
+    }
+
     match &part.status {
         TransferStatus::New => {},
         TransferStatus::Relaying | TransferStatus::Mining => {
@@ -81,7 +142,7 @@ async fn process(cloud: &ZkBobCloud, id: &str, max_attempts: u32) -> ProcessResu
     if let Some(depends_on) = part.depends_on.as_ref() {
         match part_status(cloud, depends_on).await {
             Ok(TransferStatus::Mining | TransferStatus::Done) => { },
-            Ok(TransferStatus::Failed(_)) => {
+            Ok(TransferStatus::Failed(_) | TransferStatus::Cancelled) => {
                 tracing::warn!("[send task: {}] previous task has failed, marking task as failed", id);
                 return ProcessResult::error_without_retry(part, CloudError::PreviousTxFailed)
             },
@@ -96,6 +157,16 @@ async fn process(cloud: &ZkBobCloud, id: &str, max_attempts: u32) -> ProcessResu
         }
     }
 
+    if let Some(expected_params_hash) = &cloud.config.expected_params_hash {
+        if !expected_params_hash.eq_ignore_ascii_case(&cloud.params_hash) {
+            tracing::error!("[send task: {}] ALERT: loaded transfer params don't match expected_params_hash, marking task as failed", id);
+            return ProcessResult::error_without_retry(
+                part,
+                CloudError::ParamsHashMismatch(expected_params_hash.clone(), cloud.params_hash.clone()),
+            );
+        }
+    }
+
     tracing::info!("[send task: {}] processing...", id);
 
     let account_id = match Uuid::from_str(&part.account_id) {
@@ -106,7 +177,8 @@ async fn process(cloud: &ZkBobCloud, id: &str, max_attempts: u32) -> ProcessResu
         }
     };
 
-    let tx = {  
+    let current_fee = cloud.current_fee().await;
+    let (part, tx) = {  
         let (account, _cleanup) = match cloud.get_account(account_id).await {
             Ok(account) => account,
             Err(err) => {
@@ -114,17 +186,60 @@ async fn process(cloud: &ZkBobCloud, id: &str, max_attempts: u32) -> ProcessResu
                 return ProcessResult::error_with_retry_attempts(part, err, max_attempts);
             }
         };
-        
-        let tx = match account.create_transfer(part.amount, part.to.clone(), part.fee, &cloud.relayer).await {
+
+        let tx = match create_tx(cloud, &account, &part, part.fee).await {
             Ok(tx) => tx,
             Err(err) => {
                 tracing::warn!("[send task: {}] failed to create transfer, retry attempt: {}", id, part.attempt);
                 return ProcessResult::error_with_retry_attempts(part, err, max_attempts);
             }
-        };  
-        tx
+        };
+
+        // the relayer rejects a fee below its current one, so a part that was queued before the fee
+        // was raised is sent with the current fee if the change of the planned tx covers the increase
+        let fee = current_fee.max(part.fee);
+        if fee > part.fee {
+            let change = tx.secret.tx.output.0.b.as_num().as_u64_amount();
+            if !covers_fee_increase(change, part.fee, fee) {
+                tracing::warn!("[send task: {}] relayer fee was raised from {} to {} and the change {} doesn't cover it, marking task as failed", id, part.fee, fee, change);
+                let fee_increased = CloudError::FeeIncreased(part.fee, fee);
+                return ProcessResult::error_without_retry(part, fee_increased);
+            }
+        }
+
+        let (part, tx) = if fee > part.fee {
+            tracing::info!("[send task: {}] relayer fee was raised from {} to {}, sending with the current fee", id, part.fee, fee);
+            let tx = match create_tx(cloud, &account, &part, fee).await {
+                Ok(tx) => tx,
+                Err(err) => {
+                    tracing::warn!("[send task: {}] failed to create transfer with the current fee, retry attempt: {}", id, part.attempt);
+                    return ProcessResult::error_with_retry_attempts(part, err, max_attempts);
+                }
+            };
+            let part = TransferPart { fee, ..part };
+            // the fee is persisted before proving, so the status shows the fee that is actually paid
+            if let Err(err) = cloud.db.write().await.save_part(&part) {
+                tracing::warn!("[send task: {}] failed to save the increased fee: {}, retry attempt: {}", id, err, part.attempt);
+                return ProcessResult::error_with_retry_attempts(part, err, max_attempts);
+            }
+            (part, tx)
+        } else {
+            (part, tx)
+        };
+        (part, tx)
+    };
+
+    // proving is cpu bound, so fewer proofs than send tasks can run at once
+    let _proving = match cloud.proving.acquire().await {
+        Ok(permit) => permit,
+        Err(err) => {
+            tracing::warn!("[send task: {}] failed to acquire proving permit: {}, retry attempt: {}", id, err, part.attempt);
+            return ProcessResult::error_with_retry_attempts(part, CloudError::InternalError("proving permit error".to_string()), max_attempts);
+        }
     };
     
+    let nullifier = tx.public.nullifier.to_string();
+    let started = Instant::now();
     let prove_result = {
         let params = cloud.params.clone();
         let proving_span = tracing::info_span!("proving", task_id = &part.id);
@@ -141,32 +256,95 @@ async fn process(cloud: &ZkBobCloud, id: &str, max_attempts: u32) -> ProcessResu
     };
 
     let (inputs, proof) = match prove_result {
-        Ok((inputs, proof)) => (inputs, proof),
+        Ok((inputs, proof)) => {
+            cloud.metrics.parts_proved.inc();
+            cloud.metrics.proving_duration.observe(started.elapsed().as_secs_f64());
+            (inputs, proof)
+        }
         Err(err) => {
             tracing::warn!("[send task: {}] failed to prove transfer: {}, retry attempt: {}", id, err, part.attempt);
             return ProcessResult::error_with_retry_attempts(part, CloudError::InternalError("prove error".to_string()), max_attempts);
         }
     };
 
+    let (inputs, proof) = if cloud.config.verify_proofs_before_send {
+        let vk = cloud.vk.clone();
+        let started = Instant::now();
+        let verify_result = task::spawn_blocking(move || {
+            let verified = verify(&vk, &proof, &inputs);
+            (verified, inputs, proof)
+        }).await;
+
+        match verify_result {
+            Ok((true, inputs, proof)) => {
+                tracing::info!("[send task: {}] proof verified in {} ms", id, started.elapsed().as_millis());
+                (inputs, proof)
+            }
+            Ok((false, _, _)) => {
+                // it means that the params file doesn't match the circuit, it is a deployment problem
+                tracing::error!("[send task: {}] ALERT: generated proof is invalid, check transfer params, marking task as failed", id);
+                return ProcessResult::error_without_retry(part, CloudError::ProofVerificationFailed);
+            }
+            Err(err) => {
+                tracing::warn!("[send task: {}] failed to verify proof: {}, retry attempt: {}", id, err, part.attempt);
+                return ProcessResult::error_with_retry_attempts(part, CloudError::InternalError("verify error".to_string()), max_attempts);
+            }
+        }
+    } else {
+        (inputs, proof)
+    };
+
+    // the part could be cancelled while it was proved
+    if let Ok(TransferStatus::Cancelled) = part_status(cloud, &part.id).await {
+        tracing::info!("[send task: {}] task was cancelled, deleting task", id);
+        return ProcessResult::delete_from_queue();
+    }
+
     let proof = Proof { inputs, proof };
+    let tx_type = match part.withdrawal {
+        true => TxType::Withdrawal,
+        false => TxType::Transfer,
+    };
+    let request_uuid = Uuid::new_v4().to_string();
+    let memo_hash = hex::encode(Sha256::digest(&tx.memo));
     let request = vec![TransactionRequest {
-        uuid: Some(Uuid::new_v4().to_string()),
+        uuid: Some(request_uuid.clone()),
         proof,
         memo: hex::encode(tx.memo),
-        tx_type: format!("{:0>4}", TxType::Transfer.to_u32()),
+        tx_type: format!("{:0>4}", tx_type.to_u32()),
         deposit_signature: None,
     }];
 
-    let response = match cloud.relayer.send_transactions(request).await {
+    // the part can't land before the current optimistic index of the relayer
+    let pool_index = match cloud.relayer.get_info(true).await {
+        Ok(info) => Some(info.optimistic_delta_index),
+        Err(err) => {
+            tracing::warn!("[send task: {}] failed to fetch expected pool index: {}", id, err);
+            None
+        }
+    };
+
+    let (response, relayer_url) = match cloud.relayer.send_transactions(request).await {
         Ok(response) => response,
         Err(err) => {
+            cloud.metrics.relayer_send_failures.inc();
             tracing::warn!("[send task: {}] failed send transfer to relayer, retry attempt: {}", id, part.attempt);
             return ProcessResult::error_with_retry_attempts(part, err, max_attempts);
         }
     };
 
+    // the receipt is saved along with the part marked as Relaying
+    let receipt = SubmissionReceipt {
+        part_id: part.id.clone(),
+        request_uuid,
+        memo_hash,
+        job_id: response.job_id.clone(),
+        timestamp: timestamp(),
+    };
+
     tracing::info!("[send task: {}] processed successfully, job_id: {}", id, &response.job_id);
-    ProcessResult::success(part, response.job_id)    
+    let part = TransferPart { nullifier: Some(nullifier), ..part };
+    ProcessResult::success(part, receipt, relayer_url, pool_index, cloud.params_hash.clone())
 }
 
 #[derive(Debug)]
@@ -174,13 +352,22 @@ struct ProcessResult {
     delete: bool,
     check_status: bool,
     update: Option<TransferPart>,
+    // part that exhausted its attempts on transient errors, it is kept for inspection
+    dead_letter: Option<TransferPart>,
+    // the attempt of a part that is retried after an error, it defines the backoff delay
+    retry_attempt: Option<u32>,
+    // receipt of a part accepted by the relayer
+    receipt: Option<SubmissionReceipt>,
 }
 
 impl ProcessResult {
-    fn success(part: TransferPart, job_id: String) -> ProcessResult {
+    fn success(part: TransferPart, receipt: SubmissionReceipt, relayer_url: String, pool_index: Option<u64>, params_hash: String) -> ProcessResult {
         let part = TransferPart {
             status: TransferStatus::Relaying,
-            job_id: Some(job_id),
+            job_id: Some(receipt.job_id.clone()),
+            relayer_url: Some(relayer_url),
+            pool_index,
+            params_hash: Some(params_hash),
             attempt: 0,
             timestamp: timestamp(),
             ..part
@@ -190,6 +377,9 @@ impl ProcessResult {
             delete: true,
             check_status: true,
             update: Some(part),
+            dead_letter: None,
+            retry_attempt: None,
+            receipt: Some(receipt),
         }
     }
 
@@ -198,6 +388,9 @@ impl ProcessResult {
             delete: false,
             check_status: false,
             update: None,
+            dead_letter: None,
+            retry_attempt: None,
+            receipt: None,
         }
     }
 
@@ -206,6 +399,9 @@ impl ProcessResult {
             delete: true,
             check_status: false,
             update: None,
+            dead_letter: None,
+            retry_attempt: None,
+            receipt: None,
         }
     }
 
@@ -214,12 +410,19 @@ impl ProcessResult {
             delete: true,
             check_status: true,
             update: None,
+            dead_letter: None,
+            retry_attempt: None,
+            receipt: None,
         }
     }
 
     fn error_with_retry_attempts(part: TransferPart, err: CloudError, max_attempts: u32) -> ProcessResult {
         if part.attempt >= max_attempts {
-            return ProcessResult::error_without_retry(part, err);
+            let result = ProcessResult::error_without_retry(part, err);
+            return ProcessResult {
+                dead_letter: result.update.clone(),
+                ..result
+            };
         }
 
         let part = TransferPart {
@@ -229,7 +432,10 @@ impl ProcessResult {
         ProcessResult {
             delete: false,
             check_status: false,
+            retry_attempt: Some(part.attempt),
             update: Some(part),
+            dead_letter: None,
+            receipt: None,
         }
     }
 
@@ -243,11 +449,44 @@ impl ProcessResult {
             delete: true,
             check_status: false,
             update: Some(part),
+            dead_letter: None,
+            retry_attempt: None,
+            receipt: None,
         }
     }
 }
 
 
+// The change is what the planned tx returns to the account, the increase of the fee is taken from it
+fn covers_fee_increase(change: u64, planned_fee: u64, fee: u64) -> bool {
+    change >= fee.saturating_sub(planned_fee)
+}
+
+async fn create_tx(cloud: &ZkBobCloud, account: &Account, part: &TransferPart, fee: u64) -> Result<TransactionData<Fr>, CloudError> {
+    match (part.withdrawal, part.to.as_deref()) {
+        (true, Some(to)) => account.create_withdrawal(part.amount, part.native_amount, to, fee, &cloud.relayer).await,
+        _ => account.create_transfer(part.tx_outputs(), fee, &cloud.relayer).await,
+    }
+}
+
+async fn retry_save<F, Fut>(attempts: u32, interval: Duration, save: F) -> Result<(), CloudError>
+where
+    F: Fn() -> Fut,
+    Fut: std::future::Future<Output = Result<(), CloudError>>,
+{
+    let mut attempt = 1;
+    loop {
+        match save().await {
+            Err(err) if attempt < attempts => {
+                tracing::warn!("failed to save submitted part, attempt {}: {}", attempt, err);
+                time::sleep(interval * attempt).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
 pub(crate) async fn get_part(cloud: &ZkBobCloud, part_id: &str) -> Result<TransferPart, CloudError> {
     let db = cloud.db.read().await;
     let part = db.get_part(part_id)?;
@@ -257,4 +496,92 @@ pub(crate) async fn get_part(cloud: &ZkBobCloud, part_id: &str) -> Result<Transf
 pub(crate) async fn part_status(cloud: &ZkBobCloud, part_id: &str) -> Result<TransferStatus, CloudError> {
     let part = get_part(cloud, part_id).await?;
     Ok(part.status)
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use std::{sync::atomic::{AtomicU32, Ordering}, time::Duration};
+
+    use super::{covers_fee_increase, retry_save, ProcessResult};
+    use crate::{cloud::{tests::part, types::{SubmissionReceipt, TransferStatus}}, errors::CloudError};
+
+    fn transient() -> CloudError {
+        CloudError::InternalError("prove error".to_string())
+    }
+
+    #[test]
+    fn retried_part_is_not_dead_lettered() {
+        let result = ProcessResult::error_with_retry_attempts(part(0, TransferStatus::New, 1, None), transient(), 3);
+        assert!(result.dead_letter.is_none());
+        assert_eq!(result.retry_attempt, Some(1));
+    }
+
+    #[test]
+    fn part_with_exhausted_attempts_is_dead_lettered() {
+        let mut exhausted = part(0, TransferStatus::New, 1, None);
+        exhausted.attempt = 3;
+        let result = ProcessResult::error_with_retry_attempts(exhausted, transient(), 3);
+        let dead_letter = result.dead_letter.expect("dead letter");
+        assert!(matches!(dead_letter.status, TransferStatus::Failed(CloudError::InternalError(_))));
+        assert!(result.delete);
+    }
+
+    #[test]
+    fn permanent_failure_is_not_dead_lettered() {
+        let result = ProcessResult::error_without_retry(part(0, TransferStatus::New, 1, None), CloudError::PreviousTxFailed);
+        assert!(result.dead_letter.is_none());
+        assert!(matches!(result.update.map(|part| part.status), Some(TransferStatus::Failed(CloudError::PreviousTxFailed))));
+    }
+
+    #[test]
+    fn fee_increase_is_taken_from_change() {
+        assert!(covers_fee_increase(50, 100, 150));
+        assert!(covers_fee_increase(51, 100, 150));
+        assert!(!covers_fee_increase(49, 100, 150));
+        assert!(!covers_fee_increase(0, 100, 101));
+        // the fee is not lowered, so an unchanged fee is always covered
+        assert!(covers_fee_increase(0, 100, 100));
+        assert!(covers_fee_increase(0, u64::MAX, u64::MAX));
+    }
+
+    #[test]
+    fn sent_part_carries_its_receipt() {
+        let receipt = SubmissionReceipt {
+            part_id: "transfer.0".to_string(),
+            request_uuid: "uuid".to_string(),
+            memo_hash: "hash".to_string(),
+            job_id: "42".to_string(),
+            timestamp: 0,
+        };
+        let result = ProcessResult::success(part(0, TransferStatus::New, 1, None), receipt, "url".to_string(), None, "params".to_string());
+        let update = result.update.unwrap();
+        assert_eq!(update.status, TransferStatus::Relaying);
+        assert_eq!(update.job_id.as_deref(), Some("42"));
+        assert_eq!(result.receipt.map(|receipt| receipt.part_id), Some(update.id));
+    }
+
+    #[actix_web::test]
+    async fn failed_save_is_retried() {
+        let calls = AtomicU32::new(0);
+        let result = retry_save(3, Duration::ZERO, || async {
+            match calls.fetch_add(1, Ordering::SeqCst) {
+                0 | 1 => Err(CloudError::DataBaseWriteError("busy".to_string())),
+                _ => Ok(()),
+            }
+        })
+        .await;
+        assert!(result.is_ok());
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[actix_web::test]
+    async fn save_error_is_returned_after_attempts() {
+        let calls = AtomicU32::new(0);
+        let result = retry_save(3, Duration::ZERO, || async {
+            calls.fetch_add(1, Ordering::SeqCst);
+            Err(CloudError::DataBaseWriteError("busy".to_string()))
+        })
+        .await;
+        assert_eq!(result, Err(CloudError::DataBaseWriteError("busy".to_string())));
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+}
@@ -1,59 +1,106 @@
-use std::{thread, sync::Arc};
+use std::{future::Future, sync::Arc};
 
 use actix_web::web::Data;
 use zkbob_utils_rs::{tracing, relayer::types::JobResponse};
 
-use crate::{errors::CloudError, cloud::{send_worker::get_part, types::TransferStatus}, helpers::{timestamp, queue::receive_blocking, semaphore::TaskSemaphore}};
+use crate::{errors::CloudError, cloud::{send_worker::get_part, types::TransferStatus}, helpers::{timestamp, queue::{receive_blocking, requeue_with_delay}, semaphore::TaskSemaphore}, web3::cached::ReceiptStatus};
 
-use super::{ZkBobCloud, types::TransferPart, cleanup::WorkerCleanup};
+use super::{ZkBobCloud, types::TransferPart, cleanup::{spawn_worker, run_catching_panic}, callback};
+
+// the attempt counter of a retried part is saved to the db only every N attempts
+const PERSIST_ATTEMPT_EVERY: u32 = 10;
+
+// page size of mined transactions searched from the expected index to find the actual one
+const POOL_INDEX_SEARCH_LIMIT: u64 = 100;
 
 pub(crate) fn run_status_worker(cloud: Data<ZkBobCloud>) {
-    thread::spawn( move || {
-        let _cleanup = WorkerCleanup;
-        let rt = tokio::runtime::Runtime::new().expect("failed to init tokio runtime");
-        rt.block_on(async move {
-            let max_attempts = cloud.config.status_worker.max_attempts;
-            let max_parallel = cloud.config.status_worker.max_parallel;
-            let semaphore = Arc::new(TaskSemaphore::new(max_parallel));
-            loop {
-                let (redis_id, id) = receive_blocking::<String>(cloud.status_queue.clone()).await;
-
-                let cloud = cloud.clone();
-                let semaphore = semaphore.clone();
-                tokio::spawn(async move {
-                    let _permit = match semaphore.try_acquire(&redis_id).await {
-                        Ok(permit) => permit,
-                        Err(_) => return
-                    };
+    spawn_worker("status", cloud, move |cloud| async move {
+        let max_attempts = cloud.config.status_worker.max_attempts;
+        let max_parallel = cloud.config.status_worker.max_parallel;
+        let semaphore = Arc::new(TaskSemaphore::new(max_parallel));
+        let poll_interval = cloud.config.status_worker.poll_interval();
+        let reconnect_backoff = cloud.config.status_worker.reconnect_backoff();
+        loop {
+            let (redis_id, id) = receive_blocking::<String>(cloud.status_queue.clone(), poll_interval, reconnect_backoff).await;
 
-                    let process_result = process(&cloud, &id, max_attempts).await;
-                    if postprocessing(&cloud, &process_result).await.is_err() {
-                        return;
-                    }
-                    
-                    if process_result.delete {
-                        let mut status_queue = cloud.status_queue.write().await;
-                        if let Err(err) = status_queue.delete(&redis_id).await {
-                            tracing::error!("[status task: {}] failed to delete task from queue: {}", &id, err);
-                        }
+            let cloud = cloud.clone();
+            let semaphore = semaphore.clone();
+            tokio::spawn(async move {
+                let task = {
+                    let (cloud, redis_id, id) = (cloud.clone(), redis_id.clone(), id.clone());
+                    async move {
+                        let _permit = match semaphore.try_acquire(&redis_id).await {
+                            Ok(permit) => permit,
+                            Err(_) => return
+                        };
+
+                        let process_result = process(&cloud, &id, max_attempts).await;
+                        complete(&cloud, &redis_id, &id, process_result).await;
                     }
-                });
-            }
-        });
+                };
+                if let Some(message) = run_catching_panic(task).await {
+                    let process_result = process_panic(&cloud, &id, max_attempts, &message).await;
+                    complete(&cloud, &redis_id, &id, process_result).await;
+                }
+            });
+        }
     });
 }
 
+async fn complete(cloud: &ZkBobCloud, redis_id: &str, id: &str, process_result: ProcessResult) {
+    if postprocessing(cloud, &process_result).await.is_err() {
+        return;
+    }
+
+    if let Some(delay) = process_result.retry_attempt.and_then(|attempt| cloud.config.status_worker.retry_delay(attempt)) {
+        tracing::info!("[status task: {}] retrying in {} sec", id, delay);
+        if let Err(err) = requeue_with_delay(&cloud.status_queue, redis_id, id.to_string(), delay).await {
+            tracing::error!("[status task: {}] failed to requeue task with delay: {}", id, err);
+        }
+    }
+
+    if process_result.delete {
+        cloud.status_attempts.forget(id);
+        let mut status_queue = cloud.status_queue.write().await;
+        if let Err(err) = status_queue.delete(redis_id).await {
+            tracing::error!("[status task: {}] failed to delete task from queue: {}", id, err);
+        }
+    }
+}
+
+// A panic counts as a failed attempt, so a part that panics every time fails once its attempts are exhausted
+// instead of being received again forever
+async fn process_panic(cloud: &ZkBobCloud, id: &str, max_attempts: u32, message: &str) -> ProcessResult {
+    tracing::error!("[status task: {}] panic while processing task: {}", id, message);
+    match get_part(cloud, id).await {
+        Ok(part) if matches!(part.status, TransferStatus::Relaying | TransferStatus::Mining) => {
+            let part = cloud.status_attempts.restore(part);
+            let err = CloudError::InternalError(format!("panic while checking status: {}", message));
+            ProcessResult::error_with_retry_attempts(part, err, max_attempts)
+        }
+        Ok(_) => ProcessResult::delete_from_queue(),
+        Err(err) => {
+            tracing::error!("[status task: {}] cannot get task from db: {}, deleting task", id, err);
+            ProcessResult::delete_from_queue()
+        }
+    }
+}
+
 async fn process(cloud: &ZkBobCloud, id: &str, max_attempts: u32) -> ProcessResult {
     tracing::info!("[status task: {}] processing...", id);
 
     let part = match get_part(cloud, id).await {
-        Ok(part) => part,
+        Ok(part) => cloud.status_attempts.restore(part),
         Err(err) => {
             tracing::error!("[status task: {}] cannot get task from db: {}, deleting task", id, err);
             return ProcessResult::delete_from_queue();
         }
     };
 
+    if cloud.config.status_worker.log_queue_latency {
+        tracing::info!("[status task: {}] time in queue: {} sec, attempt: {}", id, timestamp().saturating_sub(part.timestamp), part.attempt);
+    }
+
     match &part.status {
         TransferStatus::Relaying | TransferStatus::Mining => {},
         status => {
@@ -70,7 +117,9 @@ async fn process(cloud: &ZkBobCloud, id: &str, max_attempts: u32) -> ProcessResu
         }
     };
 
-    let response: Result<JobResponse, CloudError> = cloud.relayer.job(job_id).await;
+    let response: Result<JobResponse, CloudError> = cloud.relayer.job(job_id, part.relayer_url.as_deref()).await;
+    let label = poll_status_label(response.as_ref().map(|response| response.state.as_str()).ok());
+    cloud.metrics.status_polls.with_label_values(&[label]).inc();
     match response {
         Ok(response) => {
             let status = TransferStatus::from_relayer_response(
@@ -87,8 +136,30 @@ async fn process(cloud: &ZkBobCloud, id: &str, max_attempts: u32) -> ProcessResu
                             return ProcessResult::error_with_retry_attempts(part, CloudError::RelayerSendError, max_attempts);
                         }
                     };
+                    let required_confirmations = cloud.config.status_worker.required_confirmations;
+                    if required_confirmations > 0 {
+                        match confirmations(cloud, &tx_hash).await {
+                            Ok(None) => {
+                                tracing::warn!("[status task: {}] tx {} is reverted on-chain", id, &tx_hash);
+                                return ProcessResult::rejected(part, CloudError::TransactionReverted(tx_hash.clone()), Some(tx_hash));
+                            }
+                            Ok(Some(confirmations)) if confirmations >= required_confirmations => {}
+                            Ok(Some(confirmations)) => {
+                                tracing::info!("[status task: {}] tx {} has {} of {} confirmations, postpone task", id, &tx_hash, confirmations, required_confirmations);
+                                if part.status == TransferStatus::Mining && part.tx_hash.as_deref() == Some(tx_hash.as_str()) {
+                                    return ProcessResult::retry_later();
+                                }
+                                return ProcessResult::update_status(part, TransferStatus::Mining, tx_hash);
+                            }
+                            Err(err) => {
+                                tracing::warn!("[status task: {}] failed to fetch confirmations of tx {}, retry attempt: {}", id, &tx_hash, part.attempt);
+                                return ProcessResult::error_with_retry_attempts(part, err, max_attempts);
+                            }
+                        }
+                    }
                     tracing::info!("[status task: {}] processed successfully, tx_hash: {}", id, &tx_hash);
-                    ProcessResult::success(part, tx_hash)
+                    let pool_index = resolve_pool_index(cloud, &part, &tx_hash).await;
+                    ProcessResult::success(part, tx_hash, pool_index)
                 }
                 TransferStatus::Mining => {
                     let tx_hash = match response.tx_hash {
@@ -98,6 +169,10 @@ async fn process(cloud: &ZkBobCloud, id: &str, max_attempts: u32) -> ProcessResu
                             return ProcessResult::error_with_retry_attempts(part, CloudError::RelayerSendError, max_attempts);
                         }
                     };
+                    if is_expired(cloud, &part) && can_expire(cloud, id, Some(&tx_hash)).await {
+                        tracing::warn!("[status task: {}] task is not mined for too long, marking as expired", id);
+                        return ProcessResult::rejected(part, CloudError::TransactionExpired, Some(tx_hash));
+                    }
                     tracing::info!("[status task: {}] sent to contract, tx_hash: {}", id, &tx_hash);
                     ProcessResult::update_status(part, TransferStatus::Mining, tx_hash)
                 }
@@ -106,11 +181,17 @@ async fn process(cloud: &ZkBobCloud, id: &str, max_attempts: u32) -> ProcessResu
                     ProcessResult::rejected(part, err, response.tx_hash)
                 },
                 _ => {
+                    if is_expired(cloud, &part) && can_expire(cloud, id, part.tx_hash.as_deref()).await {
+                        tracing::warn!("[status task: {}] task is not finished for too long, marking as expired", id);
+                        let tx_hash = part.tx_hash.clone();
+                        return ProcessResult::rejected(part, CloudError::TransactionExpired, tx_hash);
+                    }
                     tracing::info!("[status task: {}] task is not finished yet, postpone task", id);
                     ProcessResult::retry_later()
                 }
             }
         },
+        // a relayer outage doesn't expire parts, they are retried until the relayer answers again
         Err(err) => {
             tracing::warn!("[status task: {}] failed to fetch status from relayer, retry attempt: {}", id, part.attempt);
             ProcessResult::error_with_retry_attempts(part, err, max_attempts)
@@ -118,6 +199,100 @@ async fn process(cloud: &ZkBobCloud, id: &str, max_attempts: u32) -> ProcessResu
     }
 }
 
+// The timestamp of a part is updated when it is sent to the relayer, it is kept while the part is Mining
+fn is_expired(cloud: &ZkBobCloud, part: &TransferPart) -> bool {
+    let max_age_sec = cloud.config.status_worker.max_age_sec;
+    max_age_sec > 0 && timestamp().saturating_sub(part.timestamp) > max_age_sec
+}
+
+// A part can expire only while its transaction can't land anymore: a mined transaction is reported
+// by the relayer soon and a transaction that the node still knows may be mined later.
+// The part is kept when the node can't be asked
+async fn can_expire(cloud: &ZkBobCloud, id: &str, tx_hash: Option<&str>) -> bool {
+    let tx_hash = match tx_hash {
+        Some(tx_hash) => tx_hash,
+        None => return true,
+    };
+    match cloud.web3.get_receipt_status(tx_hash).await {
+        Ok(ReceiptStatus::Pending) => {}
+        Ok(_) => {
+            tracing::info!("[status task: {}] expired tx {} is mined, waiting for the relayer", id, tx_hash);
+            return false;
+        }
+        Err(err) => {
+            tracing::warn!("[status task: {}] failed to fetch receipt of expired tx {}: {}", id, tx_hash, err);
+            return false;
+        }
+    }
+    match cloud.web3.is_known(tx_hash).await {
+        Ok(known) => !known,
+        Err(err) => {
+            tracing::warn!("[status task: {}] failed to fetch expired tx {}: {}", id, tx_hash, err);
+            false
+        }
+    }
+}
+
+// A transaction that is not mined yet or was reorged out has no confirmations, None means that it is reverted
+async fn confirmations(cloud: &ZkBobCloud, tx_hash: &str) -> Result<Option<u64>, CloudError> {
+    let block_number = match cloud.web3.get_receipt_status(tx_hash).await? {
+        ReceiptStatus::Pending => return Ok(Some(0)),
+        ReceiptStatus::Mined(block_number) => block_number,
+        ReceiptStatus::Reverted(_) => return Ok(None),
+    };
+    let head = cloud.web3.block_number().await?;
+    Ok(Some((head + 1).saturating_sub(block_number)))
+}
+
+// The relayer job doesn't report the pool index, so it is read from the receipt of the mined transaction,
+// if it's not available, mined transactions are paged from the index expected at sending
+async fn resolve_pool_index(cloud: &ZkBobCloud, part: &TransferPart, tx_hash: &str) -> Option<u64> {
+    match cloud.web3.pool_index(tx_hash).await {
+        Ok(Some(pool_index)) => return Some(pool_index),
+        Ok(None) => tracing::warn!("[status task: {}] receipt of {} has no pool message", &part.id, tx_hash),
+        Err(err) => tracing::warn!("[status task: {}] failed to read pool index from receipt: {}", &part.id, err),
+    }
+
+    let expected_index = part.pool_index?;
+    let fetch = |offset| async move {
+        cloud
+            .relayer
+            .transactions(offset, POOL_INDEX_SEARCH_LIMIT, false)
+            .await
+            .map(|txs| txs.into_iter().map(|tx| (tx.index, tx.tx_hash)).collect())
+    };
+    match find_mined_index(expected_index, tx_hash, fetch).await {
+        Ok(Some(pool_index)) => Some(pool_index),
+        Ok(None) => {
+            tracing::warn!("[status task: {}] mined transaction is not found after index {}", &part.id, expected_index);
+            None
+        }
+        Err(err) => {
+            tracing::warn!("[status task: {}] failed to fetch transactions to find pool index: {}", &part.id, err);
+            None
+        }
+    }
+}
+
+// Pages until the transaction is found or a short page shows that the end of mined transactions is reached
+async fn find_mined_index<F, Fut>(from_index: u64, tx_hash: &str, fetch: F) -> Result<Option<u64>, CloudError>
+where
+    F: Fn(u64) -> Fut,
+    Fut: Future<Output = Result<Vec<(u64, String)>, CloudError>>,
+{
+    let mut offset = from_index;
+    loop {
+        let txs = fetch(offset).await?;
+        if let Some((index, _)) = txs.iter().find(|(_, hash)| hash.eq_ignore_ascii_case(tx_hash)) {
+            return Ok(Some(*index));
+        }
+        if (txs.len() as u64) < POOL_INDEX_SEARCH_LIMIT {
+            return Ok(None);
+        }
+        offset += 128 * POOL_INDEX_SEARCH_LIMIT;
+    }
+}
+
 async fn postprocessing(cloud: &ZkBobCloud, process_result: &ProcessResult) -> Result<(), ()> {
     let part = match &process_result.part {
         Some(part) => part,
@@ -126,11 +301,16 @@ async fn postprocessing(cloud: &ZkBobCloud, process_result: &ProcessResult) -> R
         }
     };
 
+    if !process_result.delete {
+        cloud.status_attempts.record(&part.id, part.attempt);
+    }
+
     if process_result.update {
         if let Err(err) = cloud.db.write().await.save_part(part) {
             tracing::error!("[status task: {}] failed to save processed task in db: {}", &part.id, err);
             return Err(());
         }
+        callback::notify_if_final(cloud, part).await;
     }
 
     // it is not critical
@@ -151,13 +331,16 @@ struct ProcessResult {
     delete: bool,
     update: bool,
     save_transaction_id: bool,
+    // the attempt of a part that is retried after an error, it defines the backoff delay
+    retry_attempt: Option<u32>,
 }
 
 impl ProcessResult {
-    fn success(part: TransferPart, tx_hash: String) -> ProcessResult {
+    fn success(part: TransferPart, tx_hash: String, pool_index: Option<u64>) -> ProcessResult {
         let part = TransferPart {
             status: TransferStatus::Done,
             tx_hash: Some(tx_hash),
+            pool_index: pool_index.or(part.pool_index),
             timestamp: timestamp(),
             ..part
         };
@@ -166,6 +349,7 @@ impl ProcessResult {
             delete: true,
             update: true,
             save_transaction_id: true,
+            retry_attempt: None,
         }
     }
 
@@ -181,6 +365,7 @@ impl ProcessResult {
             delete: true,
             update: true,
             save_transaction_id: false,
+            retry_attempt: None,
         }
     }
 
@@ -195,6 +380,7 @@ impl ProcessResult {
             delete: false,
             update: true,
             save_transaction_id: false,
+            retry_attempt: None,
         }
     }
 
@@ -204,6 +390,7 @@ impl ProcessResult {
             delete: false,
             update: false,
             save_transaction_id: false,
+            retry_attempt: None,
         }
     }
 
@@ -213,6 +400,7 @@ impl ProcessResult {
             delete: true,
             update: false,
             save_transaction_id: false,
+            retry_attempt: None,
         }
     }
 
@@ -225,10 +413,12 @@ impl ProcessResult {
             attempt: part.attempt + 1,
             ..part
         };
+        let update = part.attempt % PERSIST_ATTEMPT_EVERY == 0;
         ProcessResult {
+            retry_attempt: Some(part.attempt),
             part: Some(part),
             delete: false,
-            update: true,
+            update,
             save_transaction_id: false,
         }
     }
@@ -244,6 +434,130 @@ impl ProcessResult {
             delete: true,
             update: true,
             save_transaction_id: false,
+            retry_attempt: None,
         }
     }
-}
\ No newline at end of file
+}
+// The state comes from the relayer, so unknown states share one label to keep the metric bounded
+fn poll_status_label(state: Option<&str>) -> &'static str {
+    match state {
+        Some("waiting") => "waiting",
+        Some("sent") => "sent",
+        Some("completed") => "completed",
+        Some("reverted") => "reverted",
+        Some("failed") => "failed",
+        Some(_) => "unknown",
+        None => "error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use crate::{errors::CloudError, cloud::{retry_attempts::RetryAttempts, tests::part, types::TransferStatus}};
+
+    use super::{find_mined_index, poll_status_label, ProcessResult, PERSIST_ATTEMPT_EVERY, POOL_INDEX_SEARCH_LIMIT};
+
+    fn mined(count: u64) -> Vec<(u64, String)> {
+        (0..count).map(|i| (i * 128, format!("0x{:x}", i))).collect()
+    }
+
+    async fn find(txs: &[(u64, String)], from_index: u64, tx_hash: &str, pages: &Mutex<u32>) -> Result<Option<u64>, CloudError> {
+        find_mined_index(from_index, tx_hash, |offset| async move {
+            *pages.lock().unwrap() += 1;
+            Ok(txs
+                .iter()
+                .filter(|(index, _)| *index >= offset)
+                .take(POOL_INDEX_SEARCH_LIMIT as usize)
+                .cloned()
+                .collect())
+        })
+        .await
+    }
+
+    #[actix_web::test]
+    async fn mined_index_is_found_on_later_page() {
+        let txs = mined(POOL_INDEX_SEARCH_LIMIT * 3);
+        let pages = Mutex::new(0);
+        let hash = format!("0x{:X}", POOL_INDEX_SEARCH_LIMIT * 2 + 5);
+        let found = find(&txs, 0, &hash, &pages).await.unwrap();
+        assert_eq!(found, Some((POOL_INDEX_SEARCH_LIMIT * 2 + 5) * 128));
+        assert_eq!(*pages.lock().unwrap(), 3);
+    }
+
+    #[actix_web::test]
+    async fn search_stops_at_last_mined_transaction() {
+        let txs = mined(POOL_INDEX_SEARCH_LIMIT + 10);
+        let pages = Mutex::new(0);
+        assert_eq!(find(&txs, 128, "0xdead", &pages).await.unwrap(), None);
+        assert_eq!(*pages.lock().unwrap(), 2);
+    }
+
+    #[actix_web::test]
+    async fn search_starts_at_expected_index() {
+        let txs = mined(10);
+        let pages = Mutex::new(0);
+        assert_eq!(find(&txs, 5 * 128, "0x2", &pages).await.unwrap(), None);
+        assert_eq!(find(&txs, 2 * 128, "0x2", &pages).await.unwrap(), Some(256));
+    }
+
+    #[test]
+    fn poll_status_labels_are_bounded() {
+        assert_eq!(poll_status_label(Some("sent")), "sent");
+        assert_eq!(poll_status_label(Some("completed")), "completed");
+        assert_eq!(poll_status_label(Some("4f1c2e0a-job-specific")), "unknown");
+        assert_eq!(poll_status_label(None), "error");
+    }
+
+    #[test]
+    fn retries_write_the_part_once_per_persisted_attempt() {
+        let attempts = RetryAttempts::new();
+        let mut stored = part(1, TransferStatus::Mining, 100, None);
+        stored.job_id = Some("job".to_string());
+        let mut writes = 0;
+        for _ in 0..20 {
+            let part = attempts.restore(stored.clone());
+            let result = ProcessResult::error_with_retry_attempts(part, CloudError::RelayerSendError, 100);
+            let retried = result.part.unwrap();
+            attempts.record(&retried.id, retried.attempt);
+            if result.update {
+                writes += 1;
+                stored = retried;
+            }
+        }
+
+        assert_eq!(writes, 20 / PERSIST_ATTEMPT_EVERY);
+        assert_eq!(attempts.restore(stored.clone()).attempt, 20);
+        // only the attempt counter is retried, the stored part is the same
+        assert_eq!(stored.attempt, 20);
+        assert_eq!(stored.status, TransferStatus::Mining);
+        assert_eq!(stored.job_id.as_deref(), Some("job"));
+        assert_eq!(stored.tx_hash.as_deref(), Some("0x1"));
+    }
+
+    #[test]
+    fn restart_loses_only_attempts_that_are_not_persisted() {
+        let attempts = RetryAttempts::new();
+        let mut stored = part(1, TransferStatus::Relaying, 100, None);
+        for _ in 0..15 {
+            let result = ProcessResult::error_with_retry_attempts(attempts.restore(stored.clone()), CloudError::RelayerSendError, 100);
+            let retried = result.part.unwrap();
+            attempts.record(&retried.id, retried.attempt);
+            if result.update {
+                stored = retried;
+            }
+        }
+        assert_eq!(attempts.restore(stored.clone()).attempt, 15);
+        assert_eq!(RetryAttempts::new().restore(stored).attempt, 10);
+    }
+
+    #[test]
+    fn last_attempt_fails_the_part_and_is_written() {
+        let mut stored = part(1, TransferStatus::Mining, 100, None);
+        stored.attempt = 3;
+        let result = ProcessResult::error_with_retry_attempts(stored, CloudError::RelayerSendError, 3);
+        assert!(result.update && result.delete);
+        assert!(matches!(result.part.unwrap().status, TransferStatus::Failed(CloudError::RelayerSendError)));
+    }
+}
@@ -0,0 +1,150 @@
+use std::{collections::HashMap, future::Future, hash::Hash, sync::Mutex};
+
+use tokio::sync::watch;
+
+use crate::errors::CloudError;
+
+type SyncResultSender = watch::Sender<Option<Result<(), CloudError>>>;
+
+// Single-flight sync: while an account is syncing, concurrent callers
+// wait for the running sync instead of querying the relayer again.
+// Only callers with the same key share a sync, e.g. a limited batch doesn't satisfy a full sync
+pub(crate) struct SyncCoalescer<K> {
+    inflight: Mutex<HashMap<K, SyncResultSender>>,
+}
+
+struct InflightGuard<'a, K: Hash + Eq + Clone> {
+    coalescer: &'a SyncCoalescer<K>,
+    id: K,
+    active: bool,
+}
+
+impl<K: Hash + Eq + Clone> Drop for InflightGuard<'_, K> {
+    fn drop(&mut self) {
+        // the leading request was cancelled, waiting callers will sync on their own
+        if self.active {
+            self.coalescer.remove(&self.id);
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone> SyncCoalescer<K> {
+    pub fn new() -> Self {
+        SyncCoalescer {
+            inflight: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // The sync future is polled only by the leading caller
+    pub async fn sync<F>(&self, id: K, sync: F) -> Result<(), CloudError>
+    where
+        F: Future<Output = Result<(), CloudError>>,
+    {
+        let receiver = {
+            let mut inflight = self.inflight.lock().map_err(|_| {
+                CloudError::InternalError("sync lock is poisoned".to_string())
+            })?;
+            match inflight.get(&id) {
+                Some(sender) => Some(sender.subscribe()),
+                None => {
+                    let (sender, _) = watch::channel(None);
+                    inflight.insert(id.clone(), sender);
+                    None
+                }
+            }
+        };
+
+        if let Some(mut receiver) = receiver {
+            loop {
+                if let Some(result) = receiver.borrow().clone() {
+                    return result;
+                }
+                if receiver.changed().await.is_err() {
+                    break;
+                }
+            }
+            return sync.await;
+        }
+
+        let mut guard = InflightGuard {
+            coalescer: self,
+            id: id.clone(),
+            active: true,
+        };
+        let result = sync.await;
+        guard.active = false;
+        if let Some(sender) = self.remove(&id) {
+            // nobody may be waiting, it is fine
+            let _ = sender.send(Some(result.clone()));
+        }
+        result
+    }
+
+    fn remove(&self, id: &K) -> Option<SyncResultSender> {
+        self.inflight
+            .lock()
+            .ok()
+            .and_then(|mut inflight| inflight.remove(id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use uuid::Uuid;
+
+    use super::*;
+
+    async fn counted_sync(calls: &AtomicUsize) -> Result<(), CloudError> {
+        calls.fetch_add(1, Ordering::SeqCst);
+        // keeps the sync running while the other callers arrive
+        tokio::task::yield_now().await;
+        Ok(())
+    }
+
+    #[actix_web::test]
+    async fn concurrent_callers_share_a_sync() {
+        let coalescer = SyncCoalescer::new();
+        let calls = AtomicUsize::new(0);
+        let id = (Uuid::new_v4(), None);
+
+        let results = futures::future::join_all((0..8).map(|_| coalescer.sync(id, counted_sync(&calls)))).await;
+
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert!(results.into_iter().all(|result| result.is_ok()));
+    }
+
+    #[actix_web::test]
+    async fn batch_does_not_satisfy_full_sync() {
+        let coalescer = SyncCoalescer::new();
+        let calls = AtomicUsize::new(0);
+        let id = Uuid::new_v4();
+
+        let (batch, full) = futures::join!(
+            coalescer.sync((id, Some(100)), counted_sync(&calls)),
+            coalescer.sync((id, None), counted_sync(&calls)),
+        );
+
+        assert!(batch.is_ok() && full.is_ok());
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[actix_web::test]
+    async fn waiters_get_the_leader_error() {
+        let coalescer = SyncCoalescer::new();
+        let id = (Uuid::new_v4(), None);
+
+        let failing = async {
+            tokio::task::yield_now().await;
+            Err(CloudError::RelayerSendError)
+        };
+        let (leader, waiter) = futures::join!(
+            coalescer.sync(id, failing),
+            coalescer.sync(id, async { Ok(()) }),
+        );
+
+        assert_eq!(leader, Err(CloudError::RelayerSendError));
+        assert_eq!(waiter, Err(CloudError::RelayerSendError));
+    }
+}
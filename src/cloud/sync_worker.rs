@@ -0,0 +1,285 @@
+use std::{collections::{HashMap, HashSet, VecDeque}, sync::{Arc, Mutex}, time::Duration};
+
+use actix_web::web::Data;
+use tokio::{sync::{Notify, Semaphore}, time};
+use uuid::Uuid;
+use zkbob_utils_rs::tracing;
+
+use crate::{errors::CloudError, helpers::timestamp};
+
+use super::{cleanup::spawn_worker, ZkBobCloud};
+
+// Time of the last successful sync of each account, it is not persisted
+// because accounts are synced again after a restart anyway
+pub(crate) struct SyncTimes {
+    synced_at: Mutex<HashMap<Uuid, u64>>,
+}
+
+impl SyncTimes {
+    pub fn new() -> Self {
+        SyncTimes {
+            synced_at: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn record(&self, id: Uuid) {
+        if let Ok(mut synced_at) = self.synced_at.lock() {
+            synced_at.insert(id, timestamp());
+        }
+    }
+
+    pub fn is_fresh(&self, id: Uuid, max_staleness_sec: u64) -> bool {
+        self.synced_at
+            .lock()
+            .ok()
+            .and_then(|synced_at| synced_at.get(&id).copied())
+            .map(|synced_at| timestamp().saturating_sub(synced_at) < max_staleness_sec)
+            .unwrap_or(false)
+    }
+}
+
+// Accounts that are synced in the background in batches, an account stays here until it catches up
+// with the relayer or its sync fails, requests to it are rejected meanwhile
+pub(crate) struct BackgroundSyncs {
+    syncing: Mutex<HashSet<Uuid>>,
+    pending: Mutex<VecDeque<Uuid>>,
+    notify: Notify,
+}
+
+impl BackgroundSyncs {
+    pub fn new() -> Self {
+        BackgroundSyncs {
+            syncing: Mutex::new(HashSet::new()),
+            pending: Mutex::new(VecDeque::new()),
+            notify: Notify::new(),
+        }
+    }
+
+    // Does nothing if the account is already syncing
+    pub fn schedule(&self, id: Uuid) {
+        let mut syncing = self.syncing.lock().expect("background syncs are poisoned");
+        if syncing.insert(id) {
+            self.pending.lock().expect("background syncs are poisoned").push_back(id);
+            self.notify.notify_one();
+        }
+    }
+
+    pub fn is_syncing(&self, id: Uuid) -> bool {
+        self.syncing
+            .lock()
+            .map(|syncing| syncing.contains(&id))
+            .unwrap_or(false)
+    }
+
+    fn next(&self) -> Option<Uuid> {
+        self.pending.lock().expect("background syncs are poisoned").pop_front()
+    }
+
+    fn finish(&self, id: Uuid) {
+        if let Ok(mut syncing) = self.syncing.lock() {
+            syncing.remove(&id);
+        }
+    }
+
+    // Syncs interrupted by a worker restart are scheduled again
+    fn resume(&self) {
+        let syncing = self.syncing.lock().expect("background syncs are poisoned");
+        let mut pending = self.pending.lock().expect("background syncs are poisoned");
+        pending.clear();
+        pending.extend(syncing.iter().copied());
+    }
+}
+
+// Share of the transactions from the first index of the account to the relayer index that are synced
+pub(crate) fn sync_progress(first_index: u64, next_index: u64, delta_index: u64) -> u64 {
+    if next_index >= delta_index || first_index >= delta_index {
+        return 100;
+    }
+    let synced = next_index.saturating_sub(first_index) as u128;
+    (synced * 100 / (delta_index - first_index) as u128) as u64
+}
+
+// Releases the account when its background sync ends, even if the task panics
+struct BackgroundSyncGuard {
+    cloud: Data<ZkBobCloud>,
+    id: Uuid,
+}
+
+impl Drop for BackgroundSyncGuard {
+    fn drop(&mut self) {
+        self.cloud.background_syncs.finish(self.id);
+    }
+}
+
+pub(crate) fn run_background_sync_worker(cloud: Data<ZkBobCloud>) {
+    if !cloud.config.incremental_sync.enabled {
+        return;
+    }
+
+    spawn_worker("background sync", cloud, move |cloud| async move {
+        cloud.background_syncs.resume();
+        let semaphore = Arc::new(Semaphore::new(cloud.config.incremental_sync.concurrency.max(1)));
+        loop {
+            let id = match cloud.background_syncs.next() {
+                Some(id) => id,
+                None => {
+                    cloud.background_syncs.notify.notified().await;
+                    continue;
+                }
+            };
+
+            let permit = match semaphore.clone().acquire_owned().await {
+                Ok(permit) => permit,
+                Err(err) => {
+                    tracing::error!("[background sync worker] failed to acquire sync permit: {}", err);
+                    cloud.background_syncs.finish(id);
+                    continue;
+                }
+            };
+            let guard = BackgroundSyncGuard { cloud: cloud.clone(), id };
+            tokio::spawn(async move {
+                let _permit = permit;
+                match catch_up(&guard.cloud, id).await {
+                    Ok(()) => tracing::info!("[background sync worker] account {} is synced", id),
+                    Err(err) => tracing::warn!("[background sync worker] failed to sync account {}: {}", id, err),
+                }
+            });
+        }
+    });
+}
+
+// Syncs the account in batches until it reaches the relayer index
+async fn catch_up(cloud: &ZkBobCloud, id: Uuid) -> Result<(), CloudError> {
+    let (account, _cleanup) = cloud.get_account(id).await?;
+    let batch_size = cloud.config.incremental_sync.batch_size.max(1);
+    loop {
+        let next_index = account.next_index().await;
+        let delta_index = cloud.relayer.info().await?.delta_index;
+        if cloud.relayer.tx_count(next_index, delta_index)? == 0 {
+            cloud.sync_times.record(id);
+            return Ok(());
+        }
+
+        cloud.sync_batch(id, &account, Some(batch_size)).await?;
+        if account.next_index().await == next_index {
+            return Err(CloudError::InternalError(format!("sync made no progress at index {}", next_index)));
+        }
+        tracing::debug!("[background sync worker] account {} is synced to index {} of {}", id, account.next_index().await, delta_index);
+    }
+}
+
+pub(crate) fn run_sync_worker(cloud: Data<ZkBobCloud>) {
+    if !cloud.config.sync_worker.enabled {
+        return;
+    }
+
+    spawn_worker("sync", cloud, move |cloud| async move {
+        let interval = Duration::from_secs(cloud.config.sync_worker.interval_sec);
+        loop {
+            match sync_all(&cloud).await {
+                Ok((processed, skipped)) => tracing::info!("[sync worker] processed {} accounts, skipped {} busy accounts", processed, skipped),
+                Err(err) => tracing::warn!("[sync worker] failed to sync accounts: {}", err),
+            }
+            time::sleep(interval).await;
+        }
+    });
+}
+
+async fn sync_all(cloud: &Data<ZkBobCloud>) -> Result<(usize, usize), CloudError> {
+    let accounts = cloud.db.read().await.get_accounts()?;
+    let semaphore = Arc::new(Semaphore::new(cloud.config.sync_worker.concurrency.max(1)));
+
+    let mut handles = Vec::new();
+    let mut skipped = 0;
+    for (id, _) in accounts {
+        // accounts in use are synced by the requests that hold them
+        if cloud.accounts.read().await.is_busy(id) {
+            skipped += 1;
+            continue;
+        }
+
+        let permit = semaphore.clone().acquire_owned().await.map_err(|err| {
+            CloudError::InternalError(format!("failed to acquire sync permit: {}", err))
+        })?;
+        let cloud = cloud.clone();
+        handles.push(tokio::spawn(async move {
+            let _permit = permit;
+            let (account, _cleanup) = match cloud.get_account(id).await {
+                Ok(account) => account,
+                Err(err) => {
+                    tracing::warn!("[sync worker] failed to load account {}: {}", id, err);
+                    return;
+                }
+            };
+            if let Err(err) = cloud.sync_to_latest(id, &account).await {
+                tracing::warn!("[sync worker] failed to sync account {}: {}", id, err);
+            }
+        }));
+    }
+
+    let processed = handles.len();
+    for handle in handles {
+        let _ = handle.await;
+    }
+    Ok((processed, skipped))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn progress_starts_at_first_index() {
+        assert_eq!(sync_progress(0, 0, 1024), 0);
+        assert_eq!(sync_progress(0, 512, 1024), 50);
+        assert_eq!(sync_progress(512, 512, 1024), 0);
+        assert_eq!(sync_progress(512, 768, 1024), 50);
+        assert_eq!(sync_progress(512, 1024, 1024), 100);
+    }
+
+    #[test]
+    fn progress_is_complete_without_transactions() {
+        assert_eq!(sync_progress(0, 0, 0), 100);
+        assert_eq!(sync_progress(1024, 1024, 512), 100);
+        assert_eq!(sync_progress(0, 2048, 1024), 100);
+        assert_eq!(sync_progress(0, u64::MAX - 1, u64::MAX), 99);
+    }
+
+    #[test]
+    fn account_is_scheduled_once() {
+        let syncs = BackgroundSyncs::new();
+        let id = Uuid::new_v4();
+
+        syncs.schedule(id);
+        syncs.schedule(id);
+        assert!(syncs.is_syncing(id));
+        assert_eq!(syncs.next(), Some(id));
+        assert_eq!(syncs.next(), None);
+
+        // a running sync is not scheduled again
+        syncs.schedule(id);
+        assert_eq!(syncs.next(), None);
+
+        syncs.finish(id);
+        assert!(!syncs.is_syncing(id));
+        syncs.schedule(id);
+        assert_eq!(syncs.next(), Some(id));
+    }
+
+    #[test]
+    fn interrupted_syncs_are_resumed() {
+        let syncs = BackgroundSyncs::new();
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+        syncs.schedule(first);
+        syncs.schedule(second);
+        assert_eq!(syncs.next(), Some(first));
+
+        syncs.resume();
+        let mut resumed = vec![syncs.next().unwrap(), syncs.next().unwrap()];
+        resumed.sort();
+        let mut expected = vec![first, second];
+        expected.sort();
+        assert_eq!(resumed, expected);
+        assert_eq!(syncs.next(), None);
+    }
+}
@@ -1,8 +1,9 @@
 use libzkbob_rs::libzeropool::fawkes_crypto::ff_uint::Num;
 use serde::{Serialize, Deserialize};
+use sha2::{Digest, Sha256};
 use uuid::Uuid;
 
-use crate::{Fr, errors::CloudError, account::history::{HistoryTxType, HistoryTx}};
+use crate::{Fr, errors::{CloudError, error_code}, account::{history::{HistoryTxType, HistoryTx}, types::AccountInfo}, config::HealthDependency, helpers::{redact_address, AsU64Amount}};
 
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -10,6 +11,28 @@ pub struct AccountData {
     pub description: String,
     pub db_path: String,
     pub sk: String,
+    #[serde(default)]
+    pub first_index: Option<u64>,
+    // consecutive failed syncs, reset by a successful one
+    #[serde(default)]
+    pub sync_failures: u32,
+    #[serde(default)]
+    pub last_sync_error: Option<String>,
+    // transactions that failed to parse and were applied as commitments only,
+    // recorded here by older versions, they are kept with the account state now
+    #[serde(default)]
+    pub skipped_indices: Vec<u64>,
+    #[serde(flatten)]
+    pub attributes: AccountAttributes,
+}
+
+// Set by the client at signup or import and kept as is, exported along with the account
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct AccountAttributes {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<serde_json::Value>,
 }
 
 #[derive(Serialize)]
@@ -18,19 +41,31 @@ pub struct AccountShortInfo {
     pub id: String,
     pub description: String,
     pub sk: String,
+    pub degraded: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_sync_error: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub enum RepairAction {
+    Recreate,
+    Delete,
 }
 
 pub struct AccountImportData {
     pub id: Uuid,
     pub description: String,
+    pub attributes: AccountAttributes,
     pub sk: Vec<u8>,
+    pub first_index: Option<u64>,
 }
 
 #[derive(Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct CloudHistoryTx {
     pub tx_type: HistoryTxType,
-    pub tx_hash: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tx_hash: Option<String>,
     pub timestamp: u64,
     pub amount: u64,
     pub fee: u64,
@@ -38,30 +73,261 @@ pub struct CloudHistoryTx {
     pub to: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub transaction_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub change: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub counterparty_account_id: Option<String>,
+    // the transaction is not mined yet
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub pending: bool,
+    // status of the transfer part that the record is made from, it isn't sent to the relayer yet
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<String>,
 }
 
 impl CloudHistoryTx {
     pub fn new(record: HistoryTx, transaction_id: Option<String>) -> CloudHistoryTx {
         CloudHistoryTx {
             tx_type: record.tx_type,
-            tx_hash: record.tx_hash,
+            tx_hash: Some(record.tx_hash),
             timestamp: record.timestamp,
             amount: record.amount,
             fee: record.fee,
             to: record.to,
             transaction_id,
+            change: record.change,
+            counterparty_account_id: None,
+            pending: false,
+            status: None,
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SyncResult {
+    pub next_index: u64,
+    pub relayer_index: u64,
+    pub synced: bool,
+    // the account was degraded and this sync cleared it
+    pub recovered: bool,
+    // transactions skipped because they failed to parse, see skip_parse_errors
+    pub skipped_indices: Vec<u64>,
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ResyncResult {
+    pub next_index: u64,
+    // ids of the parts that were failed because they were planned with the old state
+    pub failed_parts: Vec<String>,
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub enum JobState {
+    Running,
+    Completed,
+    Failed,
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ResyncStatus {
+    pub status: JobState,
+    pub started_at: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<ResyncResult>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SyncStatus {
+    pub next_index: u64,
+    pub delta_index: u64,
+    pub optimistic_delta_index: u64,
+    // transactions between the account and delta_index that the next sync has to fetch
+    pub remaining_transactions: u64,
+    pub progress_percent: u64,
+    // the account is synced by the background sync worker
+    pub syncing: bool,
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ReconcileReport {
+    pub timestamp: u64,
+    pub pool_index: u64,
+    pub optimistic_pool_index: u64,
+    pub accounts: usize,
+    pub unsynced_accounts: usize,
+    pub total_balance: u64,
+    pub in_flight_transfers: usize,
+    pub pending_outgoing: u64,
+    // accounts that failed to load, they are counted as unsynced without a balance
+    pub errors: Vec<ReconcileAccountError>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ReconcileAccountError {
+    pub account_id: String,
+    pub error: String,
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ReconcileStatus {
+    pub status: JobState,
+    pub started_at: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub report: Option<ReconcileReport>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SaturationStatus {
+    pub send_queue_depth: u64,
+    pub prover_in_flight: usize,
+    // how long every send worker slot has been busy
+    pub prover_saturated_sec: Option<u64>,
+    pub saturated: bool,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HealthStatus {
+    pub healthy: bool,
+    // a non-critical dependency fails
+    pub degraded: bool,
+    pub dependencies: Vec<DependencyHealth>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DependencyHealth {
+    pub name: HealthDependency,
+    pub critical: bool,
+    pub healthy: bool,
+    pub latency_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IndexedAddress {
+    pub index: u64,
+    pub address: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CircuitInfo {
+    pub params_hash: String,
+    pub vk_hash: String,
+    pub vk: serde_json::Value,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SupportBundle {
+    pub timestamp: u64,
+    pub account: AccountInfo,
+    pub account_index: u64,
+    pub relayer_index: u64,
+    pub history: Vec<CloudHistoryTx>,
+    pub pending_parts: Vec<TransferPart>,
+    // the latest failed parts with their errors, newest first
+    pub failed_parts: Vec<TransferPart>,
+    pub queues: SupportQueues,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SupportQueues {
+    pub send_depth: u64,
+    pub status_depth: u64,
+    // pending parts of the account that have a message in the queue
+    pub send: Vec<String>,
+    pub status: Vec<String>,
+}
+
+impl SupportBundle {
+    // All sensitive fields must go through this function before the bundle leaves the service
+    pub fn redacted(self) -> SupportBundle {
+        SupportBundle {
+            history: self
+                .history
+                .into_iter()
+                .map(|tx| CloudHistoryTx {
+                    to: tx.to.as_deref().map(redact_address),
+                    ..tx
+                })
+                .collect(),
+            pending_parts: self.pending_parts.into_iter().map(redacted_part).collect(),
+            failed_parts: self.failed_parts.into_iter().map(redacted_part).collect(),
+            ..self
         }
     }
 }
 
+fn redacted_part(part: TransferPart) -> TransferPart {
+    TransferPart {
+        to: part.to.as_deref().map(redact_address),
+        outputs: part
+            .outputs
+            .into_iter()
+            .map(|output| TransferOutput {
+                to: redact_address(&output.to),
+                ..output
+            })
+            .collect(),
+        ..part
+    }
+}
+
 pub struct Transfer {
     pub id: String,
     pub account_id: Uuid,
-    pub amount: u64,
-    pub to: String,
+    // pairs of receiver address and amount
+    pub outputs: Vec<(String, u64)>,
+    pub support_id: Option<String>,
+    // the request is authorized with the admin token
+    pub admin: bool,
+    // the only output is an L1 address and the last part is a withdrawal
+    pub withdrawal: bool,
+    // part of the withdrawn amount that is swapped to the native token
+    pub native_amount: u64,
+    // overrides the callback url from the configuration
+    pub callback_url: Option<String>,
+    // the amount of the single output is replaced with the maximum transferable amount
+    pub sweep: bool,
+    // the transfer is created by the service itself, e.g. a fee top-up
+    pub internal: bool,
+}
+
+impl Transfer {
+    // Identical requests with the same client nonce get the same id,
+    // so a retried request is rejected as a duplicate instead of creating a second transfer
+    pub fn derive_id(account_id: Uuid, outputs: &[(String, u64)], nonce: &str) -> String {
+        let outputs = outputs
+            .iter()
+            .map(|(to, amount)| format!("{}:{}", to, amount))
+            .collect::<Vec<_>>()
+            .join(":");
+        let hash = Sha256::digest(format!("{}:{}:{}", account_id.as_hyphenated(), outputs, nonce));
+        let mut bytes = [0u8; 16];
+        bytes.copy_from_slice(&hash[..16]);
+        Uuid::from_bytes(bytes).as_hyphenated().to_string()
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(from = "StoredTransferStatus", into = "StoredTransferStatus")]
 pub enum TransferStatus {
     New,
     Proving,
@@ -69,6 +335,88 @@ pub enum TransferStatus {
     Mining,
     Done,
     Failed(CloudError),
+    Cancelled,
+}
+
+// Persisted form of TransferStatus, errors are stored as a stable {code, message} pair
+// so that changes in CloudError don't break deserialization of old parts
+#[derive(Serialize, Deserialize)]
+enum StoredTransferStatus {
+    New,
+    Proving,
+    Relaying,
+    Mining,
+    Done,
+    Failed(StoredError),
+    Cancelled,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum StoredError {
+    // `error` is the serialized CloudError, it restores errors with a payload while it matches the enum,
+    // otherwise the error is restored from the code. Parts saved without it keep the code and the message
+    Stable {
+        code: String,
+        message: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<serde_json::Value>,
+    },
+    // parts saved before the stable form contain the serialized CloudError
+    Legacy(serde_json::Value),
+}
+
+impl From<StoredTransferStatus> for TransferStatus {
+    fn from(status: StoredTransferStatus) -> Self {
+        match status {
+            StoredTransferStatus::New => Self::New,
+            StoredTransferStatus::Proving => Self::Proving,
+            StoredTransferStatus::Relaying => Self::Relaying,
+            StoredTransferStatus::Mining => Self::Mining,
+            StoredTransferStatus::Done => Self::Done,
+            StoredTransferStatus::Cancelled => Self::Cancelled,
+            // cancelled parts used to be stored as failed
+            StoredTransferStatus::Failed(StoredError::Stable { code, .. }) if code == "TransferCancelled" => Self::Cancelled,
+            StoredTransferStatus::Failed(StoredError::Stable { code, message, error }) => {
+                // only unit variants can be restored from the code alone
+                let err = error
+                    .and_then(|error| serde_json::from_value(error).ok())
+                    .unwrap_or_else(|| CloudError::restore(code, message));
+                Self::Failed(err)
+            }
+            StoredTransferStatus::Failed(StoredError::Legacy(value)) => {
+                let err = serde_json::from_value(value.clone()).unwrap_or_else(|_| {
+                    CloudError::StoredError(error_code(&value), value.to_string())
+                });
+                Self::Failed(err)
+            }
+        }
+    }
+}
+
+impl From<TransferStatus> for StoredTransferStatus {
+    fn from(status: TransferStatus) -> Self {
+        match status {
+            TransferStatus::New => Self::New,
+            TransferStatus::Proving => Self::Proving,
+            TransferStatus::Relaying => Self::Relaying,
+            TransferStatus::Mining => Self::Mining,
+            TransferStatus::Done => Self::Done,
+            TransferStatus::Cancelled => Self::Cancelled,
+            TransferStatus::Failed(err) => {
+                // a restored error is stored as it was loaded
+                let error = match &err {
+                    CloudError::StoredError(_, _) => None,
+                    err => serde_json::to_value(err).ok(),
+                };
+                Self::Failed(StoredError::Stable {
+                    code: err.code(),
+                    message: err.to_string(),
+                    error,
+                })
+            }
+        }
+    }
 }
 
 impl TransferStatus {
@@ -88,7 +436,7 @@ impl TransferStatus {
     }
 
     pub fn is_final(&self) -> bool {
-        matches!(self, TransferStatus::Done | TransferStatus::Failed(_))
+        matches!(self, TransferStatus::Done | TransferStatus::Failed(_) | TransferStatus::Cancelled)
     }
 
     pub fn status(&self) -> String {
@@ -98,6 +446,13 @@ impl TransferStatus {
         }
     }
 
+    pub fn validate_name(name: &str) -> Result<String, CloudError> {
+        match name {
+            "New" | "Proving" | "Relaying" | "Mining" | "Done" | "Failed" | "Cancelled" => Ok(name.to_string()),
+            _ => Err(CloudError::BadRequest(format!("unknown transfer status: {}", name))),
+        }
+    }
+
     pub fn failure_reason(&self) -> Option<String> {
         match self {
             Self::Failed(err) => Some(err.to_string()),
@@ -111,8 +466,10 @@ pub struct TransferPart {
     pub id: String,
     pub transaction_id: String,
     pub account_id: String,
+    // total amount of the outputs or the aggregated amount
     pub amount: Num<Fr>,
     pub fee: u64,
+    // the receiver of a single output part, see `outputs`
     pub to: Option<String>,
     pub status: TransferStatus,
     pub job_id: Option<String>,
@@ -120,12 +477,141 @@ pub struct TransferPart {
     pub depends_on: Option<String>,
     pub attempt: u32,
     pub timestamp: u64,
+    // the index expected when the part was sent, replaced by the actual one once it is mined
+    #[serde(default)]
+    pub pool_index: Option<u64>,
+    // hash of the transfer params the proof was generated with
+    #[serde(default)]
+    pub params_hash: Option<String>,
+    // the part withdraws `amount` to the L1 address in `to`
+    #[serde(default)]
+    pub withdrawal: bool,
+    // part of the withdrawn amount that is swapped to the native token
+    #[serde(default)]
+    pub native_amount: u64,
+    // outputs of a part with several receivers, `to` is not set then
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub outputs: Vec<TransferOutput>,
+    // the relayer that accepted the part, its job is known only there
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub relayer_url: Option<String>,
+    // nullifier of the sent proof, parts with the same nullifier spend the same account state
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nullifier: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TransferOutput {
+    pub to: String,
+    pub amount: Num<Fr>,
+}
+
+impl TransferPart {
+    // note aggregation moves funds to the account itself
+    pub fn is_aggregation(&self) -> bool {
+        self.to.is_none() && self.outputs.is_empty()
+    }
+
+    // Funds the part takes from the account when it is mined: the fee and the sent amount
+    pub fn outflow(&self) -> u64 {
+        match self.is_aggregation() {
+            false => self.fee.saturating_add(self.amount.as_u64_amount()),
+            true => self.fee,
+        }
+    }
+
+    pub fn tx_outputs(&self) -> Vec<(String, Num<Fr>)> {
+        match &self.to {
+            Some(to) => vec![(to.clone(), self.amount)],
+            None => self
+                .outputs
+                .iter()
+                .map(|output| (output.to.clone(), output.amount))
+                .collect(),
+        }
+    }
+
+    // part id has the form `{transaction_id}.{index}`, transaction id cannot contain '.'
+    pub fn index(&self) -> Option<u64> {
+        self.id
+            .rsplit_once('.')
+            .and_then(|(_, index)| index.parse().ok())
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SubmissionReceipt {
+    pub part_id: String,
+    pub request_uuid: String,
+    pub memo_hash: String,
+    pub job_id: String,
+    pub timestamp: u64,
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct TransferPartTrace {
+    pub part_index: Option<u64>,
+    #[serde(flatten)]
+    pub part: TransferPart,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub previous_part_status: Option<TransferStatus>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub receipt: Option<SubmissionReceipt>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PendingDirectDeposit {
+    pub nonce: String,
+    pub account_id: String,
+    pub address: String,
+    pub amount: u64,
+    // the note can't appear in the pool before this index
+    pub from_index: u64,
+    pub tx_hash: Option<String>,
+    pub timestamp: u64,
+    // the relayer included the deposit into the pool, the account is synced to find its note
+    #[serde(default)]
+    pub completed: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum RetryBudget {
+    // parts get the full number of attempts configured for their worker
+    Reset,
+    // parts get the given number of attempts, capped by the worker configuration
+    Attempts(u32),
+}
+
+// Relayer fees of a terminal transfer that the fee payer has to reimburse, it is removed once the top-up is submitted
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FeeTopUp {
+    pub transaction_id: String,
+    pub account_id: String,
+    pub fee: u64,
+    pub support_id: Option<String>,
+    pub attempt: u32,
+    pub timestamp: u64,
+}
+
+// Notes planned by an in-flight transfer. The balance it takes is derived from its parts,
+// reservations saved with a `balance` are read without it
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NoteReservation {
+    pub transaction_id: String,
+    pub account_id: String,
+    pub notes: Vec<u64>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct TransferTask {
     pub transaction_id: String,
-    pub parts: Vec<String>
+    pub parts: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub support_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub callback_url: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -136,7 +622,29 @@ pub struct AccountReport {
     pub balance: u64,
     pub max_transfer_amount: u64,
     pub address: String,
-    pub sk: String,
+    // keys are included only on request, encrypted when the request has a public key
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sk: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encrypted_sk: Option<String>,
+    // the account failed to sync or load, the other fields except the description are empty
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl AccountReport {
+    pub fn failed(id: Uuid, description: String, err: CloudError) -> Self {
+        AccountReport {
+            id: id.to_string(),
+            description,
+            balance: 0,
+            max_transfer_amount: 0,
+            address: String::new(),
+            sk: None,
+            encrypted_sk: None,
+            error: Some(err.to_string()),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -147,11 +655,47 @@ pub struct Report {
     pub accounts: Vec<AccountReport>
 }
 
+impl Report {
+    // Amounts are plain integers, so spreadsheets don't reformat them
+    pub fn to_csv(&self) -> String {
+        let mut csv = format!("timestamp,{}\npoolIndex,{}\n", self.timestamp, self.pool_index);
+        csv.push_str("id,description,balance,maxTransferAmount,address,sk,encryptedSk,error\n");
+        for account in &self.accounts {
+            csv.push_str(&format!(
+                "{},{},{},{},{},{},{},{}\n",
+                csv_field(&account.id),
+                csv_field(&account.description),
+                account.balance,
+                account.max_transfer_amount,
+                csv_field(&account.address),
+                csv_field(account.sk.as_deref().unwrap_or_default()),
+                csv_field(account.encrypted_sk.as_deref().unwrap_or_default()),
+                csv_field(account.error.as_deref().unwrap_or_default()),
+            ));
+        }
+        csv
+    }
+}
+
+// Text fields are quoted when needed, values that a spreadsheet would evaluate as a formula are prefixed with '
+fn csv_field(value: &str) -> String {
+    let value = match value.starts_with(['=', '+', '-', '@']) {
+        true => format!("'{}", value),
+        false => value.to_string(),
+    };
+    match value.contains([',', '"', '\n', '\r']) {
+        true => format!("\"{}\"", value.replace('"', "\"\"")),
+        false => value,
+    }
+}
+
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum ReportStatus {
     New,
     Completed,
+    // some accounts failed, they are reported with an error
+    CompletedWithErrors,
     Failed,
 }
 
@@ -160,4 +704,192 @@ pub struct ReportTask {
     pub status: ReportStatus,
     pub attempt: u32,
     pub report: Option<Report>,
-}
\ No newline at end of file
+    // tasks created before it was stored have 0
+    #[serde(default)]
+    pub created_at: u64,
+    #[serde(default)]
+    pub include_keys: bool,
+    // hex encoded x25519 key that the included keys are encrypted with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub public_key: Option<String>,
+    // the last saved progress, it is kept when the report fails
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub progress: Option<ReportProgress>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub struct ReportProgress {
+    pub processed: usize,
+    pub total: usize,
+}
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::cloud::tests::part;
+
+    use super::*;
+
+    const RECEIVER: &str = "9SUHCagSLzNhHVUmS6xKiUzWXhxPxzSH9ykJkBnxGbf3bAxMnAB1fVqtHrzs5Gu";
+    const OUTPUT: &str = "GwT2R98Q33q5EKKCTBgMqmdz2rRdFPfuWcLJ3Af5TmYu7iDEcS9xn6XQhWKspSA";
+
+    fn bundle() -> SupportBundle {
+        let mut pending = part(1, TransferStatus::Mining, 100, Some(RECEIVER));
+        pending.outputs = vec![TransferOutput { to: OUTPUT.to_string(), amount: Num::ZERO }];
+        SupportBundle {
+            timestamp: 0,
+            account: AccountInfo {
+                id: Uuid::nil().to_string(),
+                description: "test".to_string(),
+                balance: 0,
+                max_transfer_amount: 0,
+                address: "own address".to_string(),
+                pending_balance: None,
+                spendable_balance: None,
+                degraded: false,
+                last_sync_error: None,
+            },
+            account_index: 0,
+            relayer_index: 0,
+            history: vec![CloudHistoryTx {
+                tx_type: HistoryTxType::TransferOut,
+                tx_hash: None,
+                timestamp: 0,
+                amount: 100,
+                fee: 10,
+                to: Some(RECEIVER.to_string()),
+                transaction_id: None,
+                change: None,
+                counterparty_account_id: None,
+                pending: false,
+                status: None,
+            }],
+            pending_parts: vec![pending],
+            failed_parts: vec![part(2, TransferStatus::Failed(CloudError::RelayerSendError), 100, Some(OUTPUT))],
+            queues: SupportQueues {
+                send_depth: 1,
+                status_depth: 1,
+                send: vec![],
+                status: vec!["transfer.1".to_string()],
+            },
+        }
+    }
+
+    #[test]
+    fn support_bundle_never_contains_counterparty_addresses() {
+        let unredacted = serde_json::to_string(&bundle()).unwrap();
+        assert!(unredacted.contains(RECEIVER) && unredacted.contains(OUTPUT));
+
+        let json = serde_json::to_string(&bundle().redacted()).unwrap();
+        assert!(!json.contains(RECEIVER));
+        assert!(!json.contains(OUTPUT));
+        assert!(json.contains(&redact_address(RECEIVER)));
+        assert!(json.contains(&redact_address(OUTPUT)));
+        assert!(!json.contains("\"sk\""));
+        assert!(json.contains("\"statusDepth\":1"));
+    }
+
+    fn round_trip(status: TransferStatus) -> TransferStatus {
+        serde_json::from_str(&serde_json::to_string(&status).unwrap()).unwrap()
+    }
+
+    fn load(value: serde_json::Value) -> TransferStatus {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn statuses_survive_round_trip() {
+        for status in [
+            TransferStatus::New,
+            TransferStatus::Proving,
+            TransferStatus::Relaying,
+            TransferStatus::Mining,
+            TransferStatus::Done,
+            TransferStatus::Cancelled,
+            TransferStatus::Failed(CloudError::PreviousTxFailed),
+            TransferStatus::Failed(CloudError::TaskRejectedByRelayer("nullifier is spent".to_string())),
+            TransferStatus::Failed(CloudError::TransactionReverted("0x01".to_string())),
+            TransferStatus::Failed(CloudError::FeeIncreased(10, 20)),
+        ] {
+            assert_eq!(round_trip(status.clone()), status);
+        }
+    }
+
+    #[test]
+    fn stored_error_keeps_code_and_message() {
+        let status = TransferStatus::Failed(CloudError::StoredError("Removed".to_string(), "removed error".to_string()));
+        let status = round_trip(status);
+        assert_eq!(status, TransferStatus::Failed(CloudError::StoredError("Removed".to_string(), "removed error".to_string())));
+    }
+
+    #[test]
+    fn stable_records_without_error_are_restored_by_code() {
+        let status = load(json!({"Failed": {"code": "PreviousTxFailed", "message": "previous tx failed"}}));
+        assert_eq!(status, TransferStatus::Failed(CloudError::PreviousTxFailed));
+
+        // errors with a payload keep the code, so they are still matched by it
+        let status = load(json!({"Failed": {"code": "TaskRejectedByRelayer", "message": "relayer returned error: 'x'"}}));
+        match status {
+            TransferStatus::Failed(err) => assert_eq!(err.code(), "TaskRejectedByRelayer"),
+            status => panic!("unexpected status {:?}", status),
+        }
+
+        let status = load(json!({"Failed": {"code": "TransferCancelled", "message": "transfer cancelled"}}));
+        assert_eq!(status, TransferStatus::Cancelled);
+    }
+
+    #[test]
+    fn unknown_payload_falls_back_to_code() {
+        let status = load(json!({"Failed": {"code": "TaskRejectedByRelayer", "message": "m", "error": {"TaskRejectedByRelayer": [1, 2]}}}));
+        assert_eq!(status, TransferStatus::Failed(CloudError::StoredError("TaskRejectedByRelayer".to_string(), "m".to_string())));
+    }
+
+    #[test]
+    fn legacy_records_are_restored() {
+        let status = load(json!({"Failed": {"TaskRejectedByRelayer": "nullifier is spent"}}));
+        assert_eq!(status, TransferStatus::Failed(CloudError::TaskRejectedByRelayer("nullifier is spent".to_string())));
+
+        let status = load(json!({"Failed": "RetriesExhausted"}));
+        assert_eq!(status, TransferStatus::Failed(CloudError::RetriesExhausted));
+
+        let status = load(json!({"Failed": {"RemovedVariant": 1}}));
+        match status {
+            TransferStatus::Failed(err) => assert_eq!(err.code(), "RemovedVariant"),
+            status => panic!("unexpected status {:?}", status),
+        }
+    }
+
+    #[test]
+    fn account_data_without_attributes_is_loaded() {
+        let data: AccountData = serde_json::from_value(json!({
+            "description": "Bob",
+            "db_path": "accounts/1",
+            "sk": "00",
+        }))
+        .unwrap();
+        assert_eq!(data.attributes, AccountAttributes::default());
+    }
+
+    #[test]
+    fn account_data_keeps_attributes() {
+        let attributes = AccountAttributes {
+            tags: vec!["merchant".to_string()],
+            metadata: Some(json!({"customer": 42})),
+        };
+        let data = AccountData {
+            description: "Bob".to_string(),
+            db_path: "accounts/1".to_string(),
+            sk: "00".to_string(),
+            first_index: None,
+            sync_failures: 0,
+            last_sync_error: None,
+            skipped_indices: vec![],
+            attributes: attributes.clone(),
+        };
+        let value = serde_json::to_value(&data).unwrap();
+        assert_eq!(value["tags"], json!(["merchant"]));
+        let data: AccountData = serde_json::from_value(value).unwrap();
+        assert_eq!(data.attributes, attributes);
+    }
+}
@@ -1,15 +1,224 @@
+use std::time::Duration;
+
 use config::{File, FileFormat, Environment};
 use serde::{Serialize, Deserialize};
 use zkbob_utils_rs::configuration::{TelemetrySettings, Version, Web3Settings};
 
-use crate::errors::CloudError;
+use crate::{errors::CloudError, helpers::queue::{DEFAULT_POLL_INTERVAL_MS, DEFAULT_RECONNECT_BACKOFF_MS}};
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct WorkerConfig {
     pub max_attempts: u32,
     pub max_parallel: usize,
+    // maximum number of parallel proofs, used only by the send worker
+    #[serde(default)]
+    pub max_concurrent: Option<usize>,
     pub queue_delay_sec: u32,
     pub queue_hidden_sec: u32,
+    pub log_queue_latency: bool,
+    // delay before the first retry after an error, 0 retries after queue_hidden_sec
+    pub retry_backoff_base_sec: u32,
+    pub retry_backoff_multiplier: f64,
+    pub retry_backoff_max_sec: u32,
+    #[serde(default = "default_poll_interval_ms")]
+    pub poll_interval_ms: u64,
+    #[serde(default = "default_reconnect_backoff_ms")]
+    pub reconnect_backoff_ms: u64,
+    // confirmations of the mined transaction before the part is Done, used only by the status worker
+    #[serde(default)]
+    pub required_confirmations: u64,
+    // parts that stay in Relaying or Mining longer than this are failed, used only by the status worker
+    #[serde(default)]
+    pub max_age_sec: u64,
+}
+
+fn default_poll_interval_ms() -> u64 {
+    DEFAULT_POLL_INTERVAL_MS
+}
+
+fn default_reconnect_backoff_ms() -> u64 {
+    DEFAULT_RECONNECT_BACKOFF_MS
+}
+
+fn default_fee_top_up_interval_sec() -> u64 {
+    30
+}
+
+fn default_max_generated_addresses_per_minute() -> u32 {
+    60
+}
+
+fn default_max_account_tags() -> usize {
+    16
+}
+
+fn default_max_tag_length() -> usize {
+    64
+}
+
+fn default_max_metadata_length() -> usize {
+    4096
+}
+
+fn default_max_dead_letters() -> usize {
+    1000
+}
+
+impl WorkerConfig {
+    pub fn poll_interval(&self) -> Duration {
+        Duration::from_millis(self.poll_interval_ms)
+    }
+
+    pub fn reconnect_backoff(&self) -> Duration {
+        Duration::from_millis(self.reconnect_backoff_ms)
+    }
+
+    // Proofs are computed by the running tasks, so there are at most max_parallel of them
+    pub fn max_concurrent_proofs(&self) -> usize {
+        self.max_concurrent
+            .unwrap_or(self.max_parallel)
+            .min(self.max_parallel)
+            .max(1)
+    }
+
+    // Delay before the given retry attempt, it grows exponentially up to retry_backoff_max_sec
+    pub fn retry_delay(&self, attempt: u32) -> Option<u32> {
+        if self.retry_backoff_base_sec == 0 {
+            return None;
+        }
+        let exponent = attempt.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let delay = self.retry_backoff_base_sec as f64 * self.retry_backoff_multiplier.max(1.0).powi(exponent);
+        Some(delay.min(self.retry_backoff_max_sec as f64) as u32)
+    }
+}
+
+// A single relayer url or a list of urls that are tried in order
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum RelayerUrl {
+    Single(String),
+    List(Vec<String>),
+}
+
+impl RelayerUrl {
+    pub fn urls(&self) -> Vec<String> {
+        match self {
+            RelayerUrl::Single(url) => vec![url.clone()],
+            RelayerUrl::List(urls) => urls.clone(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum QueueBackend {
+    Redis,
+    Memory,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RelayerFeeConfig {
+    pub min: u64,
+    pub max: u64,
+    pub block_transfers: bool,
+    // the fee is refetched from the relayer once the cached value is older than this
+    pub fee_ttl_sec: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LimitsConfig {
+    pub max_description_length: usize,
+    pub max_transaction_id_length: usize,
+    pub max_transfers_per_support_id: usize,
+    pub max_transfer_outputs: usize,
+    #[serde(default = "default_max_generated_addresses_per_minute")]
+    pub max_generated_addresses_per_minute: u32,
+    #[serde(default = "default_max_account_tags")]
+    pub max_account_tags: usize,
+    #[serde(default = "default_max_tag_length")]
+    pub max_tag_length: usize,
+    #[serde(default = "default_max_metadata_length")]
+    pub max_metadata_length: usize,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BackpressureConfig {
+    pub enabled: bool,
+    pub max_queue_depth: u64,
+    pub max_prover_saturation_sec: u64,
+    pub retry_after_sec: u64,
+    pub exempt_admin: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum HealthDependency {
+    Redis,
+    Relayer,
+    Rpc,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct HealthConfig {
+    pub critical: Vec<HealthDependency>,
+    pub timeout_ms: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SyncWorkerConfig {
+    pub enabled: bool,
+    pub interval_sec: u64,
+    pub concurrency: usize,
+    pub max_staleness_sec: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct IncrementalSyncConfig {
+    pub enabled: bool,
+    pub batch_size: u64,
+    pub max_blocking_gap: u64,
+    pub concurrency: usize,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ReportWorkerConfig {
+    pub concurrency: usize,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CallbackConfig {
+    pub url: Option<String>,
+    #[serde(default)]
+    pub allowed_hosts: Vec<String>,
+    pub max_attempts: u32,
+    pub retry_delay_sec: u64,
+    pub timeout_sec: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RetentionConfig {
+    pub retention_days: u64,
+    pub sweep_interval_sec: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct FaultInjectionConfig {
+    pub enabled: bool,
+    pub unsafe_testing: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DirectDepositWatcherConfig {
+    pub enabled: bool,
+    pub contract_address: String,
+    pub poll_interval_sec: u64,
+    pub max_blocks_per_poll: u64,
+    pub expiration_sec: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct KeyEncryptionConfig {
+    pub enabled: bool,
+    pub master_key: Option<String>,
+    pub backup_key: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -17,15 +226,52 @@ pub struct Config {
     pub host: String,
     pub port: u16,
     pub transfer_params_path: String,
+    pub expected_params_hash: Option<String>,
     pub db_path: String,
-    pub relayer_url: String,
+    pub relayer_url: RelayerUrl,
     pub redis_url: String,
+    pub queue_namespace: String,
+    pub queue_backend: QueueBackend,
     pub admin_token: String,
+    pub relayer_fee: RelayerFeeConfig,
+    pub max_generated_addresses: u64,
+    pub max_accounts_page_size: u64,
+    pub max_history_page_size: u64,
+    pub max_loaded_accounts: usize,
+    pub limits: LimitsConfig,
+    pub verify_proofs_before_send: bool,
+    pub recreate_diverged_accounts: bool,
+    #[serde(default)]
+    pub reserve_notes: bool,
+    pub coalesce_syncs: bool,
+    pub partial_history: bool,
+    pub report_spendable_balance: bool,
+    pub skip_parse_errors: bool,
+    pub fee_payer_account_id: Option<String>,
+    #[serde(default = "default_fee_top_up_interval_sec")]
+    pub fee_top_up_interval_sec: u64,
+    #[serde(default = "default_max_dead_letters")]
+    pub max_dead_letters: usize,
+    pub amount_denomination: Option<u64>,
+    pub degraded_sync_failures: u32,
+    pub max_relayer_index_lag: u64,
+    pub relayer_info_ttl_ms: u64,
+    pub incremental_sync: IncrementalSyncConfig,
+    pub backpressure: BackpressureConfig,
+    pub callback: CallbackConfig,
+    pub health: HealthConfig,
     pub telemetry: TelemetrySettings,
     pub version: Version,
     pub web3: Web3Settings,
     pub send_worker: WorkerConfig,
     pub status_worker: WorkerConfig,
+    pub sync_worker: SyncWorkerConfig,
+    pub report_worker: ReportWorkerConfig,
+    pub transfer_retention: RetentionConfig,
+    pub report_retention_sec: u64,
+    pub fault_injection: FaultInjectionConfig,
+    pub key_encryption: KeyEncryptionConfig,
+    pub direct_deposit_watcher: DirectDepositWatcherConfig,
 }
 
 impl Config {
@@ -41,4 +287,31 @@ impl Config {
         config = config.add_source(Environment::default().separator("__"));
         Ok(config.build()?.try_deserialize()?)
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::WorkerConfig;
+
+    fn worker(max_parallel: usize, max_concurrent: Option<usize>) -> WorkerConfig {
+        serde_json::from_value(serde_json::json!({
+            "max_attempts": 1,
+            "max_parallel": max_parallel,
+            "max_concurrent": max_concurrent,
+            "queue_delay_sec": 0,
+            "queue_hidden_sec": 0,
+            "log_queue_latency": false,
+            "retry_backoff_base_sec": 0,
+            "retry_backoff_multiplier": 1.0,
+            "retry_backoff_max_sec": 0,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn concurrent_proofs_are_bounded_by_parallel_tasks() {
+        assert_eq!(worker(10, None).max_concurrent_proofs(), 10);
+        assert_eq!(worker(10, Some(4)).max_concurrent_proofs(), 4);
+        assert_eq!(worker(10, Some(20)).max_concurrent_proofs(), 10);
+        assert_eq!(worker(10, Some(0)).max_concurrent_proofs(), 1);
+    }
+}
@@ -10,14 +10,16 @@ pub enum CloudError {
     BadRequest(String),
     #[error("internal error")]
     CustodyLockError,
-    #[error("internal error")]
-    StateSyncError,
+    #[error("failed to parse transactions at indices {0:?}")]
+    StateSyncError(Vec<u64>),
     #[error("bad account id")]
     IncorrectAccountId,
     #[error("bad account id")]
     AccountNotFound,
     #[error("duplicate account id")]
     DuplicateAccountId,
+    #[error("field '{0}' exceeds the maximum length of {1}")]
+    FieldTooLong(String, usize),
     #[error("request id cannot contain '.'")]
     InvalidTransactionId,
     #[error("request id already exists")]
@@ -46,12 +48,20 @@ pub enum CloudError {
     InsufficientBalance,
     #[error("account is busy")]
     AccountIsBusy,
+    #[error("account data is inconsistent, repair is required")]
+    AccountStateDiverged,
     #[error("account is not synced yet")]
     AccountIsNotSynced,
+    #[error("account state was rebuilt, the transfer was planned with the old state")]
+    AccountResynced,
     #[error("service is busy")]
     ServiceIsBusy,
+    #[error("too many requests")]
+    TooManyRequests,
     #[error("transaction expired")]
     TransactionExpired,
+    #[error("transaction {0} reverted on-chain")]
+    TransactionReverted(String),
     #[error("transaction status is unknown")]
     TransactionStatusUnknown,
     #[error("failed to parse config")]
@@ -60,29 +70,106 @@ pub enum CloudError {
     Web3Error,
     #[error("bad report id")]
     ReportNotFound,
+    #[error("report is not completed")]
+    ReportNotReady,
+    #[error("account was not resynced")]
+    ResyncNotFound,
+    #[error("reconciliation was not started")]
+    ReconcileNotFound,
+    #[error("relayer fee is out of bounds")]
+    InvalidRelayerFee,
+    #[error("relayer doesn't report the withdrawal fee")]
+    WithdrawalFeeUnavailable,
+    #[error("relayer fee increased from {0} to {1}, the balance doesn't cover it")]
+    FeeIncreased(u64, u64),
+    #[error("proof verification failed")]
+    ProofVerificationFailed,
+    #[error("transfer params hash mismatch: expected {0}, loaded {1}")]
+    ParamsHashMismatch(String, String),
+    #[error("injected fault: {0}")]
+    InjectedFault(String),
+    #[error("relayer index {0} is behind the account index {1}")]
+    RelayerBehindLocalState(u64, u64),
+    #[error("malformed relayer transaction at index {0}: {1}")]
+    MalformedRelayerTransaction(u64, String),
+    // an error restored from a stored transfer part: code and message
+    #[error("{1}")]
+    StoredError(String, String),
 }
 
-impl ResponseError for CloudError {
-    fn status_code(&self) -> actix_http::StatusCode {
+impl CloudError {
+    // Stable machine-readable name of the error, it doesn't change with the message
+    pub fn code(&self) -> String {
         match self {
-            CloudError::TransactionNotFound
-            | CloudError::DuplicateTransactionId
-            | CloudError::BadRequest(_)
-            | CloudError::IncorrectAccountId
-            | CloudError::AccountNotFound => StatusCode::BAD_REQUEST,
-            CloudError::AccessDenied => StatusCode::UNAUTHORIZED,
-            _ => StatusCode::INTERNAL_SERVER_ERROR,
+            CloudError::StoredError(code, _) => code.clone(),
+            err => serde_json::to_value(err)
+                .map(|value| error_code(&value))
+                .unwrap_or_default(),
         }
     }
 
+    // Unit variants are restored from the code alone, the other ones keep the code and the message
+    pub fn restore(code: String, message: String) -> CloudError {
+        serde_json::from_value(serde_json::Value::String(code.clone()))
+            .unwrap_or(CloudError::StoredError(code, message))
+    }
+}
+
+// CloudError is serialized as "Variant" or {"Variant": payload}
+pub(crate) fn error_code(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::String(code) => code.clone(),
+        serde_json::Value::Object(map) => map.keys().next().cloned().unwrap_or_default(),
+        _ => String::new(),
+    }
+}
+
+// The status is mapped by the code, so an error restored from a stored part or a response keeps it
+fn status_for_code(code: &str) -> StatusCode {
+    match code {
+        "BadRequest"
+        | "IncorrectAccountId"
+        | "AccountNotFound"
+        | "DuplicateAccountId"
+        | "FieldTooLong"
+        | "InvalidTransactionId"
+        | "DuplicateTransactionId"
+        | "InsufficientBalance"
+        | "FeeIncreased"
+        | "TransactionExpired" => StatusCode::BAD_REQUEST,
+        "AccessDenied" => StatusCode::UNAUTHORIZED,
+        "TransactionNotFound"
+        | "ReportNotFound"
+        | "ResyncNotFound"
+        | "ReconcileNotFound" => StatusCode::NOT_FOUND,
+        // the request can be repeated later
+        "AccountIsBusy"
+        | "AccountIsNotSynced"
+        | "ReportNotReady" => StatusCode::CONFLICT,
+        // the request can be repeated after Retry-After
+        "TooManyRequests"
+        | "ServiceIsBusy" => StatusCode::TOO_MANY_REQUESTS,
+        "InvalidRelayerFee"
+        | "WithdrawalFeeUnavailable" => StatusCode::SERVICE_UNAVAILABLE,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+impl ResponseError for CloudError {
+    fn status_code(&self) -> actix_http::StatusCode {
+        status_for_code(&self.code())
+    }
+
     fn error_response(&self) -> HttpResponse {
         #[derive(Serialize)]
         struct ErrorResponse {
             error: String,
+            code: String,
         }
 
         let response = serde_json::to_string(&ErrorResponse {
             error: format!("{}", self),
+            code: self.code(),
         })
         .unwrap_or(self.to_string());
 
@@ -114,4 +201,144 @@ impl From<FromHexError> for CloudError {
     fn from(e: FromHexError) -> Self {
         Self::InternalError(e.to_string())
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_http::StatusCode;
+    use actix_web::ResponseError;
+
+    use super::CloudError;
+
+    fn table() -> Vec<(CloudError, &'static str, StatusCode)> {
+        let text = || "x".to_string();
+        vec![
+            (CloudError::BadRequest(text()), "BadRequest", StatusCode::BAD_REQUEST),
+            (CloudError::CustodyLockError, "CustodyLockError", StatusCode::INTERNAL_SERVER_ERROR),
+            (CloudError::StateSyncError(vec![1]), "StateSyncError", StatusCode::INTERNAL_SERVER_ERROR),
+            (CloudError::IncorrectAccountId, "IncorrectAccountId", StatusCode::BAD_REQUEST),
+            (CloudError::AccountNotFound, "AccountNotFound", StatusCode::BAD_REQUEST),
+            (CloudError::DuplicateAccountId, "DuplicateAccountId", StatusCode::BAD_REQUEST),
+            (CloudError::FieldTooLong(text(), 1), "FieldTooLong", StatusCode::BAD_REQUEST),
+            (CloudError::InvalidTransactionId, "InvalidTransactionId", StatusCode::BAD_REQUEST),
+            (CloudError::DuplicateTransactionId, "DuplicateTransactionId", StatusCode::BAD_REQUEST),
+            (CloudError::DataBaseReadError(text()), "DataBaseReadError", StatusCode::INTERNAL_SERVER_ERROR),
+            (CloudError::DataBaseWriteError(text()), "DataBaseWriteError", StatusCode::INTERNAL_SERVER_ERROR),
+            (CloudError::RelayerSendError, "RelayerSendError", StatusCode::INTERNAL_SERVER_ERROR),
+            (CloudError::TransactionNotFound, "TransactionNotFound", StatusCode::NOT_FOUND),
+            (CloudError::InternalError(text()), "InternalError", StatusCode::INTERNAL_SERVER_ERROR),
+            (CloudError::RetriesExhausted, "RetriesExhausted", StatusCode::INTERNAL_SERVER_ERROR),
+            (CloudError::TaskRejectedByRelayer(text()), "TaskRejectedByRelayer", StatusCode::INTERNAL_SERVER_ERROR),
+            (CloudError::RetryNeeded, "RetryNeeded", StatusCode::INTERNAL_SERVER_ERROR),
+            (CloudError::AccessDenied, "AccessDenied", StatusCode::UNAUTHORIZED),
+            (CloudError::PreviousTxFailed, "PreviousTxFailed", StatusCode::INTERNAL_SERVER_ERROR),
+            (CloudError::InsufficientBalance, "InsufficientBalance", StatusCode::BAD_REQUEST),
+            (CloudError::AccountIsBusy, "AccountIsBusy", StatusCode::CONFLICT),
+            (CloudError::AccountStateDiverged, "AccountStateDiverged", StatusCode::INTERNAL_SERVER_ERROR),
+            (CloudError::AccountIsNotSynced, "AccountIsNotSynced", StatusCode::CONFLICT),
+            (CloudError::AccountResynced, "AccountResynced", StatusCode::INTERNAL_SERVER_ERROR),
+            (CloudError::ServiceIsBusy, "ServiceIsBusy", StatusCode::TOO_MANY_REQUESTS),
+            (CloudError::TooManyRequests, "TooManyRequests", StatusCode::TOO_MANY_REQUESTS),
+            (CloudError::TransactionExpired, "TransactionExpired", StatusCode::BAD_REQUEST),
+            (CloudError::TransactionReverted(text()), "TransactionReverted", StatusCode::INTERNAL_SERVER_ERROR),
+            (CloudError::TransactionStatusUnknown, "TransactionStatusUnknown", StatusCode::INTERNAL_SERVER_ERROR),
+            (CloudError::ConfigError(text()), "ConfigError", StatusCode::INTERNAL_SERVER_ERROR),
+            (CloudError::Web3Error, "Web3Error", StatusCode::INTERNAL_SERVER_ERROR),
+            (CloudError::ReportNotFound, "ReportNotFound", StatusCode::NOT_FOUND),
+            (CloudError::ReportNotReady, "ReportNotReady", StatusCode::CONFLICT),
+            (CloudError::ResyncNotFound, "ResyncNotFound", StatusCode::NOT_FOUND),
+            (CloudError::ReconcileNotFound, "ReconcileNotFound", StatusCode::NOT_FOUND),
+            (CloudError::InvalidRelayerFee, "InvalidRelayerFee", StatusCode::SERVICE_UNAVAILABLE),
+            (CloudError::WithdrawalFeeUnavailable, "WithdrawalFeeUnavailable", StatusCode::SERVICE_UNAVAILABLE),
+            (CloudError::FeeIncreased(1, 2), "FeeIncreased", StatusCode::BAD_REQUEST),
+            (CloudError::ProofVerificationFailed, "ProofVerificationFailed", StatusCode::INTERNAL_SERVER_ERROR),
+            (CloudError::ParamsHashMismatch(text(), text()), "ParamsHashMismatch", StatusCode::INTERNAL_SERVER_ERROR),
+            (CloudError::InjectedFault(text()), "InjectedFault", StatusCode::INTERNAL_SERVER_ERROR),
+            (CloudError::RelayerBehindLocalState(1, 2), "RelayerBehindLocalState", StatusCode::INTERNAL_SERVER_ERROR),
+            (CloudError::MalformedRelayerTransaction(1, text()), "MalformedRelayerTransaction", StatusCode::INTERNAL_SERVER_ERROR),
+            // a stored unit variant is restored as the variant itself, so it keeps its message
+            (CloudError::StoredError("AccountIsBusy".to_string(), "account is busy".to_string()), "AccountIsBusy", StatusCode::CONFLICT),
+        ]
+    }
+
+    // It doesn't compile when a variant is added, the new variant has to be listed here and added to table() too
+    fn covered(err: &CloudError) {
+        match err {
+            CloudError::BadRequest(_)
+            | CloudError::CustodyLockError
+            | CloudError::StateSyncError(_)
+            | CloudError::IncorrectAccountId
+            | CloudError::AccountNotFound
+            | CloudError::DuplicateAccountId
+            | CloudError::FieldTooLong(_, _)
+            | CloudError::InvalidTransactionId
+            | CloudError::DuplicateTransactionId
+            | CloudError::DataBaseReadError(_)
+            | CloudError::DataBaseWriteError(_)
+            | CloudError::RelayerSendError
+            | CloudError::TransactionNotFound
+            | CloudError::InternalError(_)
+            | CloudError::RetriesExhausted
+            | CloudError::TaskRejectedByRelayer(_)
+            | CloudError::RetryNeeded
+            | CloudError::AccessDenied
+            | CloudError::PreviousTxFailed
+            | CloudError::InsufficientBalance
+            | CloudError::AccountIsBusy
+            | CloudError::AccountStateDiverged
+            | CloudError::AccountIsNotSynced
+            | CloudError::AccountResynced
+            | CloudError::ServiceIsBusy
+            | CloudError::TooManyRequests
+            | CloudError::TransactionExpired
+            | CloudError::TransactionReverted(_)
+            | CloudError::TransactionStatusUnknown
+            | CloudError::ConfigError(_)
+            | CloudError::Web3Error
+            | CloudError::ReportNotFound
+            | CloudError::ReportNotReady
+            | CloudError::ResyncNotFound
+            | CloudError::ReconcileNotFound
+            | CloudError::InvalidRelayerFee
+            | CloudError::WithdrawalFeeUnavailable
+            | CloudError::FeeIncreased(_, _)
+            | CloudError::ProofVerificationFailed
+            | CloudError::ParamsHashMismatch(_, _)
+            | CloudError::InjectedFault(_)
+            | CloudError::RelayerBehindLocalState(_, _)
+            | CloudError::MalformedRelayerTransaction(_, _)
+            | CloudError::StoredError(_, _) => {}
+        }
+    }
+
+    #[test]
+    fn every_error_has_code_and_status() {
+        for (err, code, status) in table() {
+            covered(&err);
+            assert_eq!(err.code(), code, "{:?}", err);
+            assert_eq!(err.status_code(), status, "{:?}", err);
+        }
+    }
+
+    #[test]
+    fn errors_are_restored_by_code() {
+        for (err, code, status) in table() {
+            let restored = CloudError::restore(code.to_string(), err.to_string());
+            assert_eq!(restored.code(), code);
+            assert_eq!(restored.status_code(), status, "{:?}", err);
+            assert_eq!(restored.to_string(), err.to_string());
+        }
+        assert_eq!(CloudError::restore("AccountIsBusy".to_string(), "account is busy".to_string()), CloudError::AccountIsBusy);
+        assert_eq!(
+            CloudError::restore("FeeIncreased".to_string(), "relayer fee increased".to_string()),
+            CloudError::StoredError("FeeIncreased".to_string(), "relayer fee increased".to_string())
+        );
+    }
+
+    #[test]
+    fn unknown_code_is_internal_error() {
+        let err = CloudError::restore("Removed".to_string(), "x".to_string());
+        assert_eq!(err.status_code(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(err.code(), "Removed");
+    }
+}
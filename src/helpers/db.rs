@@ -6,9 +6,12 @@ use zkbob_utils_rs::tracing;
 
 use crate::{errors::CloudError, Database};
 
+use super::fault::FaultInjector;
+
 pub struct KeyValueDb {
     path: String,
     db: Database,
+    faults: FaultInjector,
 }
 
 impl KeyValueDb {
@@ -27,9 +30,15 @@ impl KeyValueDb {
         Ok(KeyValueDb {
             path: path.to_string(),
             db,
+            faults: FaultInjector::default(),
         })
     }
 
+    // Writes fail according to the `db.write` rule of the injector
+    pub fn set_faults(&mut self, faults: FaultInjector) {
+        self.faults = faults;
+    }
+
     pub fn get<T: DeserializeOwned>(
         &self,
         column: u32,
@@ -95,6 +104,20 @@ impl KeyValueDb {
         Ok(items)
     }
 
+    // Values that fail to deserialize are skipped, so one malformed entry doesn't hide the others
+    pub fn get_all_readable<T: DeserializeOwned>(&self, column: u32) -> Vec<T> {
+        self.db
+            .iter(column)
+            .filter_map(|(key, value)| match serde_json::from_slice(&value) {
+                Ok(item) => Some(item),
+                Err(err) => {
+                    tracing::warn!("skipping malformed value [{:?}] in db: [{}]: {:?}", key, self.path, err);
+                    None
+                }
+            })
+            .collect()
+    }
+
     pub fn get_all_with_keys<T: DeserializeOwned>(
         &self,
         column: u32,
@@ -115,6 +138,75 @@ impl KeyValueDb {
         Ok(items)
     }
 
+    // Only values inside the window are deserialized, the rest is just counted
+    pub fn get_page_with_keys<T: DeserializeOwned>(
+        &self,
+        column: u32,
+        offset: usize,
+        limit: usize,
+    ) -> Result<(Vec<(Vec<u8>, T)>, usize), CloudError> {
+        let mut items = vec![];
+        let mut total = 0;
+        for (key, value) in self.db.iter(column) {
+            total += 1;
+            if total <= offset || items.len() >= limit {
+                continue;
+            }
+            let item = serde_json::from_slice(&value).map_err(|err| {
+                tracing::error!(
+                    "failed to deserialize value [{:?}] from db: [{}] with err: {:?}",
+                    value,
+                    self.path,
+                    err
+                );
+                CloudError::DataBaseReadError("failed to deserialize value from db".to_string())
+            })?;
+            items.push((key.to_vec(), item));
+        }
+        Ok((items, total))
+    }
+
+    pub fn get_all_filtered<T, F>(&self, column: u32, filter: F) -> Result<Vec<T>, CloudError>
+    where
+        T: DeserializeOwned,
+        F: Fn(&[u8]) -> bool,
+    {
+        let mut items = vec![];
+        for (key, value) in self.db.iter(column) {
+            if !filter(&key) {
+                continue;
+            }
+            let item = serde_json::from_slice(&value).map_err(|err| {
+                tracing::error!(
+                    "failed to deserialize value [{:?}] from db: [{}] with err: {:?}",
+                    value,
+                    self.path,
+                    err
+                );
+                CloudError::DataBaseReadError("failed to deserialize value from db".to_string())
+            })?;
+            items.push(item);
+        }
+        Ok(items)
+    }
+
+    pub fn get_by_prefix<T: DeserializeOwned>(&self, column: u32, prefix: &[u8]) -> Result<Vec<T>, CloudError> {
+        let mut items = vec![];
+        for (_, value) in self.db.iter_with_prefix(column, prefix) {
+            let item = serde_json::from_slice(&value).map_err(|err| {
+                tracing::error!(
+                    "failed to deserialize value [{:?}] from db: [{}] with err: {:?}",
+                    value,
+                    self.path,
+                    err
+                );
+                CloudError::DataBaseReadError("failed to deserialize value from db".to_string())
+            })?;
+            items.push(item);
+        }
+        Ok(items)
+    }
+
     pub fn exists(&self, column: u32, key: &[u8]) -> Result<bool, CloudError> {
         Ok(self.get_raw(column, key)?.is_some())
     }
@@ -140,6 +232,7 @@ impl KeyValueDb {
     }
 
     pub fn save_raw(&mut self, column: u32, key: &[u8], value: &[u8]) -> Result<(), CloudError> {
+        self.faults.inject_sync("db.write")?;
         self.db
             .write({
                 let mut tx = self.db.transaction();
@@ -158,12 +251,61 @@ impl KeyValueDb {
             })
     }
 
+    pub fn save_all_raw<'a, I>(&mut self, column: u32, values: I) -> Result<(), CloudError>
+    where
+        I: Iterator<Item = (&'a [u8], &'a [u8])>,
+    {
+        self.faults.inject_sync("db.write")?;
+        let mut tx = self.db.transaction();
+        for (key, value) in values {
+            tx.put(column, key, value);
+        }
+        self.db.write(tx).map_err(|err| {
+            tracing::error!(
+                "failed to save tx [{}] in db: [{}] with err: {:?}",
+                column,
+                self.path,
+                err
+            );
+            CloudError::DataBaseWriteError("failed to save values".to_string())
+        })
+    }
+
+    // Values of several columns are written in one transaction, either all of them are saved or none
+    pub fn save_batch<I>(&mut self, values: I) -> Result<(), CloudError>
+    where
+        I: Iterator<Item = (u32, Vec<u8>, Vec<u8>)>,
+    {
+        self.faults.inject_sync("db.write")?;
+        let mut tx = self.db.transaction();
+        for (column, key, value) in values {
+            tx.put_vec(column, &key, value);
+        }
+        self.db.write(tx).map_err(|err| {
+            tracing::error!("failed to save batch in db: [{}] with err: {:?}", self.path, err);
+            CloudError::DataBaseWriteError("failed to save values".to_string())
+        })
+    }
+
+    pub fn serialize<T: Serialize + Debug>(&self, value: &T) -> Result<Vec<u8>, CloudError> {
+        serde_json::to_vec(value).map_err(|err| {
+            tracing::error!(
+                "failed to serialize value [{:?}] for db: [{}] with err: {:?}",
+                value,
+                self.path,
+                err
+            );
+            CloudError::DataBaseWriteError("failed to serialize value".to_string())
+        })
+    }
+
     pub fn save_all<'a, T, I, F>(&mut self, column: u32, values: I, key: F) -> Result<(), CloudError>
     where
         T: Serialize + Debug + 'a,
         I: Iterator<Item = &'a T>,
         F: Fn(&T) -> Vec<u8>,
     {
+        self.faults.inject_sync("db.write")?;
         let mut tx = self.db.transaction();
         for value in values {
             let key = key(value);
@@ -190,6 +332,7 @@ impl KeyValueDb {
     }
 
     pub fn delete(&mut self, column: u32, key: &[u8]) -> Result<(), CloudError> {
+        self.faults.inject_sync("db.write")?;
         self.db
             .write({
                 let mut tx = self.db.transaction();
@@ -208,7 +351,29 @@ impl KeyValueDb {
             })
     }
 
+    pub fn delete_keys<I, K>(&mut self, column: u32, keys: I) -> Result<(), CloudError>
+    where
+        I: Iterator<Item = K>,
+        K: AsRef<[u8]>,
+    {
+        self.faults.inject_sync("db.write")?;
+        let mut tx = self.db.transaction();
+        for key in keys {
+            tx.delete(column, key.as_ref());
+        }
+        self.db.write(tx).map_err(|err| {
+            tracing::error!(
+                "failed to delete values from column [{}] in db: [{}] with err: {:?}",
+                column,
+                self.path,
+                err
+            );
+            CloudError::DataBaseWriteError("failed to delete values".to_string())
+        })
+    }
+
     pub fn delete_all(&mut self, column: u32) -> Result<(), CloudError> {
+        self.faults.inject_sync("db.write")?;
         self.db.write({
             let mut transaction = self.db.transaction();
             transaction.delete_prefix(column, &[]);
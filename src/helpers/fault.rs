@@ -0,0 +1,185 @@
+use std::{collections::HashMap, sync::{Arc, RwLock}, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use tokio::time;
+use zkbob_utils_rs::tracing;
+
+use crate::{config::FaultInjectionConfig, errors::CloudError};
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct FaultRule {
+    #[serde(default)]
+    pub delay_ms: u64,
+    #[serde(default)]
+    pub delay_probability: f64,
+    #[serde(default)]
+    pub error_probability: f64,
+}
+
+// Rules of one service instance, shared by the clients it creates. The rules exist only
+// when fault injection is explicitly enabled, so call sites pay a single check otherwise.
+#[derive(Clone, Default)]
+pub struct FaultInjector {
+    rules: Option<Arc<RwLock<HashMap<String, FaultRule>>>>,
+}
+
+impl FaultInjector {
+    pub fn new(config: &FaultInjectionConfig) -> Result<Self, CloudError> {
+        if !config.enabled {
+            return Ok(FaultInjector::default());
+        }
+
+        if !config.unsafe_testing {
+            return Err(CloudError::ConfigError(
+                "fault injection can be enabled only with unsafe_testing flag".to_string(),
+            ));
+        }
+
+        tracing::warn!("fault injection is enabled, it must never be used in production");
+        Ok(FaultInjector {
+            rules: Some(Arc::new(RwLock::new(HashMap::new()))),
+        })
+    }
+
+    fn enabled_rules(&self) -> Result<&RwLock<HashMap<String, FaultRule>>, CloudError> {
+        self.rules
+            .as_deref()
+            .ok_or(CloudError::BadRequest("fault injection is disabled".to_string()))
+    }
+
+    pub fn rules(&self) -> Result<HashMap<String, FaultRule>, CloudError> {
+        let rules = self
+            .enabled_rules()?
+            .read()
+            .map_err(|_| CloudError::InternalError("fault rules lock is poisoned".to_string()))?;
+        Ok(rules.clone())
+    }
+
+    pub fn set_rule(&self, site: &str, rule: Option<FaultRule>) -> Result<(), CloudError> {
+        let mut rules = self
+            .enabled_rules()?
+            .write()
+            .map_err(|_| CloudError::InternalError("fault rules lock is poisoned".to_string()))?;
+        match rule {
+            Some(rule) => {
+                tracing::warn!("[fault injection] set rule for {}: {:?}", site, rule);
+                rules.insert(site.to_string(), rule);
+            }
+            None => {
+                tracing::warn!("[fault injection] removed rule for {}", site);
+                rules.remove(site);
+            }
+        }
+        Ok(())
+    }
+
+    fn rule(&self, site: &str) -> Option<FaultRule> {
+        self.rules
+            .as_ref()?
+            .read()
+            .ok()
+            .and_then(|rules| rules.get(site).cloned())
+    }
+
+    pub async fn inject(&self, site: &str) -> Result<(), CloudError> {
+        let rule = match self.rule(site) {
+            Some(rule) => rule,
+            None => return Ok(()),
+        };
+
+        if rule.delay_ms > 0 && rand::random::<f64>() < rule.delay_probability {
+            tracing::warn!("[fault injection] delaying {} for {} ms", site, rule.delay_ms);
+            time::sleep(Duration::from_millis(rule.delay_ms)).await;
+        }
+        maybe_fail(site, &rule)
+    }
+
+    // Blocking call sites (e.g. db writes) only support injected errors
+    pub fn inject_sync(&self, site: &str) -> Result<(), CloudError> {
+        match self.rule(site) {
+            Some(rule) => maybe_fail(site, &rule),
+            None => Ok(()),
+        }
+    }
+}
+
+fn maybe_fail(site: &str, rule: &FaultRule) -> Result<(), CloudError> {
+    if rand::random::<f64>() < rule.error_probability {
+        tracing::warn!("[fault injection] injecting error into {}", site);
+        return Err(CloudError::InjectedFault(site.to_string()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::{config::FaultInjectionConfig, errors::CloudError};
+
+    use super::{FaultInjector, FaultRule};
+
+    fn enabled() -> FaultInjector {
+        FaultInjector::new(&FaultInjectionConfig { enabled: true, unsafe_testing: true }).unwrap()
+    }
+
+    fn failing() -> Option<FaultRule> {
+        Some(FaultRule { error_probability: 1.0, ..Default::default() })
+    }
+
+    #[test]
+    fn test_requires_unsafe_testing() {
+        let config = FaultInjectionConfig { enabled: true, unsafe_testing: false };
+        assert!(matches!(FaultInjector::new(&config), Err(CloudError::ConfigError(_))));
+    }
+
+    #[actix_web::test]
+    async fn test_disabled_injector_never_fails() {
+        let faults = FaultInjector::new(&FaultInjectionConfig { enabled: false, unsafe_testing: true }).unwrap();
+        assert!(matches!(faults.set_rule("relayer.info", failing()), Err(CloudError::BadRequest(_))));
+        assert!(faults.rules().is_err());
+        assert!(faults.inject("relayer.info").await.is_ok());
+        assert!(faults.inject_sync("db.write").is_ok());
+    }
+
+    #[actix_web::test]
+    async fn test_rule_applies_to_its_site() {
+        let faults = enabled();
+        faults.set_rule("relayer.info", failing()).unwrap();
+        assert!(matches!(faults.inject("relayer.info").await, Err(CloudError::InjectedFault(site)) if site == "relayer.info"));
+        assert!(faults.inject("relayer.fee").await.is_ok());
+
+        faults.set_rule("relayer.info", None).unwrap();
+        assert!(faults.inject("relayer.info").await.is_ok());
+        assert!(faults.rules().unwrap().is_empty());
+    }
+
+    #[actix_web::test]
+    async fn test_instances_are_independent() {
+        let faults = enabled();
+        let other = enabled();
+        faults.set_rule("db.write", failing()).unwrap();
+        assert!(faults.inject_sync("db.write").is_err());
+        assert!(other.inject_sync("db.write").is_ok());
+        assert!(other.rules().unwrap().is_empty());
+
+        // clones share the rules of their instance
+        let shared = faults.clone();
+        faults.set_rule("db.write", None).unwrap();
+        assert!(shared.inject_sync("db.write").is_ok());
+    }
+
+    #[actix_web::test]
+    async fn test_delay_without_error() {
+        let faults = enabled();
+        faults
+            .set_rule("web3.fetch", Some(FaultRule { delay_ms: 50, delay_probability: 1.0, error_probability: 0.0 }))
+            .unwrap();
+        let started_at = Instant::now();
+        assert!(faults.inject("web3.fetch").await.is_ok());
+        assert!(started_at.elapsed() >= Duration::from_millis(50));
+        // blocking sites ignore delays
+        assert!(faults.inject_sync("web3.fetch").is_ok());
+    }
+}
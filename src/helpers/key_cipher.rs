@@ -0,0 +1,212 @@
+use std::sync::Arc;
+
+use chacha20poly1305::{
+    aead::{Aead, NewAead},
+    ChaCha20Poly1305, Key, Nonce, XChaCha20Poly1305, XNonce,
+};
+use sha2::{Digest, Sha256};
+use x25519_dalek::{EphemeralSecret, PublicKey};
+use zkbob_utils_rs::tracing;
+
+use crate::{config::KeyEncryptionConfig, errors::CloudError};
+
+// Encrypted keys are stored as prefix || nonce || ciphertext,
+// values without the prefix are plaintext keys stored before encryption was enabled
+const ENCRYPTED_PREFIX: &[u8] = b"enc:";
+const NONCE_LENGTH: usize = 12;
+const X_NONCE_LENGTH: usize = 24;
+
+#[derive(Clone)]
+pub struct KeyCipher {
+    cipher: Option<Arc<ChaCha20Poly1305>>,
+}
+
+impl KeyCipher {
+    pub fn new(config: &KeyEncryptionConfig) -> Result<Self, CloudError> {
+        if !config.enabled {
+            return Ok(KeyCipher { cipher: None });
+        }
+
+        let master_key = config.master_key.as_ref().ok_or(CloudError::ConfigError(
+            "master key is required when key encryption is enabled".to_string(),
+        ))?;
+        Self::from_hex_key(master_key)
+    }
+
+    pub fn from_hex_key(key: &str) -> Result<Self, CloudError> {
+        let key = hex::decode(key)?;
+        if key.len() != 32 {
+            return Err(CloudError::ConfigError(
+                "encryption key must be 32 bytes long".to_string(),
+            ));
+        }
+
+        Ok(KeyCipher {
+            cipher: Some(Arc::new(ChaCha20Poly1305::new(Key::from_slice(&key)))),
+        })
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.cipher.is_some()
+    }
+
+    pub fn is_encrypted(data: &[u8]) -> bool {
+        data.starts_with(ENCRYPTED_PREFIX)
+    }
+
+    pub fn encrypt(&self, sk: &[u8]) -> Result<Vec<u8>, CloudError> {
+        let cipher = match &self.cipher {
+            Some(cipher) => cipher,
+            None => return Ok(sk.to_vec()),
+        };
+
+        let nonce = rand::random::<[u8; NONCE_LENGTH]>();
+        let ciphertext = cipher
+            .encrypt(Nonce::from_slice(&nonce), sk)
+            .map_err(|_| CloudError::InternalError("failed to encrypt key".to_string()))?;
+
+        let mut data = Vec::with_capacity(ENCRYPTED_PREFIX.len() + NONCE_LENGTH + ciphertext.len());
+        data.extend_from_slice(ENCRYPTED_PREFIX);
+        data.extend_from_slice(&nonce);
+        data.extend_from_slice(&ciphertext);
+        Ok(data)
+    }
+
+    pub fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>, CloudError> {
+        if !Self::is_encrypted(data) {
+            return Ok(data.to_vec());
+        }
+
+        let cipher = self.cipher.as_ref().ok_or_else(|| {
+            tracing::error!("found encrypted key but key encryption is disabled");
+            CloudError::InternalError("failed to decrypt key".to_string())
+        })?;
+
+        let data = &data[ENCRYPTED_PREFIX.len()..];
+        if data.len() < NONCE_LENGTH {
+            return Err(CloudError::InternalError("failed to decrypt key".to_string()));
+        }
+        let (nonce, ciphertext) = data.split_at(NONCE_LENGTH);
+        cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| {
+                tracing::error!("failed to decrypt key, check the master key");
+                CloudError::InternalError("failed to decrypt key".to_string())
+            })
+    }
+}
+
+pub fn parse_public_key(public_key: &str) -> Result<[u8; 32], CloudError> {
+    hex::decode(public_key)
+        .ok()
+        .and_then(|public_key| <[u8; 32]>::try_from(public_key).ok())
+        .ok_or_else(|| CloudError::BadRequest("public key must be a hex encoded 32 byte x25519 key".to_string()))
+}
+
+// Encrypts data for the owner of an x25519 public key. The key of xchacha20poly1305 is
+// sha256(shared secret || ephemeral public key || recipient public key), the result is
+// ephemeral public key (32 bytes) || nonce (24 bytes) || ciphertext
+pub fn seal(public_key: &[u8; 32], data: &[u8]) -> Result<Vec<u8>, CloudError> {
+    let recipient = PublicKey::from(*public_key);
+    let secret = EphemeralSecret::random_from_rng(rand::thread_rng());
+    let ephemeral = PublicKey::from(&secret);
+    let shared = secret.diffie_hellman(&recipient);
+    if !shared.was_contributory() {
+        return Err(CloudError::BadRequest("invalid public key".to_string()));
+    }
+
+    let key = Sha256::new()
+        .chain_update(shared.as_bytes())
+        .chain_update(ephemeral.as_bytes())
+        .chain_update(recipient.as_bytes())
+        .finalize();
+    let cipher = XChaCha20Poly1305::new(Key::from_slice(&key));
+    let nonce = rand::random::<[u8; X_NONCE_LENGTH]>();
+    let ciphertext = cipher
+        .encrypt(XNonce::from_slice(&nonce), data)
+        .map_err(|_| CloudError::InternalError("failed to encrypt key".to_string()))?;
+
+    let mut sealed = Vec::with_capacity(32 + X_NONCE_LENGTH + ciphertext.len());
+    sealed.extend_from_slice(ephemeral.as_bytes());
+    sealed.extend_from_slice(&nonce);
+    sealed.extend_from_slice(&ciphertext);
+    Ok(sealed)
+}
+
+#[cfg(test)]
+mod tests {
+    use chacha20poly1305::{
+        aead::{Aead, NewAead},
+        Key, XChaCha20Poly1305, XNonce,
+    };
+    use sha2::{Digest, Sha256};
+    use x25519_dalek::{EphemeralSecret, PublicKey};
+
+    use super::{parse_public_key, seal, KeyCipher, X_NONCE_LENGTH};
+
+    // The recipient side of seal as a report consumer implements it
+    fn unseal(secret: EphemeralSecret, sealed: &[u8]) -> Option<Vec<u8>> {
+        let recipient = PublicKey::from(&secret);
+        let (ephemeral, rest) = sealed.split_at(32);
+        let (nonce, ciphertext) = rest.split_at(X_NONCE_LENGTH);
+        let ephemeral = PublicKey::from(<[u8; 32]>::try_from(ephemeral).unwrap());
+        let shared = secret.diffie_hellman(&ephemeral);
+        let key = Sha256::new()
+            .chain_update(shared.as_bytes())
+            .chain_update(ephemeral.as_bytes())
+            .chain_update(recipient.as_bytes())
+            .finalize();
+        XChaCha20Poly1305::new(Key::from_slice(&key))
+            .decrypt(XNonce::from_slice(nonce), ciphertext)
+            .ok()
+    }
+
+    fn recipient() -> (EphemeralSecret, [u8; 32]) {
+        let secret = EphemeralSecret::random_from_rng(rand::thread_rng());
+        let public_key = parse_public_key(&hex::encode(PublicKey::from(&secret).as_bytes())).unwrap();
+        (secret, public_key)
+    }
+
+    #[test]
+    fn test_seal_round_trip() {
+        let sk = [7u8; 32];
+        let (secret, public_key) = recipient();
+        let sealed = seal(&public_key, &sk).unwrap();
+        assert_eq!(sealed.len(), 32 + X_NONCE_LENGTH + sk.len() + 16);
+        assert_eq!(unseal(secret, &sealed).unwrap(), sk);
+    }
+
+    #[test]
+    fn test_sealed_key_is_bound_to_recipient() {
+        let sk = [7u8; 32];
+        let (_, public_key) = recipient();
+        let sealed = seal(&public_key, &sk).unwrap();
+        let (other, _) = recipient();
+        assert!(unseal(other, &sealed).is_none());
+
+        let (secret, public_key) = recipient();
+        let mut sealed = seal(&public_key, &sk).unwrap();
+        *sealed.last_mut().unwrap() ^= 1;
+        assert!(unseal(secret, &sealed).is_none());
+    }
+
+    #[test]
+    fn test_low_order_public_key_is_rejected() {
+        assert!(seal(&[0u8; 32], &[7u8; 32]).is_err());
+        assert!(parse_public_key("abcd").is_err());
+    }
+
+    #[test]
+    fn test_encrypt_round_trip() {
+        let cipher = KeyCipher::from_hex_key(&hex::encode([1u8; 32])).unwrap();
+        let sk = [7u8; 32];
+        let encrypted = cipher.encrypt(&sk).unwrap();
+        assert!(KeyCipher::is_encrypted(&encrypted));
+        assert_eq!(cipher.decrypt(&encrypted).unwrap(), sk);
+        // keys stored before encryption was enabled are read as they are
+        assert_eq!(cipher.decrypt(&sk).unwrap(), sk);
+
+        let other = KeyCipher::from_hex_key(&hex::encode([2u8; 32])).unwrap();
+        assert!(other.decrypt(&encrypted).is_err());
+    }
+}
@@ -2,9 +2,11 @@ use std::time::{SystemTime, UNIX_EPOCH};
 
 use libzkbob_rs::libzeropool::fawkes_crypto::ff_uint::Num;
 
-use crate::Fr;
+use crate::{Fr, errors::CloudError};
 
 pub mod db;
+pub mod fault;
+pub mod key_cipher;
 pub mod queue;
 pub mod semaphore;
 
@@ -26,6 +28,81 @@ pub fn timestamp() -> u64 {
         .as_secs()
 }
 
+pub fn check_length(field: &str, value: &str, limit: usize) -> Result<(), CloudError> {
+    if value.len() > limit {
+        return Err(CloudError::FieldTooLong(field.to_string(), limit));
+    }
+    Ok(())
+}
+
+// Keeps only a short prefix so the address can be matched but not reused
+pub fn redact_address(address: &str) -> String {
+    let prefix = address.chars().take(8).collect::<String>();
+    format!("{}...", prefix)
+}
+
+// Cuts the text to at most `limit` characters, a cut text ends with "..."
+pub fn truncate(text: String, limit: usize) -> String {
+    if text.chars().count() <= limit {
+        return text;
+    }
+    let mut truncated = text.chars().take(limit.saturating_sub(3)).collect::<String>();
+    truncated.push_str("...");
+    truncated
+}
+
 pub fn invert<T, E>(x: Option<Result<T, E>>) -> Result<Option<T>, E> {
     x.map_or(Ok(None), |v| v.map(Some))
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::path::PathBuf;
+
+    use uuid::Uuid;
+
+    use super::truncate;
+
+    // Directory of the databases created by a test, it is removed with them when dropped
+    pub(crate) struct TestDir(PathBuf);
+
+    impl TestDir {
+        pub(crate) fn new() -> Self {
+            let path = std::env::temp_dir().join(format!("zkbob-cloud-test-{}", Uuid::new_v4()));
+            std::fs::create_dir_all(&path).unwrap();
+            TestDir(path)
+        }
+
+        // A new empty directory for a database
+        pub(crate) fn db_path(&self) -> String {
+            let path = self.0.join(Uuid::new_v4().to_string());
+            std::fs::create_dir_all(&path).unwrap();
+            path.to_str().unwrap().to_string()
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn test_dir_is_removed() {
+        let dir = TestDir::new();
+        let path = dir.db_path();
+        std::fs::write(format!("{}/data", path), "data").unwrap();
+        let root = dir.0.clone();
+        drop(dir);
+        assert!(!root.exists());
+    }
+
+    #[test]
+    fn test_truncate() {
+        assert_eq!(truncate("short".to_string(), 10), "short");
+        assert_eq!(truncate("0123456789".to_string(), 10), "0123456789");
+        assert_eq!(truncate("0123456789a".to_string(), 10), "0123456...");
+        // multibyte characters are not split
+        assert_eq!(truncate("ééééé".to_string(), 4), "é...");
+    }
+}
@@ -1,20 +1,153 @@
-use std::{time::Duration, sync::Arc};
+use std::{collections::{HashMap, VecDeque}, time::{Duration, Instant}, sync::Arc};
 
-use rsmq_async::{Rsmq, RsmqConnection};
+use async_trait::async_trait;
+use rsmq_async::{Rsmq, RsmqConnection, RsmqOptions};
 use serde::{de::DeserializeOwned, Serialize};
 use tokio::{time, sync::RwLock};
+use uuid::Uuid;
 use zkbob_utils_rs::tracing;
 
-use crate::errors::CloudError;
+use crate::{config::{Config, QueueBackend}, errors::CloudError};
 
+use super::fault::FaultInjector;
+
+// intervals of receive_blocking if the worker doesn't configure them
+pub const DEFAULT_POLL_INTERVAL_MS: u64 = 500;
+pub const DEFAULT_RECONNECT_BACKOFF_MS: u64 = 5000;
+
+#[async_trait]
+pub trait TaskQueue: Send + Sync {
+    // the message becomes visible after `delay` seconds, the queue delay is used if it is not set
+    async fn send(&mut self, message: String, delay: Option<u32>) -> Result<(), CloudError>;
+    async fn receive(&mut self) -> Result<Option<(String, String)>, CloudError>;
+    async fn delete(&mut self, id: &str) -> Result<(), CloudError>;
+    // deletes all messages with the given content, returns the number of deleted messages
+    async fn remove(&mut self, message: String) -> Result<u64, CloudError>;
+    // returns ids and contents of all messages without receiving them
+    async fn list(&mut self) -> Result<Vec<(String, String)>, CloudError>;
+    async fn ping(&mut self) -> Result<(), CloudError>;
+    async fn reconnect(&mut self) -> Result<(), CloudError>;
+    // number of messages in the queue including the ones being processed
+    async fn len(&mut self) -> Result<u64, CloudError>;
+}
+
+// Typed wrapper over the configured queue backend
 pub struct Queue {
+    name: String,
+    backend: Box<dyn TaskQueue>,
+    faults: FaultInjector,
+}
+
+impl Queue {
+    pub async fn new(config: &Config, name: &str, delay: u32, hidden: u32, faults: FaultInjector) -> Result<Self, CloudError> {
+        let name = Self::namespaced(&config.queue_namespace, name)?;
+        let backend: Box<dyn TaskQueue> = match config.queue_backend {
+            QueueBackend::Redis => Box::new(
+                RsmqQueue::new(&config.queue_namespace, &name, &config.redis_url, delay, hidden).await?,
+            ),
+            QueueBackend::Memory => Box::new(MemoryQueue::new(delay, hidden)),
+        };
+        Ok(Queue { name, backend, faults })
+    }
+
+    // rsmq allows only alphanumeric characters, '-' and '_' in queue names
+    fn namespaced(namespace: &str, name: &str) -> Result<String, CloudError> {
+        if namespace.is_empty() {
+            return Ok(name.to_string());
+        }
+        if !namespace.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            return Err(CloudError::ConfigError(format!("invalid queue namespace: {}", namespace)));
+        }
+        Ok(format!("{}-{}", namespace, name))
+    }
+
+    pub async fn reconnect(&mut self) -> Result<(), CloudError> {
+        self.backend.reconnect().await
+    }
+
+    pub async fn send<T: Serialize>(&mut self, item: T) -> Result<(), CloudError> {
+        self.send_with_delay(item, None).await
+    }
+
+    pub async fn send_with_delay<T: Serialize>(&mut self, item: T, delay: Option<u32>) -> Result<(), CloudError> {
+        self.faults.inject("queue.send").await?;
+        let message = serde_json::to_string(&item).map_err(|err| {
+            tracing::error!("failed to serialize task: {}", err);
+            CloudError::InternalError("failed to serialize task".to_string())
+        })?;
+        self.backend.send(message, delay).await
+    }
+
+    pub async fn receive<T: DeserializeOwned>(
+        &mut self,
+    ) -> Result<Option<(String, T)>, CloudError> {
+        self.faults.inject("queue.receive").await?;
+        match self.backend.receive().await? {
+            Some((id, message)) => {
+                let message: T = serde_json::from_str(&message)
+                    .map_err(|err| {
+                        tracing::error!("failed to deserialize message from {} queue: {}", &self.name, err);
+                        CloudError::InternalError(format!("failed to deserialize message from {} queue", &self.name))
+                    })?;
+                Ok(Some((id, message)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    pub async fn delete(&mut self, id: &str) -> Result<(), CloudError> {
+        self.faults.inject("queue.delete").await?;
+        self.backend.delete(id).await
+    }
+
+    pub async fn remove<T: Serialize>(&mut self, item: T) -> Result<u64, CloudError> {
+        self.faults.inject("queue.delete").await?;
+        let message = serde_json::to_string(&item).map_err(|err| {
+            tracing::error!("failed to serialize task: {}", err);
+            CloudError::InternalError("failed to serialize task".to_string())
+        })?;
+        self.backend.remove(message).await
+    }
+
+    // Messages that fail to deserialize are skipped, so one malformed message doesn't hide the others
+    pub async fn list<T: DeserializeOwned>(&mut self) -> Result<Vec<(String, T)>, CloudError> {
+        let messages = self
+            .backend
+            .list()
+            .await?
+            .into_iter()
+            .filter_map(|(id, message)| match serde_json::from_str(&message) {
+                Ok(message) => Some((id, message)),
+                Err(err) => {
+                    tracing::warn!("skipping malformed message {} in {} queue: {}", id, &self.name, err);
+                    None
+                }
+            })
+            .collect();
+        Ok(messages)
+    }
+
+    pub async fn len(&mut self) -> Result<u64, CloudError> {
+        self.backend.len().await
+    }
+
+    pub async fn ping(&mut self) -> Result<(), CloudError> {
+        self.backend.ping().await
+    }
+}
+
+pub struct RsmqQueue {
     name: String,
     redis_url: String,
     rsmq: Rsmq,
+    // namespace of the rsmq keys in redis, the queue name already includes the configured queue_namespace
+    rsmq_namespace: String,
+    // used for the commands rsmq doesn't provide, it is reestablished along with rsmq
+    connection: Option<redis::aio::Connection>,
 }
 
-impl Queue {
-    pub async fn new(name: &str, url: &str, delay: u32, hidden: u32) -> Result<Self, CloudError> {
+impl RsmqQueue {
+    pub async fn new(namespace: &str, name: &str, url: &str, delay: u32, hidden: u32) -> Result<Self, CloudError> {
         let mut rsmq = Self::init_rsmq(url).await?;
 
         let queues = rsmq.list_queues().await.map_err(|err| {
@@ -22,6 +155,12 @@ impl Queue {
             CloudError::InternalError("failed to list redis queues".to_string())
         })?;
 
+        if let Some(legacy_name) = name.strip_prefix(&format!("{}-", namespace)) {
+            if !namespace.is_empty() && queues.contains(&legacy_name.to_string()) {
+                Self::warn_legacy_queue(&mut rsmq, legacy_name).await;
+            }
+        }
+
         if !queues.contains(&name.to_string()) {
             rsmq.create_queue(name, Some(hidden), Some(delay), None)
                 .await
@@ -38,25 +177,75 @@ impl Queue {
                 })?;
         }
 
-        Ok(Queue {
+        Ok(RsmqQueue {
             name: name.to_string(),
             redis_url: url.to_string(),
             rsmq,
+            rsmq_namespace: RsmqOptions::default().ns,
+            connection: Some(Self::connect(url).await?),
         })
     }
 
-    pub async fn reconnect(&mut self) -> Result<(), CloudError> {
-        self.rsmq = Self::init_rsmq(&self.redis_url).await?;
-        Ok(())
+    // Tasks left in an unprefixed queue are not processed after a namespace is set
+    async fn warn_legacy_queue(rsmq: &mut Rsmq, name: &str) {
+        match rsmq.get_queue_attributes(name).await {
+            Ok(attributes) if attributes.msgs > 0 => tracing::warn!(
+                "legacy queue {} still contains {} messages, they must be migrated to the namespaced queue",
+                name,
+                attributes.msgs
+            ),
+            Ok(_) => {}
+            Err(err) => tracing::warn!("failed to check legacy queue {}: {}", name, err),
+        }
     }
 
-    pub async fn send<T: Serialize>(&mut self, item: T) -> Result<(), CloudError> {
-        let message = serde_json::to_string(&item).map_err(|err| {
-            tracing::error!("failed to serialize task: {}", err);
-            CloudError::InternalError("failed to serialize task".to_string())
+    async fn init_rsmq(url: &str) -> Result<Rsmq, CloudError> {
+        let connection = Self::connect(url).await?;
+        Ok(Rsmq::new_with_connection(RsmqOptions::default(), connection))
+    }
+
+    // rsmq can't search messages, so the message hash of the queue is read directly
+    async fn messages(&mut self) -> Result<Vec<(String, String)>, CloudError> {
+        let mut cmd = redis::cmd("HGETALL");
+        cmd.arg(messages_key(&self.rsmq_namespace, &self.name));
+        let action = format!("read messages of {} queue", &self.name);
+        let messages: HashMap<String, String> = self.query(&cmd, &action).await?;
+        Ok(queued_messages(messages))
+    }
+
+    // The connection is taken for the command and put back only when the command completes,
+    // so a command cancelled by a timeout doesn't leave its reply for the next one
+    async fn query<T: redis::FromRedisValue + Send>(&mut self, cmd: &redis::Cmd, action: &str) -> Result<T, CloudError> {
+        let mut connection = match self.connection.take() {
+            Some(connection) => connection,
+            None => Self::connect(&self.redis_url).await?,
+        };
+        let value = cmd.query_async(&mut connection).await.map_err(|err| {
+            tracing::warn!("failed to {}: {}", action, err);
+            CloudError::InternalError(format!("failed to {}", action))
         })?;
+        self.connection = Some(connection);
+        Ok(value)
+    }
+
+    async fn connect(url: &str) -> Result<redis::aio::Connection, CloudError> {
+        let client = redis::Client::open(url).map_err(|err| {
+            tracing::error!("failed to connect to redis: {}", err);
+            CloudError::InternalError("failed to connect to redis".to_string())
+        })?;
+
+        client.get_async_connection().await.map_err(|err| {
+            tracing::error!("failed to connect to redis: {}", err);
+            CloudError::InternalError("failed to connect to redis".to_string())
+        })
+    }
+}
+
+#[async_trait]
+impl TaskQueue for RsmqQueue {
+    async fn send(&mut self, message: String, delay: Option<u32>) -> Result<(), CloudError> {
         self.rsmq
-            .send_message(&self.name, message, None)
+            .send_message(&self.name, message, delay.map(|delay| delay as u64))
             .await
             .map_err(|err| {
                 tracing::error!("failed to send message to {} queue: {}", &self.name, err);
@@ -65,9 +254,7 @@ impl Queue {
         Ok(())
     }
 
-    pub async fn receive<T: DeserializeOwned>(
-        &mut self,
-    ) -> Result<Option<(String, T)>, CloudError> {
+    async fn receive(&mut self) -> Result<Option<(String, String)>, CloudError> {
         let message = self
             .rsmq
             .receive_message::<String>(&self.name, None)
@@ -76,22 +263,10 @@ impl Queue {
                 tracing::error!("failed to receive message from {} queue: {}", &self.name, err);
                 CloudError::InternalError(format!("failed to receive message from {} queue", &self.name))
             })?;
-
-        match message {
-            Some(message) => {
-                let id = message.id;
-                let message: T = serde_json::from_str(&message.message)
-                    .map_err(|err| {
-                        tracing::error!("failed to deserialize message from {} queue: {}", &self.name, err);
-                        CloudError::InternalError(format!("failed to deserialize message from {} queue", &self.name))
-                    })?;
-                Ok(Some((id, message)))
-            }
-            None => Ok(None),
-        }
+        Ok(message.map(|message| (message.id, message.message)))
     }
 
-    pub async fn delete(&mut self, id: &str) -> Result<(), CloudError> {
+    async fn delete(&mut self, id: &str) -> Result<(), CloudError> {
         self.rsmq
             .delete_message(&self.name, id)
             .await
@@ -102,23 +277,157 @@ impl Queue {
         Ok(())
     }
 
-    async fn init_rsmq(url: &str) -> Result<Rsmq, CloudError> {
-        let client = redis::Client::open(url).map_err(|err| {
-            tracing::error!("failed to connect to redis: {}", err);
-            CloudError::InternalError("failed to connect to redis".to_string())
-        })?;
+    async fn remove(&mut self, message: String) -> Result<u64, CloudError> {
+        let messages = self.messages().await?;
 
-        let connection = client.get_async_connection().await.map_err(|err| {
-            tracing::error!("failed to connect to redis: {}", err);
-            CloudError::InternalError("failed to connect to redis".to_string())
-        })?;
+        let mut removed = 0;
+        for (id, _) in messages.iter().filter(|(_, value)| *value == message) {
+            self.delete(id).await?;
+            removed += 1;
+        }
+        Ok(removed)
+    }
 
-        Ok(Rsmq::new_with_connection(Default::default(), connection))
+    async fn list(&mut self) -> Result<Vec<(String, String)>, CloudError> {
+        let mut messages = self.messages().await?;
+        // rsmq ids start with the base36 send time, so they sort in the sending order
+        messages.sort();
+        Ok(messages)
+    }
+
+    async fn ping(&mut self) -> Result<(), CloudError> {
+        self.query::<String>(&redis::cmd("PING"), "ping redis").await?;
+        Ok(())
+    }
+
+    async fn reconnect(&mut self) -> Result<(), CloudError> {
+        self.rsmq = Self::init_rsmq(&self.redis_url).await?;
+        self.connection = Some(Self::connect(&self.redis_url).await?);
+        Ok(())
+    }
+
+    async fn len(&mut self) -> Result<u64, CloudError> {
+        let attributes = self
+            .rsmq
+            .get_queue_attributes(&self.name)
+            .await
+            .map_err(|err| {
+                tracing::error!("failed to get {} queue attributes: {}", &self.name, err);
+                CloudError::InternalError(format!("failed to get {} queue attributes", &self.name))
+            })?;
+        Ok(attributes.msgs)
     }
 }
 
+// Key of the rsmq hash with the messages of the queue
+fn messages_key(rsmq_namespace: &str, name: &str) -> String {
+    format!("{}:{}:Q", rsmq_namespace, name)
+}
+
+// The hash holds the message ids with their content along with `{id}:rc` and `{id}:fr` counters
+fn queued_messages(hash: HashMap<String, String>) -> Vec<(String, String)> {
+    hash.into_iter().filter(|(id, _)| !id.contains(':')).collect()
+}
+
+struct MemoryMessage {
+    id: String,
+    message: String,
+    visible_at: Instant,
+}
+
+// Single-node queue with the same delay and visibility semantics as rsmq,
+// messages are lost on restart
+pub struct MemoryQueue {
+    delay: Duration,
+    hidden: Duration,
+    messages: VecDeque<MemoryMessage>,
+}
+
+impl MemoryQueue {
+    pub fn new(delay: u32, hidden: u32) -> Self {
+        MemoryQueue {
+            delay: Duration::from_secs(delay as u64),
+            hidden: Duration::from_secs(hidden as u64),
+            messages: VecDeque::new(),
+        }
+    }
+}
+
+#[async_trait]
+impl TaskQueue for MemoryQueue {
+    async fn send(&mut self, message: String, delay: Option<u32>) -> Result<(), CloudError> {
+        let delay = delay.map(|delay| Duration::from_secs(delay as u64)).unwrap_or(self.delay);
+        self.messages.push_back(MemoryMessage {
+            id: Uuid::new_v4().to_string(),
+            message,
+            visible_at: Instant::now() + delay,
+        });
+        Ok(())
+    }
+
+    async fn receive(&mut self) -> Result<Option<(String, String)>, CloudError> {
+        let now = Instant::now();
+        let hidden = self.hidden;
+        Ok(self
+            .messages
+            .iter_mut()
+            .find(|message| message.visible_at <= now)
+            .map(|message| {
+                message.visible_at = now + hidden;
+                (message.id.clone(), message.message.clone())
+            }))
+    }
+
+    async fn delete(&mut self, id: &str) -> Result<(), CloudError> {
+        self.messages.retain(|message| message.id != id);
+        Ok(())
+    }
+
+    async fn remove(&mut self, message: String) -> Result<u64, CloudError> {
+        let len = self.messages.len();
+        self.messages.retain(|queued| queued.message != message);
+        Ok((len - self.messages.len()) as u64)
+    }
+
+    async fn list(&mut self) -> Result<Vec<(String, String)>, CloudError> {
+        Ok(self
+            .messages
+            .iter()
+            .map(|message| (message.id.clone(), message.message.clone()))
+            .collect())
+    }
+
+    async fn ping(&mut self) -> Result<(), CloudError> {
+        Ok(())
+    }
+
+    async fn reconnect(&mut self) -> Result<(), CloudError> {
+        Ok(())
+    }
+
+    async fn len(&mut self) -> Result<u64, CloudError> {
+        Ok(self.messages.len() as u64)
+    }
+}
+
+// Sends a delayed copy of the task and deletes the received one, so the task is retried after the delay
+// instead of the queue hidden interval, the task can be processed twice if the deletion fails
+pub async fn requeue_with_delay<T: Serialize>(
+    queue: &RwLock<Queue>,
+    id: &str,
+    item: T,
+    delay: u32,
+) -> Result<(), CloudError> {
+    let mut queue = queue.write().await;
+    queue.send_with_delay(item, Some(delay)).await?;
+    queue.delete(id).await
+}
+
+// Polls the queue every `poll_interval` while it is empty, a failed reconnect is retried after `reconnect_backoff`
 pub async fn receive_blocking<T: DeserializeOwned>(
     queue: Arc<RwLock<Queue>>,
+    poll_interval: Duration,
+    reconnect_backoff: Duration,
 ) -> (String, T) {
     loop {
         let task = {
@@ -129,16 +438,62 @@ pub async fn receive_blocking<T: DeserializeOwned>(
                 return task;
             },
             Ok(None) => {
-                time::sleep(Duration::from_millis(500)).await;
+                time::sleep(poll_interval).await;
             },
             Err(_) => {
                 match queue.write().await.reconnect().await {
                     Ok(_) => tracing::info!("connection to redis reestablished"),
                     Err(_) => {
-                        time::sleep(Duration::from_millis(5000)).await;
+                        time::sleep(reconnect_backoff).await;
                     }
                 }
             }
         };
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use rsmq_async::RsmqOptions;
+
+    use super::{messages_key, queued_messages, MemoryQueue, Queue, TaskQueue};
+
+    #[test]
+    fn messages_key_includes_queue_namespace() {
+        let name = Queue::namespaced("staging", "send").unwrap();
+        assert_eq!(messages_key(&RsmqOptions::default().ns, &name), "rsmq:staging-send:Q");
+        let name = Queue::namespaced("", "send").unwrap();
+        assert_eq!(messages_key(&RsmqOptions::default().ns, &name), "rsmq:send:Q");
+    }
+
+    #[test]
+    fn counters_are_not_messages() {
+        let hash = HashMap::from([
+            ("abc".to_string(), "message".to_string()),
+            ("abc:rc".to_string(), "1".to_string()),
+            ("abc:fr".to_string(), "1700000000".to_string()),
+        ]);
+        assert_eq!(queued_messages(hash), vec![("abc".to_string(), "message".to_string())]);
+    }
+
+    #[actix_web::test]
+    async fn memory_queue_removes_all_copies() {
+        let mut queue = MemoryQueue::new(0, 0);
+        queue.send("a".to_string(), None).await.unwrap();
+        queue.send("b".to_string(), None).await.unwrap();
+        queue.send("a".to_string(), None).await.unwrap();
+        assert_eq!(queue.remove("a".to_string()).await.unwrap(), 2);
+        let messages = queue.list().await.unwrap().into_iter().map(|(_, message)| message).collect::<Vec<_>>();
+        assert_eq!(messages, vec!["b".to_string()]);
+    }
+
+    #[actix_web::test]
+    async fn memory_queue_is_always_healthy() {
+        let mut queue = MemoryQueue::new(0, 0);
+        assert!(queue.ping().await.is_ok());
+        assert!(queue.reconnect().await.is_ok());
+        assert!(queue.ping().await.is_ok());
+    }
+}
@@ -1,10 +1,13 @@
-use std::{sync::Arc, collections::HashSet};
+use std::{sync::{Arc, Mutex}, collections::HashSet, time::{Duration, Instant}};
 
 use tokio::sync::{RwLock, SemaphorePermit, Semaphore, TryAcquireError};
 
 pub struct TaskSemaphore {
     in_progress: Arc<RwLock<HashSet<String>>>,
-    semaphore: Semaphore
+    semaphore: Semaphore,
+    permits: usize,
+    // set when the last permit is taken, cleared when any permit is released
+    saturated_since: Arc<Mutex<Option<Instant>>>,
 }
 
 impl TaskSemaphore {
@@ -12,6 +15,8 @@ impl TaskSemaphore {
         TaskSemaphore {
             in_progress: Arc::new(RwLock::new(HashSet::new())),
             semaphore: Semaphore::new(permits),
+            permits,
+            saturated_since: Arc::new(Mutex::new(None)),
         }
     }
 
@@ -24,27 +29,55 @@ impl TaskSemaphore {
         let permit = self.semaphore.try_acquire()?;
         in_progress.insert(id.to_string());
 
+        if self.semaphore.available_permits() == 0 {
+            if let Ok(mut saturated_since) = self.saturated_since.lock() {
+                saturated_since.get_or_insert_with(Instant::now);
+            }
+        }
+
         Ok(TaskSemaphorePermit {
             id: id.to_string(),
             in_progress: self.in_progress.clone(),
+            saturated_since: self.saturated_since.clone(),
             permit,
         })
     }
+
+    pub async fn is_in_progress(&self, id: &str) -> bool {
+        self.in_progress.read().await.contains(id)
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.permits - self.semaphore.available_permits()
+    }
+
+    pub fn saturated_for(&self) -> Option<Duration> {
+        self.saturated_since
+            .lock()
+            .ok()
+            .and_then(|saturated_since| *saturated_since)
+            .map(|saturated_since| saturated_since.elapsed())
+    }
 }
 
 pub struct TaskSemaphorePermit<'a> {
     id: String,
     in_progress: Arc<RwLock<HashSet<String>>>,
+    saturated_since: Arc<Mutex<Option<Instant>>>,
     #[allow(dead_code)]
     permit: SemaphorePermit<'a>
 }
 
 impl Drop for TaskSemaphorePermit<'_> {
     fn drop(&mut self) {
+        if let Ok(mut saturated_since) = self.saturated_since.lock() {
+            *saturated_since = None;
+        }
+
         let id = self.id.clone();
         let in_progress = self.in_progress.clone();
         tokio::spawn(async move {
             in_progress.write().await.remove(&id);
         });
     }
-}
\ No newline at end of file
+}
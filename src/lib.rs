@@ -10,6 +10,8 @@ pub mod web3;
 pub mod routes;
 pub mod version;
 pub mod types;
+#[cfg(feature = "client")]
+pub mod client;
 
 pub type PoolParams = PoolBN256;
 pub type Engine = Bn256;
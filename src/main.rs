@@ -1,13 +1,16 @@
 use actix_cors::Cors;
 use actix_web::{web::{JsonConfig, get, post, Data}, App, middleware::Logger, HttpServer, HttpResponse};
 use libzkbob_rs::libzeropool::{fawkes_crypto::backend::bellman_groth16::Parameters};
-use zkbob_cloud::{Engine, config::Config, errors::CloudError, version, cloud::ZkBobCloud, routes::{signup, account_info, list_accounts, generate_shielded_address, history, transfer, transaction_status, calculate_fee, export_key, transaction_trace, generate_report, report, clean_reports, import, delete_account}};
+use sha2::{Digest, Sha256};
+use zkbob_cloud::{Engine, config::Config, errors::CloudError, version, cloud::ZkBobCloud, routes::{signup, account_info, list_accounts, generate_shielded_address, history, transfer, transaction_status, calculate_fee, export_key, transaction_trace, generate_report, report, clean_reports, import, delete_account, fault_rules, set_fault_rule, circuit_info, support_bundle, generate_shielded_addresses, repair_account, sync_account, receipt, transfers_by_support_id, export_all, retry_budget, service_status, evict_account, reconcile, reconcile_status, withdraw, cancel_transfer, metrics, health, sync_status, failed_transfers, requeue_transfer, resync_account, resync_status, list_reports}};
 use zkbob_utils_rs::{telemetry::telemetry, contracts::pool::Pool, tracing};
 
-pub fn get_params(path: &str) -> Parameters<Engine> {
+pub fn get_params(path: &str) -> (Parameters<Engine>, String) {
     let data = std::fs::read(path).expect("failed to read file with snark params");
-    Parameters::<Engine>::read(&mut data.as_slice(), true, true)
-        .expect("failed to parse file with snark params")
+    let hash = hex::encode(Sha256::digest(&data));
+    let params = Parameters::<Engine>::read(&mut data.as_slice(), true, true)
+        .expect("failed to parse file with snark params");
+    (params, hash)
 }
 
 #[actix_web::main]
@@ -15,7 +18,8 @@ async fn main() -> std::io::Result<()> {
     let config = Data::new(Config::get().expect("failed to parse config"));
     telemetry::setup(&config.telemetry);
 
-    let params = get_params(&config.transfer_params_path);
+    let (params, params_hash) = get_params(&config.transfer_params_path);
+    tracing::info!("params hash: {}", params_hash);
     let pool = Pool::new(&config.web3).expect("failed to init pool");
     let pool_id = pool.pool_id().await.expect("failed to get pool_id from contract");
     tracing::info!("pool_id: {}", pool_id);
@@ -23,7 +27,7 @@ async fn main() -> std::io::Result<()> {
     let host = config.host.clone();
     let port = config.port;
 
-    let cloud = ZkBobCloud::new(config.clone(), pool, pool_id, params).await.expect("failed to init cloud");
+    let cloud = ZkBobCloud::new(config.clone(), pool, pool_id, params, params_hash).await.expect("failed to init cloud");
 
     tracing::info!(
         "starting webserver at http://{}:{}",
@@ -49,21 +53,46 @@ async fn main() -> std::io::Result<()> {
             .app_data(config.clone())
             .route("/", get().to(HttpResponse::Ok))
             .route("/version", get().to(version::version))
+            .route("/circuitInfo", get().to(circuit_info))
+            .route("/health", get().to(health))
+            .route("/metrics", get().to(metrics))
             .route("/signup", post().to(signup))
             .route("/import", post().to(import))
             .route("deleteAccount", post().to(delete_account))
+            .route("/evictAccount", post().to(evict_account))
             .route("/accounts", get().to(list_accounts))
             .route("/transactionTrace", get().to(transaction_trace))
             .route("/export", get().to(export_key))
+            .route("/exportAll", get().to(export_all))
             .route("/generateReport", post().to(generate_report))
             .route("/report", get().to(report))
+            .route("/reports", get().to(list_reports))
             .route("/cleanReports", post().to(clean_reports))
+            .route("/reconcile", post().to(reconcile))
+            .route("/reconcile", get().to(reconcile_status))
             .route("/account", get().to(account_info))
+            .route("/sync", post().to(sync_account))
+            .route("/syncStatus", get().to(sync_status))
             .route("/generateAddress", get().to(generate_shielded_address))
+            .route("/generateAddresses", get().to(generate_shielded_addresses))
             .route("/history", get().to(history))
             .route("/transfer", post().to(transfer))
+            .route("/withdraw", post().to(withdraw))
+            .route("/cancelTransfer", post().to(cancel_transfer))
+            .route("/transfer/retryBudget", post().to(retry_budget))
             .route("/transactionStatus", get().to(transaction_status))
             .route("/calculateFee", get().to(calculate_fee))
+            .route("/admin/supportBundle", get().to(support_bundle))
+            .route("/admin/repairAccount", post().to(repair_account))
+            .route("/admin/resyncAccount", post().to(resync_account))
+            .route("/admin/resyncStatus", get().to(resync_status))
+            .route("/admin/receipt", get().to(receipt))
+            .route("/admin/failedTransfers", get().to(failed_transfers))
+            .route("/admin/requeueTransfer", post().to(requeue_transfer))
+            .route("/admin/transfersBySupportId", get().to(transfers_by_support_id))
+            .route("/admin/status", get().to(service_status))
+            .route("/admin/faults", get().to(fault_rules))
+            .route("/admin/faults", post().to(set_fault_rule))
     })
     .bind((host, port))?
     .run()
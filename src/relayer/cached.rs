@@ -1,15 +1,18 @@
-use libzkbob_rs::libzeropool::fawkes_crypto::ff_uint::{Num, NumRepr, Uint};
+use std::{future::Future, sync::atomic::{AtomicUsize, Ordering}, time::{Duration, Instant}};
+
+use libzkbob_rs::libzeropool::{constants, fawkes_crypto::ff_uint::{Num, NumRepr, Uint}};
 use serde::{Deserialize, Serialize};
 use tokio::sync::RwLock;
 use zkbob_utils_rs::{
     relayer::{
         client::RelayerClient,
+        error::RelayerError,
         types::{InfoResponse, JobResponse, TransactionRequest, TransactionResponse},
     },
     tracing,
 };
 
-use crate::{errors::CloudError, Fr};
+use crate::{errors::CloudError, helpers::fault::FaultInjector, Fr};
 
 use super::db::Db;
 
@@ -22,38 +25,304 @@ pub struct Transaction {
     pub optimistic: bool,
 }
 
+impl Transaction {
+    // The relayer returns a transaction as the mined flag followed by hex encoded
+    // tx hash (32 bytes), commitment (32 bytes) and memo
+    pub fn try_from_relayer_string(index: u64, tx: &str) -> Result<Self, CloudError> {
+        let malformed = |reason: String| {
+            tracing::warn!("malformed relayer transaction at index {}: {}", index, reason);
+            CloudError::MalformedRelayerTransaction(index, reason)
+        };
+
+        if !tx.is_ascii() {
+            return Err(malformed("non-ascii characters".to_string()));
+        }
+        if tx.len() < 129 {
+            return Err(malformed(format!("expected at least 129 characters, got {}", tx.len())));
+        }
+
+        let optimistic = &tx[0..1] != "1";
+        let tx_hash = &tx[1..65];
+        hex::decode(tx_hash).map_err(|err| malformed(format!("invalid tx hash: {}", err)))?;
+        let commitment = hex::decode(&tx[65..129])
+            .map_err(|err| malformed(format!("invalid commitment: {}", err)))?;
+        let memo = hex::decode(&tx[129..]).map_err(|err| malformed(format!("invalid memo: {}", err)))?;
+
+        Ok(Transaction {
+            index,
+            memo,
+            commitment: Num::from_uint_reduced(NumRepr(Uint::from_big_endian(&commitment))),
+            tx_hash: format!("0x{}", tx_hash),
+            optimistic,
+        })
+    }
+}
+
+// Indices reported by the relayer /info
+#[derive(Debug, Clone, Copy)]
+pub struct RelayerInfo {
+    pub delta_index: u64,
+    pub optimistic_delta_index: u64,
+}
+
+impl From<InfoResponse> for RelayerInfo {
+    fn from(info: InfoResponse) -> Self {
+        RelayerInfo {
+            delta_index: info.delta_index,
+            optimistic_delta_index: info.optimistic_delta_index,
+        }
+    }
+}
+
+// Keeps a value for the ttl, concurrent callers wait for the running fetch and reuse its response
+pub(crate) struct TtlCache<T> {
+    value: RwLock<Option<(T, Instant)>>,
+    ttl: Duration,
+}
+
+impl<T: Copy> TtlCache<T> {
+    pub(crate) fn new(ttl: Duration) -> Self {
+        TtlCache {
+            value: RwLock::new(None),
+            ttl,
+        }
+    }
+
+    // `force` skips the cached value and always fetches a new one
+    pub(crate) async fn get<F, Fut>(&self, force: bool, fetch: F) -> Result<T, CloudError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<T, CloudError>>,
+    {
+        if !force {
+            if let Some(value) = self.fresh(&*self.value.read().await) {
+                return Ok(value);
+            }
+        }
+
+        let mut cached = self.value.write().await;
+        if !force {
+            if let Some(value) = self.fresh(&cached) {
+                return Ok(value);
+            }
+        }
+        let value = fetch().await?;
+        *cached = Some((value, Instant::now()));
+        Ok(value)
+    }
+
+    fn fresh(&self, cached: &Option<(T, Instant)>) -> Option<T> {
+        match cached {
+            Some((value, fetched_at)) if fetched_at.elapsed() < self.ttl => Some(*value),
+            _ => None,
+        }
+    }
+}
+
 pub struct CachedRelayerClient {
-    client: RelayerClient,
+    clients: Vec<(String, RelayerClient)>,
+    // index of the last relayer that responded, requests start from it
+    current: AtomicUsize,
     db: RwLock<Db>,
+    max_index_lag: u64,
+    // last /info response
+    info: TtlCache<RelayerInfo>,
+    http: reqwest::Client,
+    faults: FaultInjector,
+}
+
+// The request failed before any byte reached the relayer
+fn is_not_sent(err: &reqwest::Error) -> bool {
+    err.is_connect() || err.is_builder()
+}
+
+// Fees of the transaction types are reported as {"fee": {"transfer": .., "withdrawal": ..}},
+// the amounts are numbers or decimal strings
+fn parse_withdrawal_fee(response: &serde_json::Value) -> Option<u64> {
+    match &response["fee"]["withdrawal"] {
+        serde_json::Value::Number(fee) => fee.as_u64(),
+        serde_json::Value::String(fee) => fee.parse().ok(),
+        _ => None,
+    }
 }
 
 impl CachedRelayerClient {
-    pub fn new(relayer_url: &str, db_path: &str) -> Result<Self, CloudError> {
-        let client = RelayerClient::new(relayer_url)?;
+    pub fn new(
+        relayer_urls: &[String],
+        db_path: &str,
+        max_index_lag: u64,
+        info_ttl: Duration,
+        faults: FaultInjector,
+    ) -> Result<Self, CloudError> {
+        if relayer_urls.is_empty() {
+            return Err(CloudError::ConfigError("relayer url is not set".to_string()));
+        }
+        let mut clients = Vec::new();
+        for url in relayer_urls {
+            clients.push((url.clone(), RelayerClient::new(url)?));
+        }
         let db = Db::new(db_path)?;
         Ok(CachedRelayerClient {
-            client,
+            clients,
+            current: AtomicUsize::new(0),
             db: RwLock::new(db),
+            max_index_lag,
+            info: TtlCache::new(info_ttl),
+            http: reqwest::Client::new(),
+            faults,
         })
     }
 
-    pub async fn info(&self) -> Result<InfoResponse, CloudError> {
-        Ok(self.client.info().await?)
+    // Tries the relayers in order starting from the last one that responded
+    async fn call<'a, T, F, Fut>(&'a self, method: &str, f: F) -> Result<T, CloudError>
+    where
+        F: Fn(&'a RelayerClient) -> Fut,
+        Fut: Future<Output = Result<T, RelayerError>>,
+    {
+        self.failover(method, |_, client| f(client)).await
+    }
+
+    // Same as `call` for requests that aren't covered by the relayer client
+    async fn failover<'a, T, E, F, Fut>(&'a self, method: &str, f: F) -> Result<T, CloudError>
+    where
+        E: std::fmt::Debug + Into<CloudError>,
+        F: Fn(&'a str, &'a RelayerClient) -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        let start = self.current.load(Ordering::Relaxed);
+        let mut last_err = None;
+        for i in 0..self.clients.len() {
+            let index = (start + i) % self.clients.len();
+            let (url, client) = &self.clients[index];
+            match f(url, client).await {
+                Ok(result) => {
+                    if index != start {
+                        tracing::warn!("switched to relayer {}", url);
+                        self.current.store(index, Ordering::Relaxed);
+                    }
+                    return Ok(result);
+                }
+                Err(err) => {
+                    if self.clients.len() > 1 {
+                        tracing::warn!("relayer {} failed to process {}: {:?}", url, method, err);
+                    }
+                    last_err = Some(err);
+                }
+            }
+        }
+        Err(last_err.expect("relayer list is not empty").into())
+    }
+
+    // Number of transactions between the account and the relayer index. The relayer can be slightly
+    // behind after a rollback of its optimistic state, a larger lag usually means that the account db
+    // belongs to another pool or deployment
+    pub fn tx_count(&self, account_index: u64, relayer_index: u64) -> Result<u64, CloudError> {
+        if relayer_index < account_index {
+            tracing::warn!("relayer index {} is behind the account index {}", relayer_index, account_index);
+            if account_index - relayer_index > self.max_index_lag {
+                return Err(CloudError::RelayerBehindLocalState(relayer_index, account_index));
+            }
+            return Ok(0);
+        }
+        Ok((relayer_index - account_index) / (constants::OUT as u64 + 1))
+    }
+
+    pub async fn info(&self) -> Result<RelayerInfo, CloudError> {
+        self.get_info(false).await
+    }
+
+    // Returns the cached info if it is younger than the ttl, `force` always fetches it from the relayer.
+    // Transactions must be built against the forced info: a stale optimistic index leaves out
+    // the latest optimistic transactions and the proof is built on an outdated state
+    pub async fn get_info(&self, force: bool) -> Result<RelayerInfo, CloudError> {
+        self.info
+            .get(force, || async {
+                self.faults.inject("relayer.info").await?;
+                Ok(RelayerInfo::from(self.call("info", |client| client.info()).await?))
+            })
+            .await
     }
 
     pub async fn fee(&self) -> Result<u64, CloudError> {
-        Ok(self.client.fee().await?)
+        self.faults.inject("relayer.fee").await?;
+        self.call("fee", |client| client.fee()).await
     }
 
-    pub async fn job(&self, id: &str) -> Result<JobResponse, CloudError> {
-        Ok(self.client.job(id).await?)
+    // The withdrawal fee is fetched on every withdrawal instead of reusing the cached transfer fee.
+    // The relayer client only knows the single fee, so /fee is requested directly. Relayers that don't
+    // price transaction types separately can't be used for withdrawals: the transfer fee may not cover them
+    pub async fn withdrawal_fee(&self) -> Result<u64, CloudError> {
+        self.faults.inject("relayer.withdrawal_fee").await?;
+        self.failover("withdrawal fee", |url, _| async move {
+            let response = self.fetch_fee(url).await.map_err(|err| {
+                tracing::warn!("relayer {} failed to process fee: {}", url, err);
+                CloudError::RelayerSendError
+            })?;
+            parse_withdrawal_fee(&response).ok_or(CloudError::WithdrawalFeeUnavailable)
+        })
+        .await
     }
 
+    async fn fetch_fee(&self, url: &str) -> Result<serde_json::Value, reqwest::Error> {
+        self.http
+            .get(format!("{}/fee", url.trim_end_matches('/')))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await
+    }
+
+    // Job ids are issued by the relayer that accepted the transaction, other relayers don't know them.
+    // Jobs of parts sent before the relayer was recorded are looked up on every relayer
+    pub async fn job(&self, id: &str, relayer_url: Option<&str>) -> Result<JobResponse, CloudError> {
+        self.faults.inject("relayer.job").await?;
+        match relayer_url.and_then(|url| self.clients.iter().find(|(client_url, _)| client_url == url)) {
+            Some((_, client)) => Ok(client.job(id).await?),
+            None => self.call("job", |client| client.job(id)).await,
+        }
+    }
+
+    // Returns the response and the url of the relayer that accepted the transaction. The next relayer is
+    // tried only when the request didn't reach the previous one, otherwise the transaction could be relayed twice
     pub async fn send_transactions(
         &self,
         request: Vec<TransactionRequest>,
-    ) -> Result<TransactionResponse, CloudError> {
-        Ok(self.client.send_transactions(request).await?)
+    ) -> Result<(TransactionResponse, String), CloudError> {
+        self.faults.inject("relayer.send_transactions").await?;
+        let start = self.current.load(Ordering::Relaxed);
+        for i in 0..self.clients.len() {
+            let index = (start + i) % self.clients.len();
+            let (url, _) = &self.clients[index];
+            match self.post_transactions(url, &request).await {
+                Ok(response) => {
+                    if index != start {
+                        tracing::warn!("switched to relayer {}", url);
+                        self.current.store(index, Ordering::Relaxed);
+                    }
+                    return Ok((response, url.clone()));
+                }
+                Err(err) if is_not_sent(&err) && i + 1 < self.clients.len() => {
+                    tracing::warn!("relayer {} is unreachable, sending to the next one: {}", url, err);
+                }
+                Err(err) => {
+                    tracing::warn!("relayer {} failed to process send_transactions: {}", url, err);
+                    return Err(CloudError::RelayerSendError);
+                }
+            }
+        }
+        Err(CloudError::RelayerSendError)
+    }
+
+    async fn post_transactions(&self, url: &str, request: &[TransactionRequest]) -> Result<TransactionResponse, reqwest::Error> {
+        self.http
+            .post(format!("{}/sendTransactions", url.trim_end_matches('/')))
+            .json(request)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await
     }
 
     pub async fn transactions(
@@ -62,6 +331,7 @@ impl CachedRelayerClient {
         limit: u64,
         with_optimistic: bool,
     ) -> Result<Vec<Transaction>, CloudError> {
+        self.faults.inject("relayer.transactions").await?;
         let cached = {
             let db = self.db.read().await;
             db.get_txs(offset, limit)
@@ -73,27 +343,13 @@ impl CachedRelayerClient {
             return Ok(cached);
         }
 
-        let fetched = self.client.transactions(offset, limit).await?;
+        let fetched = self.call("transactions", |client| client.transactions(offset, limit)).await?;
 
+        // a malformed transaction fails the whole request: skipping it would leave a gap in the tree
         let mut result = cached;
         for (i, tx) in fetched.into_iter().enumerate() {
-            let index = offset + i as u64 * 128;
-            let optimistic = &tx[0..1] != "1";
-            let tx_hash = format!("0x{}", &tx[1..65]);
-            let commitment: Num<Fr> = Num::from_uint_reduced(NumRepr(Uint::from_big_endian(
-                &hex::decode(&tx[65..129])?,
-            )));
-            let memo = hex::decode(&tx[129..])?;
-
-            let tx = Transaction {
-                index,
-                memo,
-                commitment,
-                tx_hash,
-                optimistic,
-            };
-
-            if with_optimistic || !optimistic {
+            let tx = Transaction::try_from_relayer_string(offset + i as u64 * 128, &tx)?;
+            if with_optimistic || !tx.optimistic {
                 result.push(tx);
             }
         }
@@ -107,3 +363,199 @@ impl CachedRelayerClient {
         Ok(result)
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::sync::{atomic::{AtomicUsize, Ordering}, Arc};
+
+    use futures::future::join_all;
+
+    use crate::helpers::tests::TestDir;
+
+    use super::*;
+
+    // The relayer is unreachable, only the seeded info can be read
+    pub(crate) async fn test_relayer(dir: &TestDir, max_index_lag: u64, delta_index: u64) -> CachedRelayerClient {
+        let relayer = CachedRelayerClient::new(
+            &["http://127.0.0.1:1".to_string()],
+            &dir.db_path(),
+            max_index_lag,
+            Duration::from_secs(60),
+            FaultInjector::default(),
+        )
+        .unwrap();
+        let info = RelayerInfo { delta_index, optimistic_delta_index: delta_index };
+        relayer.info.get(true, || async { Ok(info) }).await.unwrap();
+        relayer
+    }
+
+    fn relayer_string(mined: bool, memo: &str) -> String {
+        format!("{}{}{}{}", if mined { "1" } else { "0" }, "ab".repeat(32), "00".repeat(31) + "07", memo)
+    }
+
+    fn malformed_reason(tx: &str) -> String {
+        match Transaction::try_from_relayer_string(128, tx) {
+            Err(CloudError::MalformedRelayerTransaction(128, reason)) => reason,
+            result => panic!("unexpected result {:?}", result.map(|tx| tx.index)),
+        }
+    }
+
+    #[test]
+    fn relayer_string_is_parsed() {
+        let tx = Transaction::try_from_relayer_string(128, &relayer_string(true, "0102")).unwrap();
+        assert_eq!(tx.index, 128);
+        assert_eq!(tx.tx_hash, format!("0x{}", "ab".repeat(32)));
+        assert_eq!(tx.commitment, Num::from_uint_reduced(NumRepr::from(7u64)));
+        assert_eq!(tx.memo, vec![1, 2]);
+        assert!(!tx.optimistic);
+
+        assert!(Transaction::try_from_relayer_string(128, &relayer_string(false, "")).unwrap().optimistic);
+    }
+
+    #[test]
+    fn empty_memo_is_allowed() {
+        let tx = Transaction::try_from_relayer_string(128, &relayer_string(true, "")).unwrap();
+        assert!(tx.memo.is_empty());
+    }
+
+    #[test]
+    fn truncated_string_is_malformed() {
+        let tx = relayer_string(true, "");
+        assert!(malformed_reason(&tx[..100]).contains("got 100"));
+        assert!(malformed_reason("").contains("got 0"));
+    }
+
+    #[test]
+    fn odd_length_hex_is_malformed() {
+        assert!(malformed_reason(&relayer_string(true, "010")).starts_with("invalid memo"));
+        let tx = relayer_string(true, "");
+        let tx = format!("{}zz{}", &tx[..1], &tx[3..]);
+        assert!(malformed_reason(&tx).starts_with("invalid tx hash"));
+        let tx = relayer_string(true, "");
+        let tx = format!("{}zz{}", &tx[..65], &tx[67..]);
+        assert!(malformed_reason(&tx).starts_with("invalid commitment"));
+    }
+
+    #[test]
+    fn non_ascii_string_is_malformed() {
+        let tx = format!("é{}", &relayer_string(true, "")[2..]);
+        assert_eq!(malformed_reason(&tx), "non-ascii characters");
+    }
+
+    #[actix_web::test]
+    async fn account_ahead_of_relayer_has_nothing_to_sync() {
+        let dir = TestDir::new();
+        let relayer = test_relayer(&dir, 1024, 0).await;
+        assert_eq!(relayer.tx_count(0, 512).unwrap(), 4);
+        assert_eq!(relayer.tx_count(512, 512).unwrap(), 0);
+        // e.g. an optimistic state was applied or the relayer rolled back
+        assert_eq!(relayer.tx_count(512, 256).unwrap(), 0);
+        assert_eq!(relayer.tx_count(1024, 0).unwrap(), 0);
+        assert!(matches!(
+            relayer.tx_count(1152, 0),
+            Err(CloudError::RelayerBehindLocalState(0, 1152))
+        ));
+    }
+
+    async fn fetch(calls: Arc<AtomicUsize>) -> Result<u64, CloudError> {
+        let call = calls.fetch_add(1, Ordering::SeqCst) as u64;
+        // keeps the fetch running while the other callers arrive
+        tokio::task::yield_now().await;
+        Ok(call)
+    }
+
+    #[actix_web::test]
+    async fn concurrent_callers_share_one_fetch() {
+        let cache = TtlCache::new(Duration::from_secs(60));
+        let calls = Arc::new(AtomicUsize::new(0));
+
+        let results = join_all((0..16).map(|_| cache.get(false, || fetch(calls.clone())))).await;
+
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert!(results.into_iter().all(|value| value.unwrap() == 0));
+    }
+
+    #[actix_web::test]
+    async fn fetches_again_after_ttl() {
+        let cache = TtlCache::new(Duration::from_millis(20));
+        let calls = Arc::new(AtomicUsize::new(0));
+
+        assert_eq!(cache.get(false, || fetch(calls.clone())).await.unwrap(), 0);
+        assert_eq!(cache.get(false, || fetch(calls.clone())).await.unwrap(), 0);
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        assert_eq!(cache.get(false, || fetch(calls.clone())).await.unwrap(), 1);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[actix_web::test]
+    async fn force_always_fetches() {
+        let cache = TtlCache::new(Duration::from_secs(60));
+        let calls = Arc::new(AtomicUsize::new(0));
+
+        cache.get(false, || fetch(calls.clone())).await.unwrap();
+        assert_eq!(cache.get(true, || fetch(calls.clone())).await.unwrap(), 1);
+        // the forced value is cached for the next callers
+        assert_eq!(cache.get(false, || fetch(calls.clone())).await.unwrap(), 1);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn withdrawal_fee_is_read_per_tx_type() {
+        let response = serde_json::json!({"fee": {"transfer": "100", "withdrawal": "250"}, "oneByteFee": "1"});
+        assert_eq!(parse_withdrawal_fee(&response), Some(250));
+        let response = serde_json::json!({"fee": {"transfer": 100, "withdrawal": 250}});
+        assert_eq!(parse_withdrawal_fee(&response), Some(250));
+    }
+
+    #[test]
+    fn single_fee_is_not_a_withdrawal_fee() {
+        assert_eq!(parse_withdrawal_fee(&serde_json::json!({"fee": "100"})), None);
+        assert_eq!(parse_withdrawal_fee(&serde_json::json!({"fee": {"transfer": "100"}})), None);
+        assert_eq!(parse_withdrawal_fee(&serde_json::json!({"fee": {"withdrawal": "-1"}})), None);
+    }
+
+    #[actix_web::test]
+    async fn withdrawal_fee_switches_to_the_relayer_that_answered() {
+        let server = actix_web::HttpServer::new(|| {
+            actix_web::App::new().route(
+                "/fee",
+                actix_web::web::get().to(|| async {
+                    let fee = serde_json::json!({"fee": {"transfer": "100", "withdrawal": "250"}});
+                    actix_web::HttpResponse::Ok().json(fee)
+                }),
+            )
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let url = format!("http://{}", server.addrs()[0]);
+        let handle = server.run();
+        let stop = handle.handle();
+        actix_web::rt::spawn(handle);
+
+        let dir = TestDir::new();
+        let relayer = CachedRelayerClient::new(
+            &["http://127.0.0.1:1".to_string(), url],
+            &dir.db_path(),
+            1024,
+            Duration::from_secs(60),
+            FaultInjector::default(),
+        )
+        .unwrap();
+
+        assert_eq!(relayer.withdrawal_fee().await.unwrap(), 250);
+        assert_eq!(relayer.current.load(Ordering::Relaxed), 1);
+        stop.stop(false).await;
+    }
+
+    #[actix_web::test]
+    async fn failed_fetch_is_not_cached() {
+        let cache = TtlCache::<u64>::new(Duration::from_secs(60));
+        let calls = Arc::new(AtomicUsize::new(0));
+
+        let failed = cache.get(false, || async { Err(CloudError::RelayerSendError) }).await;
+        assert!(failed.is_err());
+        assert_eq!(cache.get(false, || fetch(calls.clone())).await.unwrap(), 0);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+}
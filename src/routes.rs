@@ -1,11 +1,19 @@
 use std::str::FromStr;
 
-use actix_web::{web::{Json, Data, Query}, HttpResponse};
+use actix_web::{web::{Json, Data, Query, Bytes}, http::header::{HeaderValue, CONTENT_DISPOSITION, RETRY_AFTER}, HttpRequest, HttpResponse, ResponseError};
 use actix_web_httpauth::extractors::bearer::BearerAuth;
 use uuid::Uuid;
 use zkbob_utils_rs::tracing;
 
-use crate::{errors::CloudError, types::{SignupRequest, SignupResponse, AccountInfoRequest, GenerateAddressResponse, TransferRequest, TransferResponse, TransactionStatusRequest, CalculateFeeRequest, CalculateFeeResponse, ExportKeyResponse, HistoryRecord, TransactionStatusResponse, ReportRequest, ReportResponse, ImportRequest}, cloud::{ZkBobCloud, types::{Transfer, AccountImportData}}, helpers::invert};
+use crate::{errors::CloudError, types::{SignupRequest, SignupResponse, AccountInfoRequest, GenerateAddressResponse, TransferRequest, TransferResponse, TransactionStatusRequest, CalculateFeeRequest, CalculateFeeResponse, ExportKeyResponse, HistoryRecord, TransactionStatusResponse, ReportRequest, ReportResponse, ImportRequest, FaultRuleRequest, TransactionTraceRequest, SupportBundleRequest, GenerateAddressesRequest, GenerateAddressesResponse, RepairAccountRequest, ReceiptRequest, TransfersBySupportIdRequest, SupportIdTransfer, ImportRequestItem, EncryptedBundle, ExportAllRequest, RetryBudgetRequest, RetryBudgetResponse, WithdrawRequest, ListAccountsRequest, ListAccountsResponse, HistoryRequest, HistoryResponse, ReportFormat, CancelTransferRequest, CancelTransferResponse, CancelledPart, AccountRequest, ImportResponse, ImportAccountResult, ImportStatus, GenerateReportRequest, CleanReportsRequest, CleanReportsResponse, FailedTransfer, RequeueTransferRequest, RequeueTransferResponse, ReportSummary}, cloud::{ZkBobCloud, types::{Transfer, AccountImportData, TransferStatus, RetryBudget, ReportTask}}, helpers::invert, account::history::HistoryFilter};
+
+pub const SUPPORT_ID_HEADER: &str = "zkbob-support-id";
+
+const DEFAULT_ACCOUNTS_PAGE_SIZE: u64 = 100;
+
+const DEFAULT_HISTORY_PAGE_SIZE: u64 = 100;
+
+const FIRST_INDEX_WARNING: &str = "notes received before firstIndex are ignored, resync the account if it had funds earlier";
 
 pub async fn signup(
     request: Json<SignupRequest>,
@@ -17,10 +25,13 @@ pub async fn signup(
     let id = invert(request.id.as_ref().map(|id| parse_uuid(id)))?;
     let sk = invert(request.sk.as_ref().map(hex::decode))?;
     
-    let account_id = cloud.new_account(request.0.description, id, sk).await?;
+    let first_index = request.first_index;
+    let request = request.into_inner();
+    let account_id = cloud.new_account(request.description, request.attributes, id, sk, first_index).await?;
 
     Ok(HttpResponse::Ok().json(SignupResponse {
         account_id: account_id.to_string(),
+        warning: first_index.map(|_| FIRST_INDEX_WARNING.to_string()),
     }))
 }
 
@@ -30,16 +41,81 @@ pub async fn import(
     bearer: BearerAuth
 ) -> Result<HttpResponse, CloudError> {
     cloud.validate_token(bearer.token())?;
-    let accounts = request.iter().map(|account| {
-        Ok(AccountImportData {
-            id: parse_uuid(&account.id)?,
-            description: account.description.clone(),
-            sk: hex::decode(&account.sk)?
+    let items = match request.0 {
+        ImportRequest::Plain(items) => items,
+        ImportRequest::Encrypted(bundle) => {
+            let bundle = cloud.decrypt_bundle(&bundle.bundle)?;
+            serde_json::from_slice::<Vec<ImportRequestItem>>(&bundle)
+                .map_err(|err| CloudError::BadRequest(format!("invalid bundle: {}", err)))?
+        }
+    };
+    // malformed items are reported along with the accounts that failed to import
+    let mut results = Vec::new();
+    let mut accounts = Vec::new();
+    for item in items {
+        let account = parse_uuid(&item.id).and_then(|id| {
+            Ok(AccountImportData {
+                id,
+                description: item.description.clone(),
+                attributes: item.attributes.clone(),
+                sk: hex::decode(&item.sk)?,
+                first_index: item.first_index,
+            })
+        });
+        match account {
+            Ok(account) => accounts.push(account),
+            Err(err) => results.push(ImportAccountResult::new(item.id, Err(err))),
+        }
+    }
+
+    results.extend(
+        cloud
+            .import_accounts(accounts)
+            .await
+            .into_iter()
+            .map(|(id, result)| ImportAccountResult::new(id.as_hyphenated().to_string(), result)),
+    );
+
+    let count = |status: ImportStatus| results.iter().filter(|result| result.status == status).count();
+    let response = ImportResponse {
+        imported: count(ImportStatus::Imported),
+        skipped: count(ImportStatus::Skipped),
+        failed: count(ImportStatus::Failed),
+        accounts: results,
+    };
+    match response.failed {
+        0 => Ok(HttpResponse::Ok().json(response)),
+        _ => Ok(HttpResponse::MultiStatus().json(response)),
+    }
+}
+
+pub async fn export_all(
+    request: Query<ExportAllRequest>,
+    cloud: Data<ZkBobCloud>,
+    bearer: BearerAuth,
+) -> Result<HttpResponse, CloudError> {
+    cloud.validate_token(bearer.token())?;
+    let items = cloud
+        .export_accounts()
+        .await?
+        .into_iter()
+        .map(|account| ImportRequestItem {
+            id: account.id.as_hyphenated().to_string(),
+            description: account.description,
+            sk: hex::encode(account.sk),
+            first_index: account.first_index,
+            attributes: account.attributes,
         })
-    }).collect::<Result<Vec<_>, CloudError>>()?;
-    
-    cloud.import_accounts(accounts).await?;
-    Ok(HttpResponse::Ok().finish())
+        .collect::<Vec<_>>();
+
+    if request.encrypt.unwrap_or(false) {
+        let json = serde_json::to_vec(&items).map_err(|err| {
+            CloudError::InternalError(format!("failed to serialize accounts: {}", err))
+        })?;
+        let bundle = cloud.encrypt_bundle(&json)?;
+        return Ok(HttpResponse::Ok().json(EncryptedBundle { bundle }));
+    }
+    Ok(HttpResponse::Ok().json(items))
 }
 
 pub async fn delete_account(
@@ -53,26 +129,91 @@ pub async fn delete_account(
     Ok(HttpResponse::Ok().finish())
 }
 
+pub async fn evict_account(
+    request: Query<AccountInfoRequest>,
+    cloud: Data<ZkBobCloud>,
+    bearer: BearerAuth,
+) -> Result<HttpResponse, CloudError> {
+    cloud.validate_token(bearer.token())?;
+    let id = parse_uuid(&request.id)?;
+    cloud.evict_account(id).await?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+pub async fn repair_account(
+    request: Json<RepairAccountRequest>,
+    cloud: Data<ZkBobCloud>,
+    bearer: BearerAuth,
+) -> Result<HttpResponse, CloudError> {
+    cloud.validate_token(bearer.token())?;
+    let id = parse_uuid(&request.id)?;
+    cloud.repair_account(id, request.action).await?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+pub async fn resync_account(
+    request: Json<AccountInfoRequest>,
+    cloud: Data<ZkBobCloud>,
+    bearer: BearerAuth,
+) -> Result<HttpResponse, CloudError> {
+    cloud.validate_token(bearer.token())?;
+    let id = parse_uuid(&request.id)?;
+    let status = cloud.resync_account(id).await?;
+    Ok(HttpResponse::Ok().json(status))
+}
+
+pub async fn resync_status(
+    request: Query<AccountInfoRequest>,
+    cloud: Data<ZkBobCloud>,
+    bearer: BearerAuth,
+) -> Result<HttpResponse, CloudError> {
+    cloud.validate_token(bearer.token())?;
+    let id = parse_uuid(&request.id)?;
+    let status = cloud.resync_status(id)?;
+    Ok(HttpResponse::Ok().json(status))
+}
+
 pub async fn list_accounts(
+    request: Query<ListAccountsRequest>,
     bearer: BearerAuth,
     cloud: Data<ZkBobCloud>,
 ) -> Result<HttpResponse, CloudError> {
     cloud.validate_token(bearer.token())?;
-    let accounts = cloud.list_accounts().await?;
-    Ok(HttpResponse::Ok().json(accounts))
+    let offset = request.offset.unwrap_or(0);
+    let limit = request.limit.unwrap_or(DEFAULT_ACCOUNTS_PAGE_SIZE);
+    let (accounts, total) = cloud.list_accounts(offset, limit).await?;
+    Ok(HttpResponse::Ok().json(ListAccountsResponse { accounts, total }))
 }
 
 pub async fn account_info(
-    request: Query<AccountInfoRequest>,
+    request: Query<AccountRequest>,
     cloud: Data<ZkBobCloud>,
 ) -> Result<HttpResponse, CloudError> {
     let account_id = parse_uuid(&request.id)?;
     let account_info = cloud
-        .account_info(account_id)
+        .account_info(account_id, request.max_staleness_sec)
         .await?;
     Ok(HttpResponse::Ok().json(account_info))
 }
 
+pub async fn sync_account(
+    request: Query<AccountInfoRequest>,
+    cloud: Data<ZkBobCloud>,
+) -> Result<HttpResponse, CloudError> {
+    let account_id = parse_uuid(&request.id)?;
+    let result = cloud.sync_account(account_id).await?;
+    Ok(HttpResponse::Ok().json(result))
+}
+
+pub async fn sync_status(
+    request: Query<AccountInfoRequest>,
+    cloud: Data<ZkBobCloud>,
+) -> Result<HttpResponse, CloudError> {
+    let account_id = parse_uuid(&request.id)?;
+    let status = cloud.sync_status(account_id).await?;
+    Ok(HttpResponse::Ok().json(status))
+}
+
 pub async fn generate_shielded_address(
     request: Query<AccountInfoRequest>,
     cloud: Data<ZkBobCloud>,
@@ -82,41 +223,235 @@ pub async fn generate_shielded_address(
     Ok(HttpResponse::Ok().json(GenerateAddressResponse { address }))
 }
 
+pub async fn generate_shielded_addresses(
+    request: Query<GenerateAddressesRequest>,
+    cloud: Data<ZkBobCloud>,
+) -> Result<HttpResponse, CloudError> {
+    let account_id = parse_uuid(&request.id)?;
+    let addresses = cloud
+        .generate_addresses(account_id, request.start, request.count)
+        .await?;
+    Ok(HttpResponse::Ok().json(GenerateAddressesResponse { addresses }))
+}
+
 pub async fn history(
-    request: Query<AccountInfoRequest>,
+    request: Query<HistoryRequest>,
     cloud: Data<ZkBobCloud>,
 ) -> Result<HttpResponse, CloudError> {
+    let request = request.into_inner();
     let account_id = parse_uuid(&request.id)?;
-    let txs = cloud.history(account_id).await?;
-    Ok(HttpResponse::Ok().json(HistoryRecord::prepare_records(txs)))
+    let offset = request.offset.unwrap_or(0);
+    let limit = request.limit.unwrap_or(DEFAULT_HISTORY_PAGE_SIZE);
+
+    let filter = HistoryFilter {
+        from: request.from,
+        to: request.to,
+        tx_types: request.tx_types,
+    };
+    let optimistic = request.optimistic.unwrap_or(false);
+    let (txs, total, has_more) = cloud.history(account_id, &filter, offset, limit, optimistic).await?;
+    Ok(HttpResponse::Ok().json(HistoryResponse {
+        records: HistoryRecord::prepare_records(txs),
+        total,
+        has_more,
+    }))
 }
 
 pub async fn transfer(
     request: Json<TransferRequest>,
     cloud: Data<ZkBobCloud>,
+    http_request: HttpRequest,
+    bearer: Option<BearerAuth>,
+) -> Result<HttpResponse, CloudError> {
+    let account_id = parse_uuid(&request.account_id)?;
+    let support_id = http_request
+        .headers()
+        .get(SUPPORT_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_string());
+
+    let outputs = match (&request.to, request.amount, &request.outputs) {
+        // the amount of a sweep is resolved when the transfer is planned
+        (Some(to), None, None) if request.sweep => vec![(to.clone(), 0)],
+        (_, _, _) if request.sweep => return Err(CloudError::BadRequest("sweep requires to without amount".to_string())),
+        (Some(to), Some(amount), None) => vec![(to.clone(), amount)],
+        (None, None, Some(outputs)) => outputs
+            .iter()
+            .map(|output| (output.to.clone(), output.amount))
+            .collect(),
+        _ => return Err(CloudError::BadRequest("either to and amount or outputs should be set".to_string())),
+    };
+
+    let id = match (&request.transaction_id, &request.nonce) {
+        (Some(transaction_id), _) => transaction_id.clone(),
+        (None, Some(nonce)) => Transfer::derive_id(account_id, &outputs, nonce),
+        (None, None) => Uuid::new_v4().as_hyphenated().to_string(),
+    };
+
+    let result = cloud.transfer(Transfer{
+        id,
+        account_id,
+        outputs,
+        support_id,
+        admin: bearer.map(|bearer| cloud.validate_token(bearer.token()).is_ok()).unwrap_or(false),
+        withdrawal: false,
+        native_amount: 0,
+        callback_url: request.callback_url.clone(),
+        sweep: request.sweep,
+        internal: false,
+    }).await;
+
+    transfer_response(cloud.config.backpressure.retry_after_sec, result)
+}
+
+pub async fn withdraw(
+    request: Json<WithdrawRequest>,
+    cloud: Data<ZkBobCloud>,
+    http_request: HttpRequest,
+    bearer: Option<BearerAuth>,
 ) -> Result<HttpResponse, CloudError> {
     let account_id = parse_uuid(&request.account_id)?;
+    let support_id = http_request
+        .headers()
+        .get(SUPPORT_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_string());
 
-    let transaction_id = cloud.transfer(Transfer{
+    let result = cloud.withdraw(Transfer{
         id: request.transaction_id.clone().unwrap_or(Uuid::new_v4().as_hyphenated().to_string()),
         account_id,
-        amount: request.amount,
-        to: request.to.clone(),
-    }).await?;
+        outputs: vec![(request.to.clone(), request.amount)],
+        support_id,
+        admin: bearer.map(|bearer| cloud.validate_token(bearer.token()).is_ok()).unwrap_or(false),
+        withdrawal: true,
+        native_amount: request.native_amount.unwrap_or(0),
+        callback_url: request.callback_url.clone(),
+        sweep: false,
+        internal: false,
+    }).await;
+
+    transfer_response(cloud.config.backpressure.retry_after_sec, result)
+}
+
+fn transfer_response(retry_after_sec: u64, result: Result<(String, u64), CloudError>) -> Result<HttpResponse, CloudError> {
+    match result {
+        Ok((transaction_id, amount)) => Ok(HttpResponse::Ok().json(TransferResponse{ transaction_id, amount })),
+        Err(err @ CloudError::ServiceIsBusy) => {
+            let mut response = err.error_response();
+            response.headers_mut().insert(
+                RETRY_AFTER,
+                HeaderValue::from(retry_after_sec),
+            );
+            Ok(response)
+        }
+        Err(err) => Err(err),
+    }
+}
 
-    Ok(HttpResponse::Ok().json(TransferResponse{ transaction_id }))
+pub async fn cancel_transfer(
+    request: Json<CancelTransferRequest>,
+    cloud: Data<ZkBobCloud>,
+    bearer: BearerAuth,
+) -> Result<HttpResponse, CloudError> {
+    cloud.validate_token(bearer.token())?;
+    let parts = cloud
+        .cancel_transfer(&request.transaction_id)
+        .await?
+        .into_iter()
+        .map(|(part, cancelled)| CancelledPart {
+            part_id: part.id,
+            cancelled,
+            status: part.status.status(),
+        })
+        .collect();
+    Ok(HttpResponse::Ok().json(CancelTransferResponse { parts }))
+}
+
+pub async fn retry_budget(
+    request: Json<RetryBudgetRequest>,
+    cloud: Data<ZkBobCloud>,
+    bearer: BearerAuth,
+) -> Result<HttpResponse, CloudError> {
+    cloud.validate_token(bearer.token())?;
+    let budget = match (request.reset, request.max_attempts) {
+        (true, None) => RetryBudget::Reset,
+        (false, Some(attempts)) => RetryBudget::Attempts(attempts),
+        _ => return Err(CloudError::BadRequest("either reset or maxAttempts must be set".to_string())),
+    };
+    let resumed_parts = cloud.update_retry_budget(&request.transaction_id, budget).await?;
+    Ok(HttpResponse::Ok().json(RetryBudgetResponse { resumed_parts }))
+}
+
+pub async fn requeue_transfer(
+    request: Json<RequeueTransferRequest>,
+    cloud: Data<ZkBobCloud>,
+    bearer: BearerAuth,
+) -> Result<HttpResponse, CloudError> {
+    cloud.validate_token(bearer.token())?;
+    let requeued_parts = cloud.requeue_transfer(&request.transaction_id).await?;
+    Ok(HttpResponse::Ok().json(RequeueTransferResponse { requeued_parts }))
 }
 
 pub async fn transaction_trace(
-    request: Query<TransactionStatusRequest>,
+    request: Query<TransactionTraceRequest>,
     cloud: Data<ZkBobCloud>,
     bearer: BearerAuth,
 ) -> Result<HttpResponse, CloudError> {
     cloud.validate_token(bearer.token())?;
-    let parts = cloud.transfer_status(&request.transaction_id).await?;
+    let statuses = invert(request.status.as_ref().map(|statuses| {
+        statuses
+            .split(',')
+            .map(|status| TransferStatus::validate_name(status.trim()))
+            .collect::<Result<Vec<_>, _>>()
+    }))?;
+    let parts = cloud
+        .transfer_trace(&request.transaction_id, statuses, request.offset.unwrap_or(0), request.limit)
+        .await?;
     Ok(HttpResponse::Ok().json(parts))
 }
 
+pub async fn transfers_by_support_id(
+    request: Query<TransfersBySupportIdRequest>,
+    cloud: Data<ZkBobCloud>,
+    bearer: BearerAuth,
+) -> Result<HttpResponse, CloudError> {
+    cloud.validate_token(bearer.token())?;
+    let transfers = cloud
+        .transfers_by_support_id(&request.support_id)
+        .await?
+        .into_iter()
+        .map(|(transaction_id, parts)| SupportIdTransfer {
+            transaction_id,
+            status: TransactionStatusResponse::from(parts),
+        })
+        .collect::<Vec<_>>();
+    Ok(HttpResponse::Ok().json(transfers))
+}
+
+pub async fn failed_transfers(
+    cloud: Data<ZkBobCloud>,
+    bearer: BearerAuth,
+) -> Result<HttpResponse, CloudError> {
+    cloud.validate_token(bearer.token())?;
+    let transfers = cloud
+        .failed_transfers()
+        .await
+        .into_iter()
+        .map(FailedTransfer::from)
+        .collect::<Vec<_>>();
+    Ok(HttpResponse::Ok().json(transfers))
+}
+
+pub async fn receipt(
+    request: Query<ReceiptRequest>,
+    cloud: Data<ZkBobCloud>,
+    bearer: BearerAuth,
+) -> Result<HttpResponse, CloudError> {
+    cloud.validate_token(bearer.token())?;
+    let receipt = cloud.receipt(&request.part_id).await?;
+    Ok(HttpResponse::Ok().json(receipt))
+}
+
 pub async fn transaction_status(
     request: Query<TransactionStatusRequest>,
     cloud: Data<ZkBobCloud>,
@@ -130,7 +465,7 @@ pub async fn calculate_fee(
     cloud: Data<ZkBobCloud>
 ) -> Result<HttpResponse, CloudError> {
     let account_id = parse_uuid(&request.account_id)?;
-    let (transaction_count, total_fee) = cloud.calculate_fee(account_id, request.amount).await?;
+    let (transaction_count, total_fee) = cloud.calculate_fee(account_id, request.amount, request.to.clone()).await?;
     Ok(HttpResponse::Ok().json(CalculateFeeResponse{transaction_count, total_fee}))
 }
 
@@ -145,15 +480,31 @@ pub async fn export_key(
     Ok(HttpResponse::Ok().json(ExportKeyResponse { sk }))
 }
 
+pub async fn support_bundle(
+    request: Query<SupportBundleRequest>,
+    cloud: Data<ZkBobCloud>,
+    bearer: BearerAuth,
+) -> Result<HttpResponse, CloudError> {
+    cloud.validate_token(bearer.token())?;
+    let account_id = parse_uuid(&request.id)?;
+    let bundle = cloud
+        .support_bundle(account_id, request.history_size.unwrap_or(20))
+        .await?;
+    Ok(HttpResponse::Ok().json(bundle))
+}
+
 pub async fn generate_report(
+    request: Query<GenerateReportRequest>,
     cloud: Data<ZkBobCloud>,
     bearer: BearerAuth,
 ) -> Result<HttpResponse, CloudError> {
     cloud.validate_token(bearer.token())?;
-    let id = cloud.generate_report().await?;
+    let request = request.into_inner();
+    let id = cloud.generate_report(request.include_keys, request.public_key).await?;
     Ok(HttpResponse::Ok().json(ReportResponse {
         id: id.as_hyphenated().to_string(),
         status: None,
+        progress: None,
         report: None,
     }))
 }
@@ -166,21 +517,126 @@ pub async fn report(
     cloud.validate_token(bearer.token())?;
     let report_id = parse_uuid(&request.id)?;
     match cloud.get_report(report_id).await? {
+        Some(ReportTask { report: Some(report), .. }) if request.format == Some(ReportFormat::Csv) => {
+            Ok(HttpResponse::Ok()
+                .content_type("text/csv; charset=utf-8")
+                .insert_header((
+                    CONTENT_DISPOSITION,
+                    format!("attachment; filename=\"report-{}.csv\"", report_id.as_hyphenated()),
+                ))
+                .body(report.to_csv()))
+        }
+        Some(_) if request.format == Some(ReportFormat::Csv) => Err(CloudError::ReportNotReady),
         Some(task) => Ok(HttpResponse::Ok().json(ReportResponse {
             id: report_id.as_hyphenated().to_string(),
             status: Some(task.status),
+            progress: task.progress,
             report: task.report,
         })),
         None => Err(CloudError::ReportNotFound)
     }
 }
 
+pub async fn list_reports(
+    cloud: Data<ZkBobCloud>,
+    bearer: BearerAuth,
+) -> Result<HttpResponse, CloudError> {
+    cloud.validate_token(bearer.token())?;
+    let reports = cloud
+        .list_reports()
+        .await?
+        .into_iter()
+        .map(|(id, task)| ReportSummary::new(id, task))
+        .collect::<Vec<_>>();
+    Ok(HttpResponse::Ok().json(reports))
+}
+
 pub async fn clean_reports(
+    body: Bytes,
     cloud: Data<ZkBobCloud>,
     bearer: BearerAuth,
 ) -> Result<HttpResponse, CloudError> {
     cloud.validate_token(bearer.token())?;
-    cloud.clean_reports().await?;
+    // the body is optional, all reports are removed without it
+    let request = match body.is_empty() {
+        true => CleanReportsRequest::default(),
+        false => serde_json::from_slice::<CleanReportsRequest>(&body)
+            .map_err(|err| CloudError::BadRequest(format!("invalid body: {}", err)))?,
+    };
+    let removed = cloud.clean_reports(request.older_than_sec).await?;
+    Ok(HttpResponse::Ok().json(CleanReportsResponse { removed }))
+}
+
+pub async fn circuit_info(
+    cloud: Data<ZkBobCloud>,
+) -> Result<HttpResponse, CloudError> {
+    let info = cloud.circuit_info()?;
+    Ok(HttpResponse::Ok().json(info))
+}
+
+pub async fn health(
+    cloud: Data<ZkBobCloud>,
+) -> Result<HttpResponse, CloudError> {
+    let health = cloud.health().await;
+    let mut response = match health.healthy {
+        true => HttpResponse::Ok(),
+        false => HttpResponse::ServiceUnavailable(),
+    };
+    Ok(response.json(health))
+}
+
+pub async fn metrics(
+    cloud: Data<ZkBobCloud>,
+) -> Result<HttpResponse, CloudError> {
+    let metrics = cloud.metrics().await?;
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics))
+}
+
+pub async fn reconcile(
+    cloud: Data<ZkBobCloud>,
+    bearer: BearerAuth,
+) -> Result<HttpResponse, CloudError> {
+    cloud.validate_token(bearer.token())?;
+    let status = cloud.start_reconcile();
+    Ok(HttpResponse::Ok().json(status))
+}
+
+pub async fn reconcile_status(
+    cloud: Data<ZkBobCloud>,
+    bearer: BearerAuth,
+) -> Result<HttpResponse, CloudError> {
+    cloud.validate_token(bearer.token())?;
+    let status = cloud.reconcile_status()?;
+    Ok(HttpResponse::Ok().json(status))
+}
+
+pub async fn service_status(
+    cloud: Data<ZkBobCloud>,
+    bearer: BearerAuth,
+) -> Result<HttpResponse, CloudError> {
+    cloud.validate_token(bearer.token())?;
+    let saturation = cloud.saturation().await?;
+    Ok(HttpResponse::Ok().json(saturation))
+}
+
+pub async fn fault_rules(
+    cloud: Data<ZkBobCloud>,
+    bearer: BearerAuth,
+) -> Result<HttpResponse, CloudError> {
+    cloud.validate_token(bearer.token())?;
+    let rules = cloud.fault_rules()?;
+    Ok(HttpResponse::Ok().json(rules))
+}
+
+pub async fn set_fault_rule(
+    request: Json<FaultRuleRequest>,
+    cloud: Data<ZkBobCloud>,
+    bearer: BearerAuth,
+) -> Result<HttpResponse, CloudError> {
+    cloud.validate_token(bearer.token())?;
+    cloud.set_fault_rule(&request.site, request.0.rule)?;
     Ok(HttpResponse::Ok().finish())
 }
 
@@ -189,4 +645,27 @@ fn parse_uuid(id: &str) -> Result<Uuid, CloudError> {
         tracing::debug!("failed to parse uuid: {}", err);
         CloudError::IncorrectAccountId
     })
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::{header::RETRY_AFTER, StatusCode}, ResponseError};
+
+    use crate::errors::CloudError;
+
+    use super::transfer_response;
+
+    #[test]
+    fn busy_service_asks_to_retry_later() {
+        let response = transfer_response(30, Err(CloudError::ServiceIsBusy)).unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers().get(RETRY_AFTER).unwrap(), "30");
+    }
+
+    #[test]
+    fn other_errors_have_no_retry_after() {
+        let err = transfer_response(30, Err(CloudError::InsufficientBalance)).unwrap_err();
+        assert!(err.error_response().headers().get(RETRY_AFTER).is_none());
+        let response = transfer_response(30, Ok(("transfer".to_string(), 100))).unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
@@ -1,40 +1,270 @@
+use std::collections::{HashMap, HashSet};
+
 use serde::{Deserialize, Serialize};
+use uuid::Uuid;
 
 use crate::{
+    errors::CloudError,
     account::history::HistoryTxType,
-    cloud::types::{TransferPart, TransferStatus, ReportStatus, Report, CloudHistoryTx},
+    helpers::fault::FaultRule,
+    cloud::types::{AccountAttributes, AccountShortInfo, TransferPart, TransferStatus, ReportStatus, ReportTask, Report, ReportProgress, CloudHistoryTx, IndexedAddress, RepairAction},
 };
 
 #[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct SignupRequest {
     pub id: Option<String>,
     pub description: String,
     pub sk: Option<String>,
+    pub first_index: Option<u64>,
+    #[serde(flatten)]
+    pub attributes: AccountAttributes,
 }
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct ImportRequestItem {
     pub id: String,
     pub description: String,
     pub sk: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub first_index: Option<u64>,
+    #[serde(flatten)]
+    pub attributes: AccountAttributes,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum ImportRequest {
+    Plain(Vec<ImportRequestItem>),
+    Encrypted(EncryptedBundle),
+}
+
+#[derive(Serialize)]
+pub struct ImportResponse {
+    pub imported: usize,
+    pub skipped: usize,
+    pub failed: usize,
+    pub accounts: Vec<ImportAccountResult>,
+}
+
+#[derive(Serialize)]
+pub struct ImportAccountResult {
+    pub id: String,
+    pub status: ImportStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl ImportAccountResult {
+    pub fn new(id: String, result: Result<(), CloudError>) -> Self {
+        let (status, error) = match result {
+            Ok(()) => (ImportStatus::Imported, None),
+            Err(CloudError::DuplicateAccountId) => (ImportStatus::Skipped, None),
+            Err(err) => (ImportStatus::Failed, Some(err.to_string())),
+        };
+        ImportAccountResult { id, status, error }
+    }
 }
 
-pub type ImportRequest = Vec<ImportRequestItem>;
+#[derive(Serialize, PartialEq)]
+pub enum ImportStatus {
+    Imported,
+    // the account already exists
+    Skipped,
+    Failed,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct EncryptedBundle {
+    pub bundle: String,
+}
+
+#[derive(Deserialize)]
+pub struct ExportAllRequest {
+    pub encrypt: Option<bool>,
+}
 
 #[derive(Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct SignupResponse {
     pub account_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub warning: Option<String>,
 }
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize)]
 pub struct AccountInfoRequest {
     pub id: String,
 }
 
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AccountRequest {
+    pub id: String,
+    pub max_staleness_sec: Option<u64>,
+}
+
+#[derive(Deserialize)]
+pub struct ListAccountsRequest {
+    pub offset: Option<u64>,
+    pub limit: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct ListAccountsResponse {
+    pub accounts: Vec<AccountShortInfo>,
+    pub total: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct HistoryRequest {
+    pub id: String,
+    pub offset: Option<u64>,
+    pub limit: Option<u64>,
+    #[serde(alias = "fromTimestamp")]
+    pub from: Option<u64>,
+    #[serde(alias = "toTimestamp")]
+    pub to: Option<u64>,
+    #[serde(rename = "txType", default, skip_serializing_if = "Vec::is_empty", with = "tx_type_list")]
+    pub tx_types: Vec<HistoryTxType>,
+    pub optimistic: Option<bool>,
+}
+
+// txType is a comma separated list, unknown types are rejected
+mod tx_type_list {
+    use std::str::FromStr;
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    use crate::account::history::HistoryTxType;
+
+    pub fn serialize<S: Serializer>(tx_types: &[HistoryTxType], serializer: S) -> Result<S::Ok, S::Error> {
+        let tx_types = tx_types.iter().map(|tx_type| tx_type.to_string()).collect::<Vec<_>>();
+        serializer.serialize_str(&tx_types.join(","))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<HistoryTxType>, D::Error> {
+        String::deserialize(deserializer)?
+            .split(',')
+            .filter(|tx_type| !tx_type.is_empty())
+            .map(|tx_type| HistoryTxType::from_str(tx_type).map_err(serde::de::Error::custom))
+            .collect()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HistoryResponse {
+    pub records: Vec<HistoryRecord>,
+    pub total: u64,
+    // with a filter total counts only the transactions up to the end of the page
+    pub has_more: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SupportBundleRequest {
+    pub id: String,
+    pub history_size: Option<usize>,
+}
+
+#[derive(Deserialize)]
+pub struct GenerateAddressesRequest {
+    pub id: String,
+    pub start: u64,
+    pub count: u64,
+}
+
+#[derive(Deserialize)]
+pub struct RepairAccountRequest {
+    pub id: String,
+    pub action: RepairAction,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RetryBudgetRequest {
+    pub transaction_id: String,
+    pub max_attempts: Option<u32>,
+    #[serde(default)]
+    pub reset: bool,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RetryBudgetResponse {
+    pub resumed_parts: Vec<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RequeueTransferRequest {
+    pub transaction_id: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RequeueTransferResponse {
+    pub requeued_parts: Vec<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CancelTransferRequest {
+    pub transaction_id: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CancelTransferResponse {
+    pub parts: Vec<CancelledPart>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CancelledPart {
+    pub part_id: String,
+    pub cancelled: bool,
+    pub status: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReceiptRequest {
+    pub part_id: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GenerateReportRequest {
+    #[serde(default)]
+    pub include_keys: bool,
+    pub public_key: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct CleanReportsRequest {
+    pub older_than_sec: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct CleanReportsResponse {
+    pub removed: usize,
+}
+
 #[derive(Deserialize)]
 pub struct ReportRequest {
     pub id: String,
+    pub format: Option<ReportFormat>,
+}
+
+#[derive(Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ReportFormat {
+    Json,
+    Csv,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -43,6 +273,8 @@ pub struct ReportResponse {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub status: Option<ReportStatus>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub progress: Option<ReportProgress>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub report: Option<Report>,
 }
 
@@ -52,19 +284,134 @@ pub struct GenerateAddressResponse {
     pub address: String,
 }
 
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GenerateAddressesResponse {
+    pub addresses: Vec<IndexedAddress>,
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct TransferRequest {
+    pub transaction_id: Option<String>,
+    pub account_id: String,
+    // either amount and to or outputs must be set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub amount: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub to: Option<String>,
+    #[serde(alias = "recipients", skip_serializing_if = "Option::is_none")]
+    pub outputs: Option<Vec<TransferOutputRequest>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub callback_url: Option<String>,
+    // transfers the maximum amount to `to`, amount must not be set
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub sweep: bool,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct TransferOutputRequest {
+    pub to: String,
+    pub amount: u64,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct WithdrawRequest {
     pub transaction_id: Option<String>,
     pub account_id: String,
     pub amount: u64,
     pub to: String,
+    pub native_amount: Option<u64>,
+    pub callback_url: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TransfersBySupportIdRequest {
+    pub support_id: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SupportIdTransfer {
+    pub transaction_id: String,
+    #[serde(flatten)]
+    pub status: TransactionStatusResponse,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FailedTransfer {
+    pub transaction_id: String,
+    pub part_id: String,
+    pub account_id: String,
+    pub attempt: u32,
+    pub timestamp: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub failure_reason: Option<String>,
+}
+
+impl From<TransferPart> for FailedTransfer {
+    fn from(part: TransferPart) -> Self {
+        FailedTransfer {
+            transaction_id: part.transaction_id,
+            part_id: part.id,
+            account_id: part.account_id,
+            attempt: part.attempt,
+            timestamp: part.timestamp,
+            failure_reason: part.status.failure_reason(),
+        }
+    }
+}
+
+// Report without the accounts, returned by /reports
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReportSummary {
+    pub id: String,
+    pub status: ReportStatus,
+    pub created_at: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pool_index: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub progress: Option<ReportProgress>,
+}
+
+impl ReportSummary {
+    pub fn new(id: Uuid, task: ReportTask) -> Self {
+        ReportSummary {
+            id: id.as_hyphenated().to_string(),
+            status: task.status,
+            created_at: task.created_at,
+            timestamp: task.report.as_ref().map(|report| report.timestamp),
+            pool_index: task.report.as_ref().map(|report| report.pool_index),
+            progress: task.progress,
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TransferCallback {
+    pub transaction_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub support_id: Option<String>,
+    #[serde(flatten)]
+    pub status: TransactionStatusResponse,
 }
 
 #[derive(Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct TransferResponse {
     pub transaction_id: String,
+    // the total amount of the outputs, for a sweep it is the resolved maximum
+    #[serde(default)]
+    pub amount: u64,
 }
 
 #[derive(Deserialize, Serialize)]
@@ -73,31 +420,49 @@ pub struct TransactionStatusRequest {
     pub transaction_id: String,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TransactionTraceRequest {
+    pub transaction_id: String,
+    pub status: Option<String>,
+    pub offset: Option<usize>,
+    pub limit: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct CalculateFeeRequest {
     pub account_id: String,
     pub amount: u64,
+    // shielded address of the receiver, it is validated like in /transfer
+    pub to: Option<String>,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct CalculateFeeResponse {
     pub transaction_count: u64,
     pub total_fee: u64,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ExportKeyResponse {
     pub sk: String,
 }
 
-#[derive(Serialize)]
+#[derive(Deserialize)]
+pub struct FaultRuleRequest {
+    pub site: String,
+    pub rule: Option<FaultRule>,
+}
+
+#[derive(Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct HistoryRecord {
     pub tx_type: HistoryTxType,
-    pub tx_hash: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tx_hash: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub linked_tx_hashes: Option<Vec<String>>,
     pub timestamp: u64,
@@ -108,33 +473,60 @@ pub struct HistoryRecord {
     pub to: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub transaction_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub change: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub counterparty_account_id: Option<String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub pending: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<String>,
 }
 
 impl HistoryRecord {
     pub fn prepare_records(txs: Vec<CloudHistoryTx>) -> Vec<HistoryRecord> {
+        // aggregations are linked by transaction_id only, each aggregation tx is counted once
+        let mut linked = HashMap::<&str, Vec<&CloudHistoryTx>>::new();
+        for tx in txs.iter().filter(|tx| tx.tx_type == HistoryTxType::AggregateNotes) {
+            if let Some(transaction_id) = tx.transaction_id.as_deref() {
+                let linked_txs = linked.entry(transaction_id).or_default();
+                if !linked_txs.iter().any(|linked_tx| linked_tx.tx_hash == tx.tx_hash) {
+                    linked_txs.push(tx);
+                }
+            }
+        }
+
+        // a transaction can produce several records, aggregation fees are added to the first one
+        let mut fee_attributed = HashSet::new();
+
+        // aggregations are folded into the transfer they belong to, fee-only records stay visible
         txs.iter()
             .filter(|tx| tx.tx_type != HistoryTxType::AggregateNotes)
             .map(|tx| {
                 let fee = (tx.tx_type != HistoryTxType::TransferIn
-                    && tx.tx_type != HistoryTxType::DirectDeposit)
+                    && tx.tx_type != HistoryTxType::DirectDeposit
+                    && tx.tx_type != HistoryTxType::Pending)
                     .then_some(tx.fee);
 
                 match tx.transaction_id.clone() {
                     Some(transaction_id) => {
-                        let linked_txs = txs
-                            .iter()
-                            .filter(|tx| tx.transaction_id.as_ref() == Some(&transaction_id))
-                            .filter(|tx| tx.tx_type == HistoryTxType::AggregateNotes);
+                        let linked_txs = linked
+                            .get(transaction_id.as_str())
+                            .map(|linked_txs| linked_txs.as_slice())
+                            .unwrap_or_default();
 
                         let linked_tx_hashes = linked_txs
-                            .clone()
-                            .map(|linked_tx| linked_tx.tx_hash.clone())
+                            .iter()
+                            .filter_map(|linked_tx| linked_tx.tx_hash.clone())
                             .collect::<Vec<_>>();
 
                         let linked_tx_hashes =
                             (!linked_tx_hashes.is_empty()).then_some(linked_tx_hashes);
 
-                        let fee = fee.map(|fee| fee + linked_txs.map(|tx| tx.fee).sum::<u64>());
+                        let fee = fee.map(|fee| match fee_attributed.insert(transaction_id.clone()) {
+                            true => fee + linked_txs.iter().map(|tx| tx.fee).sum::<u64>(),
+                            false => fee,
+                        });
 
                         HistoryRecord {
                             tx_type: tx.tx_type.clone(),
@@ -145,6 +537,10 @@ impl HistoryRecord {
                             amount: tx.amount,
                             to: tx.to.clone(),
                             transaction_id: Some(transaction_id),
+                            change: tx.change,
+                            counterparty_account_id: tx.counterparty_account_id.clone(),
+                            pending: tx.pending,
+                            status: tx.status.clone(),
                         }
                     }
                     None => HistoryRecord {
@@ -156,6 +552,10 @@ impl HistoryRecord {
                         amount: tx.amount,
                         to: tx.to.clone(),
                         transaction_id: None,
+                        change: tx.change,
+                        counterparty_account_id: tx.counterparty_account_id.clone(),
+                        pending: tx.pending,
+                        status: tx.status.clone(),
                     },
                 }
             })
@@ -163,7 +563,7 @@ impl HistoryRecord {
     }
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct TransactionStatusResponse {
     pub status: String,
@@ -174,6 +574,8 @@ pub struct TransactionStatusResponse {
     pub linked_tx_hashes: Option<Vec<String>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub failure_reason: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pool_index: Option<u64>,
 }
 
 impl TransactionStatusResponse {
@@ -193,10 +595,11 @@ impl TransactionStatusResponse {
             let last = parts.last().unwrap();
             match last.status {
                 TransferStatus::Done => (TransferStatus::Done.status(), last.timestamp, None),
-                TransferStatus::Failed(_) => {
+                // the transfer is cancelled or failed because of the first part that didn't succeed
+                TransferStatus::Failed(_) | TransferStatus::Cancelled => {
                     let first_failed_part = &(*parts
                         .iter()
-                        .find(|job| matches!(job.status, TransferStatus::Failed(_)))
+                        .find(|job| matches!(job.status, TransferStatus::Failed(_) | TransferStatus::Cancelled))
                         .unwrap())
                     .clone();
 
@@ -223,12 +626,130 @@ impl TransactionStatusResponse {
             }
         };
 
+        let pool_index = parts.last().and_then(|part| part.pool_index);
+
         TransactionStatusResponse {
             status,
             timestamp,
             tx_hash,
             linked_tx_hashes,
             failure_reason,
+            pool_index,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{HistoryRecord, HistoryRequest, ImportRequestItem, SignupRequest};
+    use crate::{account::history::HistoryTxType, cloud::types::CloudHistoryTx};
+
+    fn history_tx(tx_type: HistoryTxType, tx_hash: &str, transaction_id: Option<&str>, amount: u64, fee: u64) -> CloudHistoryTx {
+        CloudHistoryTx {
+            tx_type,
+            tx_hash: Some(tx_hash.to_string()),
+            timestamp: 1,
+            amount,
+            fee,
+            to: None,
+            transaction_id: transaction_id.map(|id| id.to_string()),
+            change: None,
+            counterparty_account_id: None,
+            pending: false,
+            status: None,
+        }
+    }
+
+    #[test]
+    fn aggregation_fees_are_attributed_once_per_transaction_id() {
+        let records = HistoryRecord::prepare_records(vec![
+            history_tx(HistoryTxType::AggregateNotes, "0xa1", Some("first"), 50, 5),
+            history_tx(HistoryTxType::AggregateNotes, "0xa2", Some("first"), 50, 5),
+            // the same aggregation tx recorded for both transfers
+            history_tx(HistoryTxType::AggregateNotes, "0xa2", Some("second"), 50, 5),
+            history_tx(HistoryTxType::AggregateNotes, "0xa2", Some("first"), 50, 5),
+            history_tx(HistoryTxType::TransferOut, "0x1", Some("first"), 100, 5),
+            history_tx(HistoryTxType::TransferOut, "0x1", Some("first"), 20, 5),
+            history_tx(HistoryTxType::TransferOut, "0x2", Some("second"), 40, 5),
+        ]);
+        assert_eq!(records.len(), 3);
+
+        let fees = records.iter().map(|record| record.fee.unwrap()).collect::<Vec<_>>();
+        // the linked fees are added to the first record of a transfer only
+        assert_eq!(fees, vec![5 + 10, 5, 5 + 5]);
+        assert_eq!(records[0].linked_tx_hashes, Some(vec!["0xa1".to_string(), "0xa2".to_string()]));
+        assert_eq!(records[2].linked_tx_hashes, Some(vec!["0xa2".to_string()]));
+    }
+
+    #[test]
+    fn fee_only_records_stay_visible() {
+        let records = HistoryRecord::prepare_records(vec![
+            history_tx(HistoryTxType::FeeOnly, "0x1", None, 0, 5),
+            history_tx(HistoryTxType::FeeOnly, "0x2", Some("transfer"), 0, 5),
+            history_tx(HistoryTxType::ReturnedChange, "0x3", Some("self"), 30, 5),
+        ]);
+        assert_eq!(records.len(), 3);
+        assert!(records[0].tx_type == HistoryTxType::FeeOnly);
+        assert_eq!(records[0].fee, Some(5));
+        assert!(records[1].tx_type == HistoryTxType::FeeOnly);
+        assert_eq!(records[1].fee, Some(5));
+        // a self-transfer pays its fee once
+        assert!(records[2].tx_type == HistoryTxType::ReturnedChange);
+        assert_eq!((records[2].amount, records[2].fee), (30, Some(5)));
+    }
+
+    #[test]
+    fn tx_types_are_a_comma_separated_list() {
+        let request: HistoryRequest =
+            serde_json::from_str(r#"{"id": "1", "txType": "Deposit,Withdrawal"}"#).unwrap();
+        assert!(request.tx_types == vec![HistoryTxType::Deposit, HistoryTxType::Withdrawal]);
+
+        let value = serde_json::to_value(&request).unwrap();
+        assert_eq!(value["txType"], "Deposit,Withdrawal");
+    }
+
+    #[test]
+    fn tx_types_are_optional() {
+        let request: HistoryRequest = serde_json::from_str(r#"{"id": "1"}"#).unwrap();
+        assert!(request.tx_types.is_empty());
+
+        let value = serde_json::to_value(&request).unwrap();
+        assert!(value.get("txType").is_none());
+    }
+
+    #[test]
+    fn unknown_tx_type_is_rejected() {
+        let request = serde_json::from_str::<HistoryRequest>(r#"{"id": "1", "txType": "Deposit,Unknown"}"#);
+        assert!(request.is_err());
+    }
+
+    #[test]
+    fn import_item_keeps_tags_and_metadata() {
+        let item = json!({
+            "id": "4ab0ea2c-dc70-48f3-8160-980d4f1fed94",
+            "description": "AllFi",
+            "sk": "00",
+            "tags": ["merchant", "eu"],
+            "metadata": {"customer": 42, "nested": {"a": [1, 2]}},
+        });
+        let parsed: ImportRequestItem = serde_json::from_value(item.clone()).unwrap();
+        assert_eq!(parsed.attributes.tags, vec!["merchant", "eu"]);
+        assert_eq!(parsed.attributes.metadata, Some(json!({"customer": 42, "nested": {"a": [1, 2]}})));
+        // an exported item is imported as is
+        assert_eq!(serde_json::to_value(&parsed).unwrap(), item);
+    }
+
+    #[test]
+    fn tags_and_metadata_are_optional() {
+        let request: SignupRequest = serde_json::from_str(r#"{"description": "Bob"}"#).unwrap();
+        assert!(request.attributes.tags.is_empty());
+        assert!(request.attributes.metadata.is_none());
+
+        let item: ImportRequestItem = serde_json::from_str(r#"{"id": "1", "description": "Bob", "sk": "00"}"#).unwrap();
+        let value = serde_json::to_value(&item).unwrap();
+        assert!(value.get("tags").is_none());
+        assert!(value.get("metadata").is_none());
+    }
+}
@@ -1,13 +1,16 @@
 use memo_parser::calldata::{ParsedCalldata, CalldataContent, transact::memo::TxType};
 use serde::{Serialize, Deserialize};
 use tokio::sync::RwLock;
-use web3::types::H256;
+use web3::{signing::keccak256, transports::Http, types::{TransactionId, H256, U256}, Web3};
 use zkbob_utils_rs::{contracts::{pool::Pool, dd::DdContract}, tracing};
 
-use crate::errors::CloudError;
+use crate::{errors::CloudError, helpers::fault::FaultInjector};
 
 use super::db::Db;
 
+// emitted by the pool for every transaction, the first indexed argument is the pool index
+const MESSAGE_EVENT: &str = "Message(uint256,bytes32,bytes)";
+
 #[derive(Serialize, Deserialize, Debug)]
 pub enum TxWeb3Info {
     Deposit(u64, u64, i128),
@@ -15,25 +18,100 @@ pub enum TxWeb3Info {
     Withdrawal(u64, u64, i128),
     DepositPermittable(u64, u64, i128),
     DirectDeposit(u64, u64),
+    // the web3 node doesn't see the mined transaction yet, it is never cached
+    Pending,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ReceiptStatus {
+    Pending,
+    // the number of the block that includes the transaction
+    Mined(u64),
+    Reverted(u64),
 }
 
 pub struct CachedWeb3Client {
     pool: Pool,
     dd: DdContract,
     db: RwLock<Db>,
+    // plain rpc client for calls that are not related to the pool contract
+    web3: Web3<Http>,
+    faults: FaultInjector,
 }
 
 impl CachedWeb3Client {
-    pub async fn new(pool: Pool, db_path: &str) -> Result<Self, CloudError> {
+    pub async fn new(pool: Pool, provider_endpoint: &str, db_path: &str, faults: FaultInjector) -> Result<Self, CloudError> {
         let db = Db::new(db_path)?;
         let dd = pool.dd_contract().await?;
+        let transport = Http::new(provider_endpoint).map_err(|err| {
+            CloudError::ConfigError(format!("failed to init web3 transport: {}", err))
+        })?;
         Ok(CachedWeb3Client {
             pool,
             dd,
             db: RwLock::new(db),
+            web3: Web3::new(transport),
+            faults,
         })
     }
 
+    pub async fn block_number(&self) -> Result<u64, CloudError> {
+        self.faults.inject("web3.block_number").await?;
+        let block_number = self.web3.eth().block_number().await.map_err(|err| {
+            tracing::warn!("failed to fetch block number: {}", err);
+            CloudError::Web3Error
+        })?;
+        Ok(block_number.as_u64())
+    }
+
+    // A transaction that is not mined yet or was reorged out has no receipt
+    pub async fn get_receipt_status(&self, tx_hash: &str) -> Result<ReceiptStatus, CloudError> {
+        self.faults.inject("web3.receipt").await?;
+        let tx_hash: H256 = H256::from_slice(&hex::decode(tx_hash.trim_start_matches("0x"))?);
+        let receipt = self.web3.eth().transaction_receipt(tx_hash).await.map_err(|err| {
+            tracing::warn!("failed to fetch receipt of {:?}: {}", tx_hash, err);
+            CloudError::Web3Error
+        })?;
+        let receipt = match receipt {
+            Some(receipt) => receipt,
+            None => return Ok(ReceiptStatus::Pending),
+        };
+        let block_number = match receipt.block_number {
+            Some(block_number) => block_number.as_u64(),
+            None => return Ok(ReceiptStatus::Pending),
+        };
+        // status is 0 for reverted transactions, it is missing only before byzantium
+        match receipt.status.map(|status| status.as_u64()) {
+            Some(0) => Ok(ReceiptStatus::Reverted(block_number)),
+            _ => Ok(ReceiptStatus::Mined(block_number)),
+        }
+    }
+
+    // None if the transaction is not mined yet or its receipt has no pool message
+    pub async fn pool_index(&self, tx_hash: &str) -> Result<Option<u64>, CloudError> {
+        self.faults.inject("web3.receipt").await?;
+        let tx_hash: H256 = H256::from_slice(&hex::decode(tx_hash.trim_start_matches("0x"))?);
+        let receipt = self.web3.eth().transaction_receipt(tx_hash).await.map_err(|err| {
+            tracing::warn!("failed to fetch receipt of {:?}: {}", tx_hash, err);
+            CloudError::Web3Error
+        })?;
+        let topics = receipt
+            .map(|receipt| receipt.logs.into_iter().map(|log| log.topics).collect::<Vec<_>>())
+            .unwrap_or_default();
+        Ok(message_pool_index(&topics))
+    }
+
+    // The node knows a transaction that is mined or still waits in its mempool
+    pub async fn is_known(&self, tx_hash: &str) -> Result<bool, CloudError> {
+        self.faults.inject("web3.transaction").await?;
+        let tx_hash: H256 = H256::from_slice(&hex::decode(tx_hash.trim_start_matches("0x"))?);
+        let tx = self.web3.eth().transaction(TransactionId::Hash(tx_hash)).await.map_err(|err| {
+            tracing::warn!("failed to fetch transaction {:?}: {}", tx_hash, err);
+            CloudError::Web3Error
+        })?;
+        Ok(tx.is_some())
+    }
+
     pub async fn get_web3_info(&self, tx_hash: &str) -> Result<TxWeb3Info, CloudError> {
         let info = {
             self.db.read().await.get_web3(tx_hash)
@@ -42,6 +120,10 @@ impl CachedWeb3Client {
             Some(info) => Ok(info),
             None => {
                 let info = self.fetch_web3_info(tx_hash).await?;
+                if let TxWeb3Info::Pending = info {
+                    tracing::debug!("transaction {} is not visible to the web3 node yet", &tx_hash);
+                    return Ok(info);
+                }
                 if let Err(err) = self.db.write().await.save_web3(tx_hash, &info) {
                     tracing::warn!("failed to save web3 info for tx_hash: {}: {}", &tx_hash, err);
                 }
@@ -51,15 +133,17 @@ impl CachedWeb3Client {
     }
     
     async fn fetch_web3_info(&self, tx_hash: &str) -> Result<TxWeb3Info, CloudError> {
+        self.faults.inject("web3.fetch").await?;
         let tx_hash: H256 = H256::from_slice(&hex::decode(&tx_hash[2..])?);
-        let tx = self.pool
-            .get_transaction(tx_hash)
-            .await?
-            .ok_or(CloudError::InternalError(
-                "transaction not found".to_string(),
-            ))?;
+        let tx = match self.pool.get_transaction(tx_hash).await? {
+            Some(tx) => tx,
+            None => return Ok(TxWeb3Info::Pending),
+        };
 
-        let block_number = tx.block_number.ok_or(CloudError::Web3Error)?;
+        let block_number = match tx.block_number {
+            Some(block_number) => block_number,
+            None => return Ok(TxWeb3Info::Pending),
+        };
         let timestamp = self.pool
             .block_timestamp(block_number)
             .await?
@@ -86,4 +170,46 @@ impl CachedWeb3Client {
             _ => Err(CloudError::InternalError("unknown tx".to_string())),
         }
     }
-}
\ No newline at end of file
+}
+// Takes the topics of the receipt logs, indexed arguments are stored as big-endian words
+fn message_pool_index(logs: &[Vec<H256>]) -> Option<u64> {
+    let topic = H256::from(keccak256(MESSAGE_EVENT.as_bytes()));
+    logs.iter()
+        .find(|topics| topics.first() == Some(&topic))
+        .and_then(|topics| topics.get(1))
+        .map(|index| U256::from_big_endian(index.as_bytes()))
+        .filter(|index| *index <= U256::from(u64::MAX))
+        .map(|index| index.as_u64())
+}
+
+#[cfg(test)]
+mod tests {
+    use web3::{signing::keccak256, types::H256};
+
+    use super::{message_pool_index, MESSAGE_EVENT};
+
+    fn word(value: u64) -> H256 {
+        H256::from_low_u64_be(value)
+    }
+
+    #[test]
+    fn pool_index_is_read_from_message_event() {
+        let message = H256::from(keccak256(MESSAGE_EVENT.as_bytes()));
+        let transfer = H256::from(keccak256("Transfer(address,address,uint256)".as_bytes()));
+        let logs = vec![
+            vec![transfer, word(1), word(2)],
+            vec![message, word(1152), word(7)],
+        ];
+        assert_eq!(message_pool_index(&logs), Some(1152));
+    }
+
+    #[test]
+    fn pool_index_is_missing_without_message_event() {
+        let transfer = H256::from(keccak256("Transfer(address,address,uint256)".as_bytes()));
+        assert_eq!(message_pool_index(&[vec![transfer, word(1152)]]), None);
+        assert_eq!(message_pool_index(&[]), None);
+        let message = H256::from(keccak256(MESSAGE_EVENT.as_bytes()));
+        assert_eq!(message_pool_index(&[vec![message]]), None);
+        assert_eq!(message_pool_index(&[vec![message, H256::repeat_byte(0xff)]]), None);
+    }
+}